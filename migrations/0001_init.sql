@@ -0,0 +1,28 @@
+-- tables of the optional D1 backend, see src/storage.rs.
+-- apply with `wrangler d1 migrations apply beacon`.
+CREATE TABLE IF NOT EXISTS users (
+    uuid TEXT PRIMARY KEY,
+    psk TEXT,
+    path TEXT
+);
+
+CREATE TABLE IF NOT EXISTS usage (
+    uuid TEXT PRIMARY KEY,
+    bytes_up INTEGER NOT NULL DEFAULT 0,
+    bytes_down INTEGER NOT NULL DEFAULT 0,
+    sessions INTEGER NOT NULL DEFAULT 0,
+    last_seen INTEGER NOT NULL DEFAULT 0,
+    expires_at INTEGER,
+    expiry_notified INTEGER NOT NULL DEFAULT 0
+);
+
+-- daily rollups, `day` is the utc date as yyyy-mm-dd
+CREATE TABLE IF NOT EXISTS usage_daily (
+    day TEXT NOT NULL,
+    uuid TEXT NOT NULL,
+    bytes_up INTEGER NOT NULL DEFAULT 0,
+    bytes_down INTEGER NOT NULL DEFAULT 0,
+    sessions INTEGER NOT NULL DEFAULT 0,
+    last_seen INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (day, uuid)
+);
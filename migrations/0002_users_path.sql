@@ -0,0 +1,2 @@
+-- secret tunnel paths are looked up by value, see src/users.rs.
+CREATE UNIQUE INDEX IF NOT EXISTS users_path ON users (path);
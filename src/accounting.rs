@@ -0,0 +1,189 @@
+use crate::alert::Alerter;
+use crate::storage::{Storage, Store};
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+use worker::*;
+
+static QUOTA_THRESHOLDS: [u64; 2] = [80, 100]; // percent
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Usage {
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub sessions: u64,
+    pub last_seen: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub expiry_notified: bool,
+}
+
+impl Usage {
+    pub fn total(&self) -> u64 {
+        self.bytes_up + self.bytes_down
+    }
+}
+
+// utc calendar day of a unix timestamp in millis, as yyyy-mm-dd.
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn day_of(millis: u64) -> String {
+    let days = (millis / 86_400_000) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub async fn usage(storage: &Storage, uuid: &Uuid) -> Result<Usage> {
+    storage.usage(uuid).await
+}
+
+// quota percentages passed when usage went from `before` to `after` bytes
+pub fn crossed_thresholds(before: u64, after: u64, quota: u64) -> Vec<u64> {
+    QUOTA_THRESHOLDS
+        .iter()
+        .copied()
+        .filter(|pct| {
+            let threshold = quota / 100 * pct;
+            before < threshold && after >= threshold
+        })
+        .collect()
+}
+
+// adds finished sessions to the user's totals and the day's record. d1 adds
+// them in one batch, on kv concurrent sessions of the same user may race and
+// lose an update unless they're aggregated by the EVENTS consumer first.
+// errors only when the totals didn't land, so a retry never counts twice.
+pub async fn record(
+    storage: &Storage,
+    uuid: &Uuid,
+    bytes_up: u64,
+    bytes_down: u64,
+    sessions: u64,
+    quota: Option<u64>,
+    alerter: Option<&Alerter>,
+) -> Result<Usage> {
+    let delta = Usage { bytes_up, bytes_down, sessions, last_seen: Date::now().as_millis(), ..Usage::default() };
+    let mut usage = storage.add_usage(&day_of(delta.last_seen), uuid, &delta).await?;
+    let before = usage.total().saturating_sub(delta.total());
+
+    if let Some(quota) = quota {
+        let events = crossed_thresholds(before, usage.total(), quota)
+            .into_iter()
+            .map(|pct| (format!("quota-{}:{}", pct, uuid), format!("beacon: {} used {}% of its quota", uuid, pct)))
+            .collect();
+        notify(storage, uuid, &usage, Some(quota), alerter, events).await;
+    }
+    // the bytes are counted by now, a retry would count them twice
+    if let Err(e) = check_expiry(storage, uuid, &mut usage, delta.last_seen, quota, alerter).await {
+        console_log!("[accounting]: expiry of {} failed: {}", uuid, e);
+    }
+
+    Ok(usage)
+}
+
+// notifies once per expiry date when `now` is past it. runs when a tunnel is
+// refused at handshake too, an expired account never ends a session.
+pub async fn check_expiry(
+    storage: &Storage,
+    uuid: &Uuid,
+    usage: &mut Usage,
+    now: u64,
+    quota: Option<u64>,
+    alerter: Option<&Alerter>,
+) -> Result<()> {
+    if usage.expires_at.is_some_and(|at| now >= at) && !usage.expiry_notified {
+        usage.expiry_notified = true;
+        storage.put_expiry(uuid, usage.expires_at, true).await?;
+        let event = (format!("expired:{}", uuid), format!("beacon: {} has expired", uuid));
+        notify(storage, uuid, usage, quota, alerter, vec![event]).await;
+    }
+    Ok(())
+}
+
+// sends each (event, message) with a snapshot of the user's usage
+async fn notify(
+    storage: &Storage,
+    uuid: &Uuid,
+    usage: &Usage,
+    quota: Option<u64>,
+    alerter: Option<&Alerter>,
+    events: Vec<(String, String)>,
+) {
+    let Some(alerter) = alerter else { return };
+    let snapshot = json!({
+        "uuid": uuid.to_string(),
+        "bytes_up": usage.bytes_up,
+        "bytes_down": usage.bytes_down,
+        "sessions": usage.sessions,
+        "quota": quota,
+        "expires_at": usage.expires_at,
+    });
+    for (event, message) in events {
+        if let Err(e) = alerter.send(&storage.kv, &event, &message, snapshot.clone()).await {
+            console_log!("[accounting]: alert failed: {}", e);
+        }
+    }
+}
+
+// per user and day records between `from` and `to` (yyyy-mm-dd, inclusive)
+pub async fn daily_usage(storage: &Storage, from: &str, to: &str) -> Result<Vec<(String, Uuid, Usage)>> {
+    storage.daily_usage(from, to).await
+}
+
+// bytes in both directions count against the quota
+pub fn quota_remaining(usage: &Usage, quota: Option<u64>) -> Option<u64> {
+    quota.map(|quota| quota.saturating_sub(usage.total()))
+}
+
+// why a user may not open another tunnel, if anything stops them
+pub fn denied(usage: &Usage, quota: Option<u64>, now: u64) -> Option<&'static str> {
+    if usage.expires_at.is_some_and(|at| now >= at) {
+        return Some("account expired");
+    }
+    if quota_remaining(usage, quota) == Some(0) {
+        return Some("quota exhausted");
+    }
+    None
+}
+
+// None clears the expiry, a new date re-arms the expiry notification
+pub async fn set_expiry(storage: &Storage, uuid: &Uuid, expires_at: Option<u64>) -> Result<()> {
+    storage.put_expiry(uuid, expires_at, false).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_day_of() {
+        assert_eq!(day_of(0), "1970-01-01");
+        assert_eq!(day_of(1_716_422_400_000), "2024-05-23");
+        assert_eq!(day_of(951_782_400_000), "2000-02-29");
+    }
+
+    #[test]
+    fn test_crossed_thresholds() {
+        assert_eq!(crossed_thresholds(0, 50, 100), Vec::<u64>::new());
+        assert_eq!(crossed_thresholds(50, 85, 100), vec![80]);
+        assert_eq!(crossed_thresholds(79, 100, 100), vec![80, 100]);
+        assert_eq!(crossed_thresholds(100, 200, 100), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn test_denied() {
+        let usage = Usage { bytes_up: 60, bytes_down: 40, expires_at: Some(1000), ..Usage::default() };
+        assert_eq!(denied(&usage, None, 999), None);
+        assert_eq!(denied(&usage, None, 1000), Some("account expired"));
+        assert_eq!(denied(&usage, Some(100), 999), Some("quota exhausted"));
+        assert_eq!(denied(&usage, Some(101), 999), None);
+    }
+}
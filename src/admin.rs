@@ -0,0 +1,79 @@
+// management operations shared by the admin rest api and the telegram bot.
+use crate::common::secure;
+use crate::editor::Editor;
+use crate::{limiter, metrics, proxylist, registry};
+
+use serde_json::{json, Value};
+use uuid::Uuid;
+use worker::*;
+
+static BANS_KEY: &str = "admin:bans";
+
+// admin endpoints are disabled unless the ADMIN_TOKEN secret is set
+pub fn is_authorized(req: &Request, env: &Env) -> Result<bool> {
+    let Ok(expected) = env.secret("ADMIN_TOKEN") else {
+        return Ok(false);
+    };
+    let token = req.headers().get("Authorization")?;
+    Ok(token
+        .as_deref()
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .is_some_and(|token| secure::ct_eq(token, expected.to_string())))
+}
+
+pub async fn stats(kv: &kv::KvStore) -> Result<Value> {
+    Ok(json!({
+        "active_tunnels": limiter::active_tunnels(),
+        "errors": metrics::error_counts(kv).await?,
+    }))
+}
+
+pub async fn proxies(kv: &kv::KvStore, country: &str) -> Result<Vec<proxylist::Proxy>> {
+    let proxy_kv = proxylist::load(kv).await?;
+    Ok(proxy_kv.get(&country.to_uppercase()).cloned().unwrap_or_default())
+}
+
+// the whole list in the v2 schema, which is how a list still in the old
+// shape gets migrated
+pub async fn proxy_list(kv: &kv::KvStore) -> Result<Value> {
+    Ok(proxylist::to_v2(&proxylist::load(kv).await?))
+}
+
+// the edited list goes too, the next edit starts from github's again
+pub async fn purge(env: &Env) -> Result<()> {
+    proxylist::purge(&env.kv("library")?).await?;
+    if let Some(editor) = Editor::from_env(env) {
+        editor.reset().await?;
+    }
+    Ok(())
+}
+
+pub async fn banned_uuids(kv: &kv::KvStore) -> Result<Vec<Uuid>> {
+    let bans: Vec<String> = kv.get(BANS_KEY).json().await?.unwrap_or_default();
+    Ok(bans.iter().filter_map(|x| Uuid::parse_str(x).ok()).collect())
+}
+
+// returns false when the uuid was already banned
+pub async fn ban(kv: &kv::KvStore, uuid: Uuid) -> Result<bool> {
+    let mut bans = banned_uuids(kv).await?;
+    if bans.contains(&uuid) {
+        return Ok(false);
+    }
+    bans.push(uuid);
+    let bans: Vec<String> = bans.iter().map(|x| x.to_string()).collect();
+    kv.put(BANS_KEY, serde_json::to_string(&bans)?)?.execute().await?;
+    Ok(true)
+}
+
+fn registry(env: &Env) -> Result<ObjectNamespace> {
+    env.durable_object("REGISTRY")
+        .map_err(|_| Error::RustError("the REGISTRY durable object is not bound".to_string()))
+}
+
+pub async fn connections(env: &Env) -> Result<Vec<registry::Connection>> {
+    registry::list(&registry(env)?).await
+}
+
+pub async fn terminate(env: &Env, id: &str) -> Result<bool> {
+    registry::terminate(&registry(env)?, id).await
+}
@@ -0,0 +1,85 @@
+use crate::events::{Event, Events};
+
+use serde_json::{json, Value};
+use worker::*;
+
+static DEFAULT_ERROR_THRESHOLD: u64 = 50;
+static ALERT_COOLDOWN: u64 = 30 * 60; // 30 minutes
+
+pub struct Alerter {
+    pub url: String,
+    pub error_threshold: u64,
+    // USER_WEBHOOK_URL's alerter, see `for_users`
+    users: bool,
+    // alerts are queued instead when EVENTS is bound
+    events: Option<Events>,
+}
+
+impl Alerter {
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let url = env.secret("ALERT_WEBHOOK_URL").ok()?.to_string();
+        let error_threshold = env
+            .var("ALERT_ERROR_THRESHOLD")
+            .ok()
+            .and_then(|x| x.to_string().parse().ok())
+            .unwrap_or(DEFAULT_ERROR_THRESHOLD);
+        Some(Self { url, error_threshold, users: false, events: Events::from_env(env) })
+    }
+
+    // quota and expiry notifications may go to a separate webhook
+    pub fn for_users(env: &Env) -> Option<Self> {
+        match env.secret("USER_WEBHOOK_URL") {
+            Ok(url) => Some(Self {
+                url: url.to_string(),
+                error_threshold: DEFAULT_ERROR_THRESHOLD,
+                users: true,
+                events: Events::from_env(env),
+            }),
+            Err(_) => Self::from_env(env).map(|x| Self { users: true, ..x }),
+        }
+    }
+
+    // posts right away even with EVENTS bound, for its consumer
+    pub fn direct(self) -> Self {
+        Self { events: None, ..self }
+    }
+
+    // posts once per cooldown for the same `event`, the dedup marker lives in
+    // kv so isolates don't all fire for the same incident.
+    pub async fn send(&self, kv: &kv::KvStore, event: &str, message: &str, data: Value) -> Result<()> {
+        if let Some(events) = &self.events {
+            let (event, message) = (event.to_string(), message.to_string());
+            return events.send(Event::Alert { event, message, data, users: self.users }).await;
+        }
+        let dedup_key = format!("alert:{}", event);
+        if kv.get(&dedup_key).text().await?.is_some() {
+            return Ok(());
+        }
+        kv.put(&dedup_key, Date::now().as_millis().to_string())?
+            .expiration_ttl(ALERT_COOLDOWN)
+            .execute()
+            .await?;
+
+        // `content` is what discord reads, `text` is what slack reads
+        let body = json!({
+            "content": message,
+            "text": message,
+            "event": event,
+            "data": data,
+            "timestamp": Date::now().as_millis(),
+        });
+
+        let mut headers = Headers::new();
+        headers.set("Content-Type", "application/json")?;
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post)
+            .with_headers(headers)
+            .with_body(Some(body.to_string().into()));
+
+        let res = Fetch::Request(Request::new_with_init(&self.url, &init)?).send().await?;
+        if !(200..300).contains(&res.status_code()) {
+            return Err(Error::RustError(format!("webhook returned {}", res.status_code())));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+// finished tunnels as datapoints in the Analytics Engine dataset bound as
+// ANALYTICS, so where clients connect from and which networks fail can be
+// queried with sql instead of searched for in logs. workers-rs has no
+// wrapper for the binding, it's called through js.
+use crate::common::protocol::Protocol;
+use crate::config::ClientInfo;
+
+use wasm_bindgen::{JsCast, JsValue};
+use worker::js_sys::{Array, Function, Object, Reflect};
+use worker::*;
+
+pub struct Analytics {
+    dataset: Object,
+    write: Function,
+}
+
+// one finished tunnel
+pub struct Tunnel<'a> {
+    pub client: &'a ClientInfo,
+    pub protocol: Option<Protocol>,
+    // "ok", or the close reason of a failed tunnel
+    pub outcome: &'a str,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl Analytics {
+    // None unless the ANALYTICS dataset is bound
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let dataset: Object = Reflect::get(env, &"ANALYTICS".into()).ok()?.dyn_into().ok()?;
+        let write: Function = Reflect::get(&dataset, &"writeDataPoint".into()).ok()?.dyn_into().ok()?;
+        Some(Self { dataset, write })
+    }
+
+    // blobs are colo, country, asn, protocol and outcome, doubles the bytes
+    // up and down. sampled per colo.
+    pub fn write(&self, tunnel: &Tunnel) -> Result<()> {
+        let blobs: Array = [
+            tunnel.client.colo.as_str(),
+            tunnel.client.country.as_str(),
+            &tunnel.client.asn.to_string(),
+            tunnel.protocol.map_or("unknown", |x| x.name()),
+            tunnel.outcome,
+        ]
+        .iter()
+        .map(|x| JsValue::from_str(x))
+        .collect();
+        let point = Object::new();
+        Reflect::set(&point, &"indexes".into(), &Array::of1(&tunnel.client.colo.as_str().into()))?;
+        Reflect::set(&point, &"blobs".into(), &blobs)?;
+        Reflect::set(
+            &point,
+            &"doubles".into(),
+            &Array::of2(&(tunnel.bytes_up as f64).into(), &(tunnel.bytes_down as f64).into()),
+        )?;
+        self.write.call1(&self.dataset, &point)?;
+        Ok(())
+    }
+}
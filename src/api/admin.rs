@@ -0,0 +1,168 @@
+use crate::storage::Storage;
+use crate::{accounting, admin, audit, maintenance, signing};
+
+use serde_json::json;
+use uuid::Uuid;
+use worker::*;
+
+pub async fn admin_stats(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    Response::from_json(&admin::stats(&cx.kv("library")?).await?)
+}
+
+pub async fn admin_proxies(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let country = cx.param("country").cloned().unwrap_or_default();
+    Response::from_json(&admin::proxies(&cx.kv("library")?, &country).await?)
+}
+
+pub async fn admin_proxylist(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    Response::from_json(&admin::proxy_list(&cx.kv("library")?).await?)
+}
+
+pub async fn admin_purge(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    admin::purge(&cx.env).await?;
+    audit::record(&cx.env, audit::actor(&req)?, "purge", None).await;
+    Response::from_json(&json!({ "purged": true }))
+}
+
+pub async fn admin_ban(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let Some(uuid) = cx.param("uuid").and_then(|x| Uuid::parse_str(x).ok()) else {
+        return Response::error("invalid uuid", 400);
+    };
+    let kv = cx.kv("library")?;
+    let added = admin::ban(&kv, uuid).await?;
+    if added {
+        audit::record(&cx.env, audit::actor(&req)?, "ban", Some(uuid.to_string())).await;
+    }
+    Response::from_json(&json!({ "uuid": uuid.to_string(), "added": added }))
+}
+
+// ?secs=N rejects new tunnels for N seconds, 0 ends the drain early
+pub async fn admin_drain(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let secs = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "secs")
+        .and_then(|(_, v)| v.parse::<u64>().ok());
+    let Some(secs) = secs else {
+        return Response::error("missing secs", 400);
+    };
+    let kv = cx.kv("library")?;
+    let until = maintenance::start_drain(&kv, secs).await?;
+    audit::record(&cx.env, audit::actor(&req)?, "drain", Some(format!("{}s", secs))).await;
+    Response::from_json(&json!({ "drain_until": until }))
+}
+
+// ?at=<unix millis> sets when the account expires, no `at` clears it
+pub async fn admin_expiry(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let Some(uuid) = cx.param("uuid").and_then(|x| Uuid::parse_str(x).ok()) else {
+        return Response::error("invalid uuid", 400);
+    };
+    let expires_at = match req.url()?.query_pairs().find(|(k, _)| k == "at") {
+        Some((_, at)) => match at.parse::<u64>() {
+            Ok(at) => Some(at),
+            Err(_) => return Response::error("invalid expiry", 400),
+        },
+        None => None,
+    };
+    accounting::set_expiry(&Storage::from_env(&cx.env)?, &uuid, expires_at).await?;
+    let target = match expires_at {
+        Some(at) => format!("{} at {}", uuid, at),
+        None => uuid.to_string(),
+    };
+    audit::record(&cx.env, audit::actor(&req)?, "expiry", Some(target)).await;
+    Response::from_json(&json!({ "uuid": uuid.to_string(), "expires_at": expires_at }))
+}
+
+// ?from=yyyy-mm-dd&to=yyyy-mm-dd, both default to today
+pub async fn admin_usage_csv(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let url = req.url()?;
+    let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.to_string());
+    let today = accounting::day_of(Date::now().as_millis());
+    let from = param("from").unwrap_or_else(|| today.clone());
+    let to = param("to").unwrap_or(today);
+
+    let mut csv = String::from("date,uuid,bytes_up,bytes_down,sessions\n");
+    for (day, uuid, usage) in accounting::daily_usage(&Storage::from_env(&cx.env)?, &from, &to).await? {
+        csv.push_str(&format!("{},{},{},{},{}\n", day, uuid, usage.bytes_up, usage.bytes_down, usage.sessions));
+    }
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "text/csv")?;
+    headers.set("Content-Disposition", &format!("attachment; filename=\"usage-{}-{}.csv\"", from, to))?;
+    Ok(Response::ok(csv)?.with_headers(headers))
+}
+
+pub async fn connections(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    Response::from_json(&admin::connections(&cx.env).await?)
+}
+
+pub async fn admin_terminate(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let id = cx.param("id").cloned().unwrap_or_default();
+    let terminated = admin::terminate(&cx.env, &id).await?;
+    if terminated {
+        audit::record(&cx.env, audit::actor(&req)?, "terminate", Some(id.clone())).await;
+    }
+    Response::from_json(&json!({ "id": id, "terminated": terminated }))
+}
+
+// ?limit=N latest entries, 100 by default
+pub async fn admin_audit(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let limit = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "limit")
+        .and_then(|(_, v)| v.parse::<u64>().ok())
+        .unwrap_or(audit::DEFAULT_LIMIT);
+    Response::from_json(&audit::list(&cx.kv("library")?, limit.clamp(1, 1000)).await?)
+}
+
+// a signed /sub link valid for ?ttl=N seconds, a week by default, for the
+// uuid, format, type, country and tls given alongside
+pub async fn admin_sign(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let url = req.url()?;
+    let ttl = url
+        .query_pairs()
+        .find(|(k, _)| k == "ttl")
+        .and_then(|(_, v)| v.parse::<u64>().ok())
+        .unwrap_or(signing::DEFAULT_TTL);
+    let Some((url, expires)) = signing::sign_url(&cx.env, url, signing::SUB_PATH, ttl) else {
+        return Response::error("SUB_SIGNING_KEY is not set", 404);
+    };
+    Response::from_json(&json!({ "url": url.to_string(), "expires": expires }))
+}
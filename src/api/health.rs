@@ -0,0 +1,27 @@
+use crate::health::{self, Health};
+
+use serde::Serialize;
+use worker::*;
+
+#[derive(Serialize)]
+struct Row<'a> {
+    proxy: &'a str,
+    #[serde(flatten)]
+    health: &'a Health,
+}
+
+// the stored results of the scheduled checks, ?country=SG for one pool
+pub async fn proxyhealth(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    let country = req.url()?.query_pairs().find(|(k, _)| k == "country").map(|(_, v)| v.to_uppercase());
+    let report = health::load(&cx.kv("library")?).await?;
+    let rows: Vec<_> = report
+        .proxies
+        .iter()
+        .filter(|(_, x)| country.as_ref().is_none_or(|country| x.country == *country))
+        .map(|(proxy, health)| Row { proxy, health })
+        .collect();
+
+    let mut headers = Headers::new();
+    headers.set("Cache-Control", "public, max-age=60")?;
+    Ok(Response::from_json(&rows)?.with_headers(headers))
+}
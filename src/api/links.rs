@@ -0,0 +1,20 @@
+use crate::{admin, config, signing, subscription};
+
+use worker::*;
+
+// host, paths and recommended settings for the link and sub pages. the uuid
+// is only included for the admin token or a valid /sub signature, the pages
+// pass on the query of the link they were opened with, which the signature
+// covers.
+pub async fn links(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    let host = req.url()?.host_str().unwrap_or_default().to_string();
+    let authorized = admin::is_authorized(&req, &cx.env)? || signing::is_signed(&req, &cx.env, signing::SUB_PATH)?;
+    let uuid = match authorized {
+        true => Some(config::host_uuid(&cx.env, &host)?),
+        false => None,
+    };
+    let method = cx.env.var("SHADOWSOCKS_METHOD").map(|x| x.to_string()).unwrap_or_else(|_| "none".to_string());
+    let mut res = Response::from_json(&subscription::settings(&host, uuid.as_ref(), &method))?;
+    res.headers_mut().set("Cache-Control", "no-store")?;
+    Ok(res)
+}
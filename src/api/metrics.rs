@@ -0,0 +1,35 @@
+use crate::admin;
+use crate::metrics::{dns_counts, error_counts, protocol_counts};
+use crate::proxy::dns;
+
+use serde_json::json;
+use worker::*;
+
+pub async fn metrics(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let kv = cx.kv("library")?;
+    Response::from_json(&json!({
+        "errors": error_counts(&kv).await?,
+    }))
+}
+
+pub async fn protocol_stats(_: Request, cx: RouteContext<Context>) -> Result<Response> {
+    Response::from_json(&protocol_counts(&cx.kv("library")?).await?)
+}
+
+// top domains say what users browse, admin only like the error counters
+pub async fn dns_stats(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let totals = dns_counts(&cx.kv("library")?).await?;
+    let queries = totals.get("queries").copied().unwrap_or_default();
+    let hits = totals.get("cache_hits").copied().unwrap_or_default();
+    Response::from_json(&json!({
+        "cache_hit_ratio": (queries > 0).then(|| hits as f64 / queries as f64),
+        "totals": totals,
+        "isolate": dns::isolate_stats(),
+    }))
+}
@@ -0,0 +1,20 @@
+pub mod admin;
+pub mod health;
+pub mod links;
+pub mod metrics;
+pub mod ping;
+pub mod proxylist;
+pub mod speedtest;
+pub mod subscription;
+pub mod telegram;
+pub mod usage;
+pub use admin::*;
+pub use health::*;
+pub use links::*;
+pub use metrics::*;
+pub use ping::*;
+pub use proxylist::*;
+pub use speedtest::*;
+pub use subscription::*;
+pub use telegram::*;
+pub use usage::*;
@@ -0,0 +1,18 @@
+use serde_json::json;
+use worker::*;
+
+pub async fn ping(req: Request, _: RouteContext<Context>) -> Result<Response> {
+    let cf = req.cf();
+    let client_ip = req.headers().get("CF-Connecting-IP")?;
+
+    let mut headers = Headers::new();
+    headers.set("Cache-Control", "no-store")?;
+    Ok(Response::from_json(&json!({
+        "colo": cf.map(|x| x.colo()),
+        "country": cf.and_then(|x| x.country()),
+        "client_ip": client_ip,
+        "version": env!("CARGO_PKG_VERSION"),
+        "timestamp": Date::now().as_millis(),
+    }))?
+    .with_headers(headers))
+}
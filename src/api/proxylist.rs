@@ -0,0 +1,149 @@
+use crate::proxylist::{self, Edit, EditError, Rejected};
+use crate::editor::Editor;
+use crate::{admin, audit};
+
+use serde_json::{json, Value};
+use worker::*;
+
+// the list with its revision, which goes back as If-Match on edits
+pub async fn proxylist_get(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let kv = cx.kv("library")?;
+    let (list, revision) = match Editor::from_env(&cx.env) {
+        Some(editor) => editor.load(&kv).await?,
+        None => proxylist::load_revision(&kv).await?,
+    };
+    let mut doc = proxylist::to_v2(&list);
+    doc["revision"] = json!(revision);
+    with_etag(Response::from_json(&doc)?, revision)
+}
+
+// replaces a whole country group with a v2 entry array
+pub async fn proxylist_put_country(mut req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let country = country_param(&cx);
+    let Ok(body) = req.json::<Value>().await else {
+        return Response::error("expected a json array", 400);
+    };
+    let proxies = match proxylist::parse_group(&country, &body) {
+        Ok(x) => x,
+        Err(e) => return Response::error(format!("invalid proxy list at {}", e), 400),
+    };
+    edit(&req, &cx, Edit::PutCountry(country.clone(), proxies), "proxylist put", country, None).await
+}
+
+pub async fn proxylist_delete_country(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let country = country_param(&cx);
+    edit(&req, &cx, Edit::DeleteCountry(country.clone()), "proxylist delete", country, None).await
+}
+
+// adds a v2 entry to a country, creating the group if needed
+pub async fn proxylist_add_proxy(mut req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let country = country_param(&cx);
+    if !proxylist::is_country_code(&country) {
+        return Response::error("expected a two letter country code", 400);
+    }
+    let proxy = match proxy_body(&mut req).await {
+        Ok(x) => x,
+        Err(e) => return Response::error(e, 400),
+    };
+    let target = format!("{} {}", country, proxy);
+    edit(&req, &cx, Edit::AddProxy(country, proxy), "proxylist add", target, None).await
+}
+
+// replaces the entry named by `:proxy` ("addr:port")
+pub async fn proxylist_put_proxy(mut req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let (country, key) = (country_param(&cx), cx.param("proxy").cloned().unwrap_or_default());
+    let proxy = match proxy_body(&mut req).await {
+        Ok(x) => x,
+        Err(e) => return Response::error(e, 400),
+    };
+    let target = format!("{} {} -> {}", country, key, proxy);
+    edit(&req, &cx, Edit::PutProxy(country, key, proxy), "proxylist update", target, None).await
+}
+
+pub async fn proxylist_delete_proxy(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let (country, key) = (country_param(&cx), cx.param("proxy").cloned().unwrap_or_default());
+    let target = format!("{} {}", country, key);
+    edit(&req, &cx, Edit::DeleteProxy(country, key), "proxylist remove", target, None).await
+}
+
+// merges a plain text or csv body of "addr:port#CC" lines, see
+// `proxylist::parse_import`. rejected lines are listed in the response.
+pub async fn proxylist_import(mut req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    let (imported, rejected) = proxylist::parse_import(&req.text().await?);
+    if imported.is_empty() {
+        return Ok(Response::from_json(&json!({ "error": "no valid lines", "rejected": rejected }))?.with_status(400));
+    }
+    let target = format!("{} entries, {} rejected", imported.values().map(Vec::len).sum::<usize>(), rejected.len());
+    edit(&req, &cx, Edit::Merge(imported), "proxylist import", target, Some(rejected)).await
+}
+
+fn country_param(cx: &RouteContext<Context>) -> String {
+    cx.param("country").map(|x| x.to_uppercase()).unwrap_or_default()
+}
+
+async fn proxy_body(req: &mut Request) -> std::result::Result<proxylist::Proxy, String> {
+    let body: Value = req.json().await.map_err(|_| "expected a json object".to_string())?;
+    proxylist::parse_proxy(&body).map_err(|e| format!("invalid proxy at {}", e))
+}
+
+// read, edit and swap in through the editor object, then copy to kv. an
+// If-Match revision that is no longer current means someone else edited the
+// list in between, so nothing is written. editing blind isn't allowed.
+async fn edit(req: &Request, cx: &RouteContext<Context>, edit: Edit, action: &str, target: String, rejected: Option<Vec<Rejected>>) -> Result<Response> {
+    let Some(editor) = Editor::from_env(&cx.env) else {
+        return Response::error("proxy list edits need the PROXYLIST durable object", 501);
+    };
+    let Some(expected) = req.headers().get("If-Match")? else {
+        return Response::error("send the list's ETag as If-Match", 428);
+    };
+    let kv = cx.kv("library")?;
+    let (mut list, revision) = editor.load(&kv).await?;
+    if expected.trim().trim_matches('"') != revision.to_string() {
+        return Response::error(format!("proxy list is at revision {}, reload it", revision), 412);
+    }
+    if let Err(e) = proxylist::apply(&mut list, edit) {
+        let status = match e {
+            EditError::Duplicate => 409,
+            EditError::NoSuchCountry | EditError::NoSuchProxy => 404,
+        };
+        return Response::error(e.to_string(), status);
+    }
+    if !editor.swap(revision, &list).await? {
+        return Response::error("proxy list was edited meanwhile, reload it", 412);
+    }
+    proxylist::store(&kv, &list, revision + 1).await?;
+    audit::record(&cx.env, audit::actor(req)?, action, Some(target)).await;
+    let proxies: usize = list.values().map(Vec::len).sum();
+    let mut body = json!({ "revision": revision + 1, "proxies": proxies });
+    if let Some(rejected) = rejected {
+        body["rejected"] = json!(rejected);
+    }
+    with_etag(Response::from_json(&body)?, revision + 1)
+}
+
+fn with_etag(res: Response, revision: u64) -> Result<Response> {
+    let mut headers = res.headers().clone();
+    headers.set("ETag", &format!("\"{}\"", revision))?;
+    Ok(res.with_headers(headers))
+}
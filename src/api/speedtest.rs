@@ -0,0 +1,63 @@
+use crate::admin;
+use crate::config::Config;
+
+use futures_util::{stream, StreamExt};
+use serde_json::json;
+use worker::*;
+
+static CHUNK_SIZE: usize = 64 * 1024; // 64kb
+static DEFAULT_DOWNLOAD_MB: usize = 10;
+static MAX_DOWNLOAD_MB: usize = 100;
+
+// a bandwidth source and sink anyone could point at the worker, so only for
+// admins and clients holding the tunnel token
+pub async fn speedtest(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    let config = Config::from_env(&cx.env, String::new())?;
+    let is_token_holder = config.tunnel_token.is_some() && crate::is_tunnel_authorized(&req, &config)?;
+    if !is_token_holder && !admin::is_authorized(&req, &cx.env)? {
+        return Response::error("unauthorized", 401);
+    }
+    match req.method() {
+        Method::Post | Method::Put => upload(req).await,
+        _ => download(&req),
+    }
+}
+
+fn download(req: &Request) -> Result<Response> {
+    let mb = req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == "mb")
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_DOWNLOAD_MB)
+        .clamp(1, MAX_DOWNLOAD_MB);
+    let total = mb * 1024 * 1024;
+
+    // random payload so nothing along the way can compress it away
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    getrandom::getrandom(&mut chunk).map_err(|e| Error::RustError(e.to_string()))?;
+
+    let chunks = stream::iter(0..total / CHUNK_SIZE).map(move |_| Ok::<_, Error>(chunk.clone()));
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/octet-stream")?;
+    headers.set("Content-Length", &total.to_string())?;
+    headers.set("Cache-Control", "no-store")?;
+    Ok(Response::from_stream(chunks)?.with_headers(headers))
+}
+
+async fn upload(mut req: Request) -> Result<Response> {
+    let started = Date::now().as_millis();
+    let mut body = req.stream()?;
+    let mut bytes = 0usize;
+    while let Some(chunk) = body.next().await {
+        bytes += chunk?.len();
+    }
+    let duration_ms = Date::now().as_millis().saturating_sub(started).max(1);
+
+    Response::from_json(&json!({
+        "bytes": bytes,
+        "duration_ms": duration_ms,
+        "mbps": (bytes as f64 * 8.0) / (duration_ms as f64 * 1000.0),
+    }))
+}
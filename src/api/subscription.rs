@@ -0,0 +1,37 @@
+use crate::subscription::{self, Format, Options, Protocol};
+use crate::{admin, signing};
+
+use worker::*;
+
+// /sub?format=v2ray|clash, see `subscription::Options::from_url` for the
+// rest of the query. X-Total-Count is the number of proxies over all pages.
+pub async fn subscription(req: &Request, cx: &RouteContext<Context>, format: &str) -> Result<Response> {
+    let Some(format) = Format::parse(format) else {
+        return Response::error("unknown format", 400);
+    };
+    let url = req.url()?;
+    let host = url.host_str().unwrap_or_default().to_string();
+    let mut options = match Options::from_url(&url, &host, format) {
+        Ok(x) => x,
+        Err(e) => return Response::error(e, 400),
+    };
+    // the configured password is the server's, so only the admin or a signed
+    // link gets it. anyone else gets no ss links at all.
+    if let (Ok(method), Ok(password)) = (cx.env.var("SHADOWSOCKS_METHOD"), cx.env.secret("SHADOWSOCKS_PASSWORD")) {
+        if admin::is_authorized(req, &cx.env)? || signing::is_signed(req, &cx.env, signing::SUB_PATH)? {
+            options.shadowsocks = (method.to_string(), password.to_string());
+        } else {
+            options.protocols.retain(|x| *x != Protocol::Shadowsocks);
+            if options.protocols.is_empty() {
+                return Response::error("shadowsocks links need a signed link", 403);
+            }
+        }
+    }
+
+    let page = subscription::cached(&cx.kv("library")?, &options).await?;
+    let mut headers = Headers::new();
+    headers.set("Content-Type", format.content_type())?;
+    headers.set("X-Total-Count", &page.total.to_string())?;
+    headers.set("Cache-Control", "no-store")?;
+    Ok(Response::ok(page.body)?.with_headers(headers))
+}
@@ -0,0 +1,81 @@
+use crate::{admin, audit};
+use crate::common::secure;
+
+use serde_json::{json, Value};
+use uuid::Uuid;
+use worker::*;
+
+static HELP: &str = "/stats\n/proxies <country>\n/purge\n/ban <uuid>";
+
+// telegram webhook. the reply is returned in the webhook response itself,
+// so the bot token is only needed to register the webhook, not here.
+pub async fn telegram(mut req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    let Ok(secret) = cx.env.secret("TELEGRAM_WEBHOOK_SECRET") else {
+        return Response::error("not found", 404);
+    };
+    let token = req.headers().get("X-Telegram-Bot-Api-Secret-Token")?;
+    if !token.is_some_and(|token| secure::ct_eq(token, secret.to_string())) {
+        return Response::error("unauthorized", 401);
+    }
+
+    let update: Value = req.json().await?;
+    let message = &update["message"];
+    let (Some(chat_id), Some(text)) = (message["chat"]["id"].as_i64(), message["text"].as_str()) else {
+        return Response::ok("");
+    };
+
+    // only chats listed in TELEGRAM_ADMIN_CHATS may run commands
+    let admins = cx.env.var("TELEGRAM_ADMIN_CHATS").map(|x| x.to_string()).unwrap_or_default();
+    if !admins.split(',').any(|x| x.trim() == chat_id.to_string()) {
+        return Response::ok("");
+    }
+
+    let kv = cx.kv("library")?;
+    let reply = match run_command(&cx.env, &kv, &format!("telegram:{}", chat_id), text).await {
+        Ok(reply) => reply,
+        Err(e) => format!("error: {}", e),
+    };
+
+    Response::from_json(&json!({
+        "method": "sendMessage",
+        "chat_id": chat_id,
+        "text": reply,
+    }))
+}
+
+async fn run_command(env: &Env, kv: &kv::KvStore, actor: &str, text: &str) -> Result<String> {
+    let mut args = text.split_whitespace();
+    // commands may be addressed as /stats@botname in groups
+    let command = args.next().unwrap_or_default().split('@').next().unwrap_or_default();
+
+    match command {
+        "/stats" => Ok(serde_json::to_string_pretty(&admin::stats(kv).await?)?),
+        "/proxies" => {
+            let Some(country) = args.next() else {
+                return Ok("usage: /proxies <country>".to_string());
+            };
+            let proxies = admin::proxies(kv, country).await?;
+            if proxies.is_empty() {
+                return Ok(format!("no proxies for {}", country.to_uppercase()));
+            }
+            Ok(proxies.iter().map(|x| x.to_string()).collect::<Vec<_>>().join("\n"))
+        }
+        "/purge" => {
+            admin::purge(env).await?;
+            audit::record(env, actor.to_string(), "purge", None).await;
+            Ok("proxy list cache purged".to_string())
+        }
+        "/ban" => {
+            let Some(uuid) = args.next().and_then(|x| Uuid::parse_str(x).ok()) else {
+                return Ok("usage: /ban <uuid>".to_string());
+            };
+            if admin::ban(kv, uuid).await? {
+                audit::record(env, actor.to_string(), "ban", Some(uuid.to_string())).await;
+                Ok(format!("banned {}", uuid))
+            } else {
+                Ok(format!("{} is already banned", uuid))
+            }
+        }
+        _ => Ok(HELP.to_string()),
+    }
+}
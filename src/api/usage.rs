@@ -0,0 +1,24 @@
+use crate::accounting;
+use crate::config;
+use crate::storage::Storage;
+
+use serde_json::json;
+use uuid::Uuid;
+use worker::*;
+
+pub async fn usage(_: Request, cx: RouteContext<Context>) -> Result<Response> {
+    let Some(uuid) = cx.param("uuid").and_then(|x| Uuid::parse_str(x).ok()) else {
+        return Response::error("invalid uuid", 400);
+    };
+    let usage = accounting::usage(&Storage::from_env(&cx.env)?, &uuid).await?;
+    Response::from_json(&json!({
+        "uuid": uuid.to_string(),
+        "bytes_up": usage.bytes_up,
+        "bytes_down": usage.bytes_down,
+        "sessions": usage.sessions,
+        "last_seen": usage.last_seen,
+        "quota": config::user_quota(&cx.env),
+        "quota_remaining": accounting::quota_remaining(&usage, config::user_quota(&cx.env)),
+        "expires_at": usage.expires_at,
+    }))
+}
@@ -0,0 +1,68 @@
+// append-only log of admin mutations. every entry is its own kv key, keyed
+// so that kv's lexicographic listing returns the newest first, and carries
+// itself as metadata so a listing needs no extra reads.
+use crate::common::digest;
+use crate::events::{Event, Events};
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+static AUDIT_PREFIX: &str = "audit:";
+pub static DEFAULT_LIMIT: u64 = 100;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub at: u64,
+    // who did it, see `actor`
+    pub actor: String,
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+// "token:<fingerprint>" for the rest api, the token itself is never stored.
+// the client ip tells operators sharing a token apart.
+pub fn actor(req: &Request) -> Result<String> {
+    let token = req.headers().get("Authorization")?.unwrap_or_default();
+    let fingerprint: String = digest::sha256(&[token.as_bytes()])[..4].iter().map(|x| format!("{:02x}", x)).collect();
+    let ip = req.headers().get("CF-Connecting-IP")?;
+    Ok(match ip {
+        Some(ip) => format!("token:{} ({})", fingerprint, ip),
+        None => format!("token:{}", fingerprint),
+    })
+}
+
+// the mutation already happened, so a failed write is only logged. with
+// the EVENTS queue bound the entry is written by its consumer.
+pub async fn record(env: &Env, actor: String, action: &str, target: Option<String>) {
+    let entry = Entry { at: Date::now().as_millis(), actor, action: action.to_string(), target };
+    let (action, actor) = (entry.action.clone(), entry.actor.clone());
+    let stored = match Events::from_env(env) {
+        Some(events) => events.send(Event::Audit(entry)).await,
+        None => match env.kv("library") {
+            Ok(kv) => store(&kv, &entry).await,
+            Err(e) => Err(e),
+        },
+    };
+    if let Err(e) = stored {
+        console_log!("[audit]: recording {} by {} failed: {}", action, actor, e);
+    }
+}
+
+pub async fn store(kv: &kv::KvStore, entry: &Entry) -> Result<()> {
+    let mut nonce = [0u8; 4];
+    let _ = getrandom::getrandom(&mut nonce);
+    let key = format!("{}{:020}:{}", AUDIT_PREFIX, u64::MAX - entry.at, u32::from_be_bytes(nonce));
+    kv.put(&key, serde_json::to_string(entry)?)?.metadata(entry)?.execute().await?;
+    Ok(())
+}
+
+// the latest `limit` entries, newest first
+pub async fn list(kv: &kv::KvStore, limit: u64) -> Result<Vec<Entry>> {
+    let page = kv.list().prefix(AUDIT_PREFIX.to_string()).limit(limit).execute().await?;
+    Ok(page
+        .keys
+        .into_iter()
+        .filter_map(|key| serde_json::from_value(key.metadata?).ok())
+        .collect())
+}
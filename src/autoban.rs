@@ -0,0 +1,158 @@
+use crate::config::Config;
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use worker::*;
+
+static RECORD_KEY: &str = "record";
+
+// temporarily bans client ips after AUTOBAN_THRESHOLD failed handshakes
+// (wrong credentials, protocol violations, timeouts or a bad tunnel token)
+// within AUTOBAN_WINDOW_SECS, for AUTOBAN_SECS.
+#[derive(Clone)]
+pub struct AutoBan {
+    namespace: ObjectNamespace,
+    threshold: u32,
+    window_secs: u64,
+    ban_secs: u64,
+}
+
+impl AutoBan {
+    // None unless the AUTOBAN durable object is bound
+    pub fn from_env(env: &Env) -> Option<Self> {
+        let namespace = env.durable_object("AUTOBAN").ok()?;
+        let parse = |name: &str| env.var(name).ok().and_then(|x| x.to_string().parse().ok());
+        Some(Self {
+            namespace,
+            threshold: parse("AUTOBAN_THRESHOLD").unwrap_or(10) as u32,
+            window_secs: parse("AUTOBAN_WINDOW_SECS").unwrap_or(10 * 60),
+            ban_secs: parse("AUTOBAN_SECS").unwrap_or(60 * 60),
+        })
+    }
+
+    pub async fn is_banned(&self, ip: IpAddr) -> Result<bool> {
+        let res = self.call(ip, &format!("/check?ip={}", ip)).await?;
+        Ok(res.status_code() == 403)
+    }
+
+    pub async fn record_failure(&self, ip: IpAddr) {
+        let path = format!("/fail?ip={}&threshold={}&window={}&ban={}", ip, self.threshold, self.window_secs, self.ban_secs);
+        if let Err(e) = self.call(ip, &path).await {
+            console_log!("[autoban]: recording {} failed: {}", ip, e);
+        }
+    }
+
+    // one object per client, so checks don't queue behind a single global
+    // one and each object only knows about its own failures
+    async fn call(&self, ip: IpAddr, path: &str) -> Result<Response> {
+        self.namespace
+            .id_from_name(&client_key(ip))?
+            .get_stub()?
+            .fetch_with_str(&format!("https://autoban{}", path))
+            .await
+    }
+}
+
+// what a client is banned by: its ipv4 address, or the /64 an ipv6 one is
+// in, since a single host usually gets a whole /64 to rotate through
+pub fn client_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}:{:x}:{:x}::/64", segments[0], segments[1], segments[2], segments[3])
+        }
+    }
+}
+
+// checked before the websocket upgrade, so banned clients never get a
+// tunnel. fails open: the guard being unreachable mustn't take every tunnel
+// down with it.
+pub async fn check(config: &Config) -> Option<Response> {
+    let (Some(autoban), Some(ip)) = (&config.autoban, config.client.ip) else {
+        return None;
+    };
+    match autoban.is_banned(ip).await {
+        Ok(true) => Response::error("too many failed handshakes, try again later", 403).ok(),
+        Ok(false) => None,
+        Err(e) => {
+            console_log!("[autoban]: checking {} failed, letting it through: {}", ip, e);
+            None
+        }
+    }
+}
+
+// what a guard object keeps about its client, in storage so bans outlive
+// the object being evicted
+#[derive(Default, Serialize, Deserialize)]
+struct Record {
+    failures: Vec<u64>,
+    // unix millis the ban ends at
+    banned_until: u64,
+}
+
+// one instance per client key counting its failures. an alarm clears the
+// storage once neither a ban nor failures within the window are left.
+#[durable_object]
+pub struct HandshakeGuard {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for HandshakeGuard {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        let param = |name: &str| params.get(name).and_then(|x| x.parse::<u64>().ok());
+
+        let now = Date::now().as_millis();
+        let mut storage = self.state.storage();
+        let mut record: Record = storage.get(RECORD_KEY).await.unwrap_or_default();
+
+        match url.path() {
+            "/check" if record.banned_until > now => Response::error("banned", 403),
+            "/check" => Response::ok(""),
+            "/fail" => {
+                let (threshold, window, ban) = (param("threshold").unwrap_or(10), param("window").unwrap_or(600), param("ban").unwrap_or(3600));
+                record.failures.retain(|at| now.saturating_sub(*at) < window * 1000);
+                record.failures.push(now);
+                let banned = record.failures.len() as u64 >= threshold;
+                if banned {
+                    console_log!("[autoban]: banning {} for {}s after {} failed handshakes", params.get("ip").map_or("", |x| x.as_str()), ban, record.failures.len());
+                    record.failures.clear();
+                    record.banned_until = now + ban * 1000;
+                }
+                storage.put(RECORD_KEY, &record).await?;
+                let forget_at = record.banned_until.max(now + window * 1000);
+                storage.set_alarm(Duration::from_millis(forget_at - now)).await?;
+                Response::ok(if banned { "banned" } else { "" })
+            }
+            _ => Response::error("not found", 404),
+        }
+    }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        self.state.storage().delete_all().await?;
+        Response::ok("")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_key() {
+        assert_eq!(client_key("203.0.113.7".parse().unwrap()), "203.0.113.7");
+        let a = client_key("2001:db8:1:2:aaaa::1".parse().unwrap());
+        assert_eq!(a, "2001:db8:1:2::/64");
+        assert_eq!(a, client_key("2001:db8:1:2:ffff:1:2:3".parse().unwrap()));
+        assert_ne!(a, client_key("2001:db8:1:3::1".parse().unwrap()));
+    }
+}
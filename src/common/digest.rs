@@ -0,0 +1,42 @@
+// one-shot hashes over several parts without building a temporary buffer,
+// the hashers are re-exported for incremental use.
+pub use md5::Md5;
+pub use sha2::{Digest, Sha256};
+
+pub fn md5(parts: &[&[u8]]) -> [u8; 16] {
+    hash::<Md5>(parts).into()
+}
+
+pub fn sha256(parts: &[&[u8]]) -> [u8; 32] {
+    hash::<Sha256>(parts).into()
+}
+
+// the first N bytes of a digest, as the vmess key and iv derivations use
+pub fn truncated<const N: usize>(digest: &[u8]) -> [u8; N] {
+    let mut out = [0u8; N];
+    out.copy_from_slice(&digest[..N]);
+    out
+}
+
+fn hash<D: Digest>(parts: &[&[u8]]) -> sha2::digest::Output<D> {
+    let mut hasher = D::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digests() {
+        assert_eq!(md5(&[b"ab", b"c"]), md5(&[b"abc"]));
+        assert_eq!(
+            md5(&[b"abc"]),
+            [0x90, 0x01, 0x50, 0x98, 0x3c, 0xd2, 0x4f, 0xb0, 0xd6, 0x96, 0x3f, 0x7d, 0x28, 0xe1, 0x7f, 0x72]
+        );
+        assert_eq!(truncated::<4>(&sha256(&[b"abc"])), [0xba, 0x78, 0x16, 0xbf]);
+    }
+}
@@ -0,0 +1,55 @@
+// iso 3166 country codes by continent, using the same continent codes as
+// `request.cf.continent`
+static CONTINENTS: [(&str, &str); 7] = [
+    ("AF", "AO BF BI BJ BW CD CF CG CI CM CV DJ DZ EG EH ER ET GA GH GM GN GQ GW KE KM LR LS LY MA MG ML MR MU MW MZ NA NE NG RE RW SC SD SH SL SN SO SS ST SZ TD TG TN TZ UG YT ZA ZM ZW"),
+    ("AN", "AQ BV GS HM TF"),
+    ("AS", "AE AF AM AZ BD BH BN BT CC CN CX CY GE HK ID IL IN IO IQ IR JO JP KG KH KP KR KW KZ LA LB LK MM MN MO MV MY NP OM PH PK PS QA SA SG SY TH TJ TL TM TR TW UZ VN YE"),
+    ("EU", "AD AL AT AX BA BE BG BY CH CZ DE DK EE ES FI FO FR GB GG GI GR HR HU IE IM IS IT JE LI LT LU LV MC MD ME MK MT NL NO PL PT RO RS RU SE SI SJ SK SM UA VA XK"),
+    ("NA", "AG AI AW BB BL BM BQ BS BZ CA CR CU CW DM DO GD GL GP GT HN HT JM KN KY LC MF MQ MS MX NI PA PM PR SV SX TC TT US VC VG VI"),
+    ("OC", "AS AU CK FJ FM GU KI MH MP NC NF NR NU NZ PF PG PN PW SB TK TO TV UM VU WF WS"),
+    ("SA", "AR BO BR CL CO EC FK GF GY PE PY SR UY VE"),
+];
+
+pub fn continent(country: &str) -> Option<&'static str> {
+    if country.len() != 2 {
+        return None;
+    }
+    CONTINENTS
+        .iter()
+        .find(|(_, countries)| countries.split(' ').any(|x| x.eq_ignore_ascii_case(country)))
+        .map(|(continent, _)| *continent)
+}
+
+// the country code in a geoip api response, the common field names are
+// tried so most free apis work
+pub fn country_from_json(body: &serde_json::Value) -> Option<String> {
+    ["country_code", "countryCode", "country"]
+        .iter()
+        .find_map(|key| body.get(key)?.as_str())
+        .filter(|x| x.len() == 2)
+        .map(|x| x.to_ascii_uppercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_continent() {
+        assert_eq!(continent("ID"), Some("AS"));
+        assert_eq!(continent("sg"), Some("AS"));
+        assert_eq!(continent("DE"), Some("EU"));
+        assert_eq!(continent("US"), Some("NA"));
+        assert_eq!(continent("ZZ"), None);
+        assert_eq!(continent("A"), None);
+    }
+
+    #[test]
+    fn test_country_from_json() {
+        let body = serde_json::json!({ "ip": "1.1.1.1", "country": "AU" });
+        assert_eq!(country_from_json(&body), Some("AU".to_string()));
+        let body = serde_json::json!({ "country": "Australia", "countryCode": "au" });
+        assert_eq!(country_from_json(&body), Some("AU".to_string()));
+        assert_eq!(country_from_json(&serde_json::json!({})), None);
+    }
+}
@@ -1,4 +1,5 @@
-use sha2::{Digest, Sha256};
+use super::digest::{Digest, Sha256};
+use hmac::{Hmac, Mac};
 
 trait Hasher {
     fn clone(&self) -> Box<dyn Hasher>;
@@ -40,8 +41,8 @@ impl RecursiveHash {
         let mut ipad = [0u8; 64];
         let mut opad = [0u8; 64];
 
-        ipad[..key.len()].copy_from_slice(&key);
-        opad[..key.len()].copy_from_slice(&key);
+        ipad[..key.len()].copy_from_slice(key);
+        opad[..key.len()].copy_from_slice(key);
 
         for b in ipad.iter_mut() {
             *b ^= 0x36;
@@ -68,8 +69,8 @@ impl Hasher for RecursiveHash {
     fn clone(&self) -> Box<dyn Hasher> {
         let inner = self.inner.clone();
         let outer = self.outer.clone();
-        let ipad = self.ipad.clone();
-        let opad = self.opad.clone();
+        let ipad = self.ipad;
+        let opad = self.opad;
 
         Box::new(Self {
             inner,
@@ -84,10 +85,10 @@ impl Hasher for RecursiveHash {
     }
 
     fn finalize(&mut self) -> [u8; 32] {
-        let result: [u8; 32] = self.inner.finalize().into();
+        let result: [u8; 32] = self.inner.finalize();
         self.outer.update(&self.opad);
         self.outer.update(&result);
-        self.outer.finalize().into()
+        self.outer.finalize()
     }
 }
 
@@ -97,7 +98,7 @@ pub fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
         Box::new(Sha256Hash::new()),
     ));
 
-    for p in path.into_iter() {
+    for p in path.iter() {
         current = Box::new(RecursiveHash::new(p, current));
     }
 
@@ -105,15 +106,44 @@ pub fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
     current.finalize()
 }
 
+// 32-bit fnv-1a, vmess checksums its command section with it
+pub fn fnv1a32(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, b| (hash ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+// crc-32 (ieee), vmess auth ids end with it
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, b| {
+        (0..8).fold(crc ^ *b as u32, |crc, _| (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg()))
+    })
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    // hmac accepts keys of any length, new_from_slice can't fail
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac key");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+pub fn blake3(data: &[u8]) -> [u8; 32] {
+    blake3::hash(data).into()
+}
+
+// blake3 in key derivation mode, e.g. the shadowsocks 2022 session subkey
+// is blake3_derive_key("shadowsocks 2022 session subkey", key || salt)
+pub fn blake3_derive_key(context: &str, material: &[u8]) -> [u8; 32] {
+    blake3::derive_key(context, material)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use md5::Md5;
+    use crate::common::digest;
 
     #[test]
     fn test_kdf() {
         let uuid = uuid::uuid!("96850032-1b92-46e9-a4f2-b99631456894").as_bytes();
-        let key = crate::md5!(&uuid, b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
+        let key = digest::md5(&[uuid, b"c48619fe-8f02-49e0-b9e9-edf763e17e21"]);
 
         let res = kdf(&key, &[b"AES Auth ID Encryption"]);
 
@@ -122,4 +152,42 @@ mod tests {
             [117, 82, 144, 159, 147, 65, 74, 253, 91, 74, 70, 84, 114, 118, 203, 30]
         );
     }
+
+    #[test]
+    fn test_fnv1a32() {
+        assert_eq!(fnv1a32(b""), 0x811c9dc5);
+        assert_eq!(fnv1a32(b"a"), 0xe40c292c);
+        assert_eq!(fnv1a32(b"foobar"), 0xbf9cf968);
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // rfc 4231 test case 2
+        let res = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            res,
+            [
+                0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7,
+                0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43
+            ]
+        );
+    }
+
+    #[test]
+    fn test_blake3() {
+        assert_eq!(
+            blake3(b""),
+            [
+                0xaf, 0x13, 0x49, 0xb9, 0xf5, 0xf9, 0xa1, 0xa6, 0xa0, 0x40, 0x4d, 0xea, 0x36, 0xdc, 0xc9, 0x49,
+                0x9b, 0xcb, 0x25, 0xc9, 0xad, 0xc1, 0x12, 0xb7, 0xcc, 0x9a, 0x93, 0xca, 0xe4, 0x1f, 0x32, 0x62
+            ]
+        );
+        assert_ne!(blake3_derive_key("a", b"key"), blake3_derive_key("b", b"key"));
+    }
 }
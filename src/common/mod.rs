@@ -1,8 +1,12 @@
+pub mod digest;
+pub mod geo;
 pub mod hash;
-
-use std::net::{Ipv4Addr, Ipv6Addr};
-use tokio::io::{AsyncRead, AsyncReadExt};
-use worker::*;
+pub mod padding;
+pub mod policy;
+pub mod protocol;
+pub mod proxy_protocol;
+pub mod secure;
+pub mod sketch;
 
 pub const KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY: &[u8] =
     b"VMess Header AEAD Key_Length";
@@ -14,74 +18,56 @@ pub const KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY: &[u8] = b"AEAD Resp Header Len
 pub const KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV: &[u8] = b"AEAD Resp Header Len IV";
 pub const KDFSALT_CONST_AEAD_RESP_HEADER_KEY: &[u8] = b"AEAD Resp Header Key";
 pub const KDFSALT_CONST_AEAD_RESP_HEADER_IV: &[u8] = b"AEAD Resp Header IV";
+pub const KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY: &[u8] = b"AES Auth ID Encryption";
+
+// unix time in milliseconds, the js clock in the worker and the system
+// clock in native builds
+pub fn unix_millis() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    return worker::Date::now().as_millis();
+    #[cfg(not(target_arch = "wasm32"))]
+    return std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default();
+}
 
+pub fn unix_time() -> u64 {
+    unix_millis() / 1000
+}
+
+// console_log! calls into js and panics outside of wasm, native builds
+// (the test harness) print to stderr instead.
 #[macro_export]
-macro_rules! md5 {
-    ( $($v:expr),+ ) => {
+macro_rules! log {
+    ( $($t:tt)* ) => {
         {
-            let mut hash = Md5::new();
-            $(
-                hash.update($v);
-            )*
-            hash.finalize()
+            #[cfg(target_arch = "wasm32")]
+            worker::console_log!($($t)*);
+            #[cfg(not(target_arch = "wasm32"))]
+            eprintln!($($t)*);
         }
     }
 }
 
 #[macro_export]
-macro_rules! sha256 {
-    ( $($v:expr),+ ) => {
+macro_rules! log_error {
+    ( $($t:tt)* ) => {
         {
-            let mut hash = Sha256::new();
-            $(
-                hash.update($v);
-            )*
-            hash.finalize()
+            #[cfg(target_arch = "wasm32")]
+            worker::console_error!($($t)*);
+            #[cfg(not(target_arch = "wasm32"))]
+            eprintln!($($t)*);
         }
     }
 }
 
-pub async fn parse_addr<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<String> {
-    // combined addr type between Vmess, VLESS, and Trojan.
-    // VLESS wouldn't connect to ipv6 address due to mismatch addr type
-    let addr = match buf.read_u8().await? {
-        1 => {
-            let mut addr = [0u8; 4];
-            buf.read_exact(&mut addr).await?;
-            Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]).to_string()
-        }
-        2 | 3 => {
-            let len = buf.read_u8().await?;
-            let mut domain = vec![0u8; len as _];
-            buf.read_exact(&mut domain).await?;
-            String::from_utf8_lossy(&domain).to_string()
-        }
-        4 => {
-            let mut addr = [0u8; 16];
-            buf.read_exact(&mut addr).await?;
-            Ipv6Addr::new(
-                u16::from_be_bytes([addr[0], addr[1]]),
-                u16::from_be_bytes([addr[2], addr[3]]),
-                u16::from_be_bytes([addr[4], addr[5]]),
-                u16::from_be_bytes([addr[6], addr[7]]),
-                u16::from_be_bytes([addr[8], addr[9]]),
-                u16::from_be_bytes([addr[10], addr[11]]),
-                u16::from_be_bytes([addr[12], addr[13]]),
-                u16::from_be_bytes([addr[14], addr[15]]),
-            )
-            .to_string()
-        }
-        _ => {
-            return Err(Error::RustError("invalid address".to_string()));
+// verbose step-by-step logging, only for tunnels opened with `?debug=1`
+#[macro_export]
+macro_rules! log_debug {
+    ( $config:expr, $($t:tt)* ) => {
+        if $config.debug {
+            $crate::log!("{} [debug] {}", $config.trace, format_args!($($t)*));
         }
-    };
-
-    Ok(addr)
+    }
 }
-
-pub async fn parse_port<R: AsyncRead + std::marker::Unpin>(buf: &mut R) -> Result<u16> {
-    let mut port = [0u8; 2];
-    buf.read_exact(&mut port).await?;
-
-    Ok(u16::from_be_bytes([port[0], port[1]]))
-}
\ No newline at end of file
@@ -0,0 +1,67 @@
+// padded websocket framing, each frame is 2 bytes payload length + payload
+// + random filler up to the next size bucket. a zero length frame is a dummy
+// frame and carries no data. both ends have to speak this, so it's opt-in.
+static BUCKETS: [usize; 6] = [128, 256, 512, 1024, 4096, 16384];
+static LARGE_BUCKET: usize = 16384;
+pub static DUMMY_FRAME_ODDS: u8 = 16; // roughly one dummy frame every 16 writes
+pub static MAX_PAYLOAD: usize = LARGE_BUCKET - 2;
+
+fn bucket_size(len: usize) -> usize {
+    BUCKETS
+        .iter()
+        .copied()
+        .find(|b| *b >= len)
+        .unwrap_or(len.div_ceil(LARGE_BUCKET) * LARGE_BUCKET)
+}
+
+pub fn pad(data: &[u8]) -> Vec<u8> {
+    let len = bucket_size(data.len() + 2);
+    let mut frame = vec![0u8; len];
+    frame[..2].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    frame[2..2 + data.len()].copy_from_slice(data);
+    let _ = getrandom::getrandom(&mut frame[2 + data.len()..]);
+    frame
+}
+
+pub fn dummy() -> Vec<u8> {
+    let mut seed = [0u8; 1];
+    let _ = getrandom::getrandom(&mut seed);
+    pad_to(&[], BUCKETS[seed[0] as usize % BUCKETS.len()])
+}
+
+fn pad_to(data: &[u8], len: usize) -> Vec<u8> {
+    let mut frame = pad(data);
+    frame.resize(len.max(frame.len()), 0);
+    frame
+}
+
+// None for a frame too short for its length or the length it claims
+pub fn unpad(frame: &[u8]) -> Option<&[u8]> {
+    if frame.len() < 2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([frame[0], frame[1]]) as usize;
+    frame.get(2..2 + len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padding_roundtrip() {
+        let data = b"hello";
+        let frame = pad(data);
+        assert_eq!(frame.len(), 128);
+        assert_eq!(unpad(&frame), Some(&data[..]));
+
+        let large = vec![7u8; 20000];
+        let frame = pad(&large);
+        assert_eq!(frame.len(), 32768);
+        assert_eq!(unpad(&frame), Some(&large[..]));
+
+        assert_eq!(unpad(&dummy()), Some(&[][..]));
+        assert_eq!(unpad(&[0xff]), None);
+        assert_eq!(unpad(&[0, 5, 1, 2]), None);
+    }
+}
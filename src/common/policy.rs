@@ -0,0 +1,91 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// a destination rule is either a domain, matching itself and its subdomains,
+// or an ip/cidr block.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DestinationRule {
+    Domain(String),
+    Cidr(IpAddr, u8),
+}
+
+impl FromStr for DestinationRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        if let Ok(ip) = addr.parse::<IpAddr>() {
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix = match prefix {
+                Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max).ok_or(format!("invalid cidr: {}", s))?,
+                None => max,
+            };
+            return Ok(Self::Cidr(ip, prefix));
+        }
+
+        let domain = s.trim_start_matches("*.").trim_end_matches('.');
+        if domain.is_empty() || prefix.is_some() {
+            return Err(format!("invalid destination rule: {}", s));
+        }
+        // targets arrive as punycode, so rules written in unicode are too
+        let domain = idna::domain_to_ascii(domain).map_err(|_| format!("invalid destination rule: {}", s))?;
+        Ok(Self::Domain(domain))
+    }
+}
+
+impl DestinationRule {
+    pub fn matches(&self, addr: &str) -> bool {
+        match self {
+            Self::Domain(domain) => {
+                let addr = addr.trim_end_matches('.').to_lowercase();
+                addr == *domain || addr.ends_with(&format!(".{}", domain))
+            }
+            Self::Cidr(net, prefix) => match (addr.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>(), net) {
+                (Ok(IpAddr::V4(ip)), IpAddr::V4(net)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    u32::from(ip) & mask == u32::from(*net) & mask
+                }
+                (Ok(IpAddr::V6(ip)), IpAddr::V6(net)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                    u128::from(ip) & mask == u128::from(*net) & mask
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destination_rules() {
+        let domain: DestinationRule = "*.Example.com".parse().unwrap();
+        assert!(domain.matches("example.com"));
+        assert!(domain.matches("api.example.com."));
+        assert!(!domain.matches("badexample.com"));
+
+        let idn: DestinationRule = "bücher.example".parse().unwrap();
+        assert!(idn.matches("xn--bcher-kva.example"));
+
+        let cidr: DestinationRule = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.matches("10.1.2.3"));
+        assert!(!cidr.matches("11.0.0.1"));
+        assert!(!cidr.matches("example.com"));
+
+        let any: DestinationRule = "0.0.0.0/0".parse().unwrap();
+        assert!(any.matches("1.1.1.1"));
+
+        let v6: DestinationRule = "2001:db8::/32".parse().unwrap();
+        assert!(v6.matches("2001:db8::1"));
+        assert!(!v6.matches("2001:db9::1"));
+
+        assert!("10.0.0.0/33".parse::<DestinationRule>().is_err());
+    }
+}
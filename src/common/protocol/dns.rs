@@ -0,0 +1,147 @@
+// dns messages as carried in udp frames and doh bodies (rfc 1035), only the
+// parts the resolver path looks at.
+use super::{ParseError, ParseResult, Reader};
+
+pub const RCODE_NOERROR: u8 = 0;
+pub const RCODE_SERVFAIL: u8 = 2;
+pub const RCODE_NXDOMAIN: u8 = 3;
+pub const RCODE_REFUSED: u8 = 5;
+
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_ANY: u16 = 255;
+
+// the types operators are likely to name in a policy, others go by number
+static TYPE_NAMES: [(&str, u16); 12] = [
+    ("A", 1),
+    ("NS", 2),
+    ("CNAME", 5),
+    ("SOA", 6),
+    ("PTR", 12),
+    ("MX", 15),
+    ("TXT", 16),
+    ("AAAA", TYPE_AAAA),
+    ("SRV", 33),
+    ("SVCB", 64),
+    ("HTTPS", 65),
+    ("ANY", TYPE_ANY),
+];
+
+const HEADER_LEN: usize = 12;
+
+#[derive(Debug, PartialEq)]
+pub struct Question {
+    // lowercase, without the trailing dot
+    pub name: String,
+    pub qtype: u16,
+}
+
+pub fn qtype(name: &str) -> Option<u16> {
+    TYPE_NAMES
+        .iter()
+        .find(|(x, _)| x.eq_ignore_ascii_case(name))
+        .map(|(_, qtype)| *qtype)
+        .or_else(|| name.parse().ok())
+}
+
+// the first question of a query, consumed counts from the start of the
+// message so `&msg[..consumed]` is the header and question
+pub fn question(msg: &[u8]) -> ParseResult<Question> {
+    let mut r = Reader::new(msg);
+    let header = r.take(HEADER_LEN)?;
+    if u16::from_be_bytes([header[4], header[5]]) == 0 {
+        return Err(ParseError::Invalid("dns query without a question"));
+    }
+
+    let mut labels = Vec::new();
+    loop {
+        let len = r.u8()? as usize;
+        if len == 0 {
+            break;
+        }
+        // queries don't compress their only name
+        if len > 63 {
+            return Err(ParseError::Invalid("dns label too long"));
+        }
+        labels.push(String::from_utf8_lossy(r.take(len)?).to_ascii_lowercase());
+    }
+    let qtype = r.u16()?;
+    r.take(2)?; // class
+    Ok((Question { name: labels.join("."), qtype }, r.pos()))
+}
+
+// an answer made up locally: the query's header and question with `rcode`
+// and no records
+pub fn reply(query: &[u8], rcode: u8) -> Option<Vec<u8>> {
+    let (_, len) = question(query).ok()?;
+    let mut msg = query[..len].to_vec();
+    // qr, keep opcode and rd; ra and the rcode
+    msg[2] = 0x80 | (query[2] & 0x79);
+    msg[3] = 0x80 | (rcode & 0x0f);
+    msg[4..6].copy_from_slice(&1u16.to_be_bytes());
+    msg[6..12].fill(0);
+    Some(msg)
+}
+
+// response code, the low 4 bits of the second flags byte
+pub fn rcode(msg: &[u8]) -> Option<u8> {
+    (msg.len() >= HEADER_LEN).then(|| msg[3] & 0x0f)
+}
+
+// everything but the id, two queries with the same key ask the same thing
+pub fn cache_key(query: &[u8]) -> Option<&[u8]> {
+    (query.len() >= HEADER_LEN).then(|| &query[2..])
+}
+
+// a cached answer handed to another query, with that query's id
+pub fn with_id(msg: &[u8], query: &[u8]) -> Vec<u8> {
+    let mut msg = msg.to_vec();
+    if msg.len() >= 2 && query.len() >= 2 {
+        msg[..2].copy_from_slice(&query[..2]);
+    }
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // id 0x1234, rd, one question for example.com AAAA IN, and an edns opt
+    // record the replies drop
+    fn aaaa_query() -> Vec<u8> {
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1];
+        query.extend_from_slice(b"\x07Example\x03com\x00");
+        query.extend_from_slice(&[0, 28, 0, 1]);
+        query.extend_from_slice(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0]);
+        query
+    }
+
+    #[test]
+    fn test_question() {
+        let query = aaaa_query();
+        let (question, len) = question(&query).unwrap();
+        assert_eq!(question, Question { name: "example.com".to_string(), qtype: TYPE_AAAA });
+        assert_eq!(len, 29);
+        assert_eq!(super::question(&query[..20]), Err(ParseError::Incomplete));
+
+        let reply = reply(&query, RCODE_REFUSED).unwrap();
+        assert_eq!(reply.len(), 29);
+        assert_eq!(reply[..12], [0x12, 0x34, 0x81, 0x85, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(rcode(&reply), Some(RCODE_REFUSED));
+
+        assert_eq!(qtype("aaaa"), Some(28));
+        assert_eq!(qtype("257"), Some(257));
+        assert_eq!(qtype("bogus"), None);
+    }
+
+    #[test]
+    fn test_header() {
+        let query = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        let mut nxdomain = [0xab, 0xcd, 0x81, 0x83, 0, 1, 0, 0, 0, 0, 0, 0];
+        assert_eq!(rcode(&nxdomain), Some(RCODE_NXDOMAIN));
+        assert_eq!(rcode(&nxdomain[..4]), None);
+        assert_eq!(cache_key(&query), Some(&query[2..]));
+        assert_eq!(with_id(&nxdomain, &query)[..2], [0x12, 0x34]);
+        nxdomain[3] = 0x80;
+        assert_eq!(rcode(&nxdomain), Some(0));
+    }
+}
@@ -0,0 +1,174 @@
+// sans-io header parsers, each takes the bytes received so far and returns
+// the parsed request plus how many bytes it consumed, or `Incomplete` when
+// more input is needed.
+pub mod dns;
+pub mod mux;
+pub mod shadowsocks;
+pub mod shadowsocks_body;
+pub mod trojan;
+pub mod vless;
+pub mod vmess;
+pub mod vmess_body;
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    Incomplete,
+    Invalid(&'static str),
+    UnknownAddressType(u8),
+    InvalidDomain,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Incomplete => write!(f, "incomplete header"),
+            Self::Invalid(reason) => write!(f, "invalid header: {}", reason),
+            Self::UnknownAddressType(atyp) => write!(f, "invalid header: unknown address type {}", atyp),
+            Self::InvalidDomain => write!(f, "invalid header: malformed domain"),
+        }
+    }
+}
+
+// which byte means what in the address type field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AddrScheme {
+    // vless and vmess: 1 ipv4, 2 domain, 3 ipv6
+    V2ray,
+    // trojan and shadowsocks follow socks5: 1 ipv4, 3 domain, 4 ipv6
+    Socks,
+}
+
+pub type ParseResult<T> = Result<(T, usize), ParseError>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Vless,
+    Vmess,
+    Trojan,
+    Shadowsocks,
+}
+
+impl Protocol {
+    pub const ALL: [Protocol; 4] = [Self::Vless, Self::Vmess, Self::Trojan, Self::Shadowsocks];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Vless => "vless",
+            Self::Vmess => "vmess",
+            Self::Trojan => "trojan",
+            Self::Shadowsocks => "shadowsocks",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Tcp,
+    Udp,
+    Mux,
+}
+
+// what a body decoder (vmess, shadowsocks aead) takes off the stream
+#[derive(Debug, PartialEq)]
+pub enum Chunk {
+    Data(Vec<u8>),
+    // the empty chunk a vmess client sends when it is done writing
+    End,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Target {
+    pub addr: String,
+    pub port: u16,
+}
+
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], ParseError> {
+        let data = self.buf.get(self.pos..self.pos + n).ok_or(ParseError::Incomplete)?;
+        self.pos += n;
+        Ok(data)
+    }
+
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], ParseError> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.take(N)?);
+        Ok(out)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, ParseError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    // ip addresses come out unbracketed, the dialer adds brackets where
+    // the socket api wants them.
+    pub fn addr(&mut self, scheme: AddrScheme) -> Result<String, ParseError> {
+        let addr = match (scheme, self.u8()?) {
+            (_, 1) => Ipv4Addr::from(self.array::<4>()?).to_string(),
+            (AddrScheme::V2ray, 2) | (AddrScheme::Socks, 3) => {
+                let len = self.u8()?;
+                domain(self.take(len as usize)?)?
+            }
+            (AddrScheme::V2ray, 3) | (AddrScheme::Socks, 4) => Ipv6Addr::from(self.array::<16>()?).to_string(),
+            (_, atyp) => return Err(ParseError::UnknownAddressType(atyp)),
+        };
+        Ok(addr)
+    }
+}
+
+// some clients put ip literals in the domain field, possibly bracketed,
+// those are normalized to the plain ip form. unicode names are turned into
+// punycode so destination rules see the same name the resolver does.
+fn domain(raw: &[u8]) -> Result<String, ParseError> {
+    let domain = std::str::from_utf8(raw).map_err(|_| ParseError::InvalidDomain)?;
+    let unbracketed = domain.strip_prefix('[').and_then(|x| x.strip_suffix(']')).unwrap_or(domain);
+    if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+        return Ok(ip.to_string());
+    }
+
+    let malformed = domain.is_empty()
+        || domain.chars().any(|c| c.is_control() || c.is_whitespace() || matches!(c, '/' | ':' | '@' | '[' | ']'));
+    if malformed {
+        return Err(ParseError::InvalidDomain);
+    }
+    idna::domain_to_ascii(domain).map_err(|_| ParseError::InvalidDomain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_addr_schemes() {
+        let ipv6 = [[3u8].as_slice(), &Ipv6Addr::LOCALHOST.octets()].concat();
+        assert_eq!(Reader::new(&ipv6).addr(AddrScheme::V2ray), Ok("::1".to_string()));
+        assert_eq!(Reader::new(&ipv6).addr(AddrScheme::Socks), Err(ParseError::InvalidDomain));
+
+        assert_eq!(Reader::new(&[3, 5, b'[', b':', b':', b'1', b']']).addr(AddrScheme::Socks), Ok("::1".to_string()));
+        assert_eq!(Reader::new(&[3, 3, b'a', b'/', b'b']).addr(AddrScheme::Socks), Err(ParseError::InvalidDomain));
+        assert_eq!(Reader::new(&[3, 2, 0xff, 0xfe]).addr(AddrScheme::Socks), Err(ParseError::InvalidDomain));
+        let name = "bücher.example".as_bytes();
+        let idn = [[3u8, name.len() as u8].as_slice(), name].concat();
+        assert_eq!(Reader::new(&idn).addr(AddrScheme::Socks), Ok("xn--bcher-kva.example".to_string()));
+        assert_eq!(Reader::new(&[9]).addr(AddrScheme::V2ray), Err(ParseError::UnknownAddressType(9)));
+    }
+}
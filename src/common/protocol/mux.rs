@@ -0,0 +1,134 @@
+use super::{AddrScheme, ParseError, ParseResult, Reader, Target};
+
+// mux.cool frames, https://xtls.github.io/en/development/protocols/muxcool.html
+//
+// +-----------------+-----------------+-----------------+-----------------+
+// |     2 Bytes     |    L Bytes      |     2 Bytes     |     X Bytes     |
+// +-----------------+-----------------+-----------------+-----------------+
+// | Metadata len L  |    Metadata     |  Data len X     |      Data       |
+// +-----------------+-----------------+-----------------+-----------------+
+//
+// metadata: session id (2), status (1), option (1), and for new sessions
+// network (1), port (2), address type (1) and address.
+pub const STATUS_NEW: u8 = 0x01;
+pub const STATUS_KEEP: u8 = 0x02;
+pub const STATUS_END: u8 = 0x03;
+pub const STATUS_KEEP_ALIVE: u8 = 0x04;
+
+pub const OPTION_DATA: u8 = 0x01;
+pub const OPTION_ERROR: u8 = 0x02;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Network {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub id: u16,
+    pub status: u8,
+    pub option: u8,
+    // only set on new sessions
+    pub target: Option<(Network, Target)>,
+    pub data: Option<Vec<u8>>,
+}
+
+pub fn parse(buf: &[u8]) -> ParseResult<Frame> {
+    let mut r = Reader::new(buf);
+
+    let meta_len = r.u16()? as usize;
+    if meta_len < 4 {
+        return Err(ParseError::Invalid("mux metadata too short"));
+    }
+    // trailing metadata (e.g. the xudp global id) is skipped
+    let mut meta = Reader::new(r.take(meta_len)?);
+    let id = meta.u16().map_err(truncated)?;
+    let status = meta.u8().map_err(truncated)?;
+    let option = meta.u8().map_err(truncated)?;
+
+    let target = if status == STATUS_NEW {
+        let network = match meta.u8().map_err(truncated)? {
+            0x01 => Network::Tcp,
+            0x02 => Network::Udp,
+            _ => return Err(ParseError::Invalid("unknown mux network")),
+        };
+        let port = meta.u16().map_err(truncated)?;
+        let addr = meta.addr(AddrScheme::V2ray).map_err(truncated)?;
+        Some((network, Target { addr, port }))
+    } else {
+        None
+    };
+
+    let data = if option & OPTION_DATA != 0 {
+        let len = r.u16()? as usize;
+        Some(r.take(len)?.to_vec())
+    } else {
+        None
+    };
+
+    let frame = Frame {
+        id,
+        status,
+        option,
+        target,
+        data,
+    };
+    Ok((frame, r.pos()))
+}
+
+// the metadata is complete once its length is, so running short inside it
+// is malformed rather than incomplete
+fn truncated(e: ParseError) -> ParseError {
+    match e {
+        ParseError::Incomplete => ParseError::Invalid("truncated mux metadata"),
+        e => e,
+    }
+}
+
+// a frame sent back to the client, carrying data for a session
+pub fn encode(id: u16, status: u8, data: Option<&[u8]>) -> Vec<u8> {
+    let option = if data.is_some() { OPTION_DATA } else { 0 };
+    encode_with_option(id, status, option, data)
+}
+
+// ends a session, flagging whether it ended because of an error
+pub fn end(id: u16, error: bool) -> Vec<u8> {
+    let option = if error { OPTION_ERROR } else { 0 };
+    encode_with_option(id, STATUS_END, option, None)
+}
+
+fn encode_with_option(id: u16, status: u8, option: u8, data: Option<&[u8]>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(8 + data.map_or(0, |x| x.len()));
+    frame.extend_from_slice(&4u16.to_be_bytes());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.push(status);
+    frame.push(option);
+    if let Some(data) = data {
+        frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(data);
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mux() {
+        let mut buf = vec![0, 12, 0, 7, STATUS_NEW, OPTION_DATA, 0x01, 0x01, 0xbb, 1, 127, 0, 0, 1];
+        buf.extend_from_slice(&[0, 2, b'h', b'i']);
+
+        let (frame, consumed) = parse(&buf).unwrap();
+        assert_eq!(consumed, buf.len());
+        assert_eq!(frame.id, 7);
+        assert_eq!(frame.target, Some((Network::Tcp, Target { addr: "127.0.0.1".to_string(), port: 443 })));
+        assert_eq!(frame.data, Some(b"hi".to_vec()));
+        assert_eq!(parse(&buf[..15]), Err(ParseError::Incomplete));
+
+        let keep = encode(7, STATUS_KEEP, Some(b"ok"));
+        let (frame, _) = parse(&keep).unwrap();
+        assert_eq!((frame.status, frame.target, frame.data), (STATUS_KEEP, None, Some(b"ok".to_vec())));
+    }
+}
@@ -0,0 +1,46 @@
+use super::{AddrScheme, ParseError, ParseResult, Reader, Target};
+
+// +--------------+---------+---------+
+// |    1 Byte    | S Bytes | 2 Bytes |
+// +--------------+---------+---------+
+// | Address Type | Address |  Port   |
+// +--------------+---------+---------+
+pub fn parse(buf: &[u8]) -> ParseResult<Target> {
+    let mut r = Reader::new(buf);
+
+    let addr = r.addr(AddrScheme::Socks)?;
+    let port = r.u16()?;
+    Ok((Target { addr, port }, r.pos()))
+}
+
+// shadowsocks 2022 follows the address with padding
+//
+// +---------+---------+-----------------+---------+
+// | S Bytes | 2 Bytes |     2 Bytes     | P Bytes |
+// +---------+---------+-----------------+---------+
+// | Address |  Port   | Padding Length  | Padding |
+// +---------+---------+-----------------+---------+
+pub fn parse_2022(buf: &[u8]) -> ParseResult<Target> {
+    let (target, consumed) = parse(buf)?;
+    let mut r = Reader::new(&buf[consumed..]);
+    let padding_len = r.u16()?;
+    r.take(padding_len as usize)?;
+    Ok((target, consumed + r.pos()))
+}
+
+// simple-obfs in http mode wraps the first request in a websocket upgrade
+// with the shadowsocks stream as its body, and expects a 101 back.
+pub const OBFS_HTTP_RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nServer: nginx\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+
+pub fn is_obfs_http(buf: &[u8]) -> bool {
+    buf.starts_with(b"GET ") || buf.starts_with(b"POST ")
+}
+
+// consumes the obfs request header up to the blank line
+pub fn obfs_http_header(buf: &[u8]) -> ParseResult<()> {
+    let end = buf
+        .windows(4)
+        .position(|x| x == b"\r\n\r\n")
+        .ok_or(ParseError::Incomplete)?;
+    Ok(((), end + 4))
+}
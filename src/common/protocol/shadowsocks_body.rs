@@ -0,0 +1,518 @@
+// shadowsocks aead stream, as sent by clients behind v2ray-plugin: a random
+// salt followed by chunks of sealed 2 byte length + sealed payload. the
+// session key is hkdf-sha1(master key, salt, "ss-subkey") and the nonce is a
+// little endian counter bumped after every seal/open.
+// https://shadowsocks.org/doc/aead.html
+//
+// shadowsocks 2022 keeps the chunks but derives the session key with blake3
+// and puts a fixed length header (type, timestamp, length of the next chunk)
+// in front. with extensible identity headers the salt is followed by the
+// user's identity, sealed with the server key, so several users can share
+// one deployment.
+// https://github.com/Shadowsocks-NET/shadowsocks-specs/blob/main/2022-1-shadowsocks-2022-edition.md
+// https://github.com/Shadowsocks-NET/shadowsocks-specs/blob/main/2022-2-shadowsocks-2022-extensible-identity-headers.md
+use super::{Chunk, ParseError};
+use crate::common::{digest, hash, secure};
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::{Aes128, Aes256};
+use aes_gcm::{aead::Aead, Aes128Gcm, Aes256Gcm};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::BytesMut;
+use chacha20poly1305::ChaCha20Poly1305;
+use hkdf::Hkdf;
+use sha1::Sha1;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::str::FromStr;
+use uuid::Uuid;
+
+// payload lengths are 14 bits
+pub const MAX_CHUNK_PAYLOAD: usize = 0x3fff;
+static TAG_LEN: usize = 16;
+static IDENTITY_LEN: usize = 16;
+// how far a 2022 request timestamp may be off, in seconds
+static MAX_TIME_DIFF: u64 = 30;
+
+thread_local! {
+    // 2022 request salts and when they were seen. a replay has to reuse the
+    // salt within the timestamp window, so entries older than the window on
+    // both sides can go. per isolate, like the other caches.
+    static SALTS: RefCell<HashMap<Vec<u8>, u64>> = RefCell::new(HashMap::new());
+}
+
+// false when `salt` was already seen within the window
+fn remember_salt(salt: &[u8], now: u64) -> bool {
+    SALTS.with_borrow_mut(|salts| {
+        salts.retain(|_, seen| now.saturating_sub(*seen) <= 2 * MAX_TIME_DIFF);
+        salts.insert(salt.to_vec(), now).is_none()
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20Poly1305,
+    Blake3Aes128Gcm,
+    Blake3Aes256Gcm,
+}
+
+impl Method {
+    // salts are as long as the key
+    pub fn key_len(self) -> usize {
+        match self {
+            Self::Aes128Gcm | Self::Blake3Aes128Gcm => 16,
+            Self::Aes256Gcm | Self::Chacha20Poly1305 | Self::Blake3Aes256Gcm => 32,
+        }
+    }
+
+    pub fn is_2022(self) -> bool {
+        matches!(self, Self::Blake3Aes128Gcm | Self::Blake3Aes256Gcm)
+    }
+}
+
+impl FromStr for Method {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "aes-128-gcm" => Ok(Self::Aes128Gcm),
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            "chacha20-ietf-poly1305" | "chacha20-poly1305" => Ok(Self::Chacha20Poly1305),
+            "2022-blake3-aes-128-gcm" => Ok(Self::Blake3Aes128Gcm),
+            "2022-blake3-aes-256-gcm" => Ok(Self::Blake3Aes256Gcm),
+            _ => Err(format!("unsupported shadowsocks method {}", s)),
+        }
+    }
+}
+
+// the master key, derived once from the configured password. 2022 methods
+// take the key itself, base64 encoded.
+#[derive(Clone)]
+pub struct Key {
+    pub method: Method,
+    key: Vec<u8>,
+}
+
+impl Key {
+    pub fn from_password(method: Method, password: &str) -> Result<Self, String> {
+        let key = if method.is_2022() {
+            decode_psk(method, password)?
+        } else {
+            evp_bytes_to_key(password.as_bytes(), method.key_len())
+        };
+        Ok(Self { method, key })
+    }
+}
+
+// a 2022 pre-shared key has to be exactly as long as the method's key
+pub fn decode_psk(method: Method, psk: &str) -> Result<Vec<u8>, String> {
+    let key = STANDARD.decode(psk.trim()).map_err(|e| format!("invalid shadowsocks psk: {}", e))?;
+    if key.len() != method.key_len() {
+        return Err(format!("shadowsocks psk must be {} bytes", method.key_len()));
+    }
+    Ok(key)
+}
+
+// a user of a multi-user 2022 deployment, identified by its own psk
+#[derive(Clone)]
+pub struct User {
+    pub uuid: Uuid,
+    pub psk: Vec<u8>,
+}
+
+// openssl's EVP_BytesToKey with md5 and a single round, what every
+// shadowsocks implementation uses to turn a password into a key
+fn evp_bytes_to_key(password: &[u8], len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(len + 16);
+    let mut prev: Vec<u8> = Vec::new();
+    while key.len() < len {
+        prev = digest::md5(&[&prev, password]).to_vec();
+        key.extend_from_slice(&prev);
+    }
+    key.truncate(len);
+    key
+}
+
+fn session(method: Method, key: &[u8], salt: &[u8]) -> Cipher {
+    let mut subkey = vec![0u8; method.key_len()];
+    if method.is_2022() {
+        let material = [key, salt].concat();
+        subkey.copy_from_slice(&hash::blake3_derive_key("shadowsocks 2022 session subkey", &material)[..method.key_len()]);
+    } else {
+        Hkdf::<Sha1>::new(Some(salt), key)
+            .expand(b"ss-subkey", &mut subkey)
+            .expect("subkey length is valid for hkdf-sha1");
+    }
+    Cipher::new(method, &subkey)
+}
+
+// the identity header is a single aes block sealed with a key derived from
+// the server psk and the salt, `encrypt` is only used by clients (the tests)
+fn identity_block(method: Method, server_psk: &[u8], salt: &[u8], block: &mut [u8; 16], encrypt: bool) {
+    let material = [server_psk, salt].concat();
+    let subkey = hash::blake3_derive_key("shadowsocks 2022 identity subkey", &material);
+    let block = block.into();
+    match method.key_len() {
+        16 => {
+            let aes = Aes128::new(subkey[..16].into());
+            if encrypt { aes.encrypt_block(block) } else { aes.decrypt_block(block) }
+        }
+        _ => {
+            let aes = Aes256::new(subkey[..32].into());
+            if encrypt { aes.encrypt_block(block) } else { aes.decrypt_block(block) }
+        }
+    }
+}
+
+enum Cipher {
+    Aes128Gcm(Box<Aes128Gcm>),
+    Aes256Gcm(Box<Aes256Gcm>),
+    Chacha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl Cipher {
+    fn new(method: Method, key: &[u8]) -> Self {
+        match method {
+            Method::Aes128Gcm | Method::Blake3Aes128Gcm => Self::Aes128Gcm(Box::new(Aes128Gcm::new(key.into()))),
+            Method::Aes256Gcm | Method::Blake3Aes256Gcm => Self::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            Method::Chacha20Poly1305 => Self::Chacha20Poly1305(Box::new(ChaCha20Poly1305::new(key.into()))),
+        }
+    }
+
+    fn open(&self, nonce: &[u8; 12], sealed: &[u8]) -> Result<Vec<u8>, ParseError> {
+        let opened = match self {
+            Self::Aes128Gcm(cipher) => cipher.decrypt(nonce.into(), sealed),
+            Self::Aes256Gcm(cipher) => cipher.decrypt(nonce.into(), sealed),
+            Self::Chacha20Poly1305(cipher) => cipher.decrypt(nonce.into(), sealed),
+        };
+        opened.map_err(|_| ParseError::Invalid("shadowsocks chunk authentication failed"))
+    }
+
+    fn seal(&self, nonce: &[u8; 12], plain: &[u8]) -> Vec<u8> {
+        let sealed = match self {
+            Self::Aes128Gcm(cipher) => cipher.encrypt(nonce.into(), plain),
+            Self::Aes256Gcm(cipher) => cipher.encrypt(nonce.into(), plain),
+            Self::Chacha20Poly1305(cipher) => cipher.encrypt(nonce.into(), plain),
+        };
+        sealed.expect("aead encryption doesn't fail on in-memory buffers")
+    }
+}
+
+fn increment(nonce: &mut [u8; 12]) {
+    for byte in nonce.iter_mut() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+}
+
+enum State {
+    // waiting for the salt, and the identity header on multi-user 2022
+    Salt,
+    // 2022 only: the fixed length header, then the variable length one
+    // carrying the address, of the given sealed size
+    FixedHeader,
+    VariableHeader(usize),
+    Chunks,
+}
+
+pub struct ChunkReader {
+    key: Key,
+    users: Vec<User>,
+    // unix time in seconds, for the 2022 replay window
+    now: u64,
+    state: State,
+    cipher: Option<Cipher>,
+    nonce: [u8; 12],
+    // payload length of a chunk whose payload hasn't fully arrived yet, the
+    // length can't be opened twice as that would advance the nonce.
+    pending: Option<usize>,
+    // the request salt and the key the session runs on, the response
+    // depends on both
+    salt: Vec<u8>,
+    session_key: Vec<u8>,
+    // who the identity header belonged to
+    pub user: Option<Uuid>,
+}
+
+impl ChunkReader {
+    // `users` turns on identity headers for 2022 methods
+    pub fn new(key: Key, users: Vec<User>, now: u64) -> Self {
+        Self {
+            key,
+            users,
+            now,
+            state: State::Salt,
+            cipher: None,
+            nonce: [0u8; 12],
+            pending: None,
+            salt: Vec::new(),
+            session_key: Vec::new(),
+            user: None,
+        }
+    }
+
+    fn is_multi_user(&self) -> bool {
+        self.key.method.is_2022() && !self.users.is_empty()
+    }
+
+    fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>, ParseError> {
+        let cipher = self.cipher.as_ref().ok_or(ParseError::Invalid("shadowsocks session not started"))?;
+        let plain = cipher.open(&self.nonce, sealed)?;
+        increment(&mut self.nonce);
+        Ok(plain)
+    }
+
+    // finds the user whose psk hash is sealed in the identity header
+    fn identify(&mut self, identity: &[u8]) -> Result<Vec<u8>, ParseError> {
+        let mut block = [0u8; IDENTITY_LEN];
+        block.copy_from_slice(identity);
+        identity_block(self.key.method, &self.key.key, &self.salt, &mut block, false);
+
+        let user = self
+            .users
+            .iter()
+            .find(|user| secure::ct_eq(&hash::blake3(&user.psk)[..IDENTITY_LEN], block))
+            .ok_or(ParseError::Invalid("unknown shadowsocks user"))?;
+        self.user = Some(user.uuid);
+        Ok(user.psk.clone())
+    }
+
+    // takes one chunk off the front of `buf`, Incomplete when it hasn't
+    // fully arrived yet. the salt and 2022 headers in front of the first
+    // chunk are consumed along the way.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Chunk, ParseError> {
+        let method = self.key.method;
+        loop {
+            match self.state {
+                State::Salt => {
+                    let salt_len = method.key_len();
+                    let identity_len = if self.is_multi_user() { IDENTITY_LEN } else { 0 };
+                    if buf.len() < salt_len + identity_len {
+                        return Err(ParseError::Incomplete);
+                    }
+                    self.salt = buf.split_to(salt_len).to_vec();
+                    self.session_key = if identity_len > 0 {
+                        let identity = buf.split_to(identity_len);
+                        self.identify(&identity)?
+                    } else {
+                        self.key.key.clone()
+                    };
+                    self.cipher = Some(session(method, &self.session_key, &self.salt));
+                    self.state = if method.is_2022() { State::FixedHeader } else { State::Chunks };
+                }
+                State::FixedHeader => {
+                    // type, timestamp and the variable header length
+                    let size = 1 + 8 + 2 + TAG_LEN;
+                    if buf.len() < size {
+                        return Err(ParseError::Incomplete);
+                    }
+                    let header = self.open(&buf.split_to(size))?;
+                    if header[0] != 0 {
+                        return Err(ParseError::Invalid("shadowsocks 2022 header is not a request"));
+                    }
+                    let timestamp = u64::from_be_bytes(header[1..9].try_into().unwrap());
+                    if timestamp.abs_diff(self.now) > MAX_TIME_DIFF {
+                        return Err(ParseError::Invalid("shadowsocks 2022 timestamp out of range"));
+                    }
+                    if !remember_salt(&self.salt, self.now) {
+                        return Err(ParseError::Invalid("shadowsocks 2022 salt replayed"));
+                    }
+                    let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+                    self.state = State::VariableHeader(len + TAG_LEN);
+                }
+                State::VariableHeader(size) => {
+                    if buf.len() < size {
+                        return Err(ParseError::Incomplete);
+                    }
+                    let header = self.open(&buf.split_to(size))?;
+                    self.state = State::Chunks;
+                    return Ok(Chunk::Data(header));
+                }
+                State::Chunks => break,
+            }
+        }
+
+        let size = match self.pending.take() {
+            Some(size) => size,
+            None => {
+                if buf.len() < 2 + TAG_LEN {
+                    return Err(ParseError::Incomplete);
+                }
+                let len = self.open(&buf.split_to(2 + TAG_LEN))?;
+                let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+                // 2022 lifted the 14 bit limit
+                if !method.is_2022() && len > MAX_CHUNK_PAYLOAD {
+                    return Err(ParseError::Invalid("shadowsocks chunk too long"));
+                }
+                len + TAG_LEN
+            }
+        };
+
+        if buf.len() < size {
+            self.pending = Some(size);
+            return Err(ParseError::Incomplete);
+        }
+        let payload = self.open(&buf.split_to(size))?;
+        Ok(Chunk::Data(payload))
+    }
+
+    // false until the salt (and identity) arrived
+    pub fn is_started(&self) -> bool {
+        self.cipher.is_some()
+    }
+}
+
+pub struct ChunkWriter {
+    method: Method,
+    cipher: Cipher,
+    nonce: [u8; 12],
+    // our own salt sent in front of the first chunk, and for 2022 the
+    // response header that goes with it
+    salt: Option<Vec<u8>>,
+    request_salt: Vec<u8>,
+    now: u64,
+}
+
+impl ChunkWriter {
+    // the response runs on the same key as the request, so the reader has
+    // to be started
+    pub fn new(reader: &ChunkReader) -> Result<Self, ParseError> {
+        let method = reader.key.method;
+        let mut salt = vec![0u8; method.key_len()];
+        getrandom::getrandom(&mut salt).map_err(|_| ParseError::Invalid("no randomness for the shadowsocks salt"))?;
+        Ok(Self {
+            method,
+            cipher: session(method, &reader.session_key, &salt),
+            nonce: [0u8; 12],
+            salt: Some(salt),
+            request_salt: reader.salt.clone(),
+            now: reader.now,
+        })
+    }
+
+    fn seal(&mut self, plain: &[u8]) -> Vec<u8> {
+        let sealed = self.cipher.seal(&self.nonce, plain);
+        increment(&mut self.nonce);
+        sealed
+    }
+
+    // payloads longer than MAX_CHUNK_PAYLOAD have to be split by the caller
+    pub fn encode(&mut self, payload: &[u8]) -> Vec<u8> {
+        let len = (payload.len() as u16).to_be_bytes();
+        let mut chunk = Vec::with_capacity(payload.len() + 128);
+        match self.salt.take() {
+            Some(salt) if self.method.is_2022() => {
+                chunk.extend_from_slice(&salt);
+                let mut header = vec![1u8];
+                header.extend_from_slice(&self.now.to_be_bytes());
+                header.extend_from_slice(&self.request_salt);
+                header.extend_from_slice(&len);
+                let header = self.seal(&header);
+                chunk.extend_from_slice(&header);
+            }
+            salt => {
+                chunk.extend_from_slice(&salt.unwrap_or_default());
+                let len = self.seal(&len);
+                chunk.extend_from_slice(&len);
+            }
+        }
+        let payload = self.seal(payload);
+        chunk.extend_from_slice(&payload);
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evp_bytes_to_key() {
+        let key = evp_bytes_to_key(b"password", 32);
+        assert_eq!(key[..16], digest::md5(&[b"password"]));
+        assert_eq!(key[16..], digest::md5(&[&key[..16], b"password"]));
+    }
+
+    // a 2017 server writer speaks the client format too, all it needs is a
+    // reader that has seen a salt
+    fn client(key: &Key, payloads: &[&[u8]]) -> Vec<u8> {
+        let mut reader = ChunkReader::new(key.clone(), Vec::new(), 0);
+        reader.decode(&mut BytesMut::from(&[0u8; 32][..key.method.key_len()])).unwrap_err();
+        let mut writer = ChunkWriter::new(&reader).unwrap();
+        payloads.iter().flat_map(|x| writer.encode(x)).collect()
+    }
+
+    #[test]
+    fn test_chunk_roundtrip() {
+        let key = Key::from_password(Method::Chacha20Poly1305, "secret").unwrap();
+        let mut stream = BytesMut::from(&client(&key, &[b"hello", b"world"])[..]);
+        let mut reader = ChunkReader::new(key, Vec::new(), 0);
+
+        // salt and length, but not the first payload yet
+        let mut partial = stream.split_to(32 + 2 + TAG_LEN + 1);
+        assert_eq!(reader.decode(&mut partial), Err(ParseError::Incomplete));
+        partial.unsplit(stream);
+        let mut stream = partial;
+
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"hello".to_vec())));
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"world".to_vec())));
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn test_oversize_chunk() {
+        let key = Key::from_password(Method::Aes128Gcm, "secret").unwrap();
+        let mut stream = BytesMut::from(&client(&key, &[&[0u8; MAX_CHUNK_PAYLOAD + 1]])[..]);
+        let mut reader = ChunkReader::new(key, Vec::new(), 0);
+        assert_eq!(reader.decode(&mut stream), Err(ParseError::Invalid("shadowsocks chunk too long")));
+    }
+
+    #[test]
+    fn test_2022_identity_header() {
+        let method = Method::Blake3Aes128Gcm;
+        let server = Key::from_password(method, &STANDARD.encode([1u8; 16])).unwrap();
+        let user = User { uuid: Uuid::from_bytes([7; 16]), psk: vec![2u8; 16] };
+        let now = 1_700_000_000;
+
+        // salt, identity, fixed header and the variable header
+        let salt = [3u8; 16];
+        let mut identity = [0u8; 16];
+        identity.copy_from_slice(&hash::blake3(&user.psk)[..16]);
+        identity_block(method, &server.key, &salt, &mut identity, true);
+        let cipher = session(method, &user.psk, &salt);
+        let address = b"\x01\x7f\x00\x00\x01\x01\xbb\x00\x00".to_vec();
+        let mut fixed = vec![0u8];
+        fixed.extend_from_slice(&(now - 5u64).to_be_bytes());
+        fixed.extend_from_slice(&(address.len() as u16).to_be_bytes());
+        let mut request = [&salt[..], &identity].concat();
+        request.extend_from_slice(&cipher.seal(&[0u8; 12], &fixed));
+        request.extend_from_slice(&cipher.seal(&[1u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], &address));
+
+        let mut reader = ChunkReader::new(server.clone(), vec![user.clone()], now);
+        assert_eq!(reader.decode(&mut BytesMut::from(&request[..])), Ok(Chunk::Data(address)));
+        assert_eq!(reader.user, Some(user.uuid));
+
+        // the response header echoes the request salt
+        let response = ChunkWriter::new(&reader).unwrap().encode(b"ok");
+        let cipher = session(method, &user.psk, &response[..16]);
+        let header = cipher.open(&[0u8; 12], &response[16..16 + 1 + 8 + 16 + 2 + TAG_LEN]).unwrap();
+        assert_eq!((header[0], &header[9..25]), (1, &salt[..]));
+
+        // the same request again is a replay
+        let mut reader = ChunkReader::new(server.clone(), vec![user.clone()], now + 10);
+        assert_eq!(
+            reader.decode(&mut BytesMut::from(&request[..])),
+            Err(ParseError::Invalid("shadowsocks 2022 salt replayed"))
+        );
+
+        // someone else's identity doesn't get in
+        let mut reader = ChunkReader::new(server, vec![User { psk: vec![4u8; 16], ..user }], now);
+        assert_eq!(
+            reader.decode(&mut BytesMut::from(&request[..])),
+            Err(ParseError::Invalid("unknown shadowsocks user"))
+        );
+    }
+}
@@ -0,0 +1,71 @@
+use super::{AddrScheme, Command, ParseError, ParseResult, Reader, Target};
+use sha2::{Digest, Sha224};
+
+// +-----------------------+---------+---------+--------------+---------+------+---------+
+// |       56 Bytes        | 2 Bytes | 1 Byte  |    1 Byte    | S Bytes | 2 B  | 2 Bytes |
+// +-----------------------+---------+---------+--------------+---------+------+---------+
+// | hex(SHA224(password)) |  CRLF   | Command | Address Type | Address | Port |  CRLF   |
+// +-----------------------+---------+---------+--------------+---------+------+---------+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub hash: [u8; 56],
+    pub command: Command,
+    pub target: Target,
+}
+
+// what a client sends in place of its password: hex(sha224(password))
+pub fn password_hash(password: &str) -> [u8; 56] {
+    let digest = Sha224::digest(password.as_bytes());
+    let mut hash = [0u8; 56];
+    for (i, b) in digest.iter().enumerate() {
+        hash[i * 2..i * 2 + 2].copy_from_slice(format!("{:02x}", b).as_bytes());
+    }
+    hash
+}
+
+// clients send the hash as lowercase hex
+pub fn is_hex_hash(buf: &[u8]) -> bool {
+    buf.len() == 56 && buf.iter().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub fn parse(buf: &[u8]) -> ParseResult<Request> {
+    let mut r = Reader::new(buf);
+
+    let hash = r.array::<56>()?;
+    if r.take(2)? != b"\r\n" {
+        return Err(ParseError::Invalid("missing crlf after trojan hash"));
+    }
+    let command = match r.u8()? {
+        1 => Command::Tcp,
+        3 => Command::Udp,
+        _ => return Err(ParseError::Invalid("unknown trojan command")),
+    };
+    let addr = r.addr(AddrScheme::Socks)?;
+    let port = r.u16()?;
+    if r.take(2)? != b"\r\n" {
+        return Err(ParseError::Invalid("missing crlf after trojan request"));
+    }
+
+    let request = Request {
+        hash,
+        command,
+        target: Target { addr, port },
+    };
+    Ok((request, r.pos()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hash() {
+        assert_eq!(
+            &password_hash("password")[..],
+            b"d63dc919e201d7bc4c825630d2cf25fdc93d4b2f0d46706d29038d01"
+        );
+        assert!(is_hex_hash(&password_hash("password")));
+        assert!(!is_hex_hash(&[b'A'; 56]));
+        assert!(!is_hex_hash(&[b'a'; 55]));
+    }
+}
@@ -0,0 +1,180 @@
+use super::{AddrScheme, Command, ParseError, ParseResult, Reader, Target};
+use std::net::IpAddr;
+use uuid::Uuid;
+
+// +---------+----------+-------------+--------+---------+------+--------------+---------+
+// | 1 Byte  | 16 Bytes |   1 Byte    | M Bytes| 1 Byte  | 2 B  |    1 Byte    | S Bytes |
+// +---------+----------+-------------+--------+---------+------+--------------+---------+
+// | Version |   UUID   | Addons len  | Addons | Command | Port | Address Type | Address |
+// +---------+----------+-------------+--------+---------+------+--------------+---------+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub version: u8,
+    pub uuid: Uuid,
+    pub addons: Addons,
+    pub command: Command,
+    pub target: Target,
+}
+
+pub fn parse(buf: &[u8]) -> ParseResult<Request> {
+    let mut r = Reader::new(buf);
+
+    let version = r.u8()?;
+    let uuid = Uuid::from_bytes(r.array()?);
+    let addons_len = r.u8()?;
+    let addons = parse_addons(r.take(addons_len as usize)?)?;
+    let command = match r.u8()? {
+        1 => Command::Tcp,
+        2 => Command::Udp,
+        3 => Command::Mux,
+        _ => return Err(ParseError::Invalid("unknown vless command")),
+    };
+    let port = r.u16()?;
+    let addr = r.addr(AddrScheme::V2ray)?;
+
+    let request = Request {
+        version,
+        uuid,
+        addons,
+        command,
+        target: Target { addr, port },
+    };
+    Ok((request, r.pos()))
+}
+
+// protobuf `message Addons { string Flow = 1; bytes Seed = 2; }`, empty
+// for plain vless
+#[derive(Debug, Default, PartialEq)]
+pub struct Addons {
+    pub flow: String,
+    pub seed: Vec<u8>,
+}
+
+fn parse_addons(buf: &[u8]) -> Result<Addons, ParseError> {
+    let malformed = |e| match e {
+        ParseError::Incomplete => ParseError::Invalid("malformed vless addons"),
+        e => e,
+    };
+    let mut r = Reader::new(buf);
+    let mut addons = Addons::default();
+    while r.pos() < buf.len() {
+        let key = varint(&mut r).map_err(malformed)?;
+        match (key >> 3, key & 7) {
+            (_, 0) => {
+                varint(&mut r).map_err(malformed)?;
+            }
+            (field, 2) => {
+                let len = varint(&mut r).map_err(malformed)?;
+                let value = r.take(len as usize).map_err(malformed)?;
+                match field {
+                    1 => addons.flow = String::from_utf8(value.to_vec()).map_err(|_| ParseError::Invalid("vless flow is not utf-8"))?,
+                    2 => addons.seed = value.to_vec(),
+                    // unknown fields are skipped like protobuf does
+                    _ => {}
+                }
+            }
+            _ => return Err(ParseError::Invalid("malformed vless addons")),
+        }
+    }
+    Ok(addons)
+}
+
+fn varint(r: &mut Reader) -> Result<u64, ParseError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = r.u8()?;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(ParseError::Invalid("malformed vless addons"))
+}
+
+// the request a peer deployment is sent when relaying through it, always a
+// tcp command without addons
+pub fn encode(uuid: &Uuid, target: &Target) -> Vec<u8> {
+    let mut buf = vec![0u8];
+    buf.extend_from_slice(uuid.as_bytes());
+    buf.extend_from_slice(&[0, 1]);
+    buf.extend_from_slice(&target.port.to_be_bytes());
+    match target.addr.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            buf.push(1);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            buf.push(3);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            buf.push(2);
+            buf.push(target.addr.len() as u8);
+            buf.extend_from_slice(target.addr.as_bytes());
+        }
+    }
+    buf
+}
+
+// +---------+-------------+--------+
+// | 1 Byte  |   1 Byte    | M Bytes|
+// +---------+-------------+--------+
+// | Version | Addons len  | Addons |
+// +---------+-------------+--------+
+pub fn parse_response(buf: &[u8]) -> ParseResult<()> {
+    let mut r = Reader::new(buf);
+    let _version = r.u8()?;
+    let addons_len = r.u8()?;
+    r.take(addons_len as usize)?;
+    Ok(((), r.pos()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vless() {
+        let mut buf = vec![0u8];
+        buf.extend_from_slice(Uuid::nil().as_bytes());
+        buf.extend_from_slice(&[0, 1, 0x01, 0xbb, 2, 11]);
+        buf.extend_from_slice(b"example.com");
+        buf.extend_from_slice(b"payload");
+
+        let (request, consumed) = parse(&buf).unwrap();
+        assert_eq!(consumed, buf.len() - 7);
+        assert_eq!(request.command, Command::Tcp);
+        assert_eq!(request.target, Target { addr: "example.com".to_string(), port: 443 });
+
+        assert_eq!(parse(&buf[..20]), Err(ParseError::Incomplete));
+        buf[18] = 9;
+        assert_eq!(parse(&buf), Err(ParseError::Invalid("unknown vless command")));
+    }
+
+    #[test]
+    fn test_encode_vless() {
+        for addr in ["example.com", "1.2.3.4", "2001:db8::1"] {
+            let target = Target { addr: addr.to_string(), port: 8443 };
+            let uuid = Uuid::from_u128(0x38425afe_8466_4876_8223_f3d604ca3c18);
+            let (request, consumed) = parse(&encode(&uuid, &target)).unwrap();
+            assert_eq!(consumed, encode(&uuid, &target).len());
+            assert_eq!((request.uuid, request.command, request.target), (uuid, Command::Tcp, target));
+        }
+        assert_eq!(parse_response(&[0, 2, 9, 9, 7]), Ok(((), 4)));
+        assert_eq!(parse_response(&[0, 2, 9]), Err(ParseError::Incomplete));
+    }
+
+    #[test]
+    fn test_parse_addons() {
+        assert_eq!(parse_addons(&[]), Ok(Addons::default()));
+        let mut addons = vec![0x0a, 16];
+        addons.extend_from_slice(b"xtls-rprx-vision");
+        addons.extend_from_slice(&[0x12, 2, 7, 8, 0x18, 0x96, 0x01]);
+        let parsed = parse_addons(&addons).unwrap();
+        assert_eq!(parsed.flow, "xtls-rprx-vision");
+        assert_eq!(parsed.seed, [7, 8]);
+
+        assert_eq!(parse_addons(&[0x0a, 5, b'x']), Err(ParseError::Invalid("malformed vless addons")));
+        assert_eq!(parse_addons(&[0x0b]), Err(ParseError::Invalid("malformed vless addons")));
+    }
+}
@@ -0,0 +1,212 @@
+use super::{AddrScheme, Command, ParseError, ParseResult, Reader, Target};
+use crate::common::{
+    digest, hash, KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY
+};
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes128Gcm,
+};
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub version: u8,
+    pub iv: [u8; 16],
+    pub key: [u8; 16],
+    pub response_auth: u8,
+    pub options: u8,
+    pub padding_len: u8,
+    pub security: u8,
+    pub command: Command,
+    pub target: Target,
+}
+
+// how far the auth id timestamp may be off, in seconds
+static MAX_TIME_DIFF: u64 = 120;
+
+// key material derived from the uuid alone, the same for every connection
+#[derive(Clone, Copy)]
+pub struct Keys {
+    // md5(uuid + "c48619fe-8f02-49e0-b9e9-edf763e17e21")
+    pub cmd_key: [u8; 16],
+    auth_id_key: [u8; 16],
+}
+
+impl Keys {
+    pub fn new(uuid: &[u8]) -> Self {
+        Self::from_cmd_key(digest::md5(&[uuid, b"c48619fe-8f02-49e0-b9e9-edf763e17e21"]))
+    }
+
+    fn from_cmd_key(cmd_key: [u8; 16]) -> Self {
+        let auth_id_key = digest::truncated(&hash::kdf(&cmd_key, &[KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY]));
+        Self { cmd_key, auth_id_key }
+    }
+}
+
+// the auth id is a single aes block: timestamp (8), random (4) and the
+// crc32 of both (4). checking it is cheap, so it gates the aead path.
+// https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/aead/authid.go
+pub fn verify_auth_id(keys: &Keys, auth_id: &[u8], now: u64) -> bool {
+    let Ok(auth_id) = <[u8; 16]>::try_from(auth_id) else {
+        return false;
+    };
+    let mut block = auth_id.into();
+    Aes128::new(&keys.auth_id_key.into()).decrypt_block(&mut block);
+
+    let checksum = hash::crc32(&block[..12]);
+    let timestamp = u64::from_be_bytes(block[..8].try_into().unwrap());
+    block[12..] == checksum.to_be_bytes() && timestamp.abs_diff(now) <= MAX_TIME_DIFF
+}
+
+fn aead_open(key: &[u8], nonce: &[u8], msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, ParseError> {
+    Aes128Gcm::new(key.into())
+        .decrypt(nonce.into(), Payload { msg, aad })
+        .map_err(|_| ParseError::Invalid("vmess header authentication failed"))
+}
+
+// `cmd_key` is md5(uuid + "c48619fe-8f02-49e0-b9e9-edf763e17e21")
+pub fn parse(buf: &[u8], cmd_key: &[u8]) -> ParseResult<Request> {
+    let mut r = Reader::new(buf);
+
+    // +-------------------+-------------------+-------------------+
+    // |     Auth ID       |   Header Length   |       Nonce       |
+    // +-------------------+-------------------+-------------------+
+    // |     16 Bytes      |     18 Bytes      |      8 Bytes      |
+    // +-------------------+-------------------+-------------------+
+    let auth_id = r.array::<16>()?;
+    let len = r.array::<18>()?;
+    let nonce = r.array::<8>()?;
+
+    // https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/aead/kdf.go
+    let header_length = {
+        let header_length_key = &hash::kdf(
+            cmd_key,
+            &[KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY, &auth_id, &nonce],
+        )[..16];
+        let header_length_nonce = &hash::kdf(
+            cmd_key,
+            &[KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV, &auth_id, &nonce],
+        )[..12];
+
+        let len = aead_open(header_length_key, header_length_nonce, &len, &auth_id)?;
+        u16::from_be_bytes([len[0], len[1]])
+    };
+
+    // 16 bytes tag
+    let cmd = r.take(header_length as usize + 16)?;
+
+    let header_payload = {
+        let payload_key = &hash::kdf(
+            cmd_key,
+            &[KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY, &auth_id, &nonce],
+        )[..16];
+        let payload_nonce = &hash::kdf(
+            cmd_key,
+            &[KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV, &auth_id, &nonce],
+        )[..12];
+
+        aead_open(payload_key, payload_nonce, cmd, &auth_id)?
+    };
+
+    let request = match parse_command(&header_payload) {
+        Err(ParseError::Incomplete) => return Err(ParseError::Invalid("truncated vmess command")),
+        x => x?,
+    };
+    Ok((request, r.pos()))
+}
+
+// https://xtls.github.io/en/development/protocols/vmess.html#command-section
+//
+// +---------+--------------------+---------------------+-------------------------------+---------+----------+-------------------+----------+---------+---------+--------------+---------+--------------+----------+
+// | 1 Byte  |      16 Bytes      |      16 Bytes       |            1 Byte             | 1 Byte  |  4 bits  |      4 bits       |  1 Byte  | 1 Byte  | 2 Bytes |    1 Byte    | N Bytes |   P Bytes    | 4 Bytes  |
+// +---------+--------------------+---------------------+-------------------------------+---------+----------+-------------------+----------+---------+---------+--------------+---------+--------------+----------+
+// | Version | Data Encryption IV | Data Encryption Key | Response Authentication Value | Options | Reserved | Encryption Method | Reserved | Command | Port    | Address Type | Address | Random Value | Checksum |
+// +---------+--------------------+---------------------+-------------------------------+---------+----------+-------------------+----------+---------+---------+--------------+---------+--------------+----------+
+pub fn parse_command(buf: &[u8]) -> Result<Request, ParseError> {
+    let mut r = Reader::new(buf);
+
+    let version = r.u8()?;
+    if version != 1 {
+        return Err(ParseError::Invalid("invalid version"));
+    }
+    let iv = r.array::<16>()?;
+    let key = r.array::<16>()?;
+    let response_auth = r.u8()?;
+    let options = r.u8()?;
+    let padding_and_security = r.u8()?;
+    let _reserved = r.u8()?;
+    let command = match r.u8()? {
+        1 => Command::Tcp,
+        2 => Command::Udp,
+        3 => Command::Mux,
+        _ => return Err(ParseError::Invalid("unknown vmess command")),
+    };
+    let port = r.u16()?;
+    let addr = r.addr(AddrScheme::V2ray)?;
+    let padding_len = padding_and_security >> 4;
+    r.take(padding_len as usize)?;
+
+    let checksum = hash::fnv1a32(&buf[..r.pos()]);
+    if r.array::<4>()? != checksum.to_be_bytes() {
+        return Err(ParseError::Invalid("vmess command checksum mismatch"));
+    }
+
+    Ok(Request {
+        version,
+        iv,
+        key,
+        response_auth,
+        options,
+        padding_len,
+        security: padding_and_security & 0x0f,
+        command,
+        target: Target { addr, port },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command() -> Vec<u8> {
+        let mut buf = vec![1u8];
+        buf.extend_from_slice(&[0u8; 33]); // iv, key, response auth
+        buf.extend_from_slice(&[1, 0x23, 0, 1, 0x01, 0xbb, 1, 127, 0, 0, 1]);
+        buf.extend_from_slice(&[0xaa, 0xbb]); // 2 bytes padding
+        buf.extend_from_slice(&hash::fnv1a32(&buf).to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_verify_auth_id() {
+        use aes::cipher::BlockEncrypt;
+
+        let cmd_key = [9u8; 16];
+        let now = 1_700_000_000u64;
+        let mut plain = now.to_be_bytes().to_vec();
+        plain.extend_from_slice(&[1, 2, 3, 4]);
+        plain.extend_from_slice(&hash::crc32(&plain).to_be_bytes());
+        let key = &hash::kdf(&cmd_key, &[KDFSALT_CONST_AUTH_ID_ENCRYPTION_KEY])[..16];
+        let mut block = <[u8; 16]>::try_from(plain).unwrap().into();
+        Aes128::new(key.into()).encrypt_block(&mut block);
+
+        let keys = Keys::from_cmd_key(cmd_key);
+        assert!(verify_auth_id(&keys, &block, now + 60));
+        assert!(!verify_auth_id(&keys, &block, now + 600));
+        assert!(!verify_auth_id(&Keys::from_cmd_key([8u8; 16]), &block, now));
+        assert!(!verify_auth_id(&keys, &[0u8; 16], now));
+    }
+
+    #[test]
+    fn test_parse_command_checksum() {
+        let request = parse_command(&command()).unwrap();
+        assert_eq!(request.padding_len, 2);
+        assert_eq!(request.security, 3);
+        assert_eq!(request.target, Target { addr: "127.0.0.1".to_string(), port: 443 });
+
+        let mut forged = command();
+        forged[45] = 2;
+        assert_eq!(parse_command(&forged), Err(ParseError::Invalid("vmess command checksum mismatch")));
+    }
+}
@@ -0,0 +1,425 @@
+// vmess data stream after the header: a sequence of chunks, each a 2 byte
+// length followed by the (sealed) payload and optional padding. with chunk
+// masking the length is xored with a shake128 stream seeded by the body iv,
+// with global padding the same stream also decides the padding length.
+// https://github.com/v2fly/v2ray-core/blob/master/common/crypto/auth.go
+use super::{Chunk, ParseError};
+use crate::common::digest;
+use aes::cipher::KeyInit;
+use aes_gcm::aead::{Aead, AeadInPlace};
+use aes_gcm::Aes128Gcm;
+use bytes::{Buf, BytesMut};
+use chacha20poly1305::ChaCha20Poly1305;
+use sha3::digest::{ExtendableOutput, Update, XofReader};
+use sha3::{Shake128, Shake128Reader};
+
+pub const OPTION_CHUNK_STREAM: u8 = 0x01;
+pub const OPTION_CHUNK_MASKING: u8 = 0x04;
+pub const OPTION_GLOBAL_PADDING: u8 = 0x08;
+
+pub const SECURITY_AES_128_GCM: u8 = 0x03;
+pub const SECURITY_CHACHA20_POLY1305: u8 = 0x04;
+pub const SECURITY_NONE: u8 = 0x05;
+pub const SECURITY_ZERO: u8 = 0x06;
+
+// largest payload put in one chunk, leaves room for the length, tag and
+// padding within a 16k frame
+pub const MAX_CHUNK_PAYLOAD: usize = 16 * 1024 - 128;
+static TAG_LEN: usize = 16;
+
+// chunk lengths are either plain or masked, the mask stream doubles as the
+// padding length source.
+struct SizeCodec {
+    shake: Option<Shake128Reader>,
+    padding: bool,
+}
+
+impl SizeCodec {
+    fn new(options: u8, iv: &[u8]) -> Self {
+        let shake = (options & OPTION_CHUNK_MASKING != 0).then(|| {
+            let mut shake = Shake128::default();
+            shake.update(iv);
+            shake.finalize_xof()
+        });
+        let padding = shake.is_some() && options & OPTION_GLOBAL_PADDING != 0;
+        Self { shake, padding }
+    }
+
+    fn next(&mut self) -> u16 {
+        let mut mask = [0u8; 2];
+        if let Some(shake) = &mut self.shake {
+            shake.read(&mut mask);
+        }
+        u16::from_be_bytes(mask)
+    }
+
+    // padding goes first, the order matters as both draw from one stream
+    fn padding_len(&mut self) -> u16 {
+        if self.padding {
+            self.next() % 64
+        } else {
+            0
+        }
+    }
+}
+
+// built once per direction of a connection, every chunk reuses it
+enum Cipher {
+    None,
+    Aes128Gcm(Box<Aes128Gcm>),
+    // what "auto" picks on clients without aes instructions
+    Chacha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+impl Cipher {
+    fn new(security: u8, key: &[u8; 16]) -> Result<Self, ParseError> {
+        match security {
+            SECURITY_AES_128_GCM => Ok(Self::Aes128Gcm(Box::new(Aes128Gcm::new(key.into())))),
+            SECURITY_CHACHA20_POLY1305 => Ok(Self::Chacha20Poly1305(Box::new(ChaCha20Poly1305::new(&chacha_key(key).into())))),
+            SECURITY_NONE => Ok(Self::None),
+            _ => Err(ParseError::Invalid("unsupported vmess security")),
+        }
+    }
+
+    fn overhead(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::Aes128Gcm(_) | Self::Chacha20Poly1305(_) => TAG_LEN,
+        }
+    }
+
+    fn open(&self, nonce: &[u8; 12], sealed: &[u8]) -> Result<Vec<u8>, ParseError> {
+        let opened = match self {
+            Self::None => return Ok(sealed.to_vec()),
+            Self::Aes128Gcm(cipher) => cipher.decrypt(nonce.into(), sealed),
+            Self::Chacha20Poly1305(cipher) => cipher.decrypt(nonce.into(), sealed),
+        };
+        opened.map_err(|_| ParseError::Invalid("vmess chunk authentication failed"))
+    }
+
+    // seals `payload` in place and appends the tag
+    fn seal(&self, nonce: &[u8; 12], payload: &mut Vec<u8>, from: usize) -> Result<(), ParseError> {
+        let tag = match self {
+            Self::None => return Ok(()),
+            Self::Aes128Gcm(cipher) => cipher.encrypt_in_place_detached(nonce.into(), b"", &mut payload[from..]),
+            Self::Chacha20Poly1305(cipher) => cipher.encrypt_in_place_detached(nonce.into(), b"", &mut payload[from..]),
+        };
+        let tag = tag.map_err(|_| ParseError::Invalid("vmess chunk sealing failed"))?;
+        payload.extend_from_slice(&tag);
+        Ok(())
+    }
+}
+
+// chacha needs 32 key bytes, vmess stretches its 16 with md5:
+// md5(key) followed by md5(md5(key))
+fn chacha_key(key: &[u8; 16]) -> [u8; 32] {
+    let first = digest::md5(&[key]);
+    let mut stretched = [0u8; 32];
+    stretched[..16].copy_from_slice(&first);
+    stretched[16..].copy_from_slice(&digest::md5(&[&first]));
+    stretched
+}
+
+// 2 bytes chunk counter followed by bytes 2..12 of the body iv
+fn chunk_nonce(count: u16, iv: &[u8; 16]) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..2].copy_from_slice(&count.to_be_bytes());
+    nonce[2..].copy_from_slice(&iv[2..12]);
+    nonce
+}
+
+pub struct ChunkReader {
+    sizes: SizeCodec,
+    cipher: Cipher,
+    iv: [u8; 16],
+    count: u16,
+    // length and padding of a chunk whose payload hasn't fully arrived yet,
+    // they can't be decoded twice as that would advance the mask stream.
+    pending: Option<(usize, usize)>,
+}
+
+impl ChunkReader {
+    // `key` and `iv` are the request body key and iv from the header
+    pub fn new(options: u8, security: u8, key: &[u8; 16], iv: &[u8; 16]) -> Result<Self, ParseError> {
+        Ok(Self {
+            sizes: SizeCodec::new(options, iv),
+            cipher: Cipher::new(security, key)?,
+            iv: *iv,
+            count: 0,
+            pending: None,
+        })
+    }
+
+    // takes one chunk off the front of `buf`, Incomplete when it hasn't
+    // fully arrived yet.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Chunk, ParseError> {
+        let (size, padding) = match self.pending.take() {
+            Some(pending) => pending,
+            None => {
+                if buf.len() < 2 {
+                    return Err(ParseError::Incomplete);
+                }
+                let padding = self.sizes.padding_len() as usize;
+                let size = (self.sizes.next() ^ buf.get_u16()) as usize;
+                (size, padding)
+            }
+        };
+
+        let overhead = self.cipher.overhead();
+        if size < overhead + padding {
+            return Err(ParseError::Invalid("vmess chunk shorter than its overhead"));
+        }
+        if buf.len() < size {
+            self.pending = Some((size, padding));
+            return Err(ParseError::Incomplete);
+        }
+        if size == overhead + padding {
+            buf.advance(size);
+            return Ok(Chunk::End);
+        }
+
+        let chunk = buf.split_to(size);
+        let sealed = &chunk[..size - padding];
+        let payload = self.cipher.open(&chunk_nonce(self.count, &self.iv), sealed)?;
+        self.count = self.count.wrapping_add(1);
+        Ok(Chunk::Data(payload))
+    }
+}
+
+pub struct ChunkWriter {
+    sizes: SizeCodec,
+    cipher: Cipher,
+    iv: [u8; 16],
+    count: u16,
+}
+
+impl ChunkWriter {
+    // `key` and `iv` are the response body key and iv, sha256 of the
+    // request's truncated to 16 bytes
+    pub fn new(options: u8, security: u8, key: &[u8; 16], iv: &[u8; 16]) -> Result<Self, ParseError> {
+        Ok(Self {
+            sizes: SizeCodec::new(options, iv),
+            cipher: Cipher::new(security, key)?,
+            iv: *iv,
+            count: 0,
+        })
+    }
+
+    // payloads longer than MAX_CHUNK_PAYLOAD have to be split by the caller,
+    // they're refused here. an empty payload is the end of stream chunk. the
+    // payload is sealed in place in the chunk, so each chunk costs one
+    // allocation.
+    pub fn encode(&mut self, payload: &[u8]) -> Result<Vec<u8>, ParseError> {
+        if payload.len() > MAX_CHUNK_PAYLOAD {
+            return Err(ParseError::Invalid("vmess chunk payload too long"));
+        }
+        let (mut chunk, size) = self.head(payload.len() + self.cipher.overhead());
+        chunk.extend_from_slice(payload);
+        self.cipher.seal(&chunk_nonce(self.count, &self.iv), &mut chunk, 2)?;
+        self.count = self.count.wrapping_add(1);
+        pad(&mut chunk, size);
+        Ok(chunk)
+    }
+
+    // for sealing the next chunk elsewhere, e.g. with webcrypto: its nonce,
+    // None when chunks aren't sealed. the result goes to `frame`, before any
+    // other chunk is encoded.
+    pub fn next_nonce(&mut self) -> Option<[u8; 12]> {
+        let Cipher::Aes128Gcm(_) = self.cipher else {
+            return None;
+        };
+        let nonce = chunk_nonce(self.count, &self.iv);
+        self.count = self.count.wrapping_add(1);
+        Some(nonce)
+    }
+
+    // a chunk around a payload sealed with `next_nonce`'s nonce
+    pub fn frame(&mut self, sealed: &[u8]) -> Vec<u8> {
+        let (mut chunk, size) = self.head(sealed.len());
+        chunk.extend_from_slice(sealed);
+        pad(&mut chunk, size);
+        chunk
+    }
+
+    // the length of a chunk sealing to `sealed_len` bytes, and its size.
+    // padding is drawn first, both come from the mask stream.
+    fn head(&mut self, sealed_len: usize) -> (Vec<u8>, usize) {
+        let size = sealed_len + self.sizes.padding_len() as usize;
+        let mut chunk = Vec::with_capacity(2 + size);
+        chunk.extend_from_slice(&(self.sizes.next() ^ size as u16).to_be_bytes());
+        (chunk, size)
+    }
+}
+
+// random padding after the sealed payload, up to the chunk's size
+fn pad(chunk: &mut Vec<u8>, size: usize) {
+    let sealed_end = chunk.len();
+    chunk.resize(2 + size, 0);
+    let _ = getrandom::getrandom(&mut chunk[sealed_end..]);
+}
+
+// both halves of a vmess data stream, None when the client asked for a raw
+// stream (no chunk stream option or the zero security).
+pub fn codec(
+    options: u8,
+    security: u8,
+    is_stream: bool,
+    key: &[u8; 16],
+    iv: &[u8; 16],
+    response_key: &[u8; 16],
+    response_iv: &[u8; 16],
+) -> Result<Option<(ChunkReader, ChunkWriter)>, ParseError> {
+    if options & OPTION_CHUNK_STREAM == 0 || security == SECURITY_ZERO {
+        return Ok(None);
+    }
+    // unsealed tcp streams use v2ray's plain chunk stream, which never pads
+    let options = if security == SECURITY_NONE && is_stream {
+        options & !OPTION_GLOBAL_PADDING
+    } else {
+        options
+    };
+    let reader = ChunkReader::new(options, security, key, iv)?;
+    let writer = ChunkWriter::new(options, security, response_key, response_iv)?;
+    Ok(Some((reader, writer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_masked_padded_chunks() {
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING;
+        let iv = [7u8; 16];
+        let mut writer = ChunkWriter::new(options, SECURITY_NONE, &[0u8; 16], &iv).unwrap();
+        let mut reader = ChunkReader::new(options, SECURITY_NONE, &[0u8; 16], &iv).unwrap();
+
+        let mut stream = BytesMut::new();
+        stream.extend_from_slice(&writer.encode(b"hello").unwrap());
+        stream.extend_from_slice(&writer.encode(b"world").unwrap());
+        stream.extend_from_slice(&writer.encode(b"").unwrap());
+
+        // feed the first chunk in two halves to exercise the pending state
+        let mut partial = stream.split_to(3);
+        assert_eq!(reader.decode(&mut partial), Err(ParseError::Incomplete));
+        partial.unsplit(stream);
+        let mut stream = partial;
+
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"hello".to_vec())));
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"world".to_vec())));
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::End));
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn test_chacha20_poly1305_chunks() {
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING;
+        let (key, iv) = ([9u8; 16], [10u8; 16]);
+        let mut writer = ChunkWriter::new(options, SECURITY_CHACHA20_POLY1305, &key, &iv).unwrap();
+        let mut reader = ChunkReader::new(options, SECURITY_CHACHA20_POLY1305, &key, &iv).unwrap();
+        // never handed to webcrypto, which only does aes-gcm
+        assert_eq!(writer.next_nonce(), None);
+
+        let first = writer.encode(b"hello").unwrap();
+        assert!(!first.windows(5).any(|x| x == b"hello"));
+        let mut stream = BytesMut::from(&first[..]);
+        stream.extend_from_slice(&writer.encode(b"world").unwrap());
+        stream.extend_from_slice(&writer.encode(b"").unwrap());
+
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"hello".to_vec())));
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"world".to_vec())));
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::End));
+        assert!(stream.is_empty());
+
+        // the key is stretched the vmess way, not zero padded
+        let sealed = ChaCha20Poly1305::new(&chacha_key(&key).into())
+            .encrypt(&chunk_nonce(0, &iv).into(), &b"payload"[..])
+            .unwrap();
+        let mut stream = BytesMut::new();
+        stream.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+        stream.extend_from_slice(&sealed);
+        let mut reader = ChunkReader::new(OPTION_CHUNK_STREAM, SECURITY_CHACHA20_POLY1305, &key, &iv).unwrap();
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"payload".to_vec())));
+    }
+
+    #[test]
+    fn test_aes_gcm_chunk() {
+        let key = [1u8; 16];
+        let iv = [2u8; 16];
+        let sealed = Aes128Gcm::new(&key.into())
+            .encrypt(&chunk_nonce(0, &iv).into(), &b"payload"[..])
+            .unwrap();
+        let mut stream = BytesMut::new();
+        stream.extend_from_slice(&(sealed.len() as u16).to_be_bytes());
+        stream.extend_from_slice(&sealed);
+
+        let mut reader = ChunkReader::new(OPTION_CHUNK_STREAM, SECURITY_AES_128_GCM, &key, &iv).unwrap();
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"payload".to_vec())));
+    }
+
+    #[test]
+    fn test_sealed_response_chunks() {
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING;
+        let (key, iv) = ([3u8; 16], [4u8; 16]);
+        let mut writer = ChunkWriter::new(options, SECURITY_AES_128_GCM, &key, &iv).unwrap();
+        let mut reader = ChunkReader::new(options, SECURITY_AES_128_GCM, &key, &iv).unwrap();
+
+        let first = writer.encode(b"hello").unwrap();
+        assert!(!first.windows(5).any(|x| x == b"hello"));
+        let mut stream = BytesMut::from(&first[..]);
+        stream.extend_from_slice(&writer.encode(b"world").unwrap());
+        stream.extend_from_slice(&writer.encode(b"").unwrap());
+
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"hello".to_vec())));
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"world".to_vec())));
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::End));
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn test_codec_seals_responses() {
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING;
+        let (key, iv, response_key, response_iv) = ([5u8; 16], [6u8; 16], [7u8; 16], [8u8; 16]);
+        let (_, mut writer) = codec(options, SECURITY_AES_128_GCM, true, &key, &iv, &response_key, &response_iv)
+            .unwrap()
+            .unwrap();
+        let chunk = writer.encode(b"response").unwrap();
+        assert_eq!(chunk.len(), 2 + b"response".len() + TAG_LEN);
+        assert!(!chunk.windows(8).any(|x| x == b"response"));
+
+        // only the response key opens it
+        let mut stream = BytesMut::from(&chunk[..]);
+        let mut reader = ChunkReader::new(options, SECURITY_AES_128_GCM, &response_key, &response_iv).unwrap();
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"response".to_vec())));
+        let mut stream = BytesMut::from(&chunk[..]);
+        let mut reader = ChunkReader::new(options, SECURITY_AES_128_GCM, &key, &response_iv).unwrap();
+        assert!(reader.decode(&mut stream).is_err());
+    }
+
+    #[test]
+    fn test_sealed_elsewhere() {
+        let options = OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING;
+        let (key, iv) = ([3u8; 16], [4u8; 16]);
+        let mut writer = ChunkWriter::new(options, SECURITY_AES_128_GCM, &key, &iv).unwrap();
+        let mut reader = ChunkReader::new(options, SECURITY_AES_128_GCM, &key, &iv).unwrap();
+
+        let mut stream = BytesMut::from(&writer.encode(b"hello").unwrap()[..]);
+        let nonce = writer.next_nonce().unwrap();
+        let sealed = Aes128Gcm::new(&key.into()).encrypt(&nonce.into(), &b"world"[..]).unwrap();
+        stream.extend_from_slice(&writer.frame(&sealed));
+        stream.extend_from_slice(&writer.encode(b"!").unwrap());
+
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"hello".to_vec())));
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"world".to_vec())));
+        assert_eq!(reader.decode(&mut stream), Ok(Chunk::Data(b"!".to_vec())));
+        assert!(ChunkWriter::new(options, SECURITY_NONE, &key, &iv).unwrap().next_nonce().is_none());
+    }
+
+    #[test]
+    fn test_oversize_payload() {
+        let mut writer = ChunkWriter::new(OPTION_CHUNK_STREAM, SECURITY_AES_128_GCM, &[1u8; 16], &[2u8; 16]).unwrap();
+        let payload = vec![0u8; MAX_CHUNK_PAYLOAD + 1];
+        assert!(writer.encode(&payload).is_err());
+        assert!(writer.encode(&payload[..MAX_CHUNK_PAYLOAD]).is_ok());
+    }
+}
@@ -0,0 +1,90 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "1" | "v1" => Ok(Self::V1),
+            "2" | "v2" => Ok(Self::V2),
+            _ => Err(format!("unknown proxy protocol version: {}", s)),
+        }
+    }
+}
+
+impl ProxyProtocol {
+    // the destination is often a hostname, in which case (or when the address
+    // families differ) an unspecified address of the source family is sent.
+    pub fn header(&self, src: IpAddr, dst: Option<IpAddr>, dst_port: u16) -> Vec<u8> {
+        let dst = match (src, dst) {
+            (IpAddr::V4(_), Some(IpAddr::V4(d))) => IpAddr::V4(d),
+            (IpAddr::V6(_), Some(IpAddr::V6(d))) => IpAddr::V6(d),
+            (IpAddr::V4(_), _) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (IpAddr::V6(_), _) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+
+        match self {
+            Self::V1 => {
+                let family = if src.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!("PROXY {} {} {} 0 {}\r\n", family, src, dst, dst_port).into_bytes()
+            }
+            Self::V2 => {
+                let mut header = V2_SIGNATURE.to_vec();
+                header.push(0x21); // version 2, PROXY command
+                match (src, dst) {
+                    (IpAddr::V4(s), IpAddr::V4(d)) => {
+                        header.push(0x11); // TCP over IPv4
+                        header.extend_from_slice(&12u16.to_be_bytes());
+                        header.extend_from_slice(&s.octets());
+                        header.extend_from_slice(&d.octets());
+                    }
+                    (IpAddr::V6(s), IpAddr::V6(d)) => {
+                        header.push(0x21); // TCP over IPv6
+                        header.extend_from_slice(&36u16.to_be_bytes());
+                        header.extend_from_slice(&s.octets());
+                        header.extend_from_slice(&d.octets());
+                    }
+                    _ => unreachable!("address families are matched above"),
+                }
+                header.extend_from_slice(&0u16.to_be_bytes()); // source port is not exposed
+                header.extend_from_slice(&dst_port.to_be_bytes());
+                header
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v1_header() {
+        let src: IpAddr = "203.0.113.7".parse().unwrap();
+        let header = ProxyProtocol::V1.header(src, None, 443);
+        assert_eq!(header, b"PROXY TCP4 203.0.113.7 0.0.0.0 0 443\r\n");
+    }
+
+    #[test]
+    fn test_v2_header() {
+        let src: IpAddr = "203.0.113.7".parse().unwrap();
+        let dst: IpAddr = "198.51.100.1".parse().unwrap();
+        let header = ProxyProtocol::V2.header(src, Some(dst), 8443);
+        assert_eq!(header[..12], V2_SIGNATURE);
+        assert_eq!(header[12..16], [0x21, 0x11, 0x00, 0x0c]);
+        assert_eq!(header[16..24], [203, 0, 113, 7, 198, 51, 100, 1]);
+        assert_eq!(header[24..], [0x00, 0x00, 0x20, 0xfb]);
+    }
+}
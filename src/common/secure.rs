@@ -0,0 +1,27 @@
+// constant-time equality for credentials (uuids, trojan hashes, tokens), the
+// running time only depends on the input lengths which aren't secret.
+pub fn ct_eq(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> bool {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let diff = a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ct_eq() {
+        let uuid = uuid::uuid!("96850032-1b92-46e9-a4f2-b99631456894");
+        assert!(ct_eq(uuid.as_bytes(), uuid.as_bytes()));
+        assert!(!ct_eq(uuid.as_bytes(), uuid::Uuid::nil().as_bytes()));
+        assert!(ct_eq("token", "token"));
+        assert!(!ct_eq("token", "tokem"));
+        assert!(!ct_eq("token", "token2"));
+        assert!(ct_eq("", ""));
+    }
+}
@@ -0,0 +1,80 @@
+// count-min sketch with a small top-k on the side: heavy hitters of an
+// unbounded stream (queried domains) in fixed memory. counts may be over-,
+// never underestimated.
+use super::hash::fnv1a32;
+
+use std::collections::HashMap;
+
+const DEPTH: usize = 4;
+const WIDTH: usize = 512;
+
+pub struct TopK {
+    counts: Box<[[u32; WIDTH]; DEPTH]>,
+    top: HashMap<String, u32>,
+    k: usize,
+}
+
+impl TopK {
+    pub fn new(k: usize) -> Self {
+        Self { counts: Box::new([[0; WIDTH]; DEPTH]), top: HashMap::new(), k }
+    }
+
+    // each row hashes with its own seed byte in front
+    fn slots(item: &str) -> impl Iterator<Item = (usize, usize)> + '_ {
+        (0..DEPTH).map(move |row| {
+            let hash = fnv1a32(&[&[row as u8], item.as_bytes()].concat());
+            (row, hash as usize % WIDTH)
+        })
+    }
+
+    pub fn add(&mut self, item: &str) {
+        let mut estimate = u32::MAX;
+        for (row, col) in Self::slots(item) {
+            let count = &mut self.counts[row][col];
+            *count = count.saturating_add(1);
+            estimate = estimate.min(*count);
+        }
+
+        if self.top.contains_key(item) || self.top.len() < self.k {
+            self.top.insert(item.to_string(), estimate);
+            return;
+        }
+        let Some((min_item, min)) = self.top.iter().min_by_key(|(_, count)| **count).map(|(x, c)| (x.clone(), *c)) else {
+            return;
+        };
+        if estimate > min {
+            self.top.remove(&min_item);
+            self.top.insert(item.to_string(), estimate);
+        }
+    }
+
+    // most frequent first
+    pub fn top(&self) -> Vec<(String, u32)> {
+        let mut top: Vec<_> = self.top.iter().map(|(x, count)| (x.clone(), *count)).collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_top_k() {
+        let mut sketch = TopK::new(2);
+        for i in 0..200 {
+            sketch.add("a.example");
+            if i % 2 == 0 {
+                sketch.add("b.example");
+            }
+            sketch.add(&format!("{}.noise", i));
+        }
+        let top = sketch.top();
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].0, "a.example");
+        assert!(top[0].1 >= 200);
+        assert_eq!(top[1].0, "b.example");
+        assert!(top[1].1 >= 100);
+    }
+}
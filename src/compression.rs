@@ -0,0 +1,82 @@
+use worker::*;
+
+// text bodies worth compressing: pages, api json and subscriptions, which
+// are plain text (v2ray, raw) or yaml (clash)
+static COMPRESSIBLE: [&str; 4] = ["text/html", "application/json", "text/plain", "application/x-yaml"];
+
+// the runtime compresses a body on its way out according to the
+// Content-Encoding header (`EncodeBody::Automatic`), so all that's needed
+// here is picking the encoding and setting the header.
+pub fn apply(accept_encoding: Option<&str>, mut res: Response) -> Result<Response> {
+    let content_type = res.headers().get("Content-Type")?;
+    let encoded = res.headers().has("Content-Encoding")?;
+    if let Some(encoding) = encoding_for(accept_encoding, res.status_code(), content_type.as_deref(), encoded) {
+        res.headers_mut().set("Content-Encoding", encoding)?;
+        res.headers_mut().append("Vary", "Accept-Encoding")?;
+    }
+    Ok(res)
+}
+
+// what `apply` decides, apart from the headers which only exist in the
+// workers runtime
+fn encoding_for(accept_encoding: Option<&str>, status: u16, content_type: Option<&str>, encoded: bool) -> Option<&'static str> {
+    if status == 101 || encoded {
+        return None;
+    }
+    let content_type = content_type?.to_ascii_lowercase();
+    if !COMPRESSIBLE.iter().any(|x| content_type.starts_with(x)) {
+        return None;
+    }
+    accept_encoding.and_then(negotiate)
+}
+
+// brotli over gzip unless the client weights gzip higher, `q=0` disables
+fn negotiate(accept: &str) -> Option<&'static str> {
+    let mut best: Option<(&'static str, f32)> = None;
+    for entry in accept.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let q = parts
+            .find_map(|x| x.strip_prefix("q="))
+            .map_or(Some(1.0), |x| x.parse::<f32>().ok())
+            .unwrap_or(0.0);
+        let encoding = match name.to_ascii_lowercase().as_str() {
+            "br" => "br",
+            "gzip" => "gzip",
+            _ => continue,
+        };
+        if q > 0.0 && best.is_none_or(|(b, bq)| q > bq || (q == bq && encoding == "br" && b != "br")) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some("br"));
+        assert_eq!(negotiate("gzip"), Some("gzip"));
+        assert_eq!(negotiate("br;q=0.5, gzip;q=0.8"), Some("gzip"));
+        assert_eq!(negotiate("br;q=0, gzip;q=0"), None);
+        assert_eq!(negotiate("deflate, identity"), None);
+    }
+
+    #[test]
+    fn test_encoding_for() {
+        let accept = Some("gzip, br");
+        for content_type in ["text/plain; charset=utf-8", "application/x-yaml; charset=utf-8", "text/html", "application/json"] {
+            assert_eq!(encoding_for(accept, 200, Some(content_type), false), Some("br"));
+        }
+        assert_eq!(encoding_for(Some("gzip"), 200, Some("text/plain"), false), Some("gzip"));
+        // binary, untyped, already encoded, upgrades and clients without support
+        assert_eq!(encoding_for(accept, 200, Some("application/octet-stream"), false), None);
+        assert_eq!(encoding_for(accept, 200, None, false), None);
+        assert_eq!(encoding_for(accept, 200, Some("text/plain"), true), None);
+        assert_eq!(encoding_for(accept, 101, Some("text/plain"), false), None);
+        assert_eq!(encoding_for(None, 200, Some("text/plain"), false), None);
+    }
+}
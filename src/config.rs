@@ -1,14 +1,407 @@
+use crate::autoban::AutoBan;
+use crate::common::policy::DestinationRule;
+use crate::common::protocol::shadowsocks_body;
+use crate::common::proxy_protocol::ProxyProtocol;
+use crate::common::protocol::dns::qtype;
+use crate::proxy::dns::{DnsConfig, DEFAULT_DOH_URLS};
+use crate::proxy::geoip::DEFAULT_GEOIP_URL;
+use crate::proxy::relay::SlowPolicy;
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
 use uuid::Uuid;
+use worker::{console_warn, Env, Error, ObjectNamespace, Request, Result, Url};
 
+#[derive(Clone, Default)]
 pub struct Config {
     pub uuid: Uuid,
-    pub host: String,
     pub proxy_addr: String,
     pub proxy_port: u16,
+    // label of the proxy list entry the proxyip came from, for the logs
+    pub proxy_label: Option<String>,
+    pub broker: Option<ObjectNamespace>,
+    pub client: ClientInfo,
+    pub trace: Trace,
+    // `?debug=1` from an admin, logs every step of this one tunnel
+    pub debug: bool,
+    pub proxy_protocol: Option<ProxyProtocol>,
+    pub proxy_protocol_targets: Vec<String>,
+    pub max_tunnels: Option<usize>,
+    pub max_global_tunnels: Option<usize>,
+    pub limiter: Option<ObjectNamespace>,
+    pub registry: Option<ObjectNamespace>,
+    pub autoban: Option<AutoBan>,
+    pub tunnel_token: Option<String>,
+    pub allowed_countries: Vec<String>,
+    pub blocked_countries: Vec<String>,
+    pub allowed_destinations: Vec<DestinationRule>,
+    // countries direct dials may not end up in, looked up at `geoip_url`
+    pub blocked_destination_countries: Vec<String>,
+    pub geoip_url: String,
+    // TLS_ONLY: the only ports tunnels may dial out to
+    pub tls_ports: Option<Vec<u16>>,
+    // how udp dns is answered
+    pub dns: DnsConfig,
+    pub padding: bool,
+    pub banned_uuids: Vec<Uuid>,
+    // the user a secret tunnel path resolved to, accounted instead of the
+    // shared uuid
+    pub path_user: Option<Uuid>,
+    pub user_quota: Option<u64>,
+    pub maintenance: bool,
+    pub fallback: Option<(String, u16)>,
+    // how long a client may take to send its header, 10s when unset
+    pub handshake_timeout: Option<Duration>,
+    // largest protocol header accepted (4kb) and the most a tunnel may hold
+    // in its buffers (512kb), when unset
+    pub max_header_size: Option<usize>,
+    pub max_buffer_size: Option<usize>,
+    pub slow_policy: Option<SlowPolicy>,
+    pub shadowsocks_plugin: bool,
+    pub shadowsocks_key: Option<shadowsocks_body::Key>,
+    pub shadowsocks_users: Vec<shadowsocks_body::User>,
+    // seal large vmess response chunks with the runtime's webcrypto
+    pub vmess_webcrypto: bool,
+    pub options: TunnelOptions,
+    // the first bytes of the stream, when the client sent them along with
+    // the upgrade, see `Subprotocol`
+    pub early_data: Option<Vec<u8>>,
+    // set on RELAY_PATH, where only peer deployments speaking vless connect
+    pub relay_inbound: bool,
+    // a peer deployment taking over the proxyip leg
+    pub upstream_relay: Option<UpstreamRelay>,
+}
+
+impl Config {
+    // only the tunnel route needs all of this, so it's built there instead of
+    // on every request
+    pub fn from_env(env: &Env, host: String) -> Result<Self> {
+        let uuid = host_uuid(env, &host)?;
+        let fallback = env.var("FALLBACK").ok().and_then(|x| {
+            let x = x.to_string();
+            let parsed = x.rsplit_once(':').and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)));
+            if parsed.is_none() {
+                console_warn!("invalid FALLBACK {}, expected host:port", x);
+            }
+            parsed
+        });
+        let shadowsocks_key = match (env.var("SHADOWSOCKS_METHOD"), env.secret("SHADOWSOCKS_PASSWORD")) {
+            (Ok(method), Ok(password)) => method
+                .to_string()
+                .parse()
+                .map_err(|e| console_warn!("{}", e))
+                .ok()
+                .and_then(|method| {
+                    shadowsocks_body::Key::from_password(method, &password.to_string())
+                        .map_err(|e| console_warn!("{}", e))
+                        .ok()
+                }),
+            _ => None,
+        };
+
+        Ok(Self {
+            uuid,
+            proxy_port: default_proxy_port(env, &host),
+            proxy_addr: host,
+            proxy_label: None,
+            broker: env.durable_object("BROKER").ok(),
+            client: ClientInfo::default(),
+            trace: Trace::default(),
+            debug: false,
+            proxy_protocol: env_parse(env, "PROXY_PROTOCOL"),
+            proxy_protocol_targets: env_list(env, "PROXY_PROTOCOL_TARGETS"),
+            max_tunnels: env_parse(env, "MAX_TUNNELS"),
+            max_global_tunnels: env_parse(env, "MAX_GLOBAL_TUNNELS"),
+            limiter: env.durable_object("LIMITER").ok(),
+            registry: env.durable_object("REGISTRY").ok(),
+            autoban: AutoBan::from_env(env),
+            tunnel_token: env.secret("TUNNEL_TOKEN").map(|x| x.to_string()).ok(),
+            allowed_countries: env_list(env, "ALLOWED_COUNTRIES"),
+            blocked_countries: env_list(env, "BLOCKED_COUNTRIES"),
+            allowed_destinations: env_list(env, "ALLOWED_DESTINATIONS")
+                .iter()
+                .filter_map(|x| x.parse().map_err(|e| console_warn!("{}", e)).ok())
+                .collect(),
+            blocked_destination_countries: env_list(env, "BLOCKED_DESTINATION_COUNTRIES"),
+            geoip_url: env.var("GEOIP_URL").map(|x| x.to_string()).unwrap_or(DEFAULT_GEOIP_URL.to_string()),
+            tls_ports: env_flag(env, "TLS_ONLY").then(|| {
+                let ports: Vec<u16> = env_list(env, "TLS_PORTS").iter().filter_map(|x| x.parse().ok()).collect();
+                if ports.is_empty() { vec![443] } else { ports }
+            }),
+            dns: DnsConfig {
+                upstreams: Some(env_list(env, "DOH_URLS"))
+                    .filter(|x| !x.is_empty())
+                    .unwrap_or_else(|| DEFAULT_DOH_URLS.map(String::from).to_vec()),
+                refused_types: env_list(env, "DNS_REFUSED_TYPES")
+                    .iter()
+                    .filter_map(|x| qtype(x).ok_or_else(|| console_warn!("unknown dns type {}", x)).ok())
+                    .collect(),
+                empty_aaaa: env_flag(env, "DNS_EMPTY_AAAA"),
+            },
+            padding: env_flag(env, "PADDING"),
+            banned_uuids: Vec::new(),
+            path_user: None,
+            user_quota: user_quota(env),
+            maintenance: env_flag(env, "MAINTENANCE"),
+            fallback,
+            handshake_timeout: env_parse(env, "HANDSHAKE_TIMEOUT_SECS").map(Duration::from_secs),
+            max_header_size: env_parse(env, "MAX_HEADER_BYTES"),
+            max_buffer_size: env_parse(env, "MAX_BUFFER_BYTES"),
+            slow_policy: env_parse(env, "SLOW_THRESHOLD_BYTES").map(|min_rate| SlowPolicy {
+                min_rate,
+                window: Duration::from_secs(env_parse(env, "SLOW_WINDOW_SECS").unwrap_or(30)),
+            }),
+            shadowsocks_plugin: env_flag(env, "SHADOWSOCKS_PLUGIN"),
+            vmess_webcrypto: env_flag(env, "VMESS_WEBCRYPTO"),
+            shadowsocks_key,
+            shadowsocks_users: Vec::new(),
+            options: TunnelOptions::default(),
+            early_data: None,
+            relay_inbound: false,
+            upstream_relay: UpstreamRelay::from_env(env)?,
+        })
+    }
+
+    // strict mode: once an allow list is configured nothing else may be dialed
+    pub fn is_destination_allowed(&self, addr: &str) -> bool {
+        self.allowed_destinations.is_empty()
+            || self.allowed_destinations.iter().any(|rule| rule.matches(addr))
+    }
+
+    pub fn is_port_allowed(&self, port: u16) -> bool {
+        self.tls_ports.as_ref().is_none_or(|ports| ports.contains(&port))
+    }
+
+    // an empty allow list means every country not on the deny list may connect
+    pub fn is_country_allowed(&self, country: &str) -> bool {
+        let listed = |list: &[String]| list.iter().any(|x| x.eq_ignore_ascii_case(country));
+        if listed(&self.blocked_countries) {
+            return false;
+        }
+        self.allowed_countries.is_empty() || listed(&self.allowed_countries)
+    }
+
+    // targets are matched either as "host" or as "host:port"
+    pub fn wants_proxy_protocol(&self, addr: &str, port: u16) -> Option<ProxyProtocol> {
+        let target = format!("{}:{}", addr, port);
+        self.proxy_protocol.filter(|_| {
+            self.proxy_protocol_targets
+                .iter()
+                .any(|x| x == addr || *x == target)
+        })
+    }
+}
+
+// comma separated env var, empty entries are skipped
+fn env_list(env: &Env, name: &str) -> Vec<String> {
+    env.var(name)
+        .map(|x| {
+            x.to_string()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn env_flag(env: &Env, name: &str) -> bool {
+    env.var(name)
+        .map(|x| matches!(x.to_string().to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+// the entry for `host` in a per-host list ("host=value,...")
+fn host_entry<'a>(list: &'a [String], host: &str) -> Option<&'a str> {
+    list.iter()
+        .filter_map(|x| x.split_once('='))
+        .find(|(h, _)| h.trim().eq_ignore_ascii_case(host))
+        .map(|(_, value)| value.trim())
+}
+
+// port of the proxyip when the path doesn't name one: a per-host entry of
+// PROXY_PORTS, else PROXY_PORT, else 443
+fn default_proxy_port(env: &Env, host: &str) -> u16 {
+    host_entry(&env_list(env, "PROXY_PORTS"), host)
+        .and_then(|port| port.parse().ok())
+        .or_else(|| env_parse(env, "PROXY_PORT"))
+        .unwrap_or(443)
+}
+
+// each hostname bound to the worker can have its own uuid in HOST_UUIDS,
+// the others share UUID
+pub fn host_uuid(env: &Env, host: &str) -> Result<Uuid> {
+    if let Some(uuid) = host_entry(&env_list(env, "HOST_UUIDS"), host) {
+        return Uuid::parse_str(uuid).map_err(|e| Error::RustError(format!("invalid HOST_UUIDS entry for {}: {}", host, e)));
+    }
+    env.var("UUID")
+        .map(|x| Uuid::parse_str(&x.to_string()).unwrap_or_default())
+}
+
+// the page set a hostname is branded with in HOST_PAGES, see
+// `pages::localized_url`
+pub fn page_set(env: &Env, host: &str) -> Option<String> {
+    host_entry(&env_list(env, "HOST_PAGES"), host).map(|x| x.to_ascii_uppercase())
+}
+
+fn env_parse<T: FromStr>(env: &Env, name: &str) -> Option<T> {
+    env.var(name).ok().and_then(|x| x.to_string().parse().ok())
+}
+
+// per-user transfer allowance, configured in gigabytes
+pub fn user_quota(env: &Env) -> Option<u64> {
+    env_parse::<u64>(env, "USER_QUOTA_GB").map(|gb| gb * 1024 * 1024 * 1024)
+}
+
+// UPSTREAM_RELAY is the RELAY_PATH url of a peer deployment, e.g.
+// `wss://peer.example.workers.dev/relay`, and UPSTREAM_RELAY_UUID its
+// RELAY_UUID.
+#[derive(Clone, Debug)]
+pub struct UpstreamRelay {
+    pub url: Url,
+    pub uuid: Uuid,
+}
+
+impl UpstreamRelay {
+    fn from_env(env: &Env) -> Result<Option<Self>> {
+        let Ok(url) = env.var("UPSTREAM_RELAY") else {
+            return Ok(None);
+        };
+        let url = Url::parse(&url.to_string())?;
+        let uuid = env
+            .secret("UPSTREAM_RELAY_UUID")
+            .map_err(|_| Error::RustError("UPSTREAM_RELAY needs UPSTREAM_RELAY_UUID".to_string()))?;
+        let uuid = Uuid::parse_str(&uuid.to_string()).map_err(|e| Error::RustError(e.to_string()))?;
+        Ok(Some(Self { url, uuid }))
+    }
+}
+
+// per-connection tuning carried in the second path segment of the tunnel
+// url, e.g. `/1.2.3.4-443/to=proxy-first&pad=1`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TunnelOptions {
+    // shadowsocks can't tell udp from tcp, `relay=udp` says which it is
+    pub relay_udp: bool,
+    // dial the proxyip before the destination itself
+    pub proxy_first: bool,
+    // overrides the PADDING env flag
+    pub padding: Option<bool>,
+}
+
+impl FromStr for TunnelOptions {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut options = Self::default();
+        for pair in s.split('&').filter(|x| !x.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match (key, value) {
+                ("relay", "udp") => options.relay_udp = true,
+                ("relay", "tcp") => options.relay_udp = false,
+                ("to", "proxy-first") => options.proxy_first = true,
+                ("to", "direct-first") => options.proxy_first = false,
+                ("pad", "1") => options.padding = Some(true),
+                ("pad", "0") => options.padding = Some(false),
+                _ => return Err(format!("invalid tunnel option {}", pair)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+// where the client connected from, taken from `request.cf`.
+#[derive(Clone, Default)]
+pub struct ClientInfo {
+    pub colo: String,
+    pub country: String,
+    pub continent: String,
+    pub asn: u32,
+    pub ip: Option<IpAddr>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &Request) -> Self {
+        let ip = req
+            .headers()
+            .get("CF-Connecting-IP")
+            .ok()
+            .flatten()
+            .and_then(|x| x.parse().ok());
+        match req.cf() {
+            Some(cf) => Self {
+                colo: cf.colo(),
+                country: cf.country().unwrap_or_default(),
+                continent: cf.continent().unwrap_or_default(),
+                asn: cf.asn(),
+                ip,
+            },
+            None => Self {
+                ip,
+                ..Self::default()
+            },
+        }
+    }
+}
+
+// ties a tunnel's log lines together and to the cloudflare dashboard: the
+// cf-ray of the upgrade request and a short id of our own, which is also
+// the tunnel's id in the connection registry
+#[derive(Clone, Default)]
+pub struct Trace {
+    pub ray: Option<String>,
+    pub id: String,
+}
+
+impl Trace {
+    pub fn from_request(req: &Request) -> Self {
+        let mut id = [0u8; 4];
+        let _ = getrandom::getrandom(&mut id);
+        Self {
+            ray: req.headers().get("cf-ray").ok().flatten(),
+            id: id.iter().map(|x| format!("{:02x}", x)).collect(),
+        }
+    }
+}
+
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[conn={}", self.id)?;
+        if let Some(ray) = &self.ray {
+            write!(f, " ray={}", ray)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "colo={} country={} asn={}", self.colo, self.country, self.asn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tunnel_options() {
+        let options: TunnelOptions = "relay=udp&to=proxy-first&pad=0".parse().unwrap();
+        assert_eq!(
+            options,
+            TunnelOptions { relay_udp: true, proxy_first: true, padding: Some(false) }
+        );
+        assert_eq!("".parse::<TunnelOptions>().unwrap(), TunnelOptions::default());
+        assert!("pad=2".parse::<TunnelOptions>().is_err());
+        assert!("speed".parse::<TunnelOptions>().is_err());
+    }
 
-    pub main_page_url: String,
-    pub sub_page_url: String,
-    pub link_page_url: String,
-    pub converter_page_url: String,
-    pub checker_page_url: String,
+    #[test]
+    fn test_host_entry() {
+        let list = ["a.example.com=8443".to_string(), " B.example.com = 2096".to_string(), "broken".to_string()];
+        assert_eq!(host_entry(&list, "a.example.com"), Some("8443"));
+        assert_eq!(host_entry(&list, "b.example.com"), Some("2096"));
+        assert_eq!(host_entry(&list, "c.example.com"), None);
+    }
 }
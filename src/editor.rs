@@ -0,0 +1,106 @@
+// proxy list edits through the api. kv can't compare and swap and its reads
+// may lag up to a minute behind at the edge, so the edited list lives in a
+// single durable object that only takes a new revision on top of the one it
+// holds. kv keeps the copy tunnels read.
+use crate::proxylist::{self, ProxyList};
+
+use wasm_bindgen::JsValue;
+use worker::*;
+
+static LIST_KEY: &str = "list";
+
+pub struct Editor {
+    namespace: ObjectNamespace,
+}
+
+impl Editor {
+    // None unless the PROXYLIST durable object is bound
+    pub fn from_env(env: &Env) -> Option<Self> {
+        env.durable_object("PROXYLIST").ok().map(|namespace| Self { namespace })
+    }
+
+    // the list and its revision, kv's copy until the first edit
+    pub async fn load(&self, kv: &kv::KvStore) -> Result<(ProxyList, u64)> {
+        let mut res = self.call(Method::Get, "", None).await?;
+        match res.status_code() {
+            200 => proxylist::parse_revision(&res.text().await?),
+            404 => proxylist::load_revision(kv).await,
+            status => Err(Error::RustError(format!("proxy list editor failed: {}", status))),
+        }
+    }
+
+    // stores `list` as the next revision, false when the list moved past
+    // `revision` meanwhile
+    pub async fn swap(&self, revision: u64, list: &ProxyList) -> Result<bool> {
+        let doc = proxylist::to_document(list, revision + 1);
+        let res = self.call(Method::Put, &format!("?from={}", revision), Some(doc)).await?;
+        match res.status_code() {
+            200 => Ok(true),
+            412 => Ok(false),
+            status => Err(Error::RustError(format!("proxy list editor failed: {}", status))),
+        }
+    }
+
+    // forgets the edited list, a purge goes back to github
+    pub async fn reset(&self) -> Result<()> {
+        self.call(Method::Delete, "", None).await.map(|_| ())
+    }
+
+    async fn call(&self, method: Method, query: &str, body: Option<String>) -> Result<Response> {
+        let mut init = RequestInit::new();
+        init.with_method(method).with_body(body.map(Into::into));
+        let req = Request::new_with_init(&format!("https://editor/list{}", query), &init)?;
+        self.namespace.id_from_name("global")?.get_stub()?.fetch_with_request(req).await
+    }
+}
+
+// single global instance holding the edited list in its storage. nothing
+// awaits between reading the stored revision and writing the new one but
+// storage, so the runtime's input gate keeps another edit from slipping in.
+#[durable_object]
+pub struct ProxyListEditor {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for ProxyListEditor {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let method = req.method();
+        let from = req.url()?.query_pairs().find(|(k, _)| k == "from").and_then(|(_, v)| v.parse::<u64>().ok());
+        let body = match method {
+            Method::Put => req.text().await?,
+            _ => String::new(),
+        };
+
+        let mut storage = self.state.storage();
+        let stored = storage.get_multiple(vec![LIST_KEY]).await?.get(&JsValue::from_str(LIST_KEY)).as_string();
+        match method {
+            Method::Get => match stored {
+                Some(doc) => Response::ok(doc),
+                None => Response::error("not edited yet", 404),
+            },
+            Method::Put => {
+                let Some(from) = from else {
+                    return Response::error("missing from", 400);
+                };
+                // the first edit is taken on top of whatever kv had
+                if let Some(current) = stored.as_deref().map(proxylist::revision_of) {
+                    if current != from {
+                        return Response::error(current.to_string(), 412);
+                    }
+                }
+                storage.put_raw(LIST_KEY, JsValue::from_str(&body)).await?;
+                Response::ok("")
+            }
+            Method::Delete => {
+                storage.delete(LIST_KEY).await?;
+                Response::ok("")
+            }
+            _ => Response::error("method not allowed", 405),
+        }
+    }
+}
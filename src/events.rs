@@ -0,0 +1,156 @@
+// usage, audit and alert events go through the EVENTS queue when it's bound,
+// so a finished tunnel only enqueues instead of doing kv reads and writes.
+// the consumer below folds a batch into kv: one accounting write per user
+// however many sessions ended, then the audit entries and alerts. messages
+// are acked as their writes land, so a retry only repeats what failed.
+use crate::alert::Alerter;
+use crate::storage::Storage;
+use crate::{accounting, audit, config};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+use worker::*;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    // one finished session
+    Usage { uuid: String, bytes_up: u64, bytes_down: u64 },
+    Audit(audit::Entry),
+    // see `Alerter::send`, `users` picks the USER_WEBHOOK_URL alerter
+    Alert {
+        event: String,
+        message: String,
+        data: Value,
+        #[serde(default)]
+        users: bool,
+    },
+}
+
+pub struct Events {
+    queue: Queue,
+}
+
+impl Events {
+    // None unless the EVENTS queue is bound
+    pub fn from_env(env: &Env) -> Option<Self> {
+        env.queue("EVENTS").ok().map(|queue| Self { queue })
+    }
+
+    pub async fn send(&self, event: Event) -> Result<()> {
+        self.queue.send(event).await
+    }
+}
+
+// sessions of one user within a batch
+#[derive(Debug, Default, PartialEq)]
+pub struct Sessions {
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub count: u64,
+}
+
+// a batch folded for writing, everything with the indices of the events it
+// came from so their messages can be acked
+#[derive(Default)]
+pub struct Batch {
+    // summed per user
+    pub usage: BTreeMap<Uuid, (Sessions, Vec<usize>)>,
+    // in the order they came
+    pub entries: Vec<(usize, audit::Entry)>,
+    pub alerts: Vec<(usize, Event)>,
+}
+
+pub fn aggregate(events: Vec<Event>) -> Batch {
+    let Batch { mut usage, mut entries, mut alerts } = Batch::default();
+    for (i, event) in events.into_iter().enumerate() {
+        match event {
+            Event::Usage { uuid, bytes_up, bytes_down } => {
+                let Ok(uuid) = Uuid::parse_str(&uuid) else {
+                    console_log!("[events]: dropping usage of invalid uuid {}", uuid);
+                    continue;
+                };
+                let (sessions, indices) = usage.entry(uuid).or_default();
+                sessions.bytes_up += bytes_up;
+                sessions.bytes_down += bytes_down;
+                sessions.count += 1;
+                indices.push(i);
+            }
+            Event::Audit(entry) => entries.push((i, entry)),
+            alert @ Event::Alert { .. } => alerts.push((i, alert)),
+        }
+    }
+    Batch { usage, entries, alerts }
+}
+
+// each message is acked once its write landed and retried when it failed,
+// so a failing user doesn't make the queue redo everyone else's accounting.
+// for usage that write is the user's totals, see `accounting::record`.
+// alerts dedup in kv and are never retried. returning acks whatever is left,
+// the usage of invalid uuids.
+pub async fn consume(batch: MessageBatch<Event>, env: &Env) -> Result<()> {
+    let storage = Storage::from_env(env)?;
+    let kv = &storage.kv;
+    let messages: Vec<RawMessage> = batch.raw_iter().collect();
+    let events = batch.messages()?.into_iter().map(|x| x.into_body()).collect();
+    let Batch { usage, entries, alerts } = aggregate(events);
+
+    let (alerter, user_alerter) = (Alerter::from_env(env).map(Alerter::direct), Alerter::for_users(env).map(Alerter::direct));
+    let quota = config::user_quota(env);
+    for (uuid, (sessions, indices)) in &usage {
+        let recorded = accounting::record(&storage, uuid, sessions.bytes_up, sessions.bytes_down, sessions.count, quota, user_alerter.as_ref()).await;
+        if let Err(e) = &recorded {
+            console_log!("[events]: usage of {} failed, retrying: {}", uuid, e);
+        }
+        for &i in indices {
+            if recorded.is_ok() { messages[i].ack() } else { messages[i].retry() }
+        }
+    }
+    for (i, entry) in &entries {
+        match audit::store(kv, entry).await {
+            Ok(()) => messages[*i].ack(),
+            Err(e) => {
+                console_log!("[events]: audit entry failed, retrying: {}", e);
+                messages[*i].retry();
+            }
+        }
+    }
+    for (i, alert) in alerts {
+        messages[i].ack();
+        let Event::Alert { event, message, data, users } = alert else { continue };
+        let Some(alerter) = (if users { &user_alerter } else { &alerter }) else { continue };
+        if let Err(e) = alerter.send(kv, &event, &message, data).await {
+            console_log!("[events]: alert {} failed: {}", event, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_aggregate() {
+        let uuid = "38425afe-8466-4876-8223-f3d604ca3c18";
+        let usage = |up, down| Event::Usage { uuid: uuid.to_string(), bytes_up: up, bytes_down: down };
+        let alert = Event::Alert { event: "errors".to_string(), message: "m".to_string(), data: json!({}), users: false };
+        let entry = audit::Entry { at: 1, actor: "token:00".to_string(), action: "purge".to_string(), target: None };
+
+        let Batch { usage, entries, alerts } = aggregate(vec![usage(10, 20), Event::Audit(entry), usage(1, 2), alert]);
+        assert_eq!(usage[&Uuid::parse_str(uuid).unwrap()], (Sessions { bytes_up: 11, bytes_down: 22, count: 2 }, vec![0, 2]));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, 1);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].0, 3);
+    }
+
+    #[test]
+    fn test_event_json() {
+        let event: Event = serde_json::from_value(json!({ "type": "usage", "uuid": "x", "bytes_up": 1, "bytes_down": 2 })).unwrap();
+        assert_eq!(event, Event::Usage { uuid: "x".to_string(), bytes_up: 1, bytes_down: 2 });
+    }
+}
@@ -0,0 +1,162 @@
+// scheduled tcp checks of the proxy list, kept in kv for /api/proxyhealth so
+// the checker page shows pool status without visitors probing every proxy.
+use crate::alert::Alerter;
+use crate::proxy::{dial, timer};
+use crate::proxylist::{self, ProxyList};
+
+use futures_util::future::{self, join_all, Either};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+use std::pin::pin;
+use std::time::Duration;
+use worker::*;
+
+static HEALTH_KEY: &str = "proxyhealth";
+// proxies checked per cron run, the rest wait for the following runs so one
+// run stays well within the subrequest limit
+static BATCH_SIZE: usize = 40;
+static PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+// how often an isolate reports the same empty pool, the alerter's own dedup
+// decides whether the webhook actually fires
+static REPORT_INTERVAL: u64 = 5 * 60 * 1000; // 5 minutes
+
+thread_local! {
+    static REPORTED: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Health {
+    pub country: String,
+    pub alive: bool,
+    // connect time in ms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<u64>,
+    pub checked_at: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Report {
+    // where in the sorted list the next run continues
+    pub cursor: usize,
+    // "addr:port" -> last result
+    pub proxies: BTreeMap<String, Health>,
+}
+
+pub async fn load(kv: &kv::KvStore) -> Result<Report> {
+    Ok(kv.get(HEALTH_KEY).json().await?.unwrap_or_default())
+}
+
+// checks the next batch of proxies and stores the results
+pub async fn run(kv: &kv::KvStore, alerter: Option<&Alerter>) -> Result<()> {
+    let list = proxylist::load(kv).await?;
+    let mut targets: Vec<_> = list
+        .iter()
+        .flat_map(|(country, proxies)| proxies.iter().map(move |x| (proxylist::key_of(x), country, x)))
+        .collect();
+    targets.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut report = load(kv).await?;
+    let listed: HashSet<_> = targets.iter().map(|(key, ..)| key.clone()).collect();
+    report.proxies.retain(|key, _| listed.contains(key));
+
+    let batch = next_batch(targets.len(), report.cursor, BATCH_SIZE);
+    let results = join_all(targets[batch.clone()].iter().map(|(_, _, proxy)| probe(&proxy.addr, proxy.port))).await;
+    let now = Date::now().as_millis();
+    let alive = results.iter().filter(|x| x.is_some()).count();
+    for ((key, country, _), latency) in targets[batch.clone()].iter().zip(results) {
+        let health = Health { country: country.to_string(), alive: latency.is_some(), latency, checked_at: now };
+        report.proxies.insert(key.clone(), health);
+    }
+    report.cursor = batch.end;
+    console_log!("[health]: checked {} of {} proxies, {} alive", batch.len(), targets.len(), alive);
+
+    kv.put(HEALTH_KEY, serde_json::to_string(&report)?)?.execute().await?;
+
+    if let Some(alerter) = alerter {
+        for (country, size) in dead_pools(&list, &report) {
+            alert_pool(kv, alerter, &country, &format!("down, all {} proxies failed their last check", size)).await;
+        }
+    }
+    Ok(())
+}
+
+// countries whose every proxy was checked and found dead, with their size
+fn dead_pools(list: &ProxyList, report: &Report) -> Vec<(String, usize)> {
+    let mut dead: Vec<_> = list
+        .iter()
+        .filter(|(_, proxies)| {
+            !proxies.is_empty() && proxies.iter().all(|x| report.proxies.get(&proxylist::key_of(x)).is_some_and(|x| !x.alive))
+        })
+        .map(|(country, proxies)| (country.clone(), proxies.len()))
+        .collect();
+    dead.sort();
+    dead
+}
+
+// posts a "pool_down:<pool>" event, e.g. when a tunnel asked for a pool
+// with no proxies left
+pub async fn alert_pool(kv: &kv::KvStore, alerter: &Alerter, pool: &str, reason: &str) {
+    let now = Date::now().as_millis();
+    let recent = REPORTED.with_borrow_mut(|x| match x.get(pool) {
+        Some(at) if now.saturating_sub(*at) < REPORT_INTERVAL => true,
+        _ => {
+            x.insert(pool.to_string(), now);
+            false
+        }
+    });
+    if recent {
+        return;
+    }
+
+    console_log!("[health]: proxy pool {} is {}", pool, reason);
+    let message = format!("beacon: proxy pool {} is {}", pool, reason);
+    if let Err(e) = alerter.send(kv, &format!("pool_down:{}", pool), &message, json!({ "pool": pool, "reason": reason })).await {
+        console_log!("[health]: alert failed: {}", e);
+    }
+}
+
+// the next `size` proxies from `cursor`, starting over once the end was reached
+fn next_batch(len: usize, cursor: usize, size: usize) -> Range<usize> {
+    let start = if cursor >= len { 0 } else { cursor };
+    start..(start + size).min(len)
+}
+
+// the connect time, None when the dial failed or timed out
+async fn probe(addr: &str, port: u16) -> Option<u64> {
+    let started = Date::now().as_millis();
+    match future::select(pin!(dial(addr, port)), pin!(timer::sleep(PROBE_TIMEOUT))).await {
+        Either::Left((Ok(_), _)) => Some(Date::now().as_millis().saturating_sub(started)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_batch() {
+        assert_eq!(next_batch(100, 0, 40), 0..40);
+        assert_eq!(next_batch(100, 80, 40), 80..100);
+        assert_eq!(next_batch(100, 100, 40), 0..40);
+        // the list shrank since the last run
+        assert_eq!(next_batch(10, 40, 40), 0..10);
+        assert_eq!(next_batch(0, 0, 40), 0..0);
+    }
+
+    #[test]
+    fn test_dead_pools() {
+        let list = proxylist::parse(r#"{"SG": ["1.1.1.1:443", "2.2.2.2:443"], "JP": ["3.3.3.3:443"], "US": []}"#).unwrap();
+        let health = |alive| Health { country: String::new(), alive, latency: None, checked_at: 0 };
+        let mut report = Report::default();
+        report.proxies.insert("1.1.1.1:443".into(), health(false));
+        report.proxies.insert("3.3.3.3:443".into(), health(false));
+        // 2.2.2.2 wasn't checked yet
+        assert_eq!(dead_pools(&list, &report), [("JP".to_string(), 1)]);
+        report.proxies.insert("2.2.2.2:443".into(), health(false));
+        assert_eq!(dead_pools(&list, &report).len(), 2);
+    }
+}
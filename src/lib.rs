@@ -1,13 +1,40 @@
+mod accounting;
+mod admin;
+mod alert;
+mod analytics;
+mod api;
+mod audit;
+mod autoban;
 mod common;
+mod compression;
 mod config;
+mod editor;
+mod events;
+mod health;
+mod limiter;
+mod maintenance;
+mod metrics;
+mod pages;
 mod proxy;
+mod proxylist;
+mod registry;
+mod signing;
+mod storage;
+mod subscription;
+mod turnstile;
+mod users;
 
-use crate::config::Config;
+use crate::api::*;
+use crate::common::secure;
+use crate::config::{ClientInfo, Config, Trace, TunnelOptions};
 use crate::proxy::*;
+use crate::storage::Storage;
+use crate::turnstile::Turnstile;
 
-use std::collections::HashMap;
-use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use futures_util::future::{self, Either};
 use serde_json::json;
+use std::pin::pin;
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 use worker::*;
 use once_cell::sync::Lazy;
@@ -17,120 +44,527 @@ static PROXYIP_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"^.+-\d+$").unwra
 static PROXYKV_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"^([A-Z]{2})").unwrap());
 
 #[event(fetch)]
-async fn main(req: Request, env: Env, _: Context) -> Result<Response> {
-    let uuid = env
-        .var("UUID")
-        .map(|x| Uuid::parse_str(&x.to_string()).unwrap_or_default())?;
-    let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
-    let main_page_url = env.var("MAIN_PAGE_URL").map(|x| x.to_string()).unwrap();
-    let sub_page_url = env.var("SUB_PAGE_URL").map(|x| x.to_string()).unwrap();
-    let link_page_url = env.var("LINK_PAGE_URL").map(|x| x.to_string()).unwrap();
-    let converter_page_url = env.var("CONVERTER_PAGE_URL").map(|x| x.to_string()).unwrap();
-    let checker_page_url = env.var("CHECKER_PAGE_URL").map(|x| x.to_string()).unwrap();
-
-    let config = Config { 
-        uuid, 
-        host: host.clone(), 
-        proxy_addr: host, 
-        proxy_port: 443, 
-        main_page_url, 
-        sub_page_url,
-        link_page_url,
-        converter_page_url,
-        checker_page_url
-    };
-
-    Router::with_data(config)
+async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    let accept_encoding = req.headers().get("Accept-Encoding")?;
+    let relay_path = env.var("RELAY_PATH").map(|x| x.to_string()).ok();
+    let mut router = Router::with_data(ctx)
         .on_async("/", fe)
         .on_async("/sub", sub)
+        .on_async("/sub/raw", sub_raw)
         .on_async("/link", link)
         .on_async("/converter", converter)
         .on_async("/checker", checker)
+        .on_async("/ping", ping)
+        .on_async("/api/speedtest", speedtest)
+        .get_async("/api/links", links)
+        .on_async("/api/metrics", metrics)
+        .on_async("/api/stats/protocols", protocol_stats)
+        .on_async("/api/stats/dns", dns_stats)
+        .on_async("/api/proxyhealth", proxyhealth)
+        .on_async("/api/usage/:uuid", usage)
+        .on_async("/api/admin/stats", admin_stats)
+        .on_async("/api/admin/proxies/:country", admin_proxies)
+        .on_async("/api/admin/proxylist", admin_proxylist)
+        .on_async("/api/admin/usage.csv", admin_usage_csv)
+        .on_async("/api/admin/audit", admin_audit)
+        .post_async("/api/admin/sign", admin_sign)
+        .post_async("/api/admin/purge", admin_purge)
+        .post_async("/api/admin/ban/:uuid", admin_ban)
+        .post_async("/api/admin/expiry/:uuid", admin_expiry)
+        .post_async("/api/admin/drain", admin_drain)
+        .get_async("/api/proxylist", proxylist_get)
+        .post_async("/api/proxylist/import", proxylist_import)
+        .put_async("/api/proxylist/:country", proxylist_put_country)
+        .delete_async("/api/proxylist/:country", proxylist_delete_country)
+        .post_async("/api/proxylist/:country", proxylist_add_proxy)
+        .put_async("/api/proxylist/:country/:proxy", proxylist_put_proxy)
+        .delete_async("/api/proxylist/:country/:proxy", proxylist_delete_proxy)
+        .on_async("/api/connections", connections)
+        .post_async("/api/admin/terminate/:id", admin_terminate)
+        .post_async("/telegram", telegram)
+        .on_async("/tcp", tcp)
         .on_async("/:proxyip", tunnel)
         .on_async("/Geo-Project/:proxyip", tunnel)
-        .run(req, env)
-        .await
+        .on_async("/:proxyip/:opts", tunnel)
+        .on_async("/Geo-Project/:proxyip/:opts", tunnel);
+    if let Some(path) = &relay_path {
+        router = router.on_async(path, relay);
+    }
+    let res = router.run(req, env).await?;
+    compression::apply(accept_encoding.as_deref(), res)
+}
+
+// cron triggers run the proxy health checks and keep the page and proxy
+// list caches warm
+#[event(scheduled)]
+async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    let alerter = alert::Alerter::from_env(&env);
+    let checked = async { health::run(&env.kv("library")?, alerter.as_ref()).await };
+    if let Err(e) = checked.await {
+        console_log!("[health]: check failed: {}", e);
+    }
+    if let Err(e) = async { proxylist::warm(&env.kv("library")?).await }.await {
+        console_log!("[proxylist]: warming failed: {}", e);
+    }
+    if let Err(e) = pages::warm(&env).await {
+        console_log!("[pages]: warming failed: {}", e);
+    }
+}
+
+// usage, audit and alert events queued on EVENTS, see `events`
+#[event(queue)]
+async fn queue(batch: MessageBatch<events::Event>, env: Env, _ctx: Context) -> Result<()> {
+    events::consume(batch, &env).await
+}
+
+// page routes read only their own url, nothing else is parsed for them.
+// with DISABLE_PAGES set they answer like a plain site: the decoy page when
+// DECOY_URL is configured, 404 otherwise.
+async fn page(req: Request, cx: RouteContext<Context>, name: &str, gated: bool) -> Result<Response> {
+    let env = &cx.env;
+    if config::env_flag(env, "DISABLE_PAGES") {
+        return match env.var("DECOY_URL") {
+            Ok(url) => pages::fetch(env, &cx.data, &url.to_string()).await,
+            Err(_) => Response::error("Not Found", 404),
+        };
+    }
+
+    let accept_language = req.headers().get("Accept-Language")?;
+    let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
+    let page_set = config::page_set(env, &host);
+    let url = pages::localized_url(env, name, page_set.as_deref(), accept_language.as_deref())?;
+    let mut res = if gated {
+        get_gated_response_from_url(req, env, &cx.data, &url).await?
+    } else {
+        pages::fetch(env, &cx.data, &url).await?
+    };
+    res.headers_mut().append("Vary", "Accept-Language")?;
+    Ok(res)
+}
+
+async fn fe(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    page(req, cx, "MAIN_PAGE_URL", false).await
+}
+
+// serves the page only after a solved turnstile challenge when it's configured,
+// keeping scrapers away from the share links.
+async fn get_gated_response_from_url(mut req: Request, env: &Env, ctx: &Context, url: &str) -> Result<Response> {
+    let Some(turnstile) = Turnstile::from_env(env) else {
+        return pages::fetch(env, ctx, url).await;
+    };
+
+    if req.method() != Method::Post {
+        return turnstile.challenge_page();
+    }
+
+    let token = match req.form_data().await?.get(turnstile::RESPONSE_FIELD) {
+        Some(FormEntry::Field(token)) => token,
+        _ => return turnstile.challenge_page(),
+    };
+    let remote_ip = req.headers().get("CF-Connecting-IP")?;
+    if !turnstile.verify(&token, remote_ip).await? {
+        return Response::error("verification failed", 403);
+    }
+
+    pages::fetch(env, ctx, url).await
+}
+
+async fn sub(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !signing::is_valid(&req, &cx.env)? {
+        return Response::error("link expired or invalid", 403);
+    }
+    let format = req.url()?.query_pairs().find(|(k, _)| k == "format").map(|(_, v)| v.to_string());
+    // known clients get their format, browsers the page
+    let user_agent = req.headers().get("User-Agent")?.unwrap_or_default();
+    let format = format.or_else(|| subscription::Format::from_user_agent(&user_agent).map(|x| x.name().to_string()));
+    if let Some(format) = format {
+        if config::env_flag(&cx.env, "DISABLE_PAGES") {
+            return page(req, cx, "SUB_PAGE_URL", true).await;
+        }
+        if let Some(res) = subscription_gate(&req, &cx.env)? {
+            return Ok(res);
+        }
+        return subscription(&req, &cx, &format).await;
+    }
+    let mut res = page(req, cx, "SUB_PAGE_URL", true).await?;
+    res.headers_mut().append("Vary", "User-Agent")?;
+    Ok(res)
 }
 
-async fn get_response_from_url(url: String) -> Result<Response> {
-    let req = Fetch::Url(Url::parse(url.as_str())?);
-    let mut res = req.send().await?;
-    Response::from_html(res.text().await?)
+// /sub?format=raw without the page, for `curl | pbcopy`
+async fn sub_raw(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if !signing::is_valid(&req, &cx.env)? {
+        return Response::error("link expired or invalid", 403);
+    }
+    if config::env_flag(&cx.env, "DISABLE_PAGES") {
+        return page(req, cx, "SUB_PAGE_URL", true).await;
+    }
+    if let Some(res) = subscription_gate(&req, &cx.env)? {
+        return Ok(res);
+    }
+    subscription(&req, &cx, "raw").await
 }
 
-async fn fe(_: Request, cx: RouteContext<Config>) -> Result<Response> {
-    get_response_from_url(cx.data.main_page_url).await
+// clients can't solve the turnstile the /sub page is behind, so with it
+// configured they need a signed link (or the admin token) instead
+fn subscription_gate(req: &Request, env: &Env) -> Result<Option<Response>> {
+    if Turnstile::from_env(env).is_none() || admin::is_authorized(req, env)? || signing::is_signed(req, env, signing::SUB_PATH)? {
+        return Ok(None);
+    }
+    Response::error("subscriptions need a signed link", 403).map(Some)
 }
 
-async fn sub(_: Request, cx: RouteContext<Config>) -> Result<Response> {
-    get_response_from_url(cx.data.sub_page_url).await
+async fn link(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    page(req, cx, "LINK_PAGE_URL", true).await
 }
 
-async fn link(_: Request, cx: RouteContext<Config>) -> Result<Response> {
-    get_response_from_url(cx.data.link_page_url).await
+async fn converter(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    page(req, cx, "CONVERTER_PAGE_URL", true).await
 }
 
-async fn converter(_: Request, cx: RouteContext<Config>) -> Result<Response> {
-    get_response_from_url(cx.data.converter_page_url).await
+async fn checker(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    page(req, cx, "CHECKER_PAGE_URL", false).await
 }
 
-async fn checker(_: Request, cx: RouteContext<Config>) -> Result<Response> {
-    get_response_from_url(cx.data.checker_page_url).await
+// the token can come as a header or, for clients that can't set custom
+// headers on the upgrade request, as a `token` query parameter.
+fn is_tunnel_authorized(req: &Request, config: &Config) -> Result<bool> {
+    let Some(expected) = &config.tunnel_token else {
+        return Ok(true);
+    };
+
+    let token = match req.headers().get("X-Beacon-Token")? {
+        Some(token) => Some(token),
+        None => req.url()?.query_pairs().find(|(k, _)| k == "token").map(|(_, v)| v.to_string()),
+    };
+    Ok(token.is_some_and(|token| secure::ct_eq(token, expected)))
 }
 
-async fn tunnel(req: Request, mut cx: RouteContext<Config>) -> Result<Response> {
-    let mut proxyip = cx.param("proxyip").unwrap().to_string();
+// pipes the websocket straight to `?target=host:port` without any proxy
+// protocol, for single services like ssh. unlike the proxy routes it's never
+// open: without a TUNNEL_TOKEN it's disabled.
+async fn tcp(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
+    let mut config = Config::from_env(&cx.env, host)?;
+    if config.tunnel_token.is_none() {
+        return Response::error("Not Found", 404);
+    }
+    if !is_tunnel_authorized(&req, &config)? {
+        return Response::error("unauthorized", 401);
+    }
+    if req.headers().get("Upgrade")?.as_deref() != Some("websocket") {
+        return Response::error("expected websocket", 426);
+    }
+
+    let target = req.url()?.query_pairs().find(|(k, _)| k == "target").map(|(_, v)| v.to_string());
+    let Some((addr, port)) = target
+        .as_deref()
+        .and_then(|x| x.rsplit_once(':'))
+        .and_then(|(addr, port)| Some((addr.trim_matches(['[', ']']).to_string(), port.parse::<u16>().ok()?)))
+    else {
+        return Response::error("expected ?target=host:port", 400);
+    };
+    if !config.is_destination_allowed(&addr) {
+        return Response::error("destination not allowed", 403);
+    }
+
+    config.client = ClientInfo::from_request(&req);
+    config.trace = Trace::from_request(&req);
+    if !config.is_country_allowed(&config.client.country) {
+        return Response::error("not available in your region", 403);
+    }
+    let kv = cx.kv("library")?;
+    let alerter = alert::Alerter::from_env(&cx.env);
+    let WebSocketPair { server, client } = WebSocketPair::new()?;
+    server.accept()?;
+    // last, nothing may fail between taking the slot and the task releasing it
+    let permit = match limiter::acquire(&config).await? {
+        Some(permit) => permit,
+        None => return limiter::over_capacity(),
+    };
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let events = server.events().unwrap();
+        let (client, trace) = (config.client.clone(), config.trace.clone());
+        let mut stream = ProxyStream::new(config, WebSocketTransport::new(&server, events));
+        if let Err(e) = stream.handle_tcp_outbound(addr, port).await {
+            console_log!("[tcp]: {} {} {}", trace, client, e);
+        }
+        permit.release().await;
+        metrics::maybe_flush(&kv, alerter.as_ref()).await;
+    });
+
+    Response::from_websocket(client)
+}
+
+async fn tunnel(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
+    let mut config = Config::from_env(&cx.env, host)?;
+    config.client = ClientInfo::from_request(&req);
+    config.trace = Trace::from_request(&req);
+    let mut path_proxyip = cx.param("proxyip").unwrap().to_string();
+    // not options, so it may be `/:token/:proxyip` with the TUNNEL_TOKEN or
+    // a user's secret path in front
+    let mut path_token = None;
+    if let Some(opts) = cx.param("opts") {
+        match opts.parse::<TunnelOptions>() {
+            Ok(options) => {
+                config.options = options;
+                if let Some(padding) = config.options.padding {
+                    config.padding = padding;
+                }
+            }
+            Err(e) => path_token = Some((std::mem::replace(&mut path_proxyip, opts.to_string()), e)),
+        }
+    }
+
+    // the token is checked before anything touches storage, strangers
+    // guessing the hostname don't get to make the worker read kv or d1
+    let token_in_path = path_token
+        .as_ref()
+        .is_some_and(|(token, _)| !users::is_user_path(token, config.tunnel_token.as_deref()));
+    let token_authorized = token_in_path || is_tunnel_authorized(&req, &config)?;
+    let upgrade = req.headers().get("Upgrade")?.unwrap_or("".to_string());
+    if upgrade == "websocket" {
+        if let Some(banned) = autoban::check(&config).await {
+            return Ok(banned);
+        }
+    }
+    // a plain get on the tunnel path shows how its proxyip has been doing,
+    // for admins or clients holding the tunnel token
+    let probing = upgrade != "websocket"
+        && req.method() == Method::Get
+        && (admin::is_authorized(&req, &cx.env)? || (config.tunnel_token.is_some() && token_authorized));
+    if upgrade != "websocket" && !probing {
+        return Response::from_html("hi from wasm!");
+    }
+
+    // a user's secret path is a credential of its own, found with a single
+    // keyed lookup. only a miss counts as a failed attempt.
+    let mut path_miss = None;
+    if let Some((token, e)) = path_token.filter(|_| !token_in_path) {
+        match users::path_user(&Storage::from_env(&cx.env)?, &token).await? {
+            Some(uuid) => config.path_user = Some(uuid),
+            None => path_miss = Some(e),
+        }
+    }
+    if upgrade == "websocket" && !token_authorized && config.path_user.is_none() {
+        if let (Some(autoban), Some(ip)) = (config.autoban.clone(), config.client.ip) {
+            cx.data.wait_until(async move { autoban.record_failure(ip).await });
+        }
+        return Response::error("unauthorized", 401);
+    }
+    if let Some(e) = path_miss {
+        return Response::error(e, 400);
+    }
+    if maintenance::is_enabled(config.maintenance, &cx.kv("library")?).await? {
+        return maintenance::page();
+    }
+
+    // some transports mangle the path but keep the query, so `?proxyip=`
+    // takes precedence over the path segment
+    let mut proxyip = match req.url()?.query_pairs().find(|(k, _)| k == "proxyip") {
+        Some((_, v)) => v.to_string(),
+        None => path_proxyip,
+    };
+    config.debug = req.url()?.query_pairs().any(|(k, v)| k == "debug" && v == "1") && admin::is_authorized(&req, &cx.env)?;
+    let mut probed_pool = None;
     if PROXYKV_PATTERN.is_match(&proxyip)  {
         let kvid_list: Vec<String> = proxyip.split(",").map(|s| s.to_string()).collect();
         let kv = cx.kv("library")?;
-        let mut proxy_kv_str = kv.get("proxy_kv").text().await?.unwrap_or("".to_string());
         let mut rand_buf = [0u8, 1];
         getrandom::getrandom(&mut rand_buf).expect("failed generating random number");
 
-        if proxy_kv_str.len() == 0 {
-            console_log!("getting proxy kv from github...");
-            let req = Fetch::Url(Url::parse("https://raw.githubusercontent.com/FoolVPN-ID/Nautica/refs/heads/main/kvProxyList.json")?);
-            let mut res = req.send().await?;
-            if res.status_code() == 200 {
-                proxy_kv_str = res.text().await?.to_string();
-                kv.put("proxy_kv", &proxy_kv_str)?.expiration_ttl(60 * 60 * 24).execute().await?; // 24 hours
-            } else {
-                return Err(Error::from(format!("error getting proxy kv: {}", res.status_code())));
-            }
-        }
-
-        let proxy_kv: HashMap<String, Vec<String>> = serde_json::from_str(&proxy_kv_str)?;
+        let proxy_kv = proxylist::load(&kv).await?;
 
+        // a colo serves clients from its own continent, so the client's
+        // continent is the colo's and a pool there saves crossing an ocean
+        let kvid_list = proxylist::prefer_continent(&kvid_list, &config.client.continent);
         let kv_index = (rand_buf[0] as usize) % kvid_list.len();
         proxyip = kvid_list[kv_index].clone();
 
-        let proxyip_index = (rand_buf[0] as usize) % proxy_kv[&proxyip].len();
-        proxyip = proxy_kv[&proxyip][proxyip_index].clone().replace(":", "-");
+        let pool = proxylist::pool(&proxy_kv, &proxyip);
+        if probing {
+            probed_pool = Some(json!({
+                "id": proxyip,
+                "size": pool.len(),
+                "proxies": pool.iter().map(|x| probe_stats(&x.addr, x.port, x.label.as_deref())).collect::<Vec<_>>(),
+            }));
+        }
+        let Some(proxy) = proxylist::pick(&pool, u16::from_le_bytes(rand_buf) as u32) else {
+            if let Some(alerter) = alert::Alerter::from_env(&cx.env) {
+                let pool_id = proxyip.clone();
+                cx.data.wait_until(async move { health::alert_pool(&kv, &alerter, &pool_id, "empty").await });
+            }
+            return Response::error(format!("no proxy available for {}", proxyip), 404);
+        };
+        config.proxy_label = proxy.label.clone();
+        crate::log_debug!(config, "picked {} out of {} in pool {}", proxy, pool.len(), proxyip);
+        proxyip = format!("{}-{}", proxy.addr, proxy.port);
     }
 
     if PROXYIP_PATTERN.is_match(&proxyip) {
         if let Some((addr, port_str)) = proxyip.split_once('-') {
             if let Ok(port) = port_str.parse() {
-                cx.data.proxy_addr = addr.to_string();
-                cx.data.proxy_port = port;
+                config.proxy_addr = addr.to_string();
+                config.proxy_port = port;
             }
         }
     }
 
-    let upgrade = req.headers().get("Upgrade")?.unwrap_or("".to_string());
-    if upgrade == "websocket".to_string() {
-        let WebSocketPair { server, client } = WebSocketPair::new()?;
-        server.accept()?;
-
-        wasm_bindgen_futures::spawn_local(async move {
-            let events = server.events().unwrap();
-            if let Err(e) = ProxyStream::new(cx.data, &server, events).process().await {
-                console_log!("[tunnel]: {}", e);
+    if upgrade == "websocket" {
+        if !config.is_country_allowed(&config.client.country) {
+            return Response::error("not available in your region", 403);
+        }
+        if let Some(remaining) = maintenance::drain_remaining(&cx.kv("library")?).await? {
+            return maintenance::draining(remaining);
+        }
+        if let Some(uuid) = config.path_user {
+            let kv = cx.kv("library")?;
+            if admin::banned_uuids(&kv).await?.contains(&uuid) {
+                return Response::error("banned", 403);
             }
-        });
+            let storage = Storage::from_env(&cx.env)?;
+            let mut usage = accounting::usage(&storage, &uuid).await?;
+            let now = Date::now().as_millis();
+            if let Some(reason) = accounting::denied(&usage, config.user_quota, now) {
+                let alerter = alert::Alerter::for_users(&cx.env);
+                accounting::check_expiry(&storage, &uuid, &mut usage, now, config.user_quota, alerter.as_ref()).await?;
+                return Response::error(reason, 403);
+            }
+        }
 
-        Response::from_websocket(client)
+        accept_tunnel(&req, &cx, config).await
     } else {
-        Response::from_html("hi from wasm!")
+        Response::from_json(&json!({
+            "colo": config.client.colo,
+            "proxy": probe_stats(&config.proxy_addr, config.proxy_port, config.proxy_label.as_deref()),
+            "pool": probed_pool,
+        }))
+    }
+}
+
+// dial outcomes are only known to the isolate answering the probe
+fn probe_stats(addr: &str, port: u16, label: Option<&str>) -> serde_json::Value {
+    let mut stats = metrics::dial_stats(&format!("{}:{}", addr, port));
+    stats["proxy"] = json!(format!("{}:{}", addr, port));
+    stats["label"] = json!(label);
+    stats
+}
+
+// reserves a slot for the tunnel once its setup is done and runs it in the
+// background
+async fn accept_tunnel(req: &Request, cx: &RouteContext<Context>, mut config: Config) -> Result<Response> {
+    let subprotocol = Subprotocol::negotiate(req.headers().get("Sec-WebSocket-Protocol")?.as_deref());
+    config.early_data = subprotocol.early_data;
+
+    let storage = Storage::from_env(&cx.env)?;
+    config.banned_uuids = admin::banned_uuids(&storage.kv).await?;
+    if let Some(method) = config.shadowsocks_key.as_ref().map(|x| x.method).filter(|x| x.is_2022()) {
+        config.shadowsocks_users = users::shadowsocks_users(&storage, method).await?;
+    }
+    let alerter = alert::Alerter::from_env(&cx.env);
+    let user_alerter = alert::Alerter::for_users(&cx.env);
+    let event_queue = events::Events::from_env(&cx.env);
+    let analytics = analytics::Analytics::from_env(&cx.env);
+    let WebSocketPair { server, client } = WebSocketPair::new()?;
+    server.accept()?;
+    // last, nothing may fail between taking the slot and the task releasing it
+    let permit = match limiter::acquire(&config).await? {
+        Some(permit) => permit,
+        None => return limiter::over_capacity(),
+    };
+
+    wasm_bindgen_futures::spawn_local(async move {
+        let events = server.events().unwrap();
+        let (client, trace) = (config.client.clone(), config.trace.clone());
+        let quota = config.user_quota;
+        let autoban = config.autoban.clone();
+        let registration = registry::Registration::new(&config);
+        let mut stream = ProxyStream::new(config, WebSocketTransport::new(&server, events));
+        let progress = stream.progress.clone();
+        // an admin terminating the tunnel ends the watch, which drops the
+        // stream mid-relay
+        let result = match &registration {
+            Some(registration) => match future::select(pin!(stream.process()), pin!(registration.watch(&progress))).await {
+                Either::Left((result, _)) => Some(result),
+                Either::Right(_) => None,
+            },
+            None => Some(stream.process().await),
+        };
+        let result = result.unwrap_or_else(|| {
+            stream.close = Some((TERMINATED_CLOSE_CODE, "terminated"));
+            Err(stream.context(Error::RustError("terminated by admin".to_string())))
+        });
+        let mut outcome = "ok";
+        if let Err(e) = result {
+            console_log!("[tunnel]: {} {} {}", trace, client, e);
+            // what the remote already sent still reaches the client
+            let _ = stream.flush().await;
+            let (code, reason) = stream.close.unwrap_or((INTERNAL_CLOSE_CODE, "internal error"));
+            let _ = server.close(Some(code), Some(reason));
+            outcome = reason;
+        }
+        if let Some(analytics) = &analytics {
+            let tunnel = analytics::Tunnel {
+                client: &client,
+                protocol: stream.protocol,
+                outcome,
+                bytes_up: stream.bytes_up,
+                bytes_down: stream.bytes_down,
+            };
+            if let Err(e) = analytics.write(&tunnel) {
+                console_log!("[analytics]: {} {}", trace, e);
+            }
+        }
+        if let Some(registration) = registration {
+            registration.close().await;
+        }
+        if let (true, Some(autoban), Some(ip)) = (stream.handshake_failed, &autoban, client.ip) {
+            autoban.record_failure(ip).await;
+        }
+        if let Some(protocol) = stream.protocol {
+            metrics::record_tunnel(protocol, stream.bytes_up, stream.bytes_down);
+        }
+        if stream.bytes_up + stream.bytes_down > 0 {
+            let recorded = match &event_queue {
+                Some(queue) => {
+                    let (uuid, bytes_up, bytes_down) = (stream.user.to_string(), stream.bytes_up, stream.bytes_down);
+                    queue.send(events::Event::Usage { uuid, bytes_up, bytes_down }).await
+                }
+                None => accounting::record(&storage, &stream.user, stream.bytes_up, stream.bytes_down, 1, quota, user_alerter.as_ref()).await.map(|_| ()),
+            };
+            if let Err(e) = recorded {
+                console_log!("[accounting]: {} {}", trace, e);
+            }
+        }
+        permit.release().await;
+        metrics::maybe_flush(&storage.kv, alerter.as_ref()).await;
+    });
+
+    let mut res = Response::from_websocket(client)?;
+    if let Some(selected) = subprotocol.selected {
+        res.headers_mut().set("Sec-WebSocket-Protocol", &selected)?;
+    }
+    Ok(res)
+}
+
+// a peer deployment chaining through this one: its proxyip leg arrives here
+// as vless over websocket on RELAY_PATH and is dialed from this colo.
+async fn relay(req: Request, cx: RouteContext<Context>) -> Result<Response> {
+    if req.headers().get("Upgrade")?.as_deref() != Some("websocket") {
+        return Response::error("Not Found", 404);
+    }
+    let host = req.url()?.host().map(|x| x.to_string()).unwrap_or_default();
+    let mut config = Config::from_env(&cx.env, host)?;
+    if let Ok(uuid) = cx.env.secret("RELAY_UUID") {
+        config.uuid = Uuid::parse_str(&uuid.to_string()).map_err(|e| Error::RustError(e.to_string()))?;
+    }
+    config.relay_inbound = true;
+    config.client = ClientInfo::from_request(&req);
+    config.trace = Trace::from_request(&req);
+    if let Some(remaining) = maintenance::drain_remaining(&cx.kv("library")?).await? {
+        return maintenance::draining(remaining);
     }
+    accept_tunnel(&req, &cx, config).await
 }
@@ -0,0 +1,115 @@
+use crate::config::Config;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use uuid::Uuid;
+use worker::*;
+
+static ACTIVE_TUNNELS: AtomicUsize = AtomicUsize::new(0);
+static LEASE_TTL: u64 = 60 * 60 * 1000; // 1 hour, reclaims leases of isolates that died mid-tunnel
+pub static RETRY_AFTER_SECS: u32 = 5;
+
+pub struct TunnelPermit {
+    lease: Option<(ObjectNamespace, String)>,
+}
+
+impl Drop for TunnelPermit {
+    fn drop(&mut self) {
+        ACTIVE_TUNNELS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl TunnelPermit {
+    pub async fn release(mut self) {
+        if let Some((limiter, id)) = self.lease.take() {
+            if let Err(e) = limiter_call(&limiter, &format!("/release?id={}", id)).await {
+                console_log!("[limiter]: release failed: {}", e);
+            }
+        }
+    }
+}
+
+// returns None when either the isolate or the global ceiling is reached.
+pub async fn acquire(config: &Config) -> Result<Option<TunnelPermit>> {
+    let active = ACTIVE_TUNNELS.fetch_add(1, Ordering::SeqCst);
+    let mut permit = TunnelPermit { lease: None };
+    if config.max_tunnels.is_some_and(|max| active >= max) {
+        return Ok(None);
+    }
+
+    if let (Some(limiter), Some(max)) = (&config.limiter, config.max_global_tunnels) {
+        let mut id = [0u8; 16];
+        getrandom::getrandom(&mut id).map_err(|e| Error::RustError(e.to_string()))?;
+        let id = Uuid::from_bytes(id).simple().to_string();
+
+        let res = limiter_call(limiter, &format!("/acquire?id={}&max={}", id, max)).await?;
+        if res.status_code() != 200 {
+            return Ok(None);
+        }
+        permit.lease = Some((limiter.clone(), id));
+    }
+
+    Ok(Some(permit))
+}
+
+pub fn active_tunnels() -> usize {
+    ACTIVE_TUNNELS.load(Ordering::SeqCst)
+}
+
+pub fn over_capacity() -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("Retry-After", &RETRY_AFTER_SECS.to_string())?;
+    Ok(Response::error("too many active tunnels, try again later", 503)?.with_headers(headers))
+}
+
+async fn limiter_call(limiter: &ObjectNamespace, path: &str) -> Result<Response> {
+    limiter
+        .id_from_name("global")?
+        .get_stub()?
+        .fetch_with_str(&format!("https://limiter{}", path))
+        .await
+}
+
+// single global instance counting tunnel leases across all isolates.
+#[durable_object]
+pub struct TunnelLimiter {
+    leases: HashMap<String, u64>,
+}
+
+#[durable_object]
+impl DurableObject for TunnelLimiter {
+    fn new(state: State, _env: Env) -> Self {
+        let _ = state;
+        Self {
+            leases: HashMap::new(),
+        }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        let id = params
+            .get("id")
+            .cloned()
+            .ok_or_else(|| Error::RustError("missing id".to_string()))?;
+
+        let now = Date::now().as_millis();
+        self.leases.retain(|_, at| now.saturating_sub(*at) < LEASE_TTL);
+
+        match url.path() {
+            "/acquire" => {
+                let max: usize = params.get("max").and_then(|x| x.parse().ok()).unwrap_or(usize::MAX);
+                if self.leases.len() >= max {
+                    return Response::error("over capacity", 429);
+                }
+                self.leases.insert(id, now);
+                Response::ok(self.leases.len().to_string())
+            }
+            "/release" => {
+                self.leases.remove(&id);
+                Response::ok(self.leases.len().to_string())
+            }
+            _ => Response::error("not found", 404),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use worker::*;
+
+static MAINTENANCE_KEY: &str = "maintenance";
+static DRAIN_KEY: &str = "drain_until";
+pub static RETRY_AFTER_SECS: u32 = 300;
+
+// maintenance is on when the MAINTENANCE env flag is set or the
+// "maintenance" kv key exists, the latter can be flipped without a deploy.
+pub async fn is_enabled(flag: bool, kv: &kv::KvStore) -> Result<bool> {
+    if flag {
+        return Ok(true);
+    }
+    Ok(kv.get(MAINTENANCE_KEY).text().await?.is_some())
+}
+
+// seconds left in the drain window, during which running tunnels carry on
+// but new upgrades are turned away.
+pub async fn drain_remaining(kv: &kv::KvStore) -> Result<Option<u64>> {
+    let Some(until) = kv.get(DRAIN_KEY).text().await? else {
+        return Ok(None);
+    };
+    let now = Date::now().as_millis();
+    Ok(until
+        .parse::<u64>()
+        .ok()
+        .filter(|until| *until > now)
+        .map(|until| (until - now).div_ceil(1000)))
+}
+
+pub async fn start_drain(kv: &kv::KvStore, secs: u64) -> Result<u64> {
+    let until = Date::now().as_millis() + secs * 1000;
+    kv.put(DRAIN_KEY, until.to_string())?
+        .expiration_ttl(secs.max(60)) // kv won't take a shorter ttl
+        .execute()
+        .await?;
+    Ok(until)
+}
+
+pub fn draining(remaining: u64) -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("Retry-After", &remaining.to_string())?;
+    Ok(Response::error("draining, try again later", 503)?.with_headers(headers))
+}
+
+pub fn page() -> Result<Response> {
+    let mut headers = Headers::new();
+    headers.set("Retry-After", &RETRY_AFTER_SECS.to_string())?;
+    Ok(Response::from_html(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Under maintenance</title>
+</head>
+<body style="display:flex;justify-content:center;align-items:center;height:100vh;margin:0;font-family:sans-serif">
+<p>We're doing some maintenance, tunnels will be back in a few minutes.</p>
+</body>
+</html>"#,
+    )?
+    .with_status(503)
+    .with_headers(headers))
+}
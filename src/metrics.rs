@@ -0,0 +1,329 @@
+use crate::alert::Alerter;
+use crate::common::protocol::Protocol;
+
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use worker::*;
+
+static ERRORS_KEY: &str = "metrics:errors";
+static PROTOCOLS_KEY: &str = "metrics:protocols";
+static DNS_KEY: &str = "metrics:dns";
+static FLUSH_INTERVAL: u64 = 60 * 1000; // 1 minute
+
+// dials to each proxyip that count towards its success rate
+static RECENT_DIALS: usize = 20;
+
+static LAST_FLUSH: AtomicU64 = AtomicU64::new(0);
+static WINDOW_ERRORS: AtomicU64 = AtomicU64::new(0);
+static ERROR_COUNTERS: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+// protocol violations split by the protocol that was detected, the last
+// slot is for streams that didn't look like any of them
+static VIOLATION_COUNTERS: [AtomicU64; 5] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+// tunnels, bytes up and bytes down per protocol
+static PROTOCOL_COUNTERS: [[AtomicU64; 3]; 4] = [
+    [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+    [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+    [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+    [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+];
+static PROTOCOL_STATS: [&str; 3] = ["tunnels", "bytes_up", "bytes_down"];
+
+static DNS_COUNTERS: [AtomicU64; 4] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+// what happened to a udp dns query, in DNS_COUNTERS order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DnsEvent {
+    Query,
+    // answered from the negative cache
+    CacheHit,
+    // refused or answered by the query type policy
+    PolicyAnswer,
+    UpstreamError,
+}
+
+impl DnsEvent {
+    pub const ALL: [DnsEvent; 4] = [Self::Query, Self::CacheHit, Self::PolicyAnswer, Self::UpstreamError];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Query => "queries",
+            Self::CacheHit => "cache_hits",
+            Self::PolicyAnswer => "policy_answers",
+            Self::UpstreamError => "upstream_errors",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorClass {
+    ConnectFailed,
+    Timeout,
+    ProtocolViolation,
+    RelayError,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 4] = [
+        Self::ConnectFailed,
+        Self::Timeout,
+        Self::ProtocolViolation,
+        Self::RelayError,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ConnectFailed => "connect-failed",
+            Self::Timeout => "timeout",
+            Self::ProtocolViolation => "protocol-violation",
+            Self::RelayError => "relay-error",
+        }
+    }
+
+    // scanners and clients that give up mid-handshake, not worth alerting on
+    pub fn is_benign(&self) -> bool {
+        matches!(self, Self::ProtocolViolation | Self::Timeout)
+    }
+
+    fn counter(&self) -> &'static AtomicU64 {
+        &ERROR_COUNTERS[*self as usize]
+    }
+}
+
+pub fn record_error(class: ErrorClass) {
+    class.counter().fetch_add(1, Ordering::Relaxed);
+    if !class.is_benign() {
+        WINDOW_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn record_violation(protocol: Option<Protocol>) {
+    record_error(ErrorClass::ProtocolViolation);
+    violation_counter(protocol).fetch_add(1, Ordering::Relaxed);
+}
+
+fn violation_counter(protocol: Option<Protocol>) -> &'static AtomicU64 {
+    &VIOLATION_COUNTERS[protocol.map_or(Protocol::ALL.len(), |x| x as usize)]
+}
+
+// e.g. "protocol-violation:vless", "protocol-violation:unknown"
+fn violation_name(protocol: Option<Protocol>) -> String {
+    format!("{}:{}", ErrorClass::ProtocolViolation.name(), protocol.map_or("unknown", |x| x.name()))
+}
+
+fn all_counters() -> Vec<(String, &'static AtomicU64)> {
+    let errors = ErrorClass::ALL.iter().map(|class| (class.name().to_string(), class.counter()));
+    let violations = Protocol::ALL
+        .iter()
+        .map(|x| Some(*x))
+        .chain([None])
+        .map(|protocol| (violation_name(protocol), violation_counter(protocol)));
+    errors.chain(violations).collect()
+}
+
+// a finished tunnel whose protocol was detected
+pub fn record_tunnel(protocol: Protocol, bytes_up: u64, bytes_down: u64) {
+    let counters = &PROTOCOL_COUNTERS[protocol as usize];
+    counters[0].fetch_add(1, Ordering::Relaxed);
+    counters[1].fetch_add(bytes_up, Ordering::Relaxed);
+    counters[2].fetch_add(bytes_down, Ordering::Relaxed);
+}
+
+pub fn record_dns(event: DnsEvent) {
+    DNS_COUNTERS[event as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+fn dns_counters() -> Vec<(String, &'static AtomicU64)> {
+    DnsEvent::ALL
+        .iter()
+        .map(|event| (event.name().to_string(), &DNS_COUNTERS[*event as usize]))
+        .collect()
+}
+
+// e.g. "vless:tunnels", "trojan:bytes_up"
+fn protocol_counters() -> Vec<(String, &'static AtomicU64)> {
+    Protocol::ALL
+        .iter()
+        .flat_map(|protocol| {
+            PROTOCOL_STATS
+                .iter()
+                .zip(&PROTOCOL_COUNTERS[*protocol as usize])
+                .map(|(stat, counter)| (format!("{}:{}", protocol.name(), stat), counter))
+        })
+        .collect()
+}
+
+#[derive(Default)]
+struct Dials {
+    recent: VecDeque<bool>,
+    last_latency: Option<u64>,
+}
+
+thread_local! {
+    // per isolate, like the counters, but never flushed: it's only meant
+    // for a quick look through the tunnel path's probe response
+    static DIALS: RefCell<HashMap<String, Dials>> = RefCell::new(HashMap::new());
+}
+
+// a dial to the proxyip at `target` ("addr:port"), the latency only counts
+// when it connected
+pub fn record_dial(target: &str, ok: bool, latency: u64) {
+    DIALS.with_borrow_mut(|dials| {
+        let dials = dials.entry(target.to_string()).or_default();
+        if dials.recent.len() == RECENT_DIALS {
+            dials.recent.pop_front();
+        }
+        dials.recent.push_back(ok);
+        if ok {
+            dials.last_latency = Some(latency);
+        }
+    })
+}
+
+pub fn dial_stats(target: &str) -> Value {
+    DIALS.with_borrow(|dials| {
+        let dials = dials.get(target);
+        let recent = dials.map_or(0, |x| x.recent.len());
+        let successes = dials.map_or(0, |x| x.recent.iter().filter(|ok| **ok).count());
+        json!({
+            "recent_dials": recent,
+            "success_rate": (recent > 0).then(|| successes as f64 / recent as f64),
+            "last_latency_ms": dials.and_then(|x| x.last_latency),
+        })
+    })
+}
+
+// counts not yet written to kv, taken out of the isolate counters
+fn take_local(counters: &[(String, &'static AtomicU64)]) -> HashMap<String, u64> {
+    counters
+        .iter()
+        .map(|(name, counter)| (name.clone(), counter.swap(0, Ordering::Relaxed)))
+        .collect()
+}
+
+// puts counts `take_local` took back, for a flush that didn't persist them
+fn give_back(counters: &[(String, &'static AtomicU64)], taken: &HashMap<String, u64>) {
+    for (name, counter) in counters {
+        counter.fetch_add(taken.get(name).copied().unwrap_or_default(), Ordering::Relaxed);
+    }
+}
+
+async fn load(kv: &kv::KvStore, key: &str) -> Result<HashMap<String, u64>> {
+    Ok(kv.get(key).json().await?.unwrap_or_default())
+}
+
+// the counts stay in the isolate when the kv read or write fails, so the
+// next flush tries them again
+async fn merge(kv: &kv::KvStore, key: &str, counters: Vec<(String, &'static AtomicU64)>) -> Result<()> {
+    let local = take_local(&counters);
+    if local.values().all(|x| *x == 0) {
+        return Ok(());
+    }
+
+    let merged = async {
+        let mut totals = load(kv, key).await?;
+        for (name, count) in &local {
+            *totals.entry(name.clone()).or_default() += count;
+        }
+        kv.put(key, serde_json::to_string(&totals)?)?.execute().await?;
+        Ok(())
+    };
+    let result = merged.await;
+    if result.is_err() {
+        give_back(&counters, &local);
+    }
+    result
+}
+
+// adds this isolate's counts to the totals in kv, concurrent flushes from
+// other isolates can race, which is acceptable for monitoring numbers. every
+// key is tried, the first failure is returned.
+pub async fn flush(kv: &kv::KvStore) -> Result<()> {
+    LAST_FLUSH.store(Date::now().as_millis(), Ordering::Relaxed);
+    let errors = merge(kv, ERRORS_KEY, all_counters()).await;
+    let protocols = merge(kv, PROTOCOLS_KEY, protocol_counters()).await;
+    let dns = merge(kv, DNS_KEY, dns_counters()).await;
+    errors.and(protocols).and(dns)
+}
+
+pub async fn maybe_flush(kv: &kv::KvStore, alerter: Option<&Alerter>) {
+    let now = Date::now().as_millis();
+    // the isolate's first call starts the window, flushing right away would
+    // make it reach back to the epoch
+    let last_flush = match LAST_FLUSH.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => return,
+        Err(last_flush) => last_flush,
+    };
+    if now.saturating_sub(last_flush) < FLUSH_INTERVAL {
+        return;
+    }
+    if let Err(e) = flush(kv).await {
+        console_log!("[metrics]: flush failed: {}", e);
+    }
+
+    // the alert window is the time since the previous flush
+    let window_errors = WINDOW_ERRORS.swap(0, Ordering::Relaxed);
+    if let Some(alerter) = alerter.filter(|x| window_errors >= x.error_threshold) {
+        let window_secs = now.saturating_sub(last_flush) / 1000;
+        let message = format!("beacon: {} connection errors in the last {}s", window_errors, window_secs);
+        let data = json!({ "errors": window_errors, "window_secs": window_secs });
+        if let Err(e) = alerter.send(kv, "errors", &message, data).await {
+            console_log!("[metrics]: alert failed: {}", e);
+        }
+    }
+}
+
+// persisted totals plus whatever this isolate hasn't flushed yet
+pub async fn error_counts(kv: &kv::KvStore) -> Result<HashMap<String, u64>> {
+    let mut totals = load(kv, ERRORS_KEY).await?;
+    for (name, counter) in all_counters() {
+        *totals.entry(name).or_default() += counter.load(Ordering::Relaxed);
+    }
+    Ok(totals)
+}
+
+// protocol name -> {"tunnels", "bytes_up", "bytes_down"}, persisted totals
+// plus this isolate's unflushed counts
+pub async fn protocol_counts(kv: &kv::KvStore) -> Result<HashMap<&'static str, HashMap<&'static str, u64>>> {
+    let mut totals = load(kv, PROTOCOLS_KEY).await?;
+    for (name, counter) in protocol_counters() {
+        *totals.entry(name).or_default() += counter.load(Ordering::Relaxed);
+    }
+    Ok(Protocol::ALL
+        .iter()
+        .map(|protocol| {
+            let stats = PROTOCOL_STATS
+                .iter()
+                .map(|stat| (*stat, totals.get(&format!("{}:{}", protocol.name(), stat)).copied().unwrap_or_default()))
+                .collect();
+            (protocol.name(), stats)
+        })
+        .collect())
+}
+
+// persisted dns totals plus this isolate's unflushed counts
+pub async fn dns_counts(kv: &kv::KvStore) -> Result<HashMap<String, u64>> {
+    let mut totals = load(kv, DNS_KEY).await?;
+    for (name, counter) in dns_counters() {
+        *totals.entry(name).or_default() += counter.load(Ordering::Relaxed);
+    }
+    Ok(totals)
+}
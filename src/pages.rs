@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use worker::*;
+
+static PAGE_KEY_PREFIX: &str = "page:";
+static PAGE_TTL: u64 = 60 * 60; // 1 hour
+static WARM_WINDOW: u64 = 15 * 60; // a few cron runs, in case one fails
+// base variables of the page routes, warmed even before a first visit
+static PAGE_VARS: [&str; 5] = ["MAIN_PAGE_URL", "SUB_PAGE_URL", "LINK_PAGE_URL", "CONVERTER_PAGE_URL", "CHECKER_PAGE_URL"];
+
+// a page may have per-language variants named after the base variable, e.g.
+// MAIN_PAGE_URL_ID next to MAIN_PAGE_URL. the first language from
+// Accept-Language that has one wins, the base url is used otherwise.
+// hostnames with a page set (HOST_PAGES) look for the set's own variables
+// first, e.g. BRAND_MAIN_PAGE_URL_ID and BRAND_MAIN_PAGE_URL.
+pub fn localized_url(env: &Env, name: &str, page_set: Option<&str>, accept_language: Option<&str>) -> Result<String> {
+    let langs = accept_language.map(languages).unwrap_or_default();
+    for var in candidates(name, page_set, &langs) {
+        if let Ok(url) = env.var(&var) {
+            return Ok(url.to_string());
+        }
+    }
+    Ok(env.var(name)?.to_string())
+}
+
+// variable names to look a page up by, most specific first
+fn candidates(name: &str, page_set: Option<&str>, langs: &[String]) -> Vec<String> {
+    let names = page_set.map(|set| format!("{}_{}", set, name)).into_iter().chain([name.to_string()]);
+    names
+        .flat_map(|name| langs.iter().map(|lang| format!("{}_{}", name, lang)).chain([name.clone()]).collect::<Vec<_>>())
+        .collect()
+}
+
+// primary language subtags, uppercased, in order of preference
+fn languages(accept: &str) -> Vec<String> {
+    let mut langs: Vec<(String, f32)> = accept
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let tag = parts.next()?.split('-').next()?;
+            let q = parts
+                .find_map(|x| x.strip_prefix("q="))
+                .map_or(Some(1.0), |x| x.parse::<f32>().ok())?;
+            (q > 0.0 && !tag.is_empty() && tag != "*").then(|| (tag.to_ascii_uppercase(), q))
+        })
+        .collect();
+    // stable, so equal weights keep the client's order
+    langs.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let mut seen = Vec::new();
+    for (lang, _) in langs {
+        if !seen.contains(&lang) {
+            seen.push(lang);
+        }
+    }
+    seen
+}
+
+// frontend pages are kept in kv for an hour. on a miss the upstream body is
+// streamed straight to the client while a tee of it is written to kv after
+// the response, so big pages don't wait for the whole download.
+pub async fn fetch(env: &Env, ctx: &Context, url: &str) -> Result<Response> {
+    let kv = env.kv("library")?;
+    let key = page_key(url);
+    if let Some(html) = kv.get(&key).text().await? {
+        return Response::from_html(html);
+    }
+
+    let mut res = Fetch::Url(Url::parse(url)?).send().await?;
+    if res.status_code() == 200 {
+        let mut copy = res.cloned()?;
+        ctx.wait_until(async move {
+            let stored = match copy.text().await {
+                Ok(html) => match kv.put(&key, html) {
+                    Ok(put) => put.expiration_ttl(PAGE_TTL).execute().await.map_err(Error::from),
+                    Err(e) => Err(e.into()),
+                },
+                Err(e) => Err(e),
+            };
+            if let Err(e) = stored {
+                console_log!("[pages]: caching {} failed: {}", key, e);
+            }
+        });
+    }
+
+    // upstream is raw github serving text/plain, so only the body is kept
+    let status = res.status_code();
+    let (_, body) = res.into_parts();
+    Ok(ResponseBuilder::new()
+        .with_status(status)
+        .with_header("Content-Type", "text/html; charset=utf-8")?
+        .body(body))
+}
+
+fn page_key(url: &str) -> String {
+    format!("{}{}", PAGE_KEY_PREFIX, url)
+}
+
+// run from the cron trigger: cached pages expiring within WARM_WINDOW and
+// uncached base pages are fetched again, so visitors rarely wait on github.
+// returns how many pages were refreshed.
+pub async fn warm(env: &Env) -> Result<usize> {
+    let kv = env.kv("library")?;
+    let mut cached = BTreeMap::new();
+    let mut cursor = None;
+    loop {
+        let mut list = kv.list().prefix(PAGE_KEY_PREFIX.to_string());
+        if let Some(cursor) = cursor.take() {
+            list = list.cursor(cursor);
+        }
+        let page = list.execute().await?;
+        for key in page.keys {
+            cached.insert(key.name[PAGE_KEY_PREFIX.len()..].to_string(), key.expiration);
+        }
+        if page.list_complete || page.cursor.is_none() {
+            break;
+        }
+        cursor = page.cursor;
+    }
+
+    let configured = PAGE_VARS.iter().filter_map(|var| env.var(var).ok()).map(|x| x.to_string());
+    let urls = stale(&cached, configured, Date::now().as_millis() / 1000);
+    let mut refreshed = 0;
+    for url in urls {
+        match refresh(&kv, &url).await {
+            Ok(()) => refreshed += 1,
+            Err(e) => console_log!("[pages]: warming {} failed: {}", url, e),
+        }
+    }
+    Ok(refreshed)
+}
+
+// cached urls close to expiry, then configured urls missing from the cache
+fn stale(cached: &BTreeMap<String, Option<u64>>, configured: impl Iterator<Item = String>, now: u64) -> Vec<String> {
+    let mut urls: Vec<String> = cached
+        .iter()
+        .filter(|(_, expiration)| expiration.is_some_and(|at| at <= now + WARM_WINDOW))
+        .map(|(url, _)| url.clone())
+        .collect();
+    for url in configured {
+        if !cached.contains_key(&url) && !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    urls
+}
+
+async fn refresh(kv: &kv::KvStore, url: &str) -> Result<()> {
+    let mut res = Fetch::Url(Url::parse(url)?).send().await?;
+    if res.status_code() != 200 {
+        return Err(Error::RustError(format!("status {}", res.status_code())));
+    }
+    kv.put(&page_key(url), res.text().await?)?.expiration_ttl(PAGE_TTL).execute().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_languages() {
+        assert_eq!(languages("id-ID,id;q=0.9,en-US;q=0.8,en;q=0.7"), ["ID", "EN"]);
+        assert_eq!(languages("en;q=0.5, ja"), ["JA", "EN"]);
+        assert_eq!(languages("*, fr;q=0"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let langs = ["ID".to_string()];
+        assert_eq!(
+            candidates("MAIN_PAGE_URL", Some("BRAND"), &langs),
+            ["BRAND_MAIN_PAGE_URL_ID", "BRAND_MAIN_PAGE_URL", "MAIN_PAGE_URL_ID", "MAIN_PAGE_URL"]
+        );
+        assert_eq!(candidates("MAIN_PAGE_URL", None, &[]), ["MAIN_PAGE_URL"]);
+    }
+
+    #[test]
+    fn test_stale() {
+        let cached = BTreeMap::from([("https://a".to_string(), Some(1000)), ("https://b".to_string(), Some(5000))]);
+        let configured = ["https://b", "https://c", "https://c"].map(String::from).into_iter();
+        assert_eq!(stale(&cached, configured, 500), ["https://a", "https://c"]);
+    }
+}
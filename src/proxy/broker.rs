@@ -0,0 +1,165 @@
+use super::{peer_ip, relay, ProxyStream, Stage, TunnelTransport, WebSocketStream};
+use crate::metrics::{record_error, ErrorClass};
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use pretty_bytes::converter::convert;
+use worker::*;
+
+static WARM_POOL_SIZE: usize = 2;
+static WARM_SOCKET_TTL: u64 = 30 * 1000; // 30s, most relays drop idle connections shortly after
+// brokers per destination. every brokered tunnel is relayed by its broker,
+// so a single one per destination would put all of them on one object.
+static BROKER_SHARDS: u8 = 8;
+
+struct WarmSocket {
+    socket: Socket,
+    dialed_at: u64,
+}
+
+// BROKER_SHARDS broker instances per destination, named "addr:port:shard",
+// holding sockets that were dialed ahead of time and never used. tunnels
+// pick a shard at random.
+#[durable_object]
+pub struct ConnectionBroker {
+    state: State,
+    pool: Rc<RefCell<VecDeque<WarmSocket>>>,
+    // dials refill started that haven't landed in the pool yet
+    dialing: Rc<Cell<usize>>,
+}
+
+#[durable_object]
+impl DurableObject for ConnectionBroker {
+    fn new(state: State, _env: Env) -> Self {
+        Self {
+            state,
+            pool: Rc::new(RefCell::new(VecDeque::new())),
+            dialing: Rc::new(Cell::new(0)),
+        }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let params: std::collections::HashMap<_, _> = url.query_pairs().into_owned().collect();
+        let addr = params
+            .get("addr")
+            .cloned()
+            .ok_or_else(|| Error::RustError("missing addr".to_string()))?;
+        let port: u16 = params
+            .get("port")
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| Error::RustError("missing port".to_string()))?;
+
+        let socket = match self.take_warm() {
+            Some(socket) => socket,
+            None => {
+                let socket = Socket::builder().allow_half_open(true).connect(&addr, port)?;
+                socket.opened().await?;
+                socket
+            }
+        };
+
+        let WebSocketPair { server, client } = WebSocketPair::new()?;
+        server.accept()?;
+
+        // the socket's eof closes the websocket, which is the only way to
+        // tell the tunnel, the tunnel closing it is the socket's eof
+        wasm_bindgen_futures::spawn_local(async move {
+            let mut socket = socket;
+            let copied = match WebSocketStream::new(&server) {
+                Ok(mut stream) => relay::copy_bidirectional(&mut stream, &mut socket).await.map_err(|e| Error::RustError(e.to_string())),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = copied {
+                console_log!("[broker]: {}", e);
+            }
+            let _ = server.close(Some(1000), Some("done"));
+        });
+
+        self.refill(addr, port);
+        Response::from_websocket(client)
+    }
+}
+
+impl ConnectionBroker {
+    fn take_warm(&mut self) -> Option<Socket> {
+        let now = Date::now().as_millis();
+        let mut pool = self.pool.borrow_mut();
+        while let Some(mut warm) = pool.pop_front() {
+            if now.saturating_sub(warm.dialed_at) < WARM_SOCKET_TTL {
+                return Some(warm.socket);
+            }
+            self.state.wait_until(async move {
+                let _ = warm.socket.close().await;
+            });
+        }
+        None
+    }
+
+    fn refill(&self, addr: String, port: u16) {
+        let missing = WARM_POOL_SIZE.saturating_sub(self.pool.borrow().len() + self.dialing.get());
+        for _ in 0..missing {
+            let pool = self.pool.clone();
+            let dialing = self.dialing.clone();
+            let addr = addr.clone();
+            dialing.set(dialing.get() + 1);
+            self.state.wait_until(async move {
+                match Socket::builder().allow_half_open(true).connect(&addr, port) {
+                    Ok(socket) => {
+                        if socket.opened().await.is_ok() {
+                            pool.borrow_mut().push_back(WarmSocket {
+                                socket,
+                                dialed_at: Date::now().as_millis(),
+                            });
+                        }
+                    }
+                    Err(e) => console_log!("[broker]: dial {}:{} failed: {}", &addr, port, e),
+                }
+                dialing.set(dialing.get() - 1);
+            });
+        }
+    }
+}
+
+impl<T: TunnelTransport> ProxyStream<T> {
+    pub async fn handle_brokered_outbound(&mut self, broker: ObjectNamespace, addr: String, port: u16) -> Result<()> {
+        let mut shard = [0u8; 1];
+        let _ = getrandom::getrandom(&mut shard);
+        let name = format!("{}:{}:{}", addr, port, shard[0] % BROKER_SHARDS);
+        let stub = broker.id_from_name(&name)?.get_stub()?;
+
+        let mut headers = Headers::new();
+        headers.set("Upgrade", "websocket")?;
+        let url = format!("https://broker/?addr={}&port={}", addr, port);
+        let req = Request::new_with_init(&url, RequestInit::new().with_headers(headers))?;
+
+        let remote_ws = stub
+            .fetch_with_request(req)
+            .await?
+            .websocket()
+            .ok_or_else(|| Error::RustError("broker did not upgrade".to_string()))?;
+        remote_ws.accept()?;
+
+        // the broker writes messages to the socket in order, so the header
+        // still comes first on a warm socket
+        if let (Some(version), Some(client_ip)) = (self.config.wants_proxy_protocol(&addr, port), self.config.client.ip) {
+            crate::log_debug!(self.config, "sending a proxy protocol {:?} header", version);
+            remote_ws.send_with_bytes(version.header(client_ip, peer_ip(None, &addr), port))?;
+        }
+
+        // the same relay as a direct connection, so a brokered tunnel gets
+        // the same drain limits after eof and slow connection tagging
+        self.stage = Stage::Relay;
+        let mut remote = WebSocketStream::new(&remote_ws)?;
+        let monitor = self.slow_monitor(&addr, port);
+        let result = relay::copy_bidirectional_monitored(self, &mut remote, monitor.as_ref()).await;
+        let _ = remote_ws.close(Some(1000), Some("done"));
+        let (up, down) = result.map_err(|e| {
+            record_error(ErrorClass::RelayError);
+            Error::RustError(e.to_string())
+        })?;
+        crate::log!("{} copied data from {}:{} via broker, up: {} and dl: {} ({})", self.config.trace, &addr, &port, convert(up as f64), convert(down as f64), self.config.client);
+        Ok(())
+    }
+}
@@ -1,94 +1,478 @@
+use crate::common::{self, padding, secure};
+use crate::common::protocol::{dns, shadowsocks_body, trojan, vmess, vmess_body, Chunk, ParseError, ParseResult, Protocol};
 use crate::config::Config;
+use crate::metrics::{record_dial, record_error, record_violation, ErrorClass};
+use super::queue::FrameQueue;
+use super::{dial, peer_ip, relay, timer, webcrypto, TunnelTransport};
 
+use std::collections::VecDeque;
+use std::fmt;
 use std::pin::Pin;
-use std::task::{Context, Poll};
-use bytes::{BufMut, BytesMut};
-use futures_util::Stream;
-use pin_project_lite::pin_project;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::task::{ready, Context, Poll};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::future::{self, Either};
 use pretty_bytes::converter::convert;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use uuid::Uuid;
 use worker::*;
 
 static MAX_WEBSOCKET_SIZE: usize = 64 * 1024; // 64kb
-static MAX_BUFFER_SIZE: usize = 512 * 1024; // 512kb
+static DEFAULT_MAX_HEADER_SIZE: usize = 4 * 1024; // 4kb
+static DEFAULT_MAX_BUFFER_SIZE: usize = 512 * 1024; // 512kb
 
-pin_project! {
-    pub struct ProxyStream<'a> {
-        pub config: Config,
-        pub ws: &'a WebSocket,
-        pub buffer: BytesMut,
-        #[pin]
-        pub events: EventStream<'a>,
+static DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// websocket close codes for a failed tunnel, in the private range. auth
+// failures, policy blocks and violations are permanent, a client retrying
+// gets the same answer. dial failures, timeouts and internal errors may
+// pass on the next attempt.
+pub const AUTH_FAILED_CLOSE_CODE: u16 = 4005;
+pub const POLICY_CLOSE_CODE: u16 = 4006;
+pub const DIAL_FAILED_CLOSE_CODE: u16 = 4007;
+pub const HANDSHAKE_TIMEOUT_CLOSE_CODE: u16 = 4008;
+pub const INTERNAL_CLOSE_CODE: u16 = 4009;
+pub const TERMINATED_CLOSE_CODE: u16 = 4010;
+
+// close code for a rejected stream: 4000 when no protocol matched, 4001..
+// for violations of a detected protocol
+pub fn violation_close_code(protocol: Option<Protocol>) -> u16 {
+    match protocol {
+        None => 4000,
+        Some(Protocol::Vless) => 4001,
+        Some(Protocol::Vmess) => 4002,
+        Some(Protocol::Trojan) => 4003,
+        Some(Protocol::Shadowsocks) => 4004,
+    }
+}
+
+// the client didn't finish its header in time
+#[derive(Debug)]
+pub struct HandshakeTimeout;
+
+impl fmt::Display for HandshakeTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "handshake timed out")
     }
 }
 
-impl<'a> ProxyStream<'a> {
-    pub fn new(config: Config, ws: &'a WebSocket, events: EventStream<'a>) -> Self {
-        let buffer = BytesMut::with_capacity(MAX_BUFFER_SIZE);
+impl std::error::Error for HandshakeTimeout {}
+
+// how far a tunnel got before it failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    Handshake,
+    Dial,
+    Relay,
+    Fallback,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Handshake => "handshake",
+            Self::Dial => "dial",
+            Self::Relay => "relay",
+            Self::Fallback => "fallback",
+        }
+    }
+}
+
+// an error out of `process` with what the tunnel was doing at the time,
+// logged as e.g. "vless relay to example.com:443: connection reset"
+#[derive(Debug)]
+pub struct ConnError {
+    pub protocol: Option<Protocol>,
+    pub stage: Stage,
+    pub destination: Option<String>,
+    pub error: Error,
+}
+
+impl fmt::Display for ConnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.protocol.map_or("unknown", |x| x.name()), self.stage.name())?;
+        if let Some(destination) = &self.destination {
+            write!(f, " to {}", destination)?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+impl std::error::Error for ConnError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+// what a running tunnel is doing, shared with whoever reports it to the
+// connection registry while the stream is busy relaying
+#[derive(Default)]
+pub struct Progress {
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub protocol: Mutex<Option<Protocol>>,
+    pub destination: Mutex<Option<String>>,
+    // set once the relay found the connection slow, see `SlowMonitor`
+    pub slow: AtomicBool,
+}
+
+// a chunked, possibly sealed body the client switches to after its header
+// both are boxed, the codec state is large
+pub enum Body {
+    // with VMESS_WEBCRYPTO, large response chunks are sealed with the key
+    Vmess(Box<(vmess_body::ChunkReader, vmess_body::ChunkWriter, Option<webcrypto::Key>)>),
+    // the writer follows from the request salt and key, it's created on
+    // the first write
+    Shadowsocks(Box<(shadowsocks_body::ChunkReader, Option<shadowsocks_body::ChunkWriter>)>),
+}
+
+impl Body {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Vmess(_) => "vmess",
+            Self::Shadowsocks(_) => "shadowsocks",
+        }
+    }
+
+    fn decode(&mut self, buf: &mut BytesMut) -> std::result::Result<Chunk, ParseError> {
+        match self {
+            Self::Vmess(codec) => codec.0.decode(buf),
+            Self::Shadowsocks(codec) => codec.0.decode(buf),
+        }
+    }
+
+    // one chunk carrying as much of `buf` as fits, and how much that was
+    fn encode(&mut self, buf: &[u8]) -> std::io::Result<(Vec<u8>, usize)> {
+        match self {
+            Self::Vmess(codec) => {
+                let n = buf.len().min(vmess_body::MAX_CHUNK_PAYLOAD);
+                let chunk = codec.1.encode(&buf[..n]).map_err(|e| std::io::Error::other(e.to_string()))?;
+                Ok((chunk, n))
+            }
+            Self::Shadowsocks(codec) => {
+                let (reader, writer) = &mut **codec;
+                if !reader.is_started() {
+                    return Err(std::io::Error::other("shadowsocks response before the request"));
+                }
+                let writer = match writer {
+                    Some(writer) => writer,
+                    None => writer.insert(
+                        shadowsocks_body::ChunkWriter::new(reader).map_err(|e| std::io::Error::other(e.to_string()))?,
+                    ),
+                };
+                let n = buf.len().min(shadowsocks_body::MAX_CHUNK_PAYLOAD);
+                Ok((writer.encode(&buf[..n]), n))
+            }
+        }
+    }
+
+    // starts sealing a chunk with webcrypto instead, for a vmess write of at
+    // least webcrypto::MIN_CHUNK bytes when the client asked for aes-gcm
+    fn offload(&mut self, buf: &[u8]) -> Option<(webcrypto::Sealing, usize)> {
+        let Self::Vmess(codec) = self else {
+            return None;
+        };
+        let (_, writer, Some(key)) = &mut **codec else {
+            return None;
+        };
+        if buf.len() < webcrypto::MIN_CHUNK {
+            return None;
+        }
+        let n = buf.len().min(vmess_body::MAX_CHUNK_PAYLOAD);
+        let nonce = writer.next_nonce()?;
+        Some((key.seal(&nonce, &buf[..n]), n))
+    }
+
+    // the chunk around what `offload` sealed
+    fn frame(&mut self, sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Vmess(codec) => Ok(codec.1.frame(sealed)),
+            Self::Shadowsocks(_) => Err(std::io::Error::other("shadowsocks chunks aren't sealed by webcrypto")),
+        }
+    }
+}
+
+pub struct ProxyStream<T: TunnelTransport> {
+    pub config: Config,
+    pub transport: T,
+    pub buffer: FrameQueue,
+    // who the tunnel is accounted to, the configured uuid unless the
+    // protocol carries its own
+    pub user: Uuid,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    // what the stream was detected as, and the close code and reason to
+    // reject it with once the client broke that protocol or timed out
+    pub protocol: Option<Protocol>,
+    pub close: Option<(u16, &'static str)>,
+    pub stage: Stage,
+    // the client never got past the header: bad credentials, a protocol
+    // violation or a timeout. counted against its ip by AUTOBAN.
+    pub handshake_failed: bool,
+    pub progress: Arc<Progress>,
+    // unix millis by which the header has to be in, set on the first fill
+    handshake_deadline: Option<u64>,
+    // body codec once the header is done, inbound bytes wait in `raw`
+    // until a whole chunk arrived
+    pub body: Option<Body>,
+    raw: BytesMut,
+    inbound_closed: bool,
+    // encoded frames the transport hasn't fully taken yet: the frames, each
+    // its own message, how much of the first is written and how many caller
+    // bytes they carry
+    outbound: Option<(VecDeque<Vec<u8>>, usize, usize)>,
+    // a chunk webcrypto is sealing and how many caller bytes it carries,
+    // framed into `outbound` once done
+    sealing: Option<(webcrypto::Sealing, usize)>,
+}
+
+impl<T: TunnelTransport> ProxyStream<T> {
+    pub fn new(config: Config, transport: T) -> Self {
+        let buffer = FrameQueue::default();
+        let user = config.path_user.unwrap_or(config.uuid);
 
         Self {
             config,
-            ws,
+            transport,
             buffer,
-            events,
+            user,
+            bytes_up: 0,
+            bytes_down: 0,
+            protocol: None,
+            close: None,
+            stage: Stage::Handshake,
+            handshake_failed: false,
+            progress: Arc::default(),
+            handshake_deadline: None,
+            body: None,
+            raw: BytesMut::new(),
+            inbound_closed: false,
+            outbound: None,
+            sealing: None,
         }
     }
     
+    // only used while reading headers, so the wait is bounded by the
+    // handshake deadline. an eof leaves the buffer short without an error.
     pub async fn fill_buffer_until(&mut self, n: usize) -> std::io::Result<()> {
-        use futures_util::StreamExt;
+        let now = common::unix_millis();
+        let timeout = self.config.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT);
+        let deadline = *self.handshake_deadline.get_or_insert(now + timeout.as_millis() as u64);
+        let sleep = timer::sleep(Duration::from_millis(deadline.saturating_sub(now)));
+
+        let max_header_size = self.config.max_header_size.unwrap_or(DEFAULT_MAX_HEADER_SIZE);
+        if n > max_header_size {
+            self.violation();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("header exceeds {} bytes", max_header_size),
+            ));
+        }
 
+        let filled = match future::select(Box::pin(self.fill(n)), Box::pin(sleep)).await {
+            Either::Left((filled, _)) => Some(filled),
+            Either::Right(_) => None,
+        };
+        filled.unwrap_or_else(|| {
+            record_error(ErrorClass::Timeout);
+            self.handshake_failed = true;
+            self.close = Some((HANDSHAKE_TIMEOUT_CLOSE_CODE, "handshake timeout"));
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, HandshakeTimeout))
+        })
+    }
+
+    async fn fill(&mut self, n: usize) -> std::io::Result<()> {
         while self.buffer.len() < n {
-            match self.events.next().await {
-                Some(Ok(WebsocketEvent::Message(msg))) => {
-                    if let Some(data) = msg.bytes() {
-                        self.buffer.put_slice(&data);
-                    }
-                }
-                Some(Ok(WebsocketEvent::Close(_))) => {
+            match std::future::poll_fn(|cx| self.transport.poll_recv(cx)).await? {
+                Some(data) => self.receive(data)?,
+                None => {
                     break;
                 }
-                Some(Err(e)) => {
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
-                }
+            }
+        }
+        Ok(())
+    }
+
+    // caps what a single tunnel holds in memory, decoded or not
+    fn check_buffered(&self, incoming: usize) -> std::io::Result<()> {
+        let max_buffer_size = self.config.max_buffer_size.unwrap_or(DEFAULT_MAX_BUFFER_SIZE);
+        if self.buffer.len() + self.raw.len() + incoming > max_buffer_size {
+            return Err(std::io::Error::other(format!("tunnel buffers exceed {} bytes", max_buffer_size)));
+        }
+        Ok(())
+    }
+
+    // a message from the client, queued without copying unless it has to
+    // be unpadded or decoded
+    fn receive(&mut self, data: Vec<u8>) -> std::io::Result<()> {
+        self.check_buffered(data.len())?;
+        let data = Bytes::from(data);
+        let data = match self.config.padding {
+            true => match padding::unpad(&data) {
+                Some(payload) => data.slice_ref(payload),
                 None => {
-                    break;
+                    self.close = Some((violation_close_code(self.protocol), "malformed padding"));
+                    return Err(std::io::Error::other("malformed padded frame"));
                 }
+            },
+            false => data,
+        };
+        self.push_inbound(data)
+    }
+
+    // client data goes straight to the queue, or through the body decoder
+    // when one is set.
+    fn push_inbound(&mut self, data: Bytes) -> std::io::Result<()> {
+        let Some(body) = &mut self.body else {
+            self.buffer.push(data);
+            return Ok(());
+        };
+
+        self.raw.put_slice(&data);
+        while !self.inbound_closed {
+            match body.decode(&mut self.raw) {
+                Ok(Chunk::Data(payload)) => self.buffer.push(payload.into()),
+                Ok(Chunk::End) => self.inbound_closed = true,
+                Err(ParseError::Incomplete) => break,
+                Err(e) => return Err(std::io::Error::other(e.to_string())),
             }
         }
         Ok(())
     }
 
+    // switches to chunked framing, bytes that came in behind the header are
+    // already chunks.
+    pub fn set_body(&mut self, body: Body) -> std::io::Result<()> {
+        crate::log_debug!(self.config, "switching to {} chunked body, {} bytes behind the header", body.name(), self.buffer.len());
+        self.body = Some(body);
+        for leftover in self.buffer.take() {
+            self.push_inbound(leftover)?;
+        }
+        Ok(())
+    }
+
+    // feed the buffered bytes to a sans-io parser, pulling more from the
+    // client until it has a complete header.
+    pub async fn read_header<R>(&mut self, parse: impl Fn(&[u8]) -> ParseResult<R>) -> Result<R> {
+        loop {
+            match parse(self.buffer.make_contiguous()) {
+                Ok((request, consumed)) => {
+                    crate::log_debug!(self.config, "header parsed, {} of {} buffered bytes", consumed, self.buffer.len());
+                    self.buffer.advance(consumed);
+                    return Ok(request);
+                }
+                Err(ParseError::Incomplete) => {
+                    let buffered = self.buffer.len();
+                    self.fill_buffer_until(buffered + 1).await?;
+                    if self.buffer.len() == buffered {
+                        return Err(Error::RustError("unexpected eof in header".to_string()));
+                    }
+                }
+                Err(e) => {
+                    self.violation();
+                    return Err(Error::RustError(e.to_string()));
+                }
+            }
+        }
+    }
+
+    fn violation(&mut self) {
+        record_violation(self.protocol);
+        self.handshake_failed = true;
+        self.close = Some((violation_close_code(self.protocol), "protocol violation"));
+    }
+
+    // the start of the queue, after `make_contiguous`
     pub fn peek_buffer(&self, n: usize) -> &[u8] {
-        let len = self.buffer.len().min(n);
-        &self.buffer[..len]
+        let front = self.buffer.front();
+        &front[..front.len().min(n)]
+    }
+
+    pub async fn process(&mut self) -> std::result::Result<(), ConnError> {
+        let result = self.run().await;
+        result.map_err(|e| self.context(e))
+    }
+
+    // `error` with the protocol, stage and destination the tunnel is at
+    pub fn context(&self, error: Error) -> ConnError {
+        ConnError {
+            protocol: self.protocol,
+            stage: self.stage,
+            destination: self.progress.destination.lock().unwrap().clone(),
+            error,
+        }
     }
 
-    pub async fn process(&mut self) -> Result<()> {
+    async fn run(&mut self) -> Result<()> {
+        if let Some(data) = self.config.early_data.take() {
+            crate::log_debug!(self.config, "{} bytes of early data", data.len());
+            self.receive(data)?;
+        }
+
+        // peers relaying through RELAY_PATH only ever speak vless, whatever
+        // mode this deployment's own clients use
+        if self.config.relay_inbound {
+            self.set_protocol(Protocol::Vless);
+            return self.process_vless().await;
+        }
+
+        // a sealed stream starts with a random salt, there's nothing to
+        // detect so plugin deployments take everything as shadowsocks
+        if self.config.shadowsocks_plugin {
+            crate::log!("{} shadowsocks (plugin mode)", self.config.trace);
+            self.set_protocol(Protocol::Shadowsocks);
+            return self.process_shadowsocks().await;
+        }
+
         let peek_buffer_len = 62;
         self.fill_buffer_until(peek_buffer_len).await?;
+        self.buffer.make_contiguous();
         let peeked_buffer = self.peek_buffer(peek_buffer_len);
+        crate::log_debug!(
+            self.config,
+            "handshake: {} bytes buffered, starts with {}",
+            self.buffer.len(),
+            peeked_buffer.iter().take(16).map(|x| format!("{:02x}", x)).collect::<String>()
+        );
 
         if peeked_buffer.len() < (peek_buffer_len/2) {
+            self.violation();
             return Err(Error::RustError("not enough buffer".to_string()));
         }
 
-        if self.is_vless(peeked_buffer) {
-            console_log!("vless detected!");
-            self.process_vless().await
-        } else if self.is_shadowsocks(peeked_buffer) {
-            console_log!("shadowsocks detected!");
-            self.process_shadowsocks().await
+        // the exact checks go first, the first byte heuristics would take a
+        // vmess auth id or trojan hash starting with 0 or an address type
+        let protocol = if self.is_vmess(peeked_buffer) {
+            Protocol::Vmess
         } else if self.is_trojan(peeked_buffer) {
-            console_log!("trojan detected!");
-            self.process_trojan().await
-        } else if self.is_vmess(peeked_buffer) {
-            console_log!("vmess detected!");
-            self.process_vmess().await
+            Protocol::Trojan
+        } else if self.is_vless(peeked_buffer) {
+            Protocol::Vless
+        } else if self.is_shadowsocks(peeked_buffer) {
+            Protocol::Shadowsocks
         } else {
-            Err(Error::RustError("protocol not implemented".to_string()))
+            // scanners and unknown clients end up here
+            self.violation();
+            return self.handle_fallback(Error::RustError("protocol not recognized".to_string())).await;
+        };
+
+        crate::log!("{} {} detected!", self.config.trace, protocol.name());
+        self.set_protocol(protocol);
+        match protocol {
+            Protocol::Vless => self.process_vless().await,
+            Protocol::Vmess => self.process_vmess().await,
+            Protocol::Trojan => self.process_trojan().await,
+            Protocol::Shadowsocks => self.process_shadowsocks().await,
         }
     }
 
+    fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = Some(protocol);
+        *self.progress.protocol.lock().unwrap() = Some(protocol);
+    }
+
     pub fn is_vless(&self, buffer: &[u8]) -> bool {
         buffer[0] == 0
     }
@@ -127,75 +511,297 @@ impl<'a> ProxyStream<'a> {
         }
     }
 
+    // crlf at the right offset, a hex prefix and a hash we know, anything
+    // less gets confused with vmess auth ids
     fn is_trojan(&self, buffer: &[u8]) -> bool {
-        buffer.len() > 57 && buffer[56] == 13 && buffer[57] == 10
+        buffer.len() > 57
+            && buffer[56..58] == *b"\r\n"
+            && trojan::is_hex_hash(&buffer[..56])
+            && secure::ct_eq(&buffer[..56], self.trojan_hash())
     }
 
+    // the trojan password is the configured uuid
+    pub fn trojan_hash(&self) -> [u8; 56] {
+        trojan::password_hash(&self.config.uuid.to_string())
+    }
+
+    // the auth id check is what keeps random bytes off the aead path
     fn is_vmess(&self, buffer: &[u8]) -> bool {
-        buffer.len() > 0 // fallback
+        buffer.len() >= 16 && vmess::verify_auth_id(&super::vmess::keys(&self.config.uuid), &buffer[..16], common::unix_time())
+    }
+
+    // dial the requested destination, falling back to the proxyip when the
+    // direct connection fails. `to=proxy-first` swaps the two.
+    pub async fn handle_outbound(&mut self, addr: String, port: u16) -> Result<()> {
+        if !self.config.is_destination_allowed(&addr) {
+            self.close = Some((POLICY_CLOSE_CODE, "destination blocked"));
+            return Err(Error::RustError(format!("destination {} is not allowed", addr)));
+        }
+        // checked on the requested port once, the proxy leg dials the
+        // proxyip's port and the upstream relay dials on its own
+        if !self.config.is_port_allowed(port) {
+            self.close = Some((POLICY_CLOSE_CODE, "port blocked"));
+            return Err(Error::RustError(format!("port {} is not allowed in tls-only mode", port)));
+        }
+        *self.progress.destination.lock().unwrap() = Some(format!("{}:{}", addr, port));
+        self.stage = Stage::Dial;
+
+        // whether each leg goes through the proxyip, or the upstream relay
+        // standing in for it
+        let mut legs = [false, true];
+        if self.config.options.proxy_first {
+            legs.reverse();
+        }
+
+        for via_proxy in legs {
+            crate::log_debug!(self.config, "dialing {}", if via_proxy { "the proxy leg" } else { "directly" });
+            let result = match (via_proxy, self.config.upstream_relay.clone()) {
+                (false, _) => self.handle_tcp_outbound(addr.clone(), port).await,
+                (true, Some(upstream)) => self.handle_upstream_outbound(upstream, addr.clone(), port).await,
+                (true, None) => {
+                    let (proxy_addr, proxy_port) = (self.config.proxy_addr.clone(), self.config.proxy_port);
+                    if let Some(label) = &self.config.proxy_label {
+                        crate::log!("{} dialing proxyip {}:{} ({})", self.config.trace, proxy_addr, proxy_port, label);
+                    }
+                    self.handle_tcp_outbound(proxy_addr, proxy_port).await
+                }
+            };
+            match result {
+                Ok(_) => return Ok(()),
+                // refused by policy, another leg would only get around it
+                Err(e) if self.close.is_some_and(|(code, _)| code == POLICY_CLOSE_CODE) => return Err(e),
+                Err(e) => crate::log_error!("{} error handling tcp: {}", self.config.trace, e),
+            }
+        }
+
+        record_error(ErrorClass::ConnectFailed);
+        // a leg refused by policy says more than the dial failure
+        self.close.get_or_insert((DIAL_FAILED_CLOSE_CODE, "dial failed"));
+        Err(Error::RustError(format!("every leg to {}:{} failed", addr, port)))
+    }
+
+    // replays what the client sent so far to the decoy origin and relays the
+    // rest, so an active probe gets a normal website instead of a reset.
+    pub async fn handle_fallback(&mut self, reason: Error) -> Result<()> {
+        self.handshake_failed = true;
+        let Some((addr, port)) = self.config.fallback.clone() else {
+            return Err(reason);
+        };
+        self.stage = Stage::Fallback;
+        crate::log!("{} falling back to {}:{} ({}): {}", self.config.trace, addr, port, self.config.client, reason);
+
+        let (mut remote_socket, _) = dial(&addr, port).await?;
+        for replay in self.buffer.take() {
+            remote_socket.write_all(&replay).await?;
+        }
+        relay::copy_bidirectional(self, &mut remote_socket)
+            .await
+            .map_err(|e| Error::RustError(e.to_string()))?;
+        Ok(())
     }
 
     pub async fn handle_tcp_outbound(&mut self, addr: String, port: u16) -> Result<()> {
-        let mut remote_socket = Socket::builder().connect(&addr, port).map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
+        let is_proxyip = addr == self.config.proxy_addr && port == self.config.proxy_port;
+        if !is_proxyip && !self.config.is_port_allowed(port) {
+            self.close = Some((POLICY_CLOSE_CODE, "port blocked"));
+            return Err(Error::RustError(format!("port {} is not allowed in tls-only mode", port)));
+        }
+        if let Some(broker) = self.config.broker.clone() {
+            // only the proxyip leg is hot enough to be worth keeping warm
+            if addr == self.config.proxy_addr && port == self.config.proxy_port {
+                return self.handle_brokered_outbound(broker, addr, port).await;
+            }
+        }
 
-        remote_socket.opened().await.map_err(|e| {
-            Error::RustError(e.to_string())
-        })?;
+        // the country check fails closed, any error refuses the destination
+        // before a connection to it is opened
+        let dial_addr = match self.checked_destination(&addr, port).await {
+            Ok(dial_addr) => dial_addr,
+            Err(e) => {
+                self.close = Some((POLICY_CLOSE_CODE, "destination blocked"));
+                return Err(e);
+            }
+        };
+
+        let started = common::unix_millis();
+        let dialed = dial(&dial_addr, port).await;
+        if addr == self.config.proxy_addr && port == self.config.proxy_port {
+            record_dial(&format!("{}:{}", addr, port), dialed.is_ok(), common::unix_millis() - started);
+        }
+        let (mut remote_socket, remote_address) = dialed?;
+        crate::log_debug!(self.config, "connected to {}:{} at {}", addr, port, remote_address.as_deref().unwrap_or("unknown address"));
 
-        tokio::io::copy_bidirectional(self, &mut remote_socket)
+        if let (Some(version), Some(client_ip)) = (self.config.wants_proxy_protocol(&addr, port), self.config.client.ip) {
+            let dst = peer_ip(remote_address.as_deref(), &dial_addr);
+            crate::log_debug!(self.config, "sending a proxy protocol {:?} header", version);
+            remote_socket.write_all(&version.header(client_ip, dst, port)).await?;
+        }
+
+        self.stage = Stage::Relay;
+        let monitor = self.slow_monitor(&addr, port);
+        relay::copy_bidirectional_monitored(self, &mut remote_socket, monitor.as_ref())
             .await
             .map(|(a_to_b, b_to_a)| {
-                console_log!("copied data from {}:{}, up: {} and dl: {}", &addr, &port, convert(a_to_b as f64), convert(b_to_a as f64));
+                crate::log!("{} copied data from {}:{}, up: {} and dl: {} ({})", self.config.trace, &addr, &port, convert(a_to_b as f64), convert(b_to_a as f64), self.config.client);
             })
             .map_err(|e| {
+                record_error(ErrorClass::RelayError);
                 Error::RustError(e.to_string())
             })?;
         Ok(())
     }
 
+    pub fn slow_monitor(&self, addr: &str, port: u16) -> Option<relay::SlowMonitor> {
+        let policy = self.config.slow_policy?;
+        let label = format!("{} {}:{} ({})", self.config.trace, addr, port, self.config.client);
+        Some(relay::SlowMonitor::new(policy, self.progress.clone(), label))
+    }
+
     pub async fn handle_udp_outbound(&mut self) -> Result<()> {
+        self.stage = Stage::Relay;
         let mut buff = vec![0u8; 65535];
+        // a frame split across reads waits here for the rest
+        let mut carry = BytesMut::new();
 
-        let n = self.read(&mut buff).await?;
-        let data = &buff[..n];
-        if crate::dns::doh(data).await.is_ok() {
-            self.write(&data).await?;
-        };
+        loop {
+            let n = self.read(&mut buff).await?;
+            if n == 0 {
+                break;
+            }
+            carry.extend_from_slice(&buff[..n]);
+
+            // udp packets are framed as 2 bytes length + payload, a single
+            // read may carry several queries which are resolved together.
+            let mut frames = Vec::new();
+            while carry.len() >= 2 {
+                let len = u16::from_be_bytes([carry[0], carry[1]]) as usize;
+                if carry.len() < 2 + len {
+                    break;
+                }
+                carry.advance(2);
+                frames.push(carry.split_to(len));
+            }
+            if frames.is_empty() {
+                continue;
+            }
+
+            let queries: Vec<&[u8]> = frames.iter().map(|x| &x[..]).collect();
+            let answers = crate::dns::doh_batch(&self.config.dns, &queries).await;
+            let mut out = BytesMut::new();
+            for (query, answer) in queries.iter().zip(answers) {
+                // a failed lookup still gets an answer, the client would
+                // otherwise wait for its own timeout
+                let answer = match answer {
+                    Ok(answer) => answer,
+                    Err(e) => {
+                        crate::log_error!("{} dns query failed: {}", self.config.trace, e);
+                        match dns::reply(query, dns::RCODE_SERVFAIL) {
+                            Some(reply) => reply,
+                            None => continue,
+                        }
+                    }
+                };
+                out.put_u16(answer.len() as u16);
+                out.put_slice(&answer);
+            }
+            if !out.is_empty() {
+                self.write_all(&out).await?;
+            }
+        }
         Ok(())
     }
 }
 
-impl<'a> AsyncRead for ProxyStream<'a> {
+impl<T: TunnelTransport> ProxyStream<T> {
+    // pushes the pending outbound frames into the transport, returning how
+    // many caller bytes they carried once all are fully written.
+    fn poll_flush_frame(&mut self, cx: &mut Context<'_>) -> Poll<tokio::io::Result<Option<usize>>> {
+        let Self { transport, outbound, .. } = self;
+        while let Some((frames, written, consumed)) = outbound {
+            let Some(frame) = frames.front() else {
+                let consumed = *consumed;
+                *outbound = None;
+                return Poll::Ready(Ok(Some(consumed)));
+            };
+            match ready!(Pin::new(&mut *transport).poll_write(cx, &frame[*written..])) {
+                Ok(0) => return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into())),
+                Ok(n) => *written += n,
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+            if *written == frame.len() {
+                frames.pop_front();
+                *written = 0;
+            }
+        }
+        Poll::Ready(Ok(None))
+    }
+
+    // body chunking and/or padding turn a write into a frame that has to
+    // reach the transport whole.
+    fn poll_write_framed(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<tokio::io::Result<usize>> {
+        // a frame left over from a write that returned Pending carries this same buf
+        if let Some(consumed) = ready!(self.poll_flush_frame(cx))? {
+            return Poll::Ready(Ok(consumed));
+        }
+
+        // so does a chunk still being sealed
+        if self.sealing.is_none() {
+            self.sealing = self.body.as_mut().and_then(|body| body.offload(buf));
+        }
+        let (frame, n) = match (&mut self.sealing, &mut self.body) {
+            (Some((sealing, n)), Some(body)) => {
+                let n = *n;
+                let sealed = ready!(sealing.as_mut().poll(cx));
+                self.sealing = None;
+                (body.frame(&sealed?)?, n)
+            }
+            (_, Some(body)) => body.encode(buf)?,
+            (_, None) => {
+                let n = buf.len().min(padding::MAX_PAYLOAD);
+                (buf[..n].to_vec(), n)
+            }
+        };
+        let mut frames = VecDeque::from([frame]);
+        if self.config.padding {
+            frames[0] = padding::pad(&frames[0]);
+            // queued behind the real frame so it goes out whole as well
+            let mut roll = [0u8; 1];
+            let _ = getrandom::getrandom(&mut roll);
+            if roll[0] % padding::DUMMY_FRAME_ODDS == 0 {
+                frames.push_back(padding::dummy());
+            }
+        }
+        self.outbound = Some((frames, 0, n));
+        let n = ready!(self.poll_flush_frame(cx))?.unwrap_or(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<T: TunnelTransport> AsyncRead for ProxyStream<T> {
     fn poll_read(
         self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<tokio::io::Result<()>> {
-        let mut this = self.project();
+        let this = self.get_mut();
 
         loop {
-            let size = std::cmp::min(this.buffer.len(), buf.remaining());
-            if size > 0 {
-                buf.put_slice(&this.buffer.split_to(size));
+            if !this.buffer.is_empty() {
+                let size = this.buffer.read_into(buf);
+                this.bytes_up += size as u64;
+                this.progress.bytes_up.fetch_add(size as u64, Ordering::Relaxed);
+                return Poll::Ready(Ok(()));
+            }
+            if this.inbound_closed {
                 return Poll::Ready(Ok(()));
             }
 
-            match this.events.as_mut().poll_next(cx) {
-                Poll::Ready(Some(Ok(WebsocketEvent::Message(msg)))) => {
-                    if let Some(data) = msg.bytes() {
-                        if data.len() > MAX_WEBSOCKET_SIZE {
-                            return Poll::Ready(Err(std::io::Error::new(std::io::ErrorKind::Other, "websocket buffer too long")))
-                        }
-                        
-                        if this.buffer.len() + data.len() > MAX_BUFFER_SIZE {
-                            console_log!("buffer full, applying backpressure");
-                            return Poll::Pending;
-                        }
-                        
-                        this.buffer.put_slice(&data);
+            match this.transport.poll_recv(cx) {
+                Poll::Ready(Ok(Some(data))) => {
+                    if data.len() > MAX_WEBSOCKET_SIZE {
+                        return Poll::Ready(Err(std::io::Error::other("websocket buffer too long")))
                     }
+                    this.receive(data)?;
                 }
                 Poll::Pending => return Poll::Pending,
                 _ => return Poll::Ready(Ok(())),
@@ -204,31 +810,37 @@ impl<'a> AsyncRead for ProxyStream<'a> {
     }
 }
 
-impl<'a> AsyncWrite for ProxyStream<'a> {
+impl<T: TunnelTransport> AsyncWrite for ProxyStream<T> {
     fn poll_write(
         self: Pin<&mut Self>,
-        _: &mut Context<'_>,
+        cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<tokio::io::Result<usize>> {
-        return Poll::Ready(
-            self.ws
-                .send_with_bytes(buf)
-                .map(|_| buf.len())
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string())),
-        );
+        let this = self.get_mut();
+        let result = if this.config.padding || this.body.is_some() {
+            this.poll_write_framed(cx, buf)
+        } else {
+            Pin::new(&mut this.transport).poll_write(cx, buf)
+        };
+        if let Poll::Ready(Ok(n)) = result {
+            this.bytes_down += n as u64;
+            this.progress.bytes_down.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_frame(cx))?;
+        Pin::new(&mut this.transport).poll_flush(cx)
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
-        match self.ws.close(Some(1000), Some("shutdown".to_string())) {
-            Ok(_) => Poll::Ready(Ok(())),
-            Err(e) => Poll::Ready(Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                e.to_string(),
-            ))),
-        }
+    // closing the websocket drops whatever wasn't sent yet, so the tail of
+    // a framed write goes out first
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_frame(cx))?;
+        ready!(Pin::new(&mut this.transport).poll_flush(cx))?;
+        Pin::new(&mut this.transport).poll_shutdown(cx)
     }
 }
@@ -0,0 +1,48 @@
+use worker::*;
+
+// outbound sockets are workers `Socket`s, the native-test feature swaps them
+// for tokio tcp streams so the handlers can run against a local mock remote.
+#[cfg(not(feature = "native-test"))]
+pub type RemoteSocket = Socket;
+#[cfg(feature = "native-test")]
+pub type RemoteSocket = tokio::net::TcpStream;
+
+// returns the connected socket and the peer address when the platform knows it
+#[cfg(not(feature = "native-test"))]
+pub async fn dial(addr: &str, port: u16) -> Result<(RemoteSocket, Option<String>)> {
+    // connect() takes ipv6 literals in brackets, targets are kept bare so
+    // the destination rules and proxy protocol headers can parse them.
+    let host = match addr.parse::<std::net::Ipv6Addr>() {
+        Ok(ip) => format!("[{}]", ip),
+        Err(_) => addr.to_string(),
+    };
+    // half open: the remote's fin doesn't close our write side, the relay
+    // keeps passing the client's data until the client is done as well
+    let socket = Socket::builder().allow_half_open(true).connect(host, port).map_err(|e| {
+        Error::RustError(e.to_string())
+    })?;
+
+    let socket_info = socket.opened().await.map_err(|e| {
+        Error::RustError(e.to_string())
+    })?;
+
+    Ok((socket, socket_info.remote_address))
+}
+
+#[cfg(feature = "native-test")]
+pub async fn dial(addr: &str, port: u16) -> Result<(RemoteSocket, Option<String>)> {
+    let socket = tokio::net::TcpStream::connect((addr, port)).await.map_err(|e| {
+        Error::RustError(e.to_string())
+    })?;
+    let remote_address = socket.peer_addr().ok().map(|x| x.to_string());
+
+    Ok((socket, remote_address))
+}
+
+// the ip a dial ended up at: the peer address the platform reported
+// ("ip:port", or a bare ip), else the target itself when it's a literal
+pub fn peer_ip(remote_address: Option<&str>, addr: &str) -> Option<std::net::IpAddr> {
+    remote_address
+        .and_then(|x| x.parse::<std::net::SocketAddr>().map(|s| s.ip()).or_else(|_| x.parse()).ok())
+        .or_else(|| addr.parse().ok())
+}
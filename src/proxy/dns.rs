@@ -1,23 +1,235 @@
-use anyhow::Result;
+use super::timer;
+use crate::common::{self, protocol::dns, sketch::TopK};
+use crate::metrics::{record_dns, DnsEvent};
+
+use anyhow::{anyhow, Result};
+use futures_util::future::{self, join_all, Either};
 use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
 use reqwest::Client;
+use std::cell::RefCell;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::pin::pin;
+use std::time::Duration;
+
+// DOH_URLS when unset, tried in order
+pub static DEFAULT_DOH_URLS: [&str; 2] = ["https://1.1.1.1/dns-query", "https://8.8.8.8/dns-query"];
+static UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+// how long a failing upstream is skipped
+static UNHEALTHY_COOLDOWN: u64 = 30 * 1000;
+// queries and upstream latencies the isolate stats are computed over
+static STATS_WINDOW: usize = 256;
+static TOP_DOMAINS: usize = 10;
+// how long failed lookups are answered from the isolate, servfail is often
+// transient so it's retried sooner
+static NXDOMAIN_TTL: u64 = 30 * 1000;
+static SERVFAIL_TTL: u64 = 5 * 1000;
+static NEGATIVE_CACHE_SIZE: usize = 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct DnsConfig {
+    // doh resolvers, failing ones are skipped for a while
+    pub upstreams: Vec<String>,
+    // query types answered with REFUSED, e.g. ANY
+    pub refused_types: Vec<u16>,
+    // answer AAAA with an empty NOERROR, for clients that would otherwise
+    // try ipv6 destinations an ipv4-only egress can't reach
+    pub empty_aaaa: bool,
+}
+
+// query without its id to the answer and when it expires
+type NegativeCache = HashMap<Vec<u8>, (Vec<u8>, u64)>;
+
+thread_local! {
+    // one client per isolate so lookups share the underlying fetcher and its
+    // keep-alive connection instead of building a fresh client per query.
+    static CLIENT: Client = {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/dns-message"),
+        );
+        headers.insert(ACCEPT, HeaderValue::from_static("application/dns-message"));
+        Client::builder()
+            .default_headers(headers)
+            .build()
+            .unwrap_or_default()
+    };
+}
+
+thread_local! {
+    // failed answers by query (without its id), so clients spamming lookups
+    // of dead names don't cost a doh request each. dropped all at once when
+    // full.
+    static NEGATIVE: RefCell<NegativeCache> = RefCell::new(HashMap::new());
+    // upstreams that failed recently, until when they're skipped
+    static UNHEALTHY: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+    static STATS: RefCell<Stats> = RefCell::new(Stats::default());
+}
+
+// this isolate's view of the dns path, the totals are in metrics
+struct Stats {
+    // unix millis of the latest queries, for the rate
+    queries: VecDeque<u64>,
+    // of the latest answers from upstream, in millis
+    latencies: VecDeque<u64>,
+    domains: TopK,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self { queries: VecDeque::new(), latencies: VecDeque::new(), domains: TopK::new(TOP_DOMAINS) }
+    }
+}
+
+fn push_bounded(window: &mut VecDeque<u64>, value: u64) {
+    if window.len() == STATS_WINDOW {
+        window.pop_front();
+    }
+    window.push_back(value);
+}
+
+fn percentile(sorted: &[u64], pct: usize) -> Option<u64> {
+    (!sorted.is_empty()).then(|| sorted[(sorted.len() - 1) * pct / 100])
+}
+
+pub fn isolate_stats() -> Value {
+    STATS.with_borrow(|stats| {
+        let now = common::unix_millis();
+        let qps = match (stats.queries.front(), stats.queries.len()) {
+            (Some(oldest), n) if n > 1 => n as f64 * 1000.0 / now.saturating_sub(*oldest).max(1) as f64,
+            _ => 0.0,
+        };
+        let mut latencies: Vec<_> = stats.latencies.iter().copied().collect();
+        latencies.sort_unstable();
+        json!({
+            "qps": qps,
+            "latency_ms": {
+                "p50": percentile(&latencies, 50),
+                "p90": percentile(&latencies, 90),
+                "p99": percentile(&latencies, 99),
+            },
+            "top_domains": stats.domains.top().into_iter().map(|(name, count)| json!({ "name": name, "count": count })).collect::<Vec<_>>(),
+        })
+    })
+}
+
+fn client() -> Client {
+    CLIENT.with(|c| c.clone())
+}
+
+pub async fn doh(config: &DnsConfig, req_wireformat: &[u8]) -> Result<Vec<u8>> {
+    let question = dns::question(req_wireformat).ok().map(|(x, _)| x);
+    let started = common::unix_millis();
+    record_dns(DnsEvent::Query);
+    STATS.with_borrow_mut(|stats| {
+        push_bounded(&mut stats.queries, started);
+        if let Some(question) = &question {
+            stats.domains.add(&question.name);
+        }
+    });
+
+    if let Some(answer) = question.as_ref().and_then(|x| policy_answer(config, x, req_wireformat)) {
+        record_dns(DnsEvent::PolicyAnswer);
+        return Ok(answer);
+    }
+    if let Some(answer) = cached_negative(req_wireformat) {
+        record_dns(DnsEvent::CacheHit);
+        return Ok(answer);
+    }
+    let response = resolve(&config.upstreams, req_wireformat).await.inspect_err(|_| record_dns(DnsEvent::UpstreamError))?;
+    STATS.with_borrow_mut(|stats| push_bounded(&mut stats.latencies, common::unix_millis() - started));
+    remember_negative(req_wireformat, &response);
+    Ok(response)
+}
+
+// queries the policy answers locally instead of forwarding them
+fn policy_answer(config: &DnsConfig, question: &dns::Question, query: &[u8]) -> Option<Vec<u8>> {
+    if config.refused_types.contains(&question.qtype) {
+        return dns::reply(query, dns::RCODE_REFUSED);
+    }
+    if config.empty_aaaa && question.qtype == dns::TYPE_AAAA {
+        return dns::reply(query, dns::RCODE_NOERROR);
+    }
+    None
+}
+
+fn cached_negative(query: &[u8]) -> Option<Vec<u8>> {
+    let key = dns::cache_key(query)?;
+    let now = common::unix_millis();
+    NEGATIVE.with_borrow(|cache| {
+        let (answer, expires) = cache.get(key)?;
+        (*expires > now).then(|| dns::with_id(answer, query))
+    })
+}
+
+fn remember_negative(query: &[u8], answer: &[u8]) {
+    let ttl = match dns::rcode(answer) {
+        Some(dns::RCODE_NXDOMAIN) => NXDOMAIN_TTL,
+        Some(dns::RCODE_SERVFAIL) => SERVFAIL_TTL,
+        _ => return,
+    };
+    let Some(key) = dns::cache_key(query) else {
+        return;
+    };
+    let now = common::unix_millis();
+    NEGATIVE.with_borrow_mut(|cache| {
+        if cache.len() >= NEGATIVE_CACHE_SIZE {
+            cache.retain(|_, (_, expires)| *expires > now);
+            if cache.len() >= NEGATIVE_CACHE_SIZE {
+                cache.clear();
+            }
+        }
+        cache.insert(key.to_vec(), (answer.to_vec(), now + ttl));
+    });
+}
+
+// healthy upstreams in order, the ones cooling down only when all of those
+// failed too
+async fn resolve(upstreams: &[String], req_wireformat: &[u8]) -> Result<Vec<u8>> {
+    let now = common::unix_millis();
+    let (healthy, cooling): (Vec<_>, Vec<_>) = upstreams
+        .iter()
+        .partition(|url| UNHEALTHY.with_borrow(|x| x.get(*url).is_none_or(|until| *until <= now)));
+
+    let mut last_error = anyhow!("no doh upstream configured");
+    for url in healthy.into_iter().chain(cooling) {
+        match upstream(url, req_wireformat).await {
+            Ok(answer) => {
+                UNHEALTHY.with_borrow_mut(|x| x.remove(url));
+                return Ok(answer);
+            }
+            Err(e) => {
+                let until = common::unix_millis() + UNHEALTHY_COOLDOWN;
+                if UNHEALTHY.with_borrow_mut(|x| x.insert(url.clone(), until)).is_none_or(|before| before <= now) {
+                    crate::log!("[dns]: skipping {} for {}s: {}", url, UNHEALTHY_COOLDOWN / 1000, e);
+                }
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+async fn upstream(url: &str, req_wireformat: &[u8]) -> Result<Vec<u8>> {
+    let request = async {
+        client()
+            .post(url)
+            .body(req_wireformat.to_vec())
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await
+    };
+    match future::select(pin!(request), pin!(timer::sleep(UPSTREAM_TIMEOUT))).await {
+        Either::Left((response, _)) => Ok(response?.to_vec()),
+        Either::Right(_) => Err(anyhow!("timed out after {}s", UPSTREAM_TIMEOUT.as_secs())),
+    }
+}
 
-pub async fn doh(req_wireformat: &[u8]) -> Result<Vec<u8>> {
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        CONTENT_TYPE,
-        HeaderValue::from_static("application/dns-message"),
-    );
-    headers.insert(ACCEPT, HeaderValue::from_static("application/dns-message"));
-    let client = Client::new();
-    let response = client
-        .post("https://1.1.1.1/dns-query")
-        .headers(headers)
-        .body(req_wireformat.to_vec())
-        .send()
-        .await?
-        .bytes()
-        .await?;
-
-    Ok(response.to_vec())
+// resolve several queries at once over the shared client, answers are
+// returned in the same order as the queries.
+pub async fn doh_batch(config: &DnsConfig, queries: &[&[u8]]) -> Vec<Result<Vec<u8>>> {
+    join_all(queries.iter().map(|q| doh(config, q))).await
 }
@@ -0,0 +1,90 @@
+use super::{ProxyStream, TunnelTransport};
+use crate::common::geo;
+use crate::config::Config;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use worker::*;
+
+pub static DEFAULT_GEOIP_URL: &str = "https://api.country.is/{ip}";
+static RESOLVE_URL: &str = "https://cloudflare-dns.com/dns-query";
+// lookups kept per isolate, dropped all at once when full
+static CACHE_SIZE: usize = 1024;
+
+thread_local! {
+    static CACHE: RefCell<HashMap<IpAddr, Option<String>>> = RefCell::new(HashMap::new());
+}
+
+impl<T: TunnelTransport> ProxyStream<T> {
+    pub async fn checked_destination(&self, addr: &str, port: u16) -> Result<String> {
+        checked_destination(&self.config, addr, port).await
+    }
+}
+
+// the address to dial for a destination, checked before anything is dialed:
+// the destination itself, or the ip a hostname resolves to when destination
+// countries are blocked so the dial lands where the check looked. the
+// proxyip leg isn't checked, the destination behind it is a cloudflare site
+// whose anycast address says nothing about where it's served from. a failed
+// lookup refuses the dial too, operators need this for compliance.
+pub async fn checked_destination(config: &Config, addr: &str, port: u16) -> Result<String> {
+    let blocked = &config.blocked_destination_countries;
+    if blocked.is_empty() || (addr == config.proxy_addr && port == config.proxy_port) {
+        return Ok(addr.to_string());
+    }
+
+    let ip = match addr.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => resolve(addr).await?,
+    };
+    let country = country(&config.geoip_url, ip).await?;
+    crate::log_debug!(config, "{} ({}) is in {}", addr, ip, country.as_deref().unwrap_or("an unknown country"));
+    match country {
+        Some(country) if blocked.iter().any(|x| x.eq_ignore_ascii_case(&country)) => {
+            Err(Error::RustError(format!("destination {} is in blocked country {}", addr, country)))
+        }
+        _ => Ok(ip.to_string()),
+    }
+}
+
+// first ipv4 address of `name`, from cloudflare's dns json api
+async fn resolve(name: &str) -> Result<IpAddr> {
+    let mut url = Url::parse(RESOLVE_URL)?;
+    url.query_pairs_mut().append_pair("name", name).append_pair("type", "A");
+    let mut headers = Headers::new();
+    headers.set("Accept", "application/dns-json")?;
+    let mut init = RequestInit::new();
+    init.with_headers(headers);
+    let req = Request::new_with_init(url.as_str(), &init)?;
+    let answer: serde_json::Value = Fetch::Request(req).send().await?.json().await?;
+    answer["Answer"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|record| record["type"] == 1)
+        .find_map(|record| record["data"].as_str()?.parse().ok())
+        .ok_or_else(|| Error::RustError(format!("{} doesn't resolve, can't check its country", name)))
+}
+
+// country of `ip` from the GEOIP_URL api, None for addresses it doesn't
+// place (private ranges)
+async fn country(url: &str, ip: IpAddr) -> Result<Option<String>> {
+    if let Some(country) = CACHE.with_borrow(|cache| cache.get(&ip).cloned()) {
+        return Ok(country);
+    }
+
+    let mut res = Fetch::Url(Url::parse(&url.replace("{ip}", &ip.to_string()))?).send().await?;
+    let country = match res.status_code() {
+        200 => geo::country_from_json(&res.json().await?),
+        404 => None,
+        status => return Err(Error::RustError(format!("geoip lookup of {} failed: {}", ip, status))),
+    };
+    CACHE.with_borrow_mut(|cache| {
+        if cache.len() >= CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(ip, country.clone());
+    });
+    Ok(country)
+}
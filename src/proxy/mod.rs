@@ -3,5 +3,20 @@ pub mod vless;
 pub mod trojan;
 pub mod shadowsocks;
 pub mod dns;
+pub mod mux;
 pub mod conn;
+pub mod dialer;
+pub mod geoip;
+pub mod queue;
+pub mod relay;
+pub mod timer;
+pub mod transport;
+pub mod upstream;
+pub mod webcrypto;
+pub mod broker;
 pub use conn::*;
+pub use transport::*;
+pub use dialer::*;
+
+#[cfg(all(test, feature = "native-test"))]
+mod tests;
@@ -0,0 +1,302 @@
+use super::{dial, geoip, ProxyStream, RemoteSocket, Stage, TunnelTransport};
+use crate::common::protocol::mux::{self, Network};
+use crate::common::protocol::ParseError;
+use crate::config::Config;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+use bytes::{Buf, BytesMut};
+use futures_util::future::{self, Either, LocalBoxFuture};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use worker::*;
+
+static READ_SIZE: usize = 16 * 1024;
+// what a session may have queued for a slow remote before it's ended
+static DEFAULT_MAX_QUEUED: usize = 512 * 1024;
+
+// an id the client reuses after END is a new session with a new generation,
+// so what the old one's dial, reads and writes finish with can't reach it
+struct Session {
+    generation: u64,
+    // udp sessions only carry dns, answered over doh
+    dns: bool,
+}
+
+// the remote's write half of a tcp session. one write is in flight at a
+// time and what the client sends meanwhile queues here, so a slow remote
+// only holds up its own session. it outlives a session the client ended
+// with data still queued.
+#[derive(Default)]
+struct Outbox {
+    // None while dialing or writing
+    writer: Option<WriteHalf<RemoteSocket>>,
+    queued: Vec<u8>,
+    // shut the remote's write side down once the queue is written
+    ended: bool,
+}
+
+// work running alongside the tunnel, finishing for a session id and generation
+enum Task {
+    Dialed(u16, u64, Result<RemoteSocket>),
+    Read(u16, u64, ReadHalf<RemoteSocket>, Vec<u8>, std::io::Result<usize>),
+    Written(u16, u64, WriteHalf<RemoteSocket>, std::io::Result<()>),
+    Answered(u16, u64, Result<Vec<u8>>),
+    Closed,
+}
+
+struct Mux {
+    sessions: HashMap<u16, Session>,
+    // by generation
+    outboxes: HashMap<u64, Outbox>,
+    tasks: FuturesUnordered<LocalBoxFuture<'static, Task>>,
+    generations: u64,
+    // a copy the tasks can hold on to
+    config: Rc<Config>,
+}
+
+impl Mux {
+    fn is_current(&self, id: u16, generation: u64) -> bool {
+        self.sessions.get(&id).is_some_and(|x| x.generation == generation)
+    }
+
+    // starts writing what's queued, or shuts the remote's write side down
+    // once an ended session is drained. waits while dialing or writing.
+    fn flush(&mut self, id: u16, generation: u64) {
+        let Some(outbox) = self.outboxes.get_mut(&generation) else {
+            return;
+        };
+        let Some(mut writer) = outbox.writer.take() else {
+            return;
+        };
+        if !outbox.queued.is_empty() {
+            let data = std::mem::take(&mut outbox.queued);
+            self.tasks.push(Box::pin(async move {
+                let result = writer.write_all(&data).await;
+                Task::Written(id, generation, writer, result)
+            }));
+        } else if outbox.ended {
+            self.outboxes.remove(&generation);
+            self.tasks.push(Box::pin(async move {
+                let _ = writer.shutdown().await;
+                Task::Closed
+            }));
+        } else {
+            outbox.writer = Some(writer);
+        }
+    }
+}
+
+fn read_remote(id: u16, generation: u64, mut reader: ReadHalf<RemoteSocket>) -> LocalBoxFuture<'static, Task> {
+    Box::pin(async move {
+        let mut buf = vec![0u8; READ_SIZE];
+        let result = reader.read(&mut buf).await;
+        Task::Read(id, generation, reader, buf, result)
+    })
+}
+
+// same order as handle_outbound: the destination itself, then the proxyip
+// unless the tunnel asked for proxy-first. each leg is checked before it's
+// dialed, a refused one isn't dodged through the other.
+async fn dial_target(config: &Config, addr: &str, port: u16) -> Result<RemoteSocket> {
+    let (mut first, mut second) = ((addr, port), (config.proxy_addr.as_str(), config.proxy_port));
+    if config.options.proxy_first {
+        std::mem::swap(&mut first, &mut second);
+    }
+    let dial_addr = geoip::checked_destination(config, first.0, first.1).await?;
+    match dial(&dial_addr, first.1).await {
+        Ok((socket, _)) => Ok(socket),
+        Err(_) => {
+            let dial_addr = geoip::checked_destination(config, second.0, second.1).await?;
+            dial(&dial_addr, second.1).await.map(|(socket, _)| socket)
+        }
+    }
+}
+
+impl<T: TunnelTransport> ProxyStream<T> {
+    // mux.cool: many sessions over one tunnel. client frames are parsed
+    // from `pending`, dials, remote reads and writes and dns lookups run
+    // concurrently as tasks, so a slow session doesn't stall the others.
+    pub async fn handle_mux(&mut self) -> Result<()> {
+        self.stage = Stage::Relay;
+        let mut mux = Mux {
+            sessions: HashMap::new(),
+            outboxes: HashMap::new(),
+            tasks: FuturesUnordered::new(),
+            generations: 0,
+            config: Rc::new(self.config.clone()),
+        };
+        let mut pending = BytesMut::new();
+        let mut buf = vec![0u8; READ_SIZE];
+
+        loop {
+            match mux::parse(&pending) {
+                Ok((frame, consumed)) => {
+                    pending.advance(consumed);
+                    self.handle_mux_frame(&mut mux, frame).await?;
+                    continue;
+                }
+                Err(ParseError::Incomplete) => {}
+                Err(e) => return Err(Error::RustError(e.to_string())),
+            }
+
+            // reading from the client is cancel safe, so it can lose the race
+            // against a task without dropping data.
+            let event = if mux.tasks.is_empty() {
+                Either::Left(self.read(&mut buf).await)
+            } else {
+                match future::select(Box::pin(self.read(&mut buf)), mux.tasks.next()).await {
+                    Either::Left((n, _)) => Either::Left(n),
+                    Either::Right((task, _)) => Either::Right(task),
+                }
+            };
+
+            match event {
+                Either::Left(n) => {
+                    let n = n?;
+                    if n == 0 {
+                        break;
+                    }
+                    pending.extend_from_slice(&buf[..n]);
+                }
+                Either::Right(Some(task)) => self.handle_mux_task(&mut mux, task).await?,
+                Either::Right(None) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_mux_frame(&mut self, mux: &mut Mux, frame: mux::Frame) -> Result<()> {
+        crate::log_debug!(self.config, "mux frame for session {}: status {}, {} bytes", frame.id, frame.status, frame.data.as_ref().map_or(0, Vec::len));
+        match frame.status {
+            mux::STATUS_NEW => {
+                let Some((network, target)) = frame.target else {
+                    return Ok(());
+                };
+                let refused = match network {
+                    Network::Tcp if !self.config.is_destination_allowed(&target.addr) => Some("destination is not allowed"),
+                    Network::Tcp if !self.config.is_port_allowed(target.port) => Some("port is not allowed in tls-only mode"),
+                    Network::Udp if target.port != 53 => Some("udp is only carried for dns"),
+                    _ => None,
+                };
+                if let Some(reason) = refused {
+                    crate::log_error!("{} mux session {} to {}:{} refused: {}", self.config.trace, frame.id, target.addr, target.port, reason);
+                    self.write_all(&mux::end(frame.id, true)).await?;
+                    return Ok(());
+                }
+
+                mux.generations += 1;
+                let generation = mux.generations;
+                let dns = network == Network::Udp;
+                mux.sessions.insert(frame.id, Session { generation, dns });
+                if !dns {
+                    mux.outboxes.insert(generation, Outbox::default());
+                    let config = mux.config.clone();
+                    mux.tasks.push(Box::pin(async move {
+                        let dialed = dial_target(&config, &target.addr, target.port).await;
+                        if let Err(e) = &dialed {
+                            crate::log_error!("{} mux session {} to {}:{} failed: {}", config.trace, frame.id, target.addr, target.port, e);
+                        }
+                        Task::Dialed(frame.id, generation, dialed)
+                    }));
+                }
+            }
+            mux::STATUS_END => {
+                if let Some(session) = mux.sessions.remove(&frame.id) {
+                    if let Some(outbox) = mux.outboxes.get_mut(&session.generation) {
+                        outbox.ended = true;
+                    }
+                    mux.flush(frame.id, session.generation);
+                }
+                return Ok(());
+            }
+            mux::STATUS_KEEP | mux::STATUS_KEEP_ALIVE => {}
+            _ => return Err(Error::RustError(format!("unknown mux status {}", frame.status))),
+        }
+
+        let Some(data) = frame.data.filter(|x| !x.is_empty()) else {
+            return Ok(());
+        };
+        let Some(session) = mux.sessions.get(&frame.id) else {
+            return Ok(());
+        };
+        let generation = session.generation;
+        if session.dns {
+            let config = mux.config.clone();
+            mux.tasks.push(Box::pin(async move {
+                let answer = crate::dns::doh(&config.dns, &data).await.map_err(|e| Error::RustError(e.to_string()));
+                Task::Answered(frame.id, generation, answer)
+            }));
+            return Ok(());
+        }
+        let max_queued = self.config.max_buffer_size.unwrap_or(DEFAULT_MAX_QUEUED);
+        let Some(outbox) = mux.outboxes.get_mut(&generation) else {
+            return Ok(());
+        };
+        if outbox.queued.len() + data.len() > max_queued {
+            crate::log_error!("{} mux session {} queued more than {} bytes", self.config.trace, frame.id, max_queued);
+            mux.sessions.remove(&frame.id);
+            mux.outboxes.remove(&generation);
+            return self.write_all(&mux::end(frame.id, true)).await.map_err(Error::from);
+        }
+        outbox.queued.extend_from_slice(&data);
+        mux.flush(frame.id, generation);
+        Ok(())
+    }
+
+    async fn handle_mux_task(&mut self, mux: &mut Mux, task: Task) -> Result<()> {
+        match task {
+            Task::Dialed(id, generation, Ok(socket)) => {
+                let (rd, wr) = tokio::io::split(socket);
+                let Some(outbox) = mux.outboxes.get_mut(&generation) else {
+                    return Ok(());
+                };
+                outbox.writer = Some(wr);
+                // a session the client ended while dialing only gets what it
+                // queued written
+                if mux.is_current(id, generation) {
+                    mux.tasks.push(read_remote(id, generation, rd));
+                }
+                mux.flush(id, generation);
+            }
+            Task::Dialed(id, generation, Err(_)) | Task::Written(id, generation, _, Err(_)) => {
+                mux.outboxes.remove(&generation);
+                if mux.is_current(id, generation) {
+                    mux.sessions.remove(&id);
+                    self.write_all(&mux::end(id, true)).await?;
+                }
+            }
+            Task::Written(id, generation, writer, Ok(())) => {
+                if let Some(outbox) = mux.outboxes.get_mut(&generation) {
+                    outbox.writer = Some(writer);
+                    mux.flush(id, generation);
+                }
+            }
+            // sessions the client ended already are left to finish
+            Task::Read(id, generation, reader, data, Ok(n)) if n > 0 && mux.is_current(id, generation) => {
+                self.write_all(&mux::encode(id, mux::STATUS_KEEP, Some(&data[..n]))).await?;
+                mux.tasks.push(read_remote(id, generation, reader));
+            }
+            Task::Read(id, generation, _, _, result) => {
+                // remote closed or failed, tell the client the session is over
+                if mux.is_current(id, generation) {
+                    mux.sessions.remove(&id);
+                    if let Some(outbox) = mux.outboxes.get_mut(&generation) {
+                        outbox.ended = true;
+                    }
+                    mux.flush(id, generation);
+                    self.write_all(&mux::end(id, result.is_err())).await?;
+                }
+            }
+            Task::Answered(id, generation, Ok(answer)) => {
+                if mux.is_current(id, generation) {
+                    self.write_all(&mux::encode(id, mux::STATUS_KEEP, Some(&answer))).await?;
+                }
+            }
+            Task::Answered(_, _, Err(e)) => crate::log_error!("{} mux dns query failed: {}", self.config.trace, e),
+            Task::Closed => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::ReadBuf;
+
+// client data waiting to be read, kept as the frames it arrived in and
+// consumed front to back. header parsers need one contiguous slice, so the
+// frames are merged only while a header is being read.
+#[derive(Default)]
+pub struct FrameQueue {
+    frames: VecDeque<Bytes>,
+    len: usize,
+}
+
+impl FrameQueue {
+    pub fn push(&mut self, frame: Bytes) {
+        if !frame.is_empty() {
+            self.len += frame.len();
+            self.frames.push_back(frame);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // merges everything queued into the front frame, headers are small so
+    // the copy is cheap
+    pub fn make_contiguous(&mut self) -> &[u8] {
+        if self.frames.len() > 1 {
+            let mut merged = BytesMut::with_capacity(self.len);
+            for frame in self.frames.drain(..) {
+                merged.extend_from_slice(&frame);
+            }
+            self.frames.push_back(merged.freeze());
+        }
+        self.front()
+    }
+
+    // the first frame, all of the queue after `make_contiguous`
+    pub fn front(&self) -> &[u8] {
+        self.frames.front().map_or(&[], |x| &x[..])
+    }
+
+    pub fn advance(&mut self, mut n: usize) {
+        n = n.min(self.len);
+        self.len -= n;
+        while n > 0 {
+            let front = self.frames.front_mut().expect("queue length is tracked");
+            if front.len() > n {
+                front.advance(n);
+                return;
+            }
+            n -= front.len();
+            self.frames.pop_front();
+        }
+    }
+
+    // copies as much as fits into `buf`, returns how much that was
+    pub fn read_into(&mut self, buf: &mut ReadBuf<'_>) -> usize {
+        let mut copied = 0;
+        while buf.remaining() > 0 {
+            let Some(front) = self.frames.front_mut() else {
+                break;
+            };
+            let n = front.len().min(buf.remaining());
+            buf.put_slice(&front[..n]);
+            front.advance(n);
+            if front.is_empty() {
+                self.frames.pop_front();
+            }
+            copied += n;
+        }
+        self.len -= copied;
+        copied
+    }
+
+    // empties the queue, returning the frames in order
+    pub fn take(&mut self) -> VecDeque<Bytes> {
+        self.len = 0;
+        std::mem::take(&mut self.frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_queue() {
+        let mut queue = FrameQueue::default();
+        queue.push(Bytes::from_static(b"hel"));
+        queue.push(Bytes::new());
+        queue.push(Bytes::from_static(b"lo world"));
+        assert_eq!(queue.len(), 11);
+        assert_eq!(queue.front(), b"hel");
+        assert_eq!(queue.make_contiguous(), b"hello world");
+
+        queue.advance(6);
+        queue.push(Bytes::from_static(b"!"));
+        let mut out = [0u8; 4];
+        let mut buf = ReadBuf::new(&mut out);
+        assert_eq!(queue.read_into(&mut buf), 4);
+        assert_eq!(buf.filled(), b"worl");
+        assert_eq!(queue.take(), [Bytes::from_static(b"d"), Bytes::from_static(b"!")]);
+        assert!(queue.is_empty());
+    }
+}
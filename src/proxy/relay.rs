@@ -0,0 +1,324 @@
+use super::{timer, Progress};
+use crate::common;
+
+use bytes::{Bytes, BytesMut};
+use futures_util::future::{self, Either};
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+static READ_SIZE: usize = 16 * 1024; // 16kb
+// frames in flight per direction, a full channel stops the reader, which is
+// the backpressure on whichever side sends faster than the other takes
+static CHANNEL_DEPTH: usize = 16;
+// frames already queued behind the one being written are merged up to this
+// size, tls handshakes and other chatty protocols send many tiny frames
+static MAX_BATCH_SIZE: usize = 16 * 1024; // 16kb
+// once one direction finished, the other gets this long and this many more
+// bytes to finish as well, so a remote that keeps sending after the client
+// left, or a jammed websocket, doesn't hold the tunnel open
+static DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+static DRAIN_MAX_BYTES: u64 = 1024 * 1024; // 1mb
+
+// write time a direction has to accumulate before its rate is judged, a
+// few quick writes say nothing about the link
+static SAMPLE_MS: u64 = 1000;
+
+// a connection is slow once the rate at which a direction's writes complete
+// stays below `min_rate` bytes/s for `window`. it's measured on the writes
+// rather than overall so an idle tunnel isn't taken for a slow one.
+#[derive(Clone, Copy, Debug)]
+pub struct SlowPolicy {
+    pub min_rate: u64,
+    pub window: Duration,
+}
+
+pub struct SlowMonitor {
+    policy: SlowPolicy,
+    progress: Arc<Progress>,
+    // for the log line, e.g. "[conn=..] example.com:443 (colo=SIN ...)"
+    label: String,
+    directions: [Mutex<Sample>; 2],
+}
+
+#[derive(Default)]
+struct Sample {
+    bytes: u64,
+    busy_ms: u64,
+    slow_since: Option<u64>,
+    tagged: bool,
+}
+
+impl Sample {
+    // true when this write made the direction slow for the whole window
+    fn record(&mut self, bytes: u64, busy_ms: u64, now: u64, policy: &SlowPolicy) -> bool {
+        self.bytes += bytes;
+        self.busy_ms += busy_ms;
+        if self.busy_ms < SAMPLE_MS {
+            return false;
+        }
+        let rate = self.bytes * 1000 / self.busy_ms;
+        (self.bytes, self.busy_ms) = (0, 0);
+        if rate >= policy.min_rate {
+            self.slow_since = None;
+            return false;
+        }
+        let since = *self.slow_since.get_or_insert(now);
+        if self.tagged || now.saturating_sub(since) < policy.window.as_millis() as u64 {
+            return false;
+        }
+        self.tagged = true;
+        true
+    }
+}
+
+impl SlowMonitor {
+    pub fn new(policy: SlowPolicy, progress: Arc<Progress>, label: String) -> Self {
+        Self { policy, progress, label, directions: Default::default() }
+    }
+
+    fn record(&self, direction: usize, bytes: u64, busy_ms: u64) {
+        let now = common::unix_millis();
+        let slow = self.directions[direction].lock().unwrap().record(bytes, busy_ms, now, &self.policy);
+        if slow {
+            self.progress.slow.store(true, Ordering::Relaxed);
+            let name = if direction == 0 { "up" } else { "down" };
+            crate::log!("slow connection {}: {} below {}/s for {}s", self.label, name, self.policy.min_rate, self.policy.window.as_secs());
+        }
+    }
+}
+
+// relays both directions until each hit eof, like tokio's
+// copy_bidirectional, but every direction is a reader and a writer joined by
+// a bounded channel so a slow write never holds up the read side of the
+// other direction. returns the bytes copied a to b and b to a.
+pub async fn copy_bidirectional<A, B>(a: &mut A, b: &mut B) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    copy_bidirectional_monitored(a, b, None).await
+}
+
+// copy_bidirectional, tagging the connection when it turns slow
+pub async fn copy_bidirectional_monitored<A, B>(a: &mut A, b: &mut B, monitor: Option<&SlowMonitor>) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (a_reader, a_writer) = tokio::io::split(a);
+    let (b_reader, b_writer) = tokio::io::split(b);
+    let (up_budget, down_budget) = (Budget::default(), Budget::default());
+    let up = pin!(pipe(a_reader, b_writer, monitor.map(|x| (x, 0)), &up_budget));
+    let down = pin!(pipe(b_reader, a_writer, monitor.map(|x| (x, 1)), &down_budget));
+    match future::select(up, down).await {
+        Either::Left((up, down)) => Ok((up?, drain(down, &down_budget).await?)),
+        Either::Right((down, up)) => {
+            let down = down?;
+            Ok((drain(up, &up_budget).await?, down))
+        }
+    }
+}
+
+// bytes a direction wrote so far, and the most it may write in total once
+// it's draining
+#[derive(Default)]
+struct Budget {
+    copied: Cell<u64>,
+    limit: Cell<Option<u64>>,
+}
+
+async fn drain<F: Future<Output = std::io::Result<u64>>>(rest: F, budget: &Budget) -> std::io::Result<u64> {
+    budget.limit.set(Some(budget.copied.get() + DRAIN_MAX_BYTES));
+    match future::select(pin!(rest), pin!(timer::sleep(DRAIN_TIMEOUT))).await {
+        Either::Left((copied, _)) => copied,
+        Either::Right(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "other direction didn't finish after eof")),
+    }
+}
+
+// one direction, the writer shuts down once the reader hit eof and
+// everything queued is written
+async fn pipe<R, W>(mut reader: R, mut writer: W, monitor: Option<(&SlowMonitor, usize)>, budget: &Budget) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (tx, mut rx) = mpsc::channel::<Bytes>(CHANNEL_DEPTH);
+
+    let read = async move {
+        loop {
+            let mut buf = BytesMut::with_capacity(READ_SIZE);
+            if reader.read_buf(&mut buf).await? == 0 {
+                break;
+            }
+            // the writer went away after an error, which it reports
+            if tx.send(buf.freeze()).await.is_err() {
+                break;
+            }
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    let write = async move {
+        let mut copied = 0u64;
+        while let Some(frame) = rx.recv().await {
+            let frame = batch(frame, &mut rx);
+            let started = common::unix_millis();
+            writer.write_all(&frame).await?;
+            copied += frame.len() as u64;
+            budget.copied.set(copied);
+            if let Some((monitor, direction)) = monitor {
+                monitor.record(direction, frame.len() as u64, common::unix_millis().saturating_sub(started));
+            }
+            if budget.limit.get().is_some_and(|limit| copied > limit) {
+                return Err(std::io::Error::other("still sending after the other direction finished"));
+            }
+        }
+        writer.shutdown().await?;
+        Ok(copied)
+    };
+
+    future::try_join(read, write).await.map(|(_, copied)| copied)
+}
+
+// one write for `first` and whatever is waiting behind it, without waiting
+// for more
+fn batch(first: Bytes, rx: &mut mpsc::Receiver<Bytes>) -> Bytes {
+    if first.len() >= MAX_BATCH_SIZE || rx.is_empty() {
+        return first;
+    }
+    let mut batch = BytesMut::from(&first[..]);
+    while batch.len() < MAX_BATCH_SIZE {
+        match rx.try_recv() {
+            Ok(frame) => batch.extend_from_slice(&frame),
+            Err(_) => break,
+        }
+    }
+    batch.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch() {
+        let (tx, mut rx) = mpsc::channel(CHANNEL_DEPTH);
+        for frame in [&b"b"[..], b"c", &[0u8; MAX_BATCH_SIZE], b"d"] {
+            tx.try_send(Bytes::copy_from_slice(frame)).unwrap();
+        }
+
+        // small frames merge, the batch stops once it's full
+        let first = batch(Bytes::from_static(b"a"), &mut rx);
+        assert_eq!(first.len(), 3 + MAX_BATCH_SIZE);
+        assert_eq!(&first[..3], b"abc");
+        assert_eq!(batch(rx.try_recv().unwrap(), &mut rx), Bytes::from_static(b"d"));
+    }
+
+    #[test]
+    fn test_slow_sample() {
+        let policy = SlowPolicy { min_rate: 1000, window: Duration::from_secs(10) };
+        let mut sample = Sample::default();
+        // 500 bytes/s, judged once a second of writing piled up
+        assert!(!sample.record(250, 500, 0, &policy));
+        assert!(!sample.record(250, 500, 1000, &policy));
+        assert_eq!(sample.slow_since, Some(1000));
+        assert!(!sample.record(500, 1000, 6000, &policy));
+        assert!(sample.record(500, 1000, 11000, &policy));
+        // tagged once
+        assert!(!sample.record(500, 1000, 20000, &policy));
+
+        // a fast sample in between restarts the window
+        let mut sample = Sample::default();
+        assert!(!sample.record(500, 1000, 0, &policy));
+        assert!(!sample.record(5000, 1000, 5000, &policy));
+        assert!(!sample.record(500, 1000, 11000, &policy));
+        assert_eq!(sample.slow_since, Some(11000));
+    }
+
+    #[tokio::test]
+    async fn test_copy_bidirectional() {
+        let (mut client, mut a) = tokio::io::duplex(64);
+        let (mut b, mut remote) = tokio::io::duplex(64);
+        // more than the duplex capacity, so backpressure kicks in
+        let sent: Vec<u8> = (0..4096u32).map(|x| x as u8).collect();
+
+        let client_side = async {
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut echoed = Vec::new();
+            client.read_to_end(&mut echoed).await.unwrap();
+            echoed
+        };
+        let remote_side = async {
+            let mut received = Vec::new();
+            remote.read_to_end(&mut received).await.unwrap();
+            remote.write_all(b"done").await.unwrap();
+            remote.shutdown().await.unwrap();
+            received
+        };
+
+        let (copied, echoed, received) = tokio::join!(copy_bidirectional(&mut a, &mut b), client_side, remote_side);
+        assert_eq!(copied.unwrap(), (4096, 4));
+        assert_eq!(received, sent);
+        assert_eq!(echoed, b"done");
+    }
+
+    #[tokio::test]
+    async fn test_drain_limit() {
+        let (mut client, mut a) = tokio::io::duplex(64 * 1024);
+        let (mut b, mut remote) = tokio::io::duplex(64 * 1024);
+
+        // the client is done sending, the remote never stops
+        let client_side = async {
+            client.shutdown().await.unwrap();
+            let mut buf = [0u8; 16 * 1024];
+            while client.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        };
+        let remote_side = async {
+            let chunk = [0u8; 16 * 1024];
+            while remote.write_all(&chunk).await.is_ok() {}
+        };
+        let copied = async {
+            let result = copy_bidirectional(&mut a, &mut b).await;
+            drop((a, b));
+            result
+        };
+
+        let (copied, _, _) = tokio::join!(copied, client_side, remote_side);
+        assert_eq!(copied.unwrap_err().to_string(), "still sending after the other direction finished");
+    }
+
+    #[tokio::test]
+    async fn test_remote_closes_first() {
+        let (mut client, mut a) = tokio::io::duplex(64);
+        let (mut b, mut remote) = tokio::io::duplex(64);
+
+        // the remote finishes its side first, the client still gets to send
+        // once it read everything
+        let client_side = async {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            client.write_all(b"late").await.unwrap();
+            client.shutdown().await.unwrap();
+            received
+        };
+        let remote_side = async {
+            remote.write_all(b"bye").await.unwrap();
+            remote.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            remote.read_to_end(&mut received).await.unwrap();
+            received
+        };
+
+        let (copied, received_by_client, received_by_remote) = tokio::join!(copy_bidirectional(&mut a, &mut b), client_side, remote_side);
+        assert_eq!(copied.unwrap(), (4, 3));
+        assert_eq!(received_by_client, b"bye");
+        assert_eq!(received_by_remote, b"late");
+    }
+}
@@ -1,30 +1,53 @@
-use super::ProxyStream;
-use crate::common::{parse_addr, parse_port};
+use super::{Body, ProxyStream, TunnelTransport, AUTH_FAILED_CLOSE_CODE};
+use crate::common;
+use crate::common::protocol::{shadowsocks, shadowsocks_body};
+use tokio::io::AsyncWriteExt;
 use worker::*;
 
-impl <'a> ProxyStream<'a> {
+impl<T: TunnelTransport> ProxyStream<T> {
     pub async fn process_shadowsocks(&mut self) -> Result<()> {
-        // read port and address
-        let remote_addr = parse_addr(self).await?;
-        let remote_port = parse_port(self).await?;
-        
-        let is_tcp = true; // difficult to detect udp packet from shadowsocks
-        
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
+        // the 2022 header only exists inside the body codec
+        let mut is_2022 = false;
+        if self.config.shadowsocks_plugin {
+            self.fill_buffer_until(5).await?;
+            if shadowsocks::is_obfs_http(self.buffer.make_contiguous()) {
+                self.read_header(shadowsocks::obfs_http_header).await?;
+                self.write_all(shadowsocks::OBFS_HTTP_RESPONSE).await?;
+            }
+            if let Some(key) = self.config.shadowsocks_key.clone() {
+                is_2022 = key.method.is_2022();
+                let users = self.config.shadowsocks_users.clone();
+                let reader = shadowsocks_body::ChunkReader::new(key, users, common::unix_time());
+                self.set_body(Body::Shadowsocks(Box::new((reader, None))))?;
+            }
+        }
+
+        let target = if is_2022 {
+            self.read_header(shadowsocks::parse_2022).await?
+        } else {
+            self.read_header(shadowsocks::parse).await?
+        };
 
-            // send header
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
+        // multi-user 2022 knows who is connecting
+        if let Some(Body::Shadowsocks(codec)) = &self.body {
+            if let Some(user) = codec.0.user {
+                if self.config.banned_uuids.contains(&user) {
+                    self.close = Some((AUTH_FAILED_CLOSE_CODE, "uuid banned"));
+                    return Err(Error::RustError(format!("uuid {} is banned", user)));
                 }
+                self.user = user;
             }
+        }
+
+        // difficult to detect udp packet from shadowsocks, the client says so
+        // with the `relay=udp` tunnel option
+        let is_tcp = !self.config.options.relay_udp;
+
+        if is_tcp {
+            self.handle_outbound(target.addr, target.port).await?;
         } else {
             if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
+                crate::log_error!("{} error handling udp: {}", self.config.trace, e)
             }
         }
 
@@ -0,0 +1,483 @@
+// end-to-end handshakes against a local echo server, run with
+// `cargo test --features native-test`.
+use super::ProxyStream;
+use crate::common::protocol::trojan;
+use crate::config::Config;
+
+use std::pin::Pin;
+use std::task::Poll;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpListener;
+
+// echoes everything back until the peer shuts down its write half
+async fn echo_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let (mut rd, mut wr) = socket.split();
+        tokio::io::copy(&mut rd, &mut wr).await.unwrap();
+        wr.shutdown().await.unwrap();
+    });
+    port
+}
+
+// a port nothing listens on, to exercise the proxyip fallback
+async fn closed_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+fn config(proxy_port: u16) -> Config {
+    Config {
+        proxy_addr: "127.0.0.1".to_string(),
+        proxy_port,
+        ..Config::default()
+    }
+}
+
+// runs the handshake through a ProxyStream and returns what the client read back
+async fn roundtrip(config: Config, header: Vec<u8>, payload: &[u8]) -> Vec<u8> {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config, server);
+
+    let client_side = async {
+        let mut request = header;
+        request.extend_from_slice(payload);
+        client.write_all(&request).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    };
+
+    let (result, response) = tokio::join!(stream.process(), client_side);
+    result.unwrap();
+    response
+}
+
+fn payload() -> Vec<u8> {
+    (0..64u8).collect()
+}
+
+fn vless_header(port: u16) -> Vec<u8> {
+    let mut header = vec![0u8]; // version
+    header.extend_from_slice(uuid::Uuid::nil().as_bytes());
+    header.push(0); // no addons
+    header.push(1); // tcp
+    header.extend_from_slice(&port.to_be_bytes());
+    header.extend_from_slice(&[1, 127, 0, 0, 1]);
+    header
+}
+
+fn trojan_header(port: u16) -> Vec<u8> {
+    let mut header = trojan::password_hash(&uuid::Uuid::nil().to_string()).to_vec();
+    header.extend_from_slice(b"\r\n");
+    header.push(1); // tcp
+    header.extend_from_slice(&[1, 127, 0, 0, 1]);
+    header.extend_from_slice(&port.to_be_bytes());
+    header.extend_from_slice(b"\r\n");
+    header
+}
+
+fn shadowsocks_header(port: u16) -> Vec<u8> {
+    let mut header = vec![1, 127, 0, 0, 1];
+    header.extend_from_slice(&port.to_be_bytes());
+    header
+}
+
+#[tokio::test]
+async fn test_vless_handshake() {
+    let port = echo_server().await;
+    let response = roundtrip(config(0), vless_header(port), &payload()).await;
+    assert_eq!(response[..2], [0, 0]);
+    assert_eq!(response[2..], payload());
+}
+
+#[tokio::test]
+async fn test_trojan_handshake() {
+    let port = echo_server().await;
+    let response = roundtrip(config(0), trojan_header(port), &payload()).await;
+    assert_eq!(response, payload());
+}
+
+#[tokio::test]
+async fn test_shadowsocks_handshake() {
+    let port = echo_server().await;
+    let response = roundtrip(config(0), shadowsocks_header(port), &payload()).await;
+    assert_eq!(response, payload());
+}
+
+#[tokio::test]
+async fn test_trojan_fallback_to_decoy() {
+    let decoy_port = echo_server().await;
+    let config = Config {
+        fallback: Some(("127.0.0.1".to_string(), decoy_port)),
+        ..config(0)
+    };
+    let mut probe = trojan_header(decoy_port);
+    probe[0] ^= 1; // wrong password
+
+    // the decoy sees everything the probe sent, header included
+    let response = roundtrip(config, probe.clone(), &payload()).await;
+    assert_eq!(response, [probe, payload()].concat());
+}
+
+#[tokio::test]
+async fn test_vless_fallback_to_decoy() {
+    let decoy_port = echo_server().await;
+    let config = Config {
+        fallback: Some(("127.0.0.1".to_string(), decoy_port)),
+        ..config(0)
+    };
+    let mut probe = vless_header(decoy_port);
+    probe[1] = 0xff; // unknown uuid
+
+    let response = roundtrip(config, probe.clone(), &payload()).await;
+    assert_eq!(response, [probe, payload()].concat());
+}
+
+#[tokio::test]
+async fn test_shadowsocks_plugin_mode() {
+    use crate::common::protocol::{shadowsocks, shadowsocks_body::*, Chunk};
+
+    let port = echo_server().await;
+    let key = Key::from_password(Method::Aes128Gcm, "secret").unwrap();
+    let config = Config {
+        shadowsocks_plugin: true,
+        shadowsocks_key: Some(key.clone()),
+        ..config(0)
+    };
+    let mut request = b"GET / HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\r\n".to_vec();
+    // a 2017 server writer speaks the client format too
+    let mut client = ChunkReader::new(key.clone(), Vec::new(), 0);
+    let _ = client.decode(&mut bytes::BytesMut::from(&[0u8; 16][..]));
+    request.extend_from_slice(&ChunkWriter::new(&client).unwrap().encode(&[shadowsocks_header(port), payload()].concat()));
+
+    let response = roundtrip(config, request, &[]).await;
+    let sealed = response.strip_prefix(shadowsocks::OBFS_HTTP_RESPONSE).unwrap();
+    let mut sealed = bytes::BytesMut::from(sealed);
+    let mut reader = ChunkReader::new(key, Vec::new(), 0);
+    let mut echoed = Vec::new();
+    while !sealed.is_empty() {
+        let Ok(Chunk::Data(data)) = reader.decode(&mut sealed) else { panic!("bad chunk") };
+        echoed.extend_from_slice(&data);
+    }
+    assert_eq!(echoed, payload());
+}
+
+#[tokio::test]
+async fn test_violation_close_codes() {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config(0), server);
+    client.write_all(&[0xff; 64]).await.unwrap();
+    assert!(stream.process().await.is_err());
+    assert_eq!(stream.close, Some((4000, "protocol violation")));
+
+    // detected as vless, then an unknown command
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config(0), server);
+    let mut request = vless_header(443);
+    request[18] = 9;
+    client.write_all(&[request, payload()].concat()).await.unwrap();
+    assert!(stream.process().await.is_err());
+    assert_eq!(stream.close, Some((4001, "protocol violation")));
+}
+
+#[tokio::test]
+async fn test_banned_trojan_user() {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let config = Config { banned_uuids: vec![uuid::Uuid::nil()], ..config(0) };
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config, server);
+    client.write_all(&[trojan_header(443), payload()].concat()).await.unwrap();
+    assert!(stream.process().await.is_err());
+    assert_eq!(stream.close, Some((super::AUTH_FAILED_CLOSE_CODE, "uuid banned")));
+}
+
+#[tokio::test]
+async fn test_handshake_timeout() {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let config = Config {
+        handshake_timeout: Some(std::time::Duration::from_millis(100)),
+        ..config(0)
+    };
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config, server);
+    // half a header, and the client keeps the stream open
+    client.write_all(&vless_header(443)[..10]).await.unwrap();
+
+    let e = stream.process().await.unwrap_err();
+    assert_eq!(e.stage, super::Stage::Handshake);
+    assert_eq!(e.to_string(), "unknown handshake: IO Error: handshake timed out");
+    assert_eq!(stream.close, Some((super::HANDSHAKE_TIMEOUT_CLOSE_CODE, "handshake timeout")));
+}
+
+#[tokio::test]
+async fn test_max_header_size() {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let config = Config {
+        max_header_size: Some(64),
+        ..config(0)
+    };
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config, server);
+    // a vless header with a 200 byte addons section that never completes
+    let mut request = vless_header(443);
+    request[17] = 200;
+    client.write_all(&[request, payload()].concat()).await.unwrap();
+
+    let e = stream.process().await.unwrap_err();
+    assert!(e.to_string().contains("header exceeds 64 bytes"));
+    assert_eq!(stream.close, Some((4001, "protocol violation")));
+}
+
+#[tokio::test]
+async fn test_fallback_to_proxyip() {
+    let proxy_port = echo_server().await;
+    let dead_port = closed_port().await;
+    let response = roundtrip(config(proxy_port), vless_header(dead_port), &payload()).await;
+    assert_eq!(response[2..], payload());
+}
+
+#[tokio::test]
+async fn test_tls_only_egress() {
+    let (_client, server) = tokio::io::duplex(64 * 1024);
+    let config = Config { tls_ports: Some(vec![443]), ..config(0) };
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config, server);
+    let port = echo_server().await;
+    let e = stream.handle_tcp_outbound("127.0.0.1".to_string(), port).await.unwrap_err();
+    assert!(e.to_string().contains("tls-only"));
+    assert_eq!(stream.close, Some((super::POLICY_CLOSE_CODE, "port blocked")));
+}
+
+#[tokio::test]
+async fn test_tls_only_proxy_leg() {
+    // a blocked port isn't dialed through the proxyip either
+    let proxy_port = echo_server().await;
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let config = Config { tls_ports: Some(vec![443]), ..config(proxy_port) };
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config, server);
+    client.write_all(&vless_header(25)).await.unwrap();
+    client.write_all(&payload()).await.unwrap();
+    client.shutdown().await.unwrap();
+    assert!(stream.process().await.is_err());
+    assert_eq!(stream.close, Some((super::POLICY_CLOSE_CODE, "port blocked")));
+}
+
+#[tokio::test]
+async fn test_vless_mux_session() {
+    use crate::common::protocol::mux;
+
+    let port = echo_server().await;
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config(0), server);
+
+    let client_side = async {
+        let mut request = vless_header(0);
+        request[18] = 3; // mux
+        request.extend_from_slice(&[0, 12, 0, 1, mux::STATUS_NEW, mux::OPTION_DATA, 0x01]);
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&[1, 127, 0, 0, 1, 0, 64]);
+        request.extend_from_slice(&payload());
+        client.write_all(&request).await.unwrap();
+
+        let expected = mux::encode(1, mux::STATUS_KEEP, Some(&payload()));
+        let mut response = vec![0u8; 2 + expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(response[2..], expected);
+    };
+
+    let (result, _) = tokio::join!(stream.process(), client_side);
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn test_mux_udp_only_dns() {
+    use crate::common::protocol::mux;
+
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config(0), server);
+
+    let client_side = async {
+        let mut request = vless_header(0);
+        request[18] = 3; // mux
+        request.extend_from_slice(&[0, 12, 0, 1, mux::STATUS_NEW, 0, 0x02, 0x01, 0xbb, 1, 127, 0, 0, 1]);
+        // data for a session that doesn't exist, ignored but it gets the
+        // request past protocol detection
+        request.extend_from_slice(&mux::encode(2, mux::STATUS_KEEP, Some(&payload())));
+        client.write_all(&request).await.unwrap();
+
+        let expected = mux::end(1, true);
+        let mut response = vec![0u8; 2 + expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(response[2..], expected);
+    };
+
+    let (result, _) = tokio::join!(stream.process(), client_side);
+    result.unwrap();
+}
+
+// the first session's echo arrives after the id was reused, it must not
+// reach the second session or end it
+#[tokio::test]
+async fn test_mux_reused_session_id() {
+    use crate::common::protocol::mux;
+
+    let (first, second) = (echo_server().await, echo_server().await);
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config(0), server);
+    let new = |port: u16, data: &[u8]| {
+        let mut frame = vec![0, 12, 0, 1, mux::STATUS_NEW, mux::OPTION_DATA, 0x01];
+        frame.extend_from_slice(&port.to_be_bytes());
+        frame.extend_from_slice(&[1, 127, 0, 0, 1]);
+        frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    };
+
+    let client_side = async {
+        let mut request = vless_header(0);
+        request[18] = 3; // mux
+        request.extend_from_slice(&new(first, &[1u8; 64]));
+        request.extend_from_slice(&mux::end(1, false));
+        request.extend_from_slice(&new(second, &payload()));
+        client.write_all(&request).await.unwrap();
+
+        let expected = mux::encode(1, mux::STATUS_KEEP, Some(&payload()));
+        let mut response = vec![0u8; 2 + expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(response[2..], expected);
+    };
+
+    let (result, _) = tokio::join!(stream.process(), client_side);
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn test_shutdown_flushes_frame() {
+    let (mut client, server) = tokio::io::duplex(64);
+    let config = Config { padding: true, ..config(0) };
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config, server);
+
+    // the padded frame doesn't fit the pipe, so the write stays pending
+    let data = vec![7u8; 1024];
+    let pending = std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut stream).poll_write(cx, &data))).await;
+    assert!(pending.is_pending());
+
+    let client_side = async {
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    };
+    let (result, response) = tokio::join!(stream.shutdown(), client_side);
+    result.unwrap();
+    assert!(response.len() > data.len());
+}
+
+// takes at most 100 bytes per write and every other write is pending
+#[derive(Default)]
+struct ChoppyTransport {
+    written: Vec<u8>,
+    ready: bool,
+}
+
+impl tokio::io::AsyncRead for ChoppyTransport {
+    fn poll_read(self: Pin<&mut Self>, _: &mut std::task::Context<'_>, _: &mut tokio::io::ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ChoppyTransport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        this.ready = !this.ready;
+        if !this.ready {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        let n = buf.len().min(100);
+        this.written.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut std::task::Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_dummy_frames_written_whole() {
+    let config = Config { padding: true, ..config(0) };
+    let mut stream = ProxyStream::new(config, ChoppyTransport::default());
+    for i in 0..200u8 {
+        stream.write_all(&[i; 50]).await.unwrap();
+    }
+    stream.shutdown().await.unwrap();
+
+    // 50 bytes and dummies both pad to multiples of 128, the tail of a
+    // larger dummy reads as more dummies. a dummy cut short or a real frame
+    // split by one would throw every frame after it off.
+    let written = &stream.transport.written;
+    assert_eq!(written.len() % 128, 0);
+    let (mut data, mut dummies) = (Vec::new(), 0);
+    for frame in written.chunks(128) {
+        match crate::common::padding::unpad(frame).unwrap() {
+            [] => dummies += 1,
+            payload => data.push(payload.to_vec()),
+        }
+    }
+    assert_eq!(data, (0..200u8).map(|i| vec![i; 50]).collect::<Vec<_>>());
+    assert!(dummies > 0);
+}
+
+#[tokio::test]
+async fn test_dial_failed_close_code() {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config(closed_port().await), server);
+    client.write_all(&[vless_header(closed_port().await), payload()].concat()).await.unwrap();
+
+    let e = stream.process().await.unwrap_err();
+    assert_eq!(e.stage, super::Stage::Dial);
+    assert_eq!(stream.close, Some((super::DIAL_FAILED_CLOSE_CODE, "dial failed")));
+}
+
+#[tokio::test]
+async fn test_early_data() {
+    let port = echo_server().await;
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    // the header came with the upgrade, only the payload follows
+    let config = Config { early_data: Some(vless_header(port)), ..config(0) };
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config, server);
+
+    let client_side = async {
+        client.write_all(&payload()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        response
+    };
+    let (result, response) = tokio::join!(stream.process(), client_side);
+    result.unwrap();
+    assert_eq!(response[2..], payload());
+}
+
+#[tokio::test]
+async fn test_vless_unsupported_flow() {
+    let (mut client, server) = tokio::io::duplex(64 * 1024);
+    let mut stream: ProxyStream<DuplexStream> = ProxyStream::new(config(0), server);
+    let mut request = vless_header(443);
+    let flow = b"xtls-rprx-vision";
+    request.splice(17..18, [flow.len() as u8 + 2, 0x0a, flow.len() as u8].into_iter().chain(flow.iter().copied()));
+    client.write_all(&[request, payload()].concat()).await.unwrap();
+
+    let e = stream.process().await.unwrap_err();
+    assert!(e.to_string().contains("unsupported vless flow xtls-rprx-vision"));
+    assert_eq!(stream.close, Some((4001, "unsupported flow")));
+    assert!(!stream.handshake_failed);
+}
@@ -0,0 +1,19 @@
+use std::time::Duration;
+
+// timers follow the dialer: workers' setTimeout backed `Delay`, swapped for
+// tokio's sleep under the native-test feature. plain native builds, i.e. the
+// unit tests, have no timer at all and their sleeps never finish.
+#[cfg(all(target_arch = "wasm32", not(feature = "native-test")))]
+pub async fn sleep(duration: Duration) {
+    worker::Delay::from(duration).await
+}
+
+#[cfg(feature = "native-test")]
+pub async fn sleep(duration: Duration) {
+    tokio::time::sleep(duration).await
+}
+
+#[cfg(all(not(target_arch = "wasm32"), not(feature = "native-test")))]
+pub async fn sleep(_: Duration) {
+    std::future::pending::<()>().await
+}
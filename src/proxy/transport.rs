@@ -0,0 +1,211 @@
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::Stream;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use worker::*;
+
+// subprotocols a client may offer by name
+static SUBPROTOCOLS: [&str; 1] = ["binary"];
+// names other clients offer that happen to be valid base64, never early data
+static KNOWN_TOKENS: [&str; 8] = ["chat", "superchat", "v1", "v2", "mqtt", "wamp", "soap", "json"];
+// the shortest header early data can start with: shadowsocks' address type,
+// an ipv4 address and a port
+static MIN_EARLY_DATA: usize = 7;
+
+// what the client offered in Sec-WebSocket-Protocol. browsers and strict
+// libraries drop the connection unless the 101 echoes one of their offers.
+// xray and sing-box put early data there instead, the first bytes of the
+// stream as base64url, which saves a round trip on the handshake.
+#[derive(Debug, Default, PartialEq)]
+pub struct Subprotocol {
+    pub selected: Option<String>,
+    pub early_data: Option<Vec<u8>>,
+}
+
+impl Subprotocol {
+    pub fn negotiate(header: Option<&str>) -> Self {
+        let offers: Vec<&str> = header.unwrap_or_default().split(',').map(str::trim).filter(|x| !x.is_empty()).collect();
+        if let Some(name) = offers.iter().find(|x| SUBPROTOCOLS.iter().any(|name| name.eq_ignore_ascii_case(x))) {
+            return Self { selected: Some(name.to_string()), early_data: None };
+        }
+        // early data is the only value, echoed back as is. a known name or
+        // something too short for any header is an offer we don't speak.
+        match offers[..] {
+            [offer] if !KNOWN_TOKENS.iter().any(|name| name.eq_ignore_ascii_case(offer)) => {
+                match decode_early_data(offer).filter(|x| x.len() >= MIN_EARLY_DATA) {
+                    Some(data) => Self { selected: Some(offer.to_string()), early_data: Some(data) },
+                    None => Self::default(),
+                }
+            }
+            _ => Self::default(),
+        }
+    }
+}
+
+// base64url without padding, some clients send the standard alphabet
+fn decode_early_data(offer: &str) -> Option<Vec<u8>> {
+    let offer = offer.trim_end_matches('=').replace('+', "-").replace('/', "_");
+    URL_SAFE_NO_PAD.decode(offer).ok()
+}
+
+// the client side of a tunnel: inbound data arrives as whole messages,
+// outbound data is written as bytes. `ProxyStream` is generic over it so the
+// protocol handlers don't depend on the workers websocket types.
+pub trait TunnelTransport: AsyncWrite + Unpin {
+    // next message from the client, None once the client went away
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<Option<Vec<u8>>>>;
+}
+
+pub struct WebSocketTransport<'a> {
+    ws: &'a WebSocket,
+    events: EventStream<'a>,
+}
+
+impl<'a> WebSocketTransport<'a> {
+    pub fn new(ws: &'a WebSocket, events: EventStream<'a>) -> Self {
+        Self { ws, events }
+    }
+}
+
+impl<'a> TunnelTransport for WebSocketTransport<'a> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<Option<Vec<u8>>>> {
+        match Pin::new(&mut self.events).poll_next(cx) {
+            Poll::Ready(Some(Ok(WebsocketEvent::Message(msg)))) => {
+                Poll::Ready(Ok(Some(msg.bytes().unwrap_or_default())))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Err(std::io::Error::other(e.to_string()))),
+            Poll::Ready(_) => Poll::Ready(Ok(None)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a> AsyncWrite for WebSocketTransport<'a> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<tokio::io::Result<usize>> {
+        Poll::Ready(
+            self.ws
+                .send_with_bytes(buf)
+                .map(|_| buf.len())
+                .map_err(|e| std::io::Error::other(e.to_string())),
+        )
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        match self.ws.close(Some(1000), Some("shutdown".to_string())) {
+            Ok(_) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(std::io::Error::other(e.to_string()))),
+        }
+    }
+}
+
+// a websocket as a byte stream for `relay`, for websockets the tunnel opens
+// itself rather than the client's. a message that doesn't fit the read
+// buffer is handed out over several reads.
+pub struct WebSocketStream<'a> {
+    transport: WebSocketTransport<'a>,
+    pending: Vec<u8>,
+}
+
+impl<'a> WebSocketStream<'a> {
+    pub fn new(ws: &'a WebSocket) -> Result<Self> {
+        Ok(Self { transport: WebSocketTransport::new(ws, ws.events()?), pending: Vec::new() })
+    }
+}
+
+impl<'a> AsyncRead for WebSocketStream<'a> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<tokio::io::Result<()>> {
+        let this = self.get_mut();
+        while this.pending.is_empty() {
+            match ready!(this.transport.poll_recv(cx))? {
+                Some(data) => this.pending = data,
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let n = this.pending.len().min(buf.remaining());
+        buf.put_slice(&this.pending[..n]);
+        this.pending.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a> AsyncWrite for WebSocketStream<'a> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<tokio::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().transport).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.get_mut().transport).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<tokio::io::Result<()>> {
+        Pin::new(&mut self.get_mut().transport).poll_shutdown(cx)
+    }
+}
+
+// any tokio byte stream (e.g. `tokio::io::duplex`) can stand in for the
+// websocket when running the handlers natively, every read is one message.
+#[cfg(not(target_arch = "wasm32"))]
+impl<S: tokio::io::AsyncRead + AsyncWrite + Unpin> TunnelTransport for S {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<Option<Vec<u8>>>> {
+        let mut data = vec![0u8; 16 * 1024];
+        let mut buf = tokio::io::ReadBuf::new(&mut data);
+        match Pin::new(self).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => {
+                let n = buf.filled().len();
+                if n == 0 {
+                    return Poll::Ready(Ok(None));
+                }
+                data.truncate(n);
+                Poll::Ready(Ok(Some(data)))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_subprotocol() {
+        assert_eq!(Subprotocol::negotiate(None), Subprotocol::default());
+        assert_eq!(Subprotocol::negotiate(Some("chat, binary")).selected.as_deref(), Some("binary"));
+        assert_eq!(Subprotocol::negotiate(Some("chat, superchat")), Subprotocol::default());
+
+        let header = vec![1, 127, 0, 0, 1, 1, 187, 255];
+        let early = Subprotocol::negotiate(Some("AX8AAAEBu_8"));
+        assert_eq!(early.selected.as_deref(), Some("AX8AAAEBu_8"));
+        assert_eq!(early.early_data, Some(header.clone()));
+        assert_eq!(Subprotocol::negotiate(Some("AX8AAAEBu/8=")).early_data, Some(header));
+
+        // names and values too short for a header aren't early data
+        assert_eq!(Subprotocol::negotiate(Some("chat")), Subprotocol::default());
+        assert_eq!(Subprotocol::negotiate(Some("v1")), Subprotocol::default());
+        assert_eq!(Subprotocol::negotiate(Some("AAEC_w")), Subprotocol::default());
+    }
+
+    #[tokio::test]
+    async fn test_duplex_transport() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"hello").await.unwrap();
+        drop(client);
+
+        let first = std::future::poll_fn(|cx| server.poll_recv(cx)).await.unwrap();
+        assert_eq!(first.as_deref(), Some(&b"hello"[..]));
+        let eof = std::future::poll_fn(|cx| server.poll_recv(cx)).await.unwrap();
+        assert_eq!(eof, None);
+    }
+}
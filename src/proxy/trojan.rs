@@ -1,46 +1,37 @@
-use super::ProxyStream;
-use tokio::io::AsyncReadExt;
-use crate::common::{parse_addr, parse_port};
+use super::{ProxyStream, TunnelTransport, AUTH_FAILED_CLOSE_CODE};
+use crate::common::protocol::{trojan, Command, ParseError};
+use crate::common::secure;
 use worker::*;
 
-impl <'a> ProxyStream<'a> {
+impl<T: TunnelTransport> ProxyStream<T> {
     pub async fn process_trojan(&mut self) -> Result<()> {
-        // ignore user_id
-        let mut _user_id = [0u8; 56];
-        self.read_exact(&mut _user_id).await?;
-
-        // remove crlf
-        self.read_u16().await?;
-        
-        // read instruction
-        let network_type = self.read_u8().await?;
-        let is_tcp = network_type == 1;
-
-        // read port and address
-        let remote_addr = parse_addr(self).await?;
-        let remote_port = parse_port(self).await?;
-
-        // remove crlf
-        self.read_u16().await?;
-
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
-
-            // send header
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
+        let expected = self.trojan_hash();
+        let parsed = self
+            .read_header(|buf| {
+                let (request, consumed) = trojan::parse(buf)?;
+                if !secure::ct_eq(request.hash, expected) {
+                    return Err(ParseError::Invalid("trojan password mismatch"));
                 }
-            }
+                Ok((request, consumed))
+            })
+            .await;
+        let request = match parsed {
+            Ok(request) => request,
+            Err(e) => return self.handle_fallback(e).await,
+        };
+        if self.config.banned_uuids.contains(&self.user) {
+            self.close = Some((AUTH_FAILED_CLOSE_CODE, "uuid banned"));
+            return Err(Error::RustError(format!("uuid {} is banned", self.user)));
+        }
+
+        if request.command == Command::Tcp {
+            self.handle_outbound(request.target.addr, request.target.port).await?;
         } else {
             if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
+                crate::log_error!("{} error handling udp: {}", self.config.trace, e)
             }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,94 @@
+use super::{relay, ProxyStream, Stage, TunnelTransport, WebSocketTransport, POLICY_CLOSE_CODE};
+use crate::common::protocol::{vless, ParseError, Target};
+use crate::config::UpstreamRelay;
+
+use pretty_bytes::converter::convert;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use worker::*;
+
+impl<T: TunnelTransport> ProxyStream<T> {
+    // the proxyip leg through a peer deployment: the target is sent as a
+    // vless request over a websocket to the peer's RELAY_PATH, which dials it
+    // from its own colo.
+    pub async fn handle_upstream_outbound(&mut self, upstream: UpstreamRelay, addr: String, port: u16) -> Result<()> {
+        // the peer dials the hostname itself, only the check is done here
+        if let Err(e) = self.checked_destination(&addr, port).await {
+            self.close = Some((POLICY_CLOSE_CODE, "destination blocked"));
+            return Err(e);
+        }
+        let ws = WebSocket::connect(upstream.url.clone()).await?;
+        ws.accept()?;
+        let events = ws.events()?;
+        let mut remote = UpstreamStream::new(WebSocketTransport::new(&ws, events));
+
+        let target = Target { addr, port };
+        remote.write_all(&vless::encode(&upstream.uuid, &target)).await?;
+        self.stage = Stage::Relay;
+        let monitor = self.slow_monitor(&target.addr, target.port);
+        relay::copy_bidirectional_monitored(self, &mut remote, monitor.as_ref())
+            .await
+            .map(|(up, down)| {
+                crate::log!("{} relayed {}:{} via {}, up: {} and dl: {}", self.config.trace, target.addr, target.port, upstream.url, convert(up as f64), convert(down as f64));
+            })
+            .map_err(|e| Error::RustError(e.to_string()))
+    }
+}
+
+// the peer's websocket as a byte stream, with the vless response header it
+// sends first stripped off
+struct UpstreamStream<'a> {
+    inner: WebSocketTransport<'a>,
+    pending: Vec<u8>,
+    pos: usize,
+    // collected until the response header is complete, None afterwards
+    header: Option<Vec<u8>>,
+}
+
+impl<'a> UpstreamStream<'a> {
+    fn new(inner: WebSocketTransport<'a>) -> Self {
+        Self { inner, pending: Vec::new(), pos: 0, header: Some(Vec::new()) }
+    }
+}
+
+impl AsyncRead for UpstreamStream<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.pos < this.pending.len() {
+                let n = (this.pending.len() - this.pos).min(buf.remaining());
+                buf.put_slice(&this.pending[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            let Some(data) = std::task::ready!(this.inner.poll_recv(cx))? else {
+                return Poll::Ready(Ok(()));
+            };
+            (this.pending, this.pos) = (data, 0);
+            if let Some(mut header) = this.header.take() {
+                header.append(&mut this.pending);
+                match vless::parse_response(&header) {
+                    Ok(((), n)) => this.pending = header.split_off(n),
+                    Err(ParseError::Incomplete) => this.header = Some(header),
+                    Err(e) => return Poll::Ready(Err(std::io::Error::other(e.to_string()))),
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream<'_> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -1,51 +1,55 @@
-use super::ProxyStream;
-use crate::common::{parse_addr, parse_port};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use uuid::Uuid;
+use super::{violation_close_code, ProxyStream, TunnelTransport, AUTH_FAILED_CLOSE_CODE};
+use crate::common::protocol::{vless, Command, ParseError};
+use tokio::io::AsyncWriteExt;
 use worker::*;
 
-impl <'a> ProxyStream<'a> {
+impl<T: TunnelTransport> ProxyStream<T> {
     pub async fn process_vless(&mut self) -> Result<()> {
-        // ignore version
-        self.read_u8().await?;
-        
-        // read uuid
-        let mut user_id = [0u8; 16];
-        self.read_exact(&mut user_id).await?;
-        let _uuid = Uuid::from_bytes(user_id);
-        
-        // read protobuf
-        let m_len = self.read_u8().await?;
-        let mut protobuf = vec![0u8; m_len as _];
-        self.read_exact(&mut protobuf).await?;
-
-        // read instruction
-        let network_type = self.read_u8().await?;
-        let is_tcp = network_type == 1;
-
-        // read port and address
-        let remote_port = parse_port(self).await?;
-        let remote_addr = parse_addr(self).await?;
+        let uuid = self.config.uuid;
+        let parsed = self
+            .read_header(|buf| {
+                let (request, consumed) = vless::parse(buf)?;
+                if request.version != 0 {
+                    return Err(ParseError::Invalid("unsupported vless version"));
+                }
+                if request.uuid != uuid {
+                    return Err(ParseError::Invalid("vless uuid mismatch"));
+                }
+                Ok((request, consumed))
+            })
+            .await;
+        let request = match parsed {
+            Ok(request) => request,
+            Err(e) => return self.handle_fallback(e).await,
+        };
+        if self.config.banned_uuids.contains(&request.uuid) {
+            self.close = Some((AUTH_FAILED_CLOSE_CODE, "uuid banned"));
+            return Err(Error::RustError(format!("uuid {} is banned", request.uuid)));
+        }
+        // flows like xtls-rprx-vision splice the client's tls, which a
+        // worker can't do. failing here beats garbage mid-stream.
+        if !request.addons.flow.is_empty() {
+            self.close = Some((violation_close_code(self.protocol), "unsupported flow"));
+            return Err(Error::RustError(format!("unsupported vless flow {}", request.addons.flow)));
+        }
+        if !request.addons.seed.is_empty() {
+            crate::log_debug!(self.config, "ignoring a {} byte vless seed", request.addons.seed.len());
+        }
+        self.user = self.config.path_user.unwrap_or(request.uuid);
 
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
+        // send header
+        self.write_all(&[0u8; 2]).await?;
 
-            // send header
-            self.write(&[0u8; 2]).await?;
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
+        match request.command {
+            Command::Tcp => self.handle_outbound(request.target.addr, request.target.port).await?,
+            Command::Mux => self.handle_mux().await?,
+            Command::Udp => {
+                if let Err(e) = self.handle_udp_outbound().await {
+                    crate::log_error!("{} error handling udp: {}", self.config.trace, e)
                 }
             }
-        } else {
-            if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
-            }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
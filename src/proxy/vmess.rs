@@ -1,134 +1,53 @@
-use super::ProxyStream;
+use super::{webcrypto, Body, ProxyStream, TunnelTransport, AUTH_FAILED_CLOSE_CODE};
 use crate::common::{
-    hash, parse_port, parse_addr, KDFSALT_CONST_AEAD_RESP_HEADER_IV, KDFSALT_CONST_AEAD_RESP_HEADER_KEY, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV, KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY
+    digest, hash, KDFSALT_CONST_AEAD_RESP_HEADER_IV, KDFSALT_CONST_AEAD_RESP_HEADER_KEY, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_IV, KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY
 };
-use std::io::Cursor;
+use crate::common::protocol::{vmess, vmess_body, Command};
 use aes::cipher::KeyInit;
-use aes_gcm::{
-    aead::{Aead, Payload},
-    Aes128Gcm,
-};
-use md5::{Digest, Md5};
-use sha2::Sha256;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use aes_gcm::{aead::Aead, Aes128Gcm};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
 use worker::*;
 
+// uuid derived keys kept per isolate, keyed by the uuid so a changed UUID or
+// another HOST_UUIDS entry misses instead of reusing stale keys. dropped all
+// at once when full.
+static KEYS_CACHE_SIZE: usize = 64;
 
-impl <'a> ProxyStream<'a> {
-    async fn aead_decrypt(&mut self) -> Result<Vec<u8>> {
-        let key = crate::md5!(
-            &self.config.uuid.as_bytes(),
-            b"c48619fe-8f02-49e0-b9e9-edf763e17e21"
-        );
-
-        // +-------------------+-------------------+-------------------+
-        // |     Auth ID       |   Header Length   |       Nonce       |
-        // +-------------------+-------------------+-------------------+
-        // |     16 Bytes      |     18 Bytes      |      8 Bytes      |
-        // +-------------------+-------------------+-------------------+
-        let mut auth_id = [0u8; 16];
-        self.read_exact(&mut auth_id).await?;
-        let mut len = [0u8; 18];
-        self.read_exact(&mut len).await?;
-        let mut nonce = [0u8; 8];
-        self.read_exact(&mut nonce).await?;
-
-        // https://github.com/v2fly/v2ray-core/blob/master/proxy/vmess/aead/kdf.go
-        let header_length = {
-            let header_length_key = &hash::kdf(
-                &key,
-                &[
-                    KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
-                    &auth_id,
-                    &nonce,
-                ],
-            )[..16];
-            let header_length_nonce = &hash::kdf(
-                &key,
-                &[
-                    KDFSALT_CONST_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
-                    &auth_id,
-                    &nonce,
-                ],
-            )[..12];
-
-            let payload = Payload {
-                msg: &len,
-                aad: &auth_id,
-            };
-
-            let len = Aes128Gcm::new(header_length_key.into())
-                .decrypt(header_length_nonce.into(), payload)
-                .map_err(|e| Error::RustError(e.to_string()))?;
-
-            ((len[0] as u16) << 8) | (len[1] as u16)
-        };
-
-        // 16 bytes padding
-        let mut cmd = vec![0u8; (header_length + 16) as _];
-        self.read_exact(&mut cmd).await?;
-
-        let header_payload = {
-            let payload_key = &hash::kdf(
-                &key,
-                &[
-                    KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_KEY,
-                    &auth_id,
-                    &nonce,
-                ],
-            )[..16];
-            let payload_nonce = &hash::kdf(
-                &key,
-                &[KDFSALT_CONST_VMESS_HEADER_PAYLOAD_AEAD_IV, &auth_id, &nonce],
-            )[..12];
-
-            let payload = Payload {
-                msg: &cmd,
-                aad: &auth_id,
-            };
-
-            Aes128Gcm::new(payload_key.into())
-                .decrypt(payload_nonce.into(), payload)
-                .map_err(|e| Error::RustError(e.to_string()))?
-        };
+thread_local! {
+    static KEYS: RefCell<HashMap<Uuid, vmess::Keys>> = RefCell::new(HashMap::new());
+}
 
-        Ok(header_payload)
+pub fn keys(uuid: &Uuid) -> vmess::Keys {
+    if let Some(keys) = KEYS.with_borrow(|cache| cache.get(uuid).copied()) {
+        return keys;
     }
+    let keys = vmess::Keys::new(uuid.as_bytes());
+    KEYS.with_borrow_mut(|cache| {
+        if cache.len() >= KEYS_CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(*uuid, keys);
+    });
+    keys
+}
 
+impl<T: TunnelTransport> ProxyStream<T> {
     pub async fn process_vmess(&mut self) -> Result<()> {
-        let mut buf = Cursor::new(self.aead_decrypt().await?);
-
-        // https://xtls.github.io/en/development/protocols/vmess.html#command-section
-        //
-        // +---------+--------------------+---------------------+-------------------------------+---------+----------+-------------------+----------+---------+---------+--------------+---------+--------------+----------+
-        // | 1 Byte  |      16 Bytes      |      16 Bytes       |            1 Byte             | 1 Byte  |  4 bits  |      4 bits       |  1 Byte  | 1 Byte  | 2 Bytes |    1 Byte    | N Bytes |   P Bytes    | 4 Bytes  |
-        // +---------+--------------------+---------------------+-------------------------------+---------+----------+-------------------+----------+---------+---------+--------------+---------+--------------+----------+
-        // | Version | Data Encryption IV | Data Encryption Key | Response Authentication Value | Options | Reserved | Encryption Method | Reserved | Command | Port    | Address Type | Address | Random Value | Checksum |
-        // +---------+--------------------+---------------------+-------------------------------+---------+----------+-------------------+----------+---------+---------+--------------+---------+--------------+----------+
-
-        let version = buf.read_u8().await?;
-        if version != 1 {
-            return Err(Error::RustError("invalid version".to_string()));
+        let keys = keys(&self.config.uuid);
+        let request = self.read_header(|buf| vmess::parse(buf, &keys.cmd_key)).await?;
+        // the header is sealed with the configured uuid, the tunnel's user
+        // is who it's accounted to
+        if self.config.banned_uuids.contains(&self.user) {
+            self.close = Some((AUTH_FAILED_CLOSE_CODE, "uuid banned"));
+            return Err(Error::RustError(format!("uuid {} is banned", self.user)));
         }
 
-        let mut iv = [0u8; 16];
-        buf.read_exact(&mut iv).await?;
-        let mut key = [0u8; 16];
-        buf.read_exact(&mut key).await?;
-
-        // ignore options for now
-        let mut options = [0u8; 4];
-        buf.read_exact(&mut options).await?;
-
-        let cmd = buf.read_u8().await?;
-        let is_tcp = cmd == 0x1;
-
-        let remote_port = parse_port(&mut buf).await?;
-        let remote_addr = parse_addr(&mut buf).await?;
-
         // encrypt payload
-        let key = &crate::sha256!(&key)[..16];
-        let iv = &crate::sha256!(&iv)[..16];
+        let key: [u8; 16] = digest::truncated(&digest::sha256(&[&request.key]));
+        let iv: [u8; 16] = digest::truncated(&digest::sha256(&[&request.iv]));
 
         // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L196
         let length_key = &hash::kdf(&key, &[KDFSALT_CONST_AEAD_RESP_HEADER_LEN_KEY])[..16];
@@ -137,36 +56,50 @@ impl <'a> ProxyStream<'a> {
             // 4 bytes header: https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L238
             .encrypt(length_iv.into(), &4u16.to_be_bytes()[..])
             .map_err(|e| Error::RustError(e.to_string()))?;
-        self.write(&length).await?;
+        self.write_all(&length).await?;
 
         let payload_key = &hash::kdf(&key, &[KDFSALT_CONST_AEAD_RESP_HEADER_KEY])[..16];
         let payload_iv = &hash::kdf(&iv, &[KDFSALT_CONST_AEAD_RESP_HEADER_IV])[..12];
         let header = {
             let header = [
-                options[0], // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L242
+                request.response_auth, // https://github.com/v2ray/v2ray-core/blob/master/proxy/vmess/encoding/client.go#L242
                 0x00, 0x00, 0x00,
             ];
             Aes128Gcm::new(payload_key.into())
                 .encrypt(payload_iv.into(), &header[..])
                 .map_err(|e| Error::RustError(e.to_string()))?
         };
-        self.write(&header).await?;
-
-        if is_tcp {
-            let addr_pool = [
-                (remote_addr.clone(), remote_port),
-                (self.config.proxy_addr.clone(), self.config.proxy_port)
-            ];
+        self.write_all(&header).await?;
+
+        let codec = vmess_body::codec(
+            request.options,
+            request.security,
+            request.command == Command::Tcp,
+            &request.key,
+            &request.iv,
+            &key,
+            &iv,
+        )
+        .map_err(|e| Error::RustError(e.to_string()))?;
+        if let Some((reader, writer)) = codec {
+            let webcrypto = match self.config.vmess_webcrypto && request.security == vmess_body::SECURITY_AES_128_GCM {
+                true => webcrypto::Key::import(&key)
+                    .await
+                    .map_err(|e| crate::log_debug!(self.config, "no webcrypto, sealing in wasm: {}", e))
+                    .ok(),
+                false => None,
+            };
+            self.set_body(Body::Vmess(Box::new((reader, writer, webcrypto))))?;
+        }
 
-            for (target_addr, target_port) in addr_pool {
-                if let Err(e) = self.handle_tcp_outbound(target_addr, target_port).await {
-                    console_error!("error handling tcp: {}", e)
+        match request.command {
+            Command::Tcp => self.handle_outbound(request.target.addr, request.target.port).await?,
+            Command::Mux => self.handle_mux().await?,
+            Command::Udp => {
+                if let Err(e) = self.handle_udp_outbound().await {
+                    crate::log_error!("{} error handling udp: {}", self.config.trace, e)
                 }
             }
-        } else {
-            if let Err(e) = self.handle_udp_outbound().await {
-                console_error!("error handling udp: {}", e)
-            }
         }
 
         Ok(())
@@ -0,0 +1,65 @@
+// aes-gcm through the runtime's SubtleCrypto, which runs natively instead of
+// as wasm. every call is a promise round trip, so only response chunks of at
+// least MIN_CHUNK bytes go there, smaller ones and the request direction,
+// which is decoded as frames arrive, stay on the aes-gcm crate.
+use std::future::Future;
+use std::pin::Pin;
+use wasm_bindgen::{JsCast, JsValue};
+use worker::js_sys::{self, Array, Function, Object, Promise, Reflect, Uint8Array};
+use worker::wasm_bindgen_futures::JsFuture;
+use worker::*;
+
+pub static MIN_CHUNK: usize = 4 * 1024;
+
+// a chunk being sealed, ciphertext followed by the tag once done
+pub type Sealing = Pin<Box<dyn Future<Output = std::io::Result<Vec<u8>>>>>;
+
+pub struct Key {
+    subtle: Object,
+    encrypt: Function,
+    key: JsValue,
+}
+
+impl Key {
+    // an aes-128-gcm key usable for encryption only. fails outside workers,
+    // the caller falls back to the aes-gcm crate then.
+    pub async fn import(raw: &[u8; 16]) -> Result<Self> {
+        if !cfg!(target_arch = "wasm32") {
+            return Err(Error::RustError("webcrypto needs the workers runtime".to_string()));
+        }
+        let subtle: Object = Reflect::get(&Reflect::get(&js_sys::global(), &"crypto".into())?, &"subtle".into())?.dyn_into()?;
+        let import: Function = Reflect::get(&subtle, &"importKey".into())?.dyn_into()?;
+        let encrypt: Function = Reflect::get(&subtle, &"encrypt".into())?.dyn_into()?;
+        let args = Array::of5(
+            &"raw".into(),
+            &Uint8Array::from(&raw[..]),
+            &"AES-GCM".into(),
+            &false.into(),
+            &Array::of1(&"encrypt".into()),
+        );
+        let promise: Promise = Reflect::apply(&import, &subtle, &args)?.dyn_into()?;
+        let key = JsFuture::from(promise).await?;
+        Ok(Self { subtle, encrypt, key })
+    }
+
+    pub fn seal(&self, nonce: &[u8; 12], payload: &[u8]) -> Sealing {
+        let promise = self.encrypt_promise(nonce, payload);
+        Box::pin(async move {
+            let sealed = JsFuture::from(promise.map_err(to_io)?).await.map_err(to_io)?;
+            Ok(Uint8Array::new(&sealed).to_vec())
+        })
+    }
+
+    fn encrypt_promise(&self, nonce: &[u8; 12], payload: &[u8]) -> std::result::Result<Promise, JsValue> {
+        let algorithm = Object::new();
+        Reflect::set(&algorithm, &"name".into(), &"AES-GCM".into())?;
+        Reflect::set(&algorithm, &"iv".into(), &Uint8Array::from(&nonce[..]))?;
+        self.encrypt
+            .call3(&self.subtle, &algorithm, &self.key, &Uint8Array::from(payload))?
+            .dyn_into()
+    }
+}
+
+fn to_io(e: JsValue) -> std::io::Error {
+    std::io::Error::other(format!("webcrypto: {}", Error::from(e)))
+}
@@ -0,0 +1,651 @@
+use crate::common;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use worker::*;
+
+static PROXY_KV_KEY: &str = "proxy_kv";
+static PROXY_KV_URL: &str = "https://raw.githubusercontent.com/FoolVPN-ID/Nautica/refs/heads/main/kvProxyList.json";
+static PROXY_KV_TTL: u64 = 60 * 60 * 24; // 24 hours
+static WARM_WINDOW: u64 = 15 * 60; // a few cron runs, in case one fails
+
+pub type ProxyList = HashMap<String, Vec<Proxy>>;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Proxy {
+    pub addr: String,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    // relative odds of being picked from its pool
+    pub weight: u32,
+    // last measured latency in ms, informational
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.addr, self.port)?;
+        if let Some(label) = &self.label {
+            write!(f, "#{}", label)?;
+        }
+        Ok(())
+    }
+}
+
+// where in the document a proxy list is invalid, e.g.
+// `countries.SG[1].port: expected a port number`
+#[derive(Debug, PartialEq)]
+pub struct SchemaError {
+    pub path: String,
+    pub message: &'static str,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+fn invalid<T>(path: impl Into<String>, message: &'static str) -> std::result::Result<T, SchemaError> {
+    Err(SchemaError { path: path.into(), message })
+}
+
+// schema v2:
+//   {"version": 2, "countries": {"SG": [{"addr": "1.2.3.4", "port": 443,
+//    "label": "SG-Oracle-1", "weight": 2, "latency": 40, "tags": [".."]}]}}
+// anything without a version is the original shape, country -> entries as
+// "addr:port" strings, optionally labeled as in "1.2.3.4:443#SG-Oracle-1",
+// or objects with the port inside addr.
+pub fn parse(text: &str) -> std::result::Result<ProxyList, SchemaError> {
+    let root: Value = serde_json::from_str(text).map_err(|_| SchemaError { path: "$".to_string(), message: "not json" })?;
+    let Some(root) = root.as_object() else {
+        return invalid("$", "expected an object");
+    };
+    let (countries, v2, prefix) = match root.get("version") {
+        None => (root, false, ""),
+        Some(version) if version.as_u64() == Some(2) => match root.get("countries").and_then(Value::as_object) {
+            Some(countries) => (countries, true, "countries."),
+            None => return invalid("countries", "expected an object"),
+        },
+        Some(_) => return invalid("version", "unsupported version"),
+    };
+
+    let mut list = ProxyList::new();
+    for (country, entries) in countries {
+        let proxies = parse_country(country, entries, v2, &format!("{}{}", prefix, country))?;
+        list.insert(country.clone(), proxies);
+    }
+    Ok(list)
+}
+
+fn parse_country(country: &str, entries: &Value, v2: bool, path: &str) -> std::result::Result<Vec<Proxy>, SchemaError> {
+    if !is_country_code(country) {
+        return invalid(path, "expected a two letter country code");
+    }
+    let Some(entries) = entries.as_array() else {
+        return invalid(path, "expected an array");
+    };
+    entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| parse_entry(entry, v2, &format!("{}[{}]", path, i)))
+        .collect()
+}
+
+pub fn is_country_code(country: &str) -> bool {
+    country.len() == 2 && country.bytes().all(|x| x.is_ascii_uppercase())
+}
+
+// a whole country group or a single entry in the v2 shape, as the crud api
+// takes them, normalized like an import
+pub fn parse_group(country: &str, entries: &Value) -> std::result::Result<Vec<Proxy>, SchemaError> {
+    let mut proxies = parse_country(country, entries, true, "$")?;
+    let mut seen = HashMap::new();
+    for (i, proxy) in proxies.iter_mut().enumerate() {
+        if let Err(message) = check_proxy(proxy) {
+            return invalid(format!("$[{}].addr", i), message);
+        }
+        if seen.insert(key_of(proxy), ()).is_some() {
+            return invalid(format!("$[{}]", i), "duplicate entry");
+        }
+    }
+    Ok(proxies)
+}
+
+pub fn parse_proxy(entry: &Value) -> std::result::Result<Proxy, SchemaError> {
+    let mut proxy = parse_entry(entry, true, "$")?;
+    check_proxy(&mut proxy).or_else(|message| invalid("$.addr", message))?;
+    Ok(proxy)
+}
+
+// an entry left out of an import or a refreshed list, and why
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Rejected {
+    // "line 3" of an import, or the country of a list entry
+    pub at: String,
+    pub entry: String,
+    pub reason: String,
+}
+
+// trims the entry and checks the address is a public ip or a hostname, so
+// typos and private ranges never get picked
+fn check_proxy(proxy: &mut Proxy) -> std::result::Result<(), &'static str> {
+    proxy.addr = normalize_addr(&proxy.addr)?;
+    proxy.label = proxy.label.take().map(|x| x.trim().to_string()).filter(|x| !x.is_empty());
+    Ok(())
+}
+
+fn normalize_addr(addr: &str) -> std::result::Result<String, &'static str> {
+    let addr = addr.trim();
+    let (inner, bracketed) = match addr.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+        Some(inner) => (inner, true),
+        None => (addr, false),
+    };
+    if let Ok(ip) = inner.parse::<IpAddr>() {
+        if !is_public(ip) {
+            return Err("not a public ip address");
+        }
+        return Ok(match ip {
+            IpAddr::V6(ip) if bracketed => format!("[{}]", ip),
+            ip => ip.to_string(),
+        });
+    }
+    let host = addr.to_ascii_lowercase();
+    let label_ok = |x: &str| !x.is_empty() && x.len() <= 63 && !x.starts_with('-') && !x.ends_with('-') && x.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    // an all numeric last label is a mistyped ip such as 1.2.3.256
+    let numeric = host.rsplit('.').next().is_some_and(|x| x.bytes().all(|b| b.is_ascii_digit()));
+    if !host.contains('.') || numeric || !host.split('.').all(label_ok) {
+        return Err("expected an ip address or a hostname");
+    }
+    Ok(host)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()),
+        IpAddr::V6(ip) => {
+            let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+            let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || unique_local || link_local)
+        }
+    }
+}
+
+// the same addr:port is kept only in the first country listing it
+fn dedup(seen: &mut HashMap<String, String>, country: &str, proxy: &Proxy) -> std::result::Result<(), String> {
+    match seen.get(&key_of(proxy)) {
+        Some(other) if other == country => Err("duplicate entry".to_string()),
+        Some(other) => Err(format!("already listed in {}", other)),
+        None => {
+            seen.insert(key_of(proxy), country.to_string());
+            Ok(())
+        }
+    }
+}
+
+// the validation and dedup pass over a whole list, countries in
+// alphabetical order so the same list always keeps the same entries
+pub fn normalize(list: ProxyList) -> (ProxyList, Vec<Rejected>) {
+    let mut countries: Vec<_> = list.into_iter().collect();
+    countries.sort_by(|a, b| a.0.cmp(&b.0));
+    let (mut clean, mut rejected, mut seen) = (ProxyList::new(), Vec::new(), HashMap::new());
+    for (country, proxies) in countries {
+        let mut kept = Vec::new();
+        for mut proxy in proxies {
+            let entry = proxy.to_string();
+            match check_proxy(&mut proxy).map_err(str::to_string).and_then(|()| dedup(&mut seen, &country, &proxy)) {
+                Ok(()) => kept.push(proxy),
+                Err(reason) => rejected.push(Rejected { at: country.clone(), entry, reason }),
+            }
+        }
+        if !kept.is_empty() {
+            clean.insert(country, kept);
+        }
+    }
+    (clean, rejected)
+}
+
+fn parse_entry(entry: &Value, v2: bool, path: &str) -> std::result::Result<Proxy, SchemaError> {
+    let field = |name: &str| format!("{}.{}", path, name);
+    let split_port = |addr: &str, at: String| match addr.rsplit_once(':') {
+        Some((addr, port)) => match port.parse::<u16>() {
+            Ok(port) if port != 0 && !addr.is_empty() => Ok((addr.to_string(), port)),
+            _ => invalid(at, "expected addr:port"),
+        },
+        None => invalid(at, "expected addr:port"),
+    };
+
+    if let (Some(line), false) = (entry.as_str(), v2) {
+        let (addr, label) = match line.split_once('#') {
+            Some((addr, label)) => (addr.trim(), Some(label.trim()).filter(|x| !x.is_empty())),
+            None => (line.trim(), None),
+        };
+        let (addr, port) = split_port(addr, path.to_string())?;
+        return Ok(Proxy { addr, port, label: label.map(str::to_string), weight: 1, latency: None, tags: Vec::new() });
+    }
+
+    let Some(entry) = entry.as_object() else {
+        return invalid(path, if v2 { "expected an object" } else { "expected a string or an object" });
+    };
+    let Some(addr) = entry.get("addr").and_then(Value::as_str).filter(|x| !x.is_empty()) else {
+        return invalid(field("addr"), "expected a string");
+    };
+    let (addr, port) = if v2 {
+        let port = entry.get("port").and_then(Value::as_u64).and_then(|x| u16::try_from(x).ok()).filter(|x| *x != 0);
+        match port {
+            Some(port) => (addr.to_string(), port),
+            None => return invalid(field("port"), "expected a port number"),
+        }
+    } else {
+        split_port(addr, field("addr"))?
+    };
+    let label = match entry.get("label") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(label)) => Some(label.clone()),
+        Some(_) => return invalid(field("label"), "expected a string"),
+    };
+    let number = |name: &str| match entry.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(x) => match x.as_u64().and_then(|x| u32::try_from(x).ok()) {
+            Some(x) => Ok(Some(x)),
+            None => invalid(field(name), "expected a non-negative integer"),
+        },
+    };
+    let weight = number("weight")?.unwrap_or(1);
+    let latency = number("latency")?;
+    let tags = match entry.get("tags") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(tags)) => tags
+            .iter()
+            .enumerate()
+            .map(|(i, tag)| match tag.as_str() {
+                Some(tag) => Ok(tag.to_string()),
+                None => invalid(format!("{}.tags[{}]", path, i), "expected a string"),
+            })
+            .collect::<std::result::Result<_, _>>()?,
+        Some(_) => return invalid(field("tags"), "expected an array"),
+    };
+    Ok(Proxy { addr, port, label, weight, latency, tags })
+}
+
+// community lists as plain text, one "addr:port#CC" per line, or csv rows
+// of "addr,port,CC" optionally followed by a label such as the provider.
+// blank lines, "#" comments and a csv header are skipped. lines that don't
+// pass the validation and dedup pass are reported instead of failing the
+// whole import.
+pub fn parse_import(text: &str) -> (ProxyList, Vec<Rejected>) {
+    let (mut list, mut rejected, mut seen) = (ProxyList::new(), Vec::new(), HashMap::new());
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if i == 0 && line.split(',').nth(1).is_some_and(|x| x.trim().parse::<u16>().is_err()) {
+            continue;
+        }
+        let parsed = import_line(line).and_then(|(country, proxy)| dedup(&mut seen, &country, &proxy).map(|()| (country, proxy)));
+        match parsed {
+            Ok((country, proxy)) => list.entry(country).or_default().push(proxy),
+            Err(reason) => rejected.push(Rejected { at: format!("line {}", i + 1), entry: line.to_string(), reason }),
+        }
+    }
+    (list, rejected)
+}
+
+fn import_line(line: &str) -> std::result::Result<(String, Proxy), String> {
+    let (mut proxy, country) = if line.contains(',') {
+        let fields: Vec<_> = line.split(',').map(str::trim).collect();
+        let [addr, port, country, ..] = fields[..] else {
+            return Err("expected addr,port,CC".to_string());
+        };
+        let mut proxy = parse_entry(&json!(format!("{}:{}", addr, port)), false, "").map_err(|e| e.message.to_string())?;
+        proxy.label = fields.get(3).map(|x| x.to_string());
+        (proxy, country)
+    } else {
+        let Some((addr, country)) = line.rsplit_once('#') else {
+            return Err("expected addr:port#CC".to_string());
+        };
+        (parse_entry(&json!(addr), false, "").map_err(|e| e.message.to_string())?, country.trim())
+    };
+    let country = country.to_uppercase();
+    if !is_country_code(&country) {
+        return Err("expected a two letter country code".to_string());
+    }
+    check_proxy(&mut proxy)?;
+    Ok((country, proxy))
+}
+
+// the list in the v2 shape, to republish a migrated PROXY_LIST
+pub fn to_v2(list: &ProxyList) -> Value {
+    json!({ "version": 2, "countries": list })
+}
+
+// country code -> proxy entries, cached in kv and refetched from
+// github once the cache expires or gets purged.
+pub async fn load(kv: &kv::KvStore) -> Result<ProxyList> {
+    Ok(load_revision(kv).await?.0)
+}
+
+// the list and its revision, which every edit through the api bumps. the
+// github copy is revision 0.
+pub async fn load_revision(kv: &kv::KvStore) -> Result<(ProxyList, u64)> {
+    let mut proxy_kv_str = kv.get(PROXY_KV_KEY).text().await?.unwrap_or_default();
+
+    if proxy_kv_str.is_empty() {
+        proxy_kv_str = fetch_github(kv).await?;
+    }
+
+    parse_revision(&proxy_kv_str)
+}
+
+// a stored list document and the revision it's at
+pub fn parse_revision(text: &str) -> Result<(ProxyList, u64)> {
+    let list = parse(text).map_err(|e| Error::RustError(format!("invalid proxy list at {}", e)))?;
+    Ok((list, revision_of(text)))
+}
+
+pub fn revision_of(text: &str) -> u64 {
+    serde_json::from_str::<Revision>(text).map(|x| x.revision).unwrap_or(0)
+}
+
+// the github copy, normalized and cached for PROXY_KV_TTL
+async fn fetch_github(kv: &kv::KvStore) -> Result<String> {
+    console_log!("getting proxy kv from github...");
+    let req = Fetch::Url(Url::parse(PROXY_KV_URL)?);
+    let mut res = req.send().await?;
+    if res.status_code() != 200 {
+        return Err(Error::from(format!("error getting proxy kv: {}", res.status_code())));
+    }
+    let fetched = parse(&res.text().await?).map_err(|e| Error::RustError(format!("invalid proxy list at {}", e)))?;
+    let (list, rejected) = normalize(fetched);
+    if !rejected.is_empty() {
+        console_log!("dropped {} proxy entries, first: {} in {}: {}", rejected.len(), rejected[0].entry, rejected[0].at, rejected[0].reason);
+    }
+    let proxy_kv_str = to_v2(&list).to_string();
+    kv.put(PROXY_KV_KEY, &proxy_kv_str)?.expiration_ttl(PROXY_KV_TTL).execute().await?;
+    Ok(proxy_kv_str)
+}
+
+// run from the cron trigger: a missing list or a github copy expiring within
+// WARM_WINDOW is fetched again, so requests don't wait on github. an edited
+// list has no expiry and is left alone. true when github was fetched.
+pub async fn warm(kv: &kv::KvStore) -> Result<bool> {
+    let listed = kv.list().prefix(PROXY_KV_KEY.to_string()).execute().await?;
+    let expiration = match listed.keys.into_iter().find(|key| key.name == PROXY_KV_KEY) {
+        Some(key) => key.expiration,
+        None => {
+            fetch_github(kv).await?;
+            return Ok(true);
+        }
+    };
+    if expiration.is_some_and(|at| at <= Date::now().as_millis() / 1000 + WARM_WINDOW) {
+        fetch_github(kv).await?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+#[derive(Deserialize)]
+struct Revision {
+    #[serde(default)]
+    revision: u64,
+}
+
+// an edited list is kept without expiry, so the github copy doesn't replace
+// it anymore. purging goes back to github.
+pub async fn store(kv: &kv::KvStore, list: &ProxyList, revision: u64) -> Result<()> {
+    kv.put(PROXY_KV_KEY, to_document(list, revision))?.execute().await?;
+    Ok(())
+}
+
+// the v2 shape with the revision, as it's stored
+pub fn to_document(list: &ProxyList, revision: u64) -> String {
+    let mut doc = to_v2(list);
+    doc["revision"] = json!(revision);
+    doc.to_string()
+}
+
+pub enum Edit {
+    PutCountry(String, Vec<Proxy>),
+    DeleteCountry(String),
+    AddProxy(String, Proxy),
+    // the entry named "addr:port" in a country
+    PutProxy(String, String, Proxy),
+    DeleteProxy(String, String),
+    // adds the entries not listed yet, e.g. from an import
+    Merge(ProxyList),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EditError {
+    NoSuchCountry,
+    NoSuchProxy,
+    Duplicate,
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSuchCountry => write!(f, "no such country"),
+            Self::NoSuchProxy => write!(f, "no such proxy"),
+            Self::Duplicate => write!(f, "proxy already listed"),
+        }
+    }
+}
+
+pub fn key_of(proxy: &Proxy) -> String {
+    format!("{}:{}", proxy.addr, proxy.port)
+}
+
+// the country listing an entry, duplicates across countries are edit errors
+// like within one
+fn listed_in<'a>(list: &'a ProxyList, key: &str) -> Option<&'a str> {
+    list.iter().find(|(_, proxies)| proxies.iter().any(|x| key_of(x) == key)).map(|(country, _)| country.as_str())
+}
+
+pub fn apply(list: &mut ProxyList, edit: Edit) -> std::result::Result<(), EditError> {
+    match edit {
+        Edit::PutCountry(country, proxies) => {
+            if proxies.iter().any(|x| listed_in(list, &key_of(x)).is_some_and(|other| other != country)) {
+                return Err(EditError::Duplicate);
+            }
+            list.insert(country, proxies);
+        }
+        Edit::DeleteCountry(country) => {
+            list.remove(&country).ok_or(EditError::NoSuchCountry)?;
+        }
+        Edit::AddProxy(country, proxy) => {
+            if listed_in(list, &key_of(&proxy)).is_some() {
+                return Err(EditError::Duplicate);
+            }
+            list.entry(country).or_default().push(proxy);
+        }
+        Edit::PutProxy(country, key, proxy) => {
+            if key_of(&proxy) != key && listed_in(list, &key_of(&proxy)).is_some() {
+                return Err(EditError::Duplicate);
+            }
+            let proxies = list.get_mut(&country).ok_or(EditError::NoSuchCountry)?;
+            let entry = proxies.iter_mut().find(|x| key_of(x) == key).ok_or(EditError::NoSuchProxy)?;
+            *entry = proxy;
+        }
+        Edit::DeleteProxy(country, key) => {
+            let proxies = list.get_mut(&country).ok_or(EditError::NoSuchCountry)?;
+            let before = proxies.len();
+            proxies.retain(|x| key_of(x) != key);
+            if proxies.len() == before {
+                return Err(EditError::NoSuchProxy);
+            }
+        }
+        Edit::Merge(imported) => {
+            for (country, entries) in imported {
+                for proxy in entries {
+                    if listed_in(list, &key_of(&proxy)).is_none() {
+                        list.entry(country.clone()).or_default().push(proxy);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// a pool is a country code, optionally narrowed to one port as in "SG-443"
+// for clients behind firewalls that only let some ports through
+pub fn pool<'a>(proxies: &'a ProxyList, id: &str) -> Vec<&'a Proxy> {
+    let (country, port) = match id.split_once('-') {
+        Some((country, port)) => (country, Some(port)),
+        None => (id, None),
+    };
+    proxies
+        .get(country)
+        .map(|list| {
+            list.iter()
+                .filter(|x| port.is_none_or(|port| port.parse() == Ok(x.port)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// weighted by `weight`, `roll` being any random number. zero weights count
+// as one so every entry stays reachable.
+pub fn pick<'a>(pool: &[&'a Proxy], roll: u32) -> Option<&'a Proxy> {
+    let total: u32 = pool.iter().map(|x| x.weight.max(1)).sum();
+    let mut roll = roll % total.max(1);
+    for proxy in pool {
+        let weight = proxy.weight.max(1);
+        if roll < weight {
+            return Some(proxy);
+        }
+        roll -= weight;
+    }
+    None
+}
+
+// of the requested countries, the ones on the given continent, or all of
+// them when none is
+pub fn prefer_continent<'a>(countries: &'a [String], continent: &str) -> Vec<&'a String> {
+    let near: Vec<_> = countries
+        .iter()
+        .filter(|x| common::geo::continent(x.get(..2).unwrap_or_default()) == Some(continent))
+        .collect();
+    if near.is_empty() {
+        countries.iter().collect()
+    } else {
+        near
+    }
+}
+
+pub async fn purge(kv: &kv::KvStore) -> Result<()> {
+    Ok(kv.delete(PROXY_KV_KEY).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let v1 = parse(r#"{"SG": ["1.1.1.1:443#SG-Oracle-1", {"addr": "2.2.2.2:8443", "weight": 3}]}"#).unwrap();
+        let v2 = parse(
+            r#"{"version": 2, "countries": {"SG": [
+                {"addr": "1.1.1.1", "port": 443, "label": "SG-Oracle-1"},
+                {"addr": "2.2.2.2", "port": 8443, "weight": 3}
+            ]}}"#,
+        )
+        .unwrap();
+        assert_eq!(v1, v2);
+        assert_eq!(v1["SG"][0].to_string(), "1.1.1.1:443#SG-Oracle-1");
+        assert_eq!(parse(&to_v2(&v1).to_string()).unwrap(), v1);
+
+        let error = |text: &str| parse(text).unwrap_err().to_string();
+        assert_eq!(error(r#"{"SG": ["1.1.1.1"]}"#), "SG[0]: expected addr:port");
+        assert_eq!(error(r#"{"sg": []}"#), "sg: expected a two letter country code");
+        assert_eq!(
+            error(r#"{"version": 2, "countries": {"SG": [{"addr": "1.1.1.1", "port": 70000}]}}"#),
+            "countries.SG[0].port: expected a port number"
+        );
+        assert_eq!(
+            error(r#"{"version": 2, "countries": {"SG": [{"addr": "1.1.1.1", "port": 1, "tags": [1]}]}}"#),
+            "countries.SG[0].tags[0]: expected a string"
+        );
+        assert_eq!(error(r#"{"version": 3}"#), "version: unsupported version");
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut list = parse(r#"{"SG": ["1.1.1.1:443"]}"#).unwrap();
+        let proxy = |x: Value| parse_proxy(&x).unwrap();
+        let added = proxy(json!({ "addr": "2.2.2.2", "port": 443 }));
+        assert_eq!(apply(&mut list, Edit::AddProxy("SG".into(), added.clone())), Ok(()));
+        assert_eq!(apply(&mut list, Edit::AddProxy("SG".into(), added)), Err(EditError::Duplicate));
+
+        let moved = proxy(json!({ "addr": "2.2.2.2", "port": 8443, "weight": 2 }));
+        assert_eq!(apply(&mut list, Edit::PutProxy("SG".into(), "2.2.2.2:443".into(), moved)), Ok(()));
+        assert_eq!(list["SG"][1].to_string(), "2.2.2.2:8443");
+        let clash = proxy(json!({ "addr": "1.1.1.1", "port": 443 }));
+        assert_eq!(apply(&mut list, Edit::PutProxy("SG".into(), "2.2.2.2:8443".into(), clash)), Err(EditError::Duplicate));
+
+        assert_eq!(apply(&mut list, Edit::DeleteProxy("SG".into(), "1.1.1.1:443".into())), Ok(()));
+        assert_eq!(apply(&mut list, Edit::DeleteProxy("SG".into(), "1.1.1.1:443".into())), Err(EditError::NoSuchProxy));
+        assert_eq!(apply(&mut list, Edit::DeleteCountry("JP".into())), Err(EditError::NoSuchCountry));
+        assert_eq!(parse_group("sg", &json!([])).unwrap_err().to_string(), "$: expected a two letter country code");
+    }
+
+    #[test]
+    fn test_import() {
+        let text = "ip,port,country,org\n1.1.1.1, 443, SG, Oracle \n\n# a comment\n2.2.2.2:8443#jp\n2.2.2.2:8443#JP\n2.2.2.2:8443#SG\n1.1.1.1:443\n1.1.1.1,0,SG\n10.0.0.1:443#SG\n1.2.3.256:443#SG\n";
+        let (imported, rejected) = parse_import(text);
+        assert_eq!(imported["SG"][0].to_string(), "1.1.1.1:443#Oracle");
+        assert_eq!(imported["JP"].len(), 1);
+        let reasons: Vec<_> = rejected.iter().map(|x| format!("{}: {}", x.at, x.reason)).collect();
+        assert_eq!(
+            reasons,
+            [
+                "line 6: duplicate entry",
+                "line 7: already listed in JP",
+                "line 8: expected addr:port#CC",
+                "line 9: expected addr:port",
+                "line 10: not a public ip address",
+                "line 11: expected an ip address or a hostname",
+            ]
+        );
+
+        let mut list = parse(r#"{"SG": ["1.1.1.1:443"]}"#).unwrap();
+        apply(&mut list, Edit::Merge(imported)).unwrap();
+        assert_eq!((list["SG"].len(), list["JP"].len()), (1, 1));
+    }
+
+    #[test]
+    fn test_normalize() {
+        let list = parse(r#"{"SG": [" 1.1.1.1 :443", "[2606:4700:0::1111]:443", "proxy.Example.com:443#"], "US": ["1.1.1.1:443", "127.0.0.1:443"]}"#).unwrap();
+        let (list, rejected) = normalize(list);
+        let kept: Vec<_> = list["SG"].iter().map(ToString::to_string).collect();
+        assert_eq!(kept, ["1.1.1.1:443", "[2606:4700::1111]:443", "proxy.example.com:443"]);
+        assert!(!list.contains_key("US"));
+        let reasons: Vec<_> = rejected.iter().map(|x| format!("{} {}: {}", x.at, x.entry, x.reason)).collect();
+        assert_eq!(reasons, ["US 1.1.1.1:443: already listed in SG", "US 127.0.0.1:443: not a public ip address"]);
+    }
+
+    #[test]
+    fn test_pool() {
+        let proxies = parse(r#"{"SG": ["1.1.1.1:443", {"addr": "2.2.2.2:8443", "weight": 3}]}"#).unwrap();
+        let sg = &proxies["SG"];
+        assert_eq!(pool(&proxies, "SG").len(), 2);
+        assert_eq!(pool(&proxies, "SG-8443"), [&sg[1]]);
+        assert!(pool(&proxies, "SG-80").is_empty());
+        assert!(pool(&proxies, "JP").is_empty());
+
+        let all = pool(&proxies, "SG");
+        assert_eq!(pick(&all, 0), Some(&sg[0]));
+        assert_eq!(pick(&all, 1), Some(&sg[1]));
+        assert_eq!(pick(&all, 4), Some(&sg[0]));
+        assert_eq!(pick(&[], 7), None);
+    }
+}
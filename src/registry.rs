@@ -0,0 +1,158 @@
+use crate::config::Config;
+use crate::proxy::{timer, Progress};
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use worker::*;
+
+static REPORT_INTERVAL: Duration = Duration::from_secs(15);
+// entries of isolates that stopped reporting, e.g. because they died
+static STALE_AFTER: u64 = 60 * 1000; // 1 minute
+
+// an open tunnel as listed by /api/connections
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Connection {
+    pub id: String,
+    pub ray: Option<String>,
+    pub protocol: Option<String>,
+    pub destination: Option<String>,
+    pub client: String,
+    pub started_at: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub slow: bool,
+}
+
+// a tunnel's entry in the registry, kept up to date by `watch`
+pub struct Registration {
+    registry: ObjectNamespace,
+    pub id: String,
+    ray: Option<String>,
+    client: String,
+    started_at: u64,
+}
+
+impl Registration {
+    // None unless the REGISTRY durable object is bound
+    pub fn new(config: &Config) -> Option<Self> {
+        let registry = config.registry.clone()?;
+        Some(Self {
+            registry,
+            id: config.trace.id.clone(),
+            ray: config.trace.ray.clone(),
+            client: config.client.to_string(),
+            started_at: Date::now().as_millis(),
+        })
+    }
+
+    // reports the tunnel every few seconds, only returns once an admin
+    // terminated it
+    pub async fn watch(&self, progress: &Progress) {
+        loop {
+            match self.report(progress).await {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(e) => console_log!("[registry]: report failed: {}", e),
+            }
+            timer::sleep(REPORT_INTERVAL).await;
+        }
+    }
+
+    async fn report(&self, progress: &Progress) -> Result<bool> {
+        let connection = Connection {
+            id: self.id.clone(),
+            ray: self.ray.clone(),
+            protocol: progress.protocol.lock().unwrap().map(|x| x.name().to_string()),
+            destination: progress.destination.lock().unwrap().clone(),
+            client: self.client.clone(),
+            started_at: self.started_at,
+            bytes_up: progress.bytes_up.load(Ordering::Relaxed),
+            bytes_down: progress.bytes_down.load(Ordering::Relaxed),
+            slow: progress.slow.load(Ordering::Relaxed),
+        };
+        let res = registry_call(&self.registry, "/report", Some(serde_json::to_string(&connection)?)).await?;
+        Ok(res.status_code() == 410)
+    }
+
+    pub async fn close(self) {
+        if let Err(e) = registry_call(&self.registry, &format!("/close?id={}", self.id), None).await {
+            console_log!("[registry]: close failed: {}", e);
+        }
+    }
+}
+
+pub async fn list(registry: &ObjectNamespace) -> Result<Vec<Connection>> {
+    registry_call(registry, "/list", None).await?.json().await
+}
+
+// false when no such tunnel is open. the tunnel learns about it on its next
+// report and is closed then.
+pub async fn terminate(registry: &ObjectNamespace, id: &str) -> Result<bool> {
+    let res = registry_call(registry, &format!("/terminate?id={}", id), None).await?;
+    Ok(res.status_code() == 200)
+}
+
+async fn registry_call(registry: &ObjectNamespace, path: &str, body: Option<String>) -> Result<Response> {
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post).with_body(body.map(Into::into));
+    let req = Request::new_with_init(&format!("https://registry{}", path), &init)?;
+    registry.id_from_name("global")?.get_stub()?.fetch_with_request(req).await
+}
+
+// single global instance holding every open tunnel, in memory like the
+// limiter's leases
+#[durable_object]
+pub struct ConnectionRegistry {
+    connections: HashMap<String, (Connection, u64)>,
+    terminated: HashSet<String>,
+}
+
+#[durable_object]
+impl DurableObject for ConnectionRegistry {
+    fn new(state: State, _env: Env) -> Self {
+        let _ = state;
+        Self {
+            connections: HashMap::new(),
+            terminated: HashSet::new(),
+        }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let url = req.url()?;
+        let id = url.query_pairs().find(|(k, _)| k == "id").map(|(_, v)| v.to_string());
+
+        let now = Date::now().as_millis();
+        self.connections.retain(|_, (_, seen)| now.saturating_sub(*seen) < STALE_AFTER);
+        self.terminated.retain(|id| self.connections.contains_key(id));
+
+        match (url.path(), id) {
+            ("/report", _) => {
+                let connection: Connection = req.json().await?;
+                if self.terminated.remove(&connection.id) {
+                    self.connections.remove(&connection.id);
+                    return Response::error("terminated", 410);
+                }
+                self.connections.insert(connection.id.clone(), (connection, now));
+                Response::ok("")
+            }
+            ("/close", Some(id)) => {
+                self.connections.remove(&id);
+                self.terminated.remove(&id);
+                Response::ok("")
+            }
+            ("/list", _) => {
+                let mut connections: Vec<_> = self.connections.values().map(|(x, _)| x.clone()).collect();
+                connections.sort_by_key(|x| x.started_at);
+                Response::from_json(&connections)
+            }
+            ("/terminate", Some(id)) if self.connections.contains_key(&id) => {
+                self.terminated.insert(id);
+                Response::ok("")
+            }
+            ("/terminate", Some(_)) => Response::error("no such connection", 404),
+            _ => Response::error("not found", 404),
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// signed, expiring subscription urls. with the SUB_SIGNING_KEY secret set,
+// /sub only answers `?<query>&expires=<unix secs>&sig=<hex>` where sig is the
+// hmac-sha256 of "<path>?<query>:<expires>", so a leaked link dies with its
+// window and can't be pointed at another uuid or filter. <query> is the
+// canonical form of the subscription params, see `canonical_query`.
+use crate::common::{hash, secure};
+
+use worker::*;
+
+pub static SUB_PATH: &str = "/sub";
+pub static DEFAULT_TTL: u64 = 7 * 24 * 60 * 60; // 1 week
+// what a signature covers besides the expiry, in this order
+static SIGNED_PARAMS: [&str; 5] = ["uuid", "format", "type", "country", "tls"];
+
+// the signed params `url` carries, in SIGNED_PARAMS order and url encoded so
+// one param's value can't pass for another param
+fn canonical_query(url: &Url) -> String {
+    let mut canonical = url.clone();
+    canonical.set_query(None);
+    for name in SIGNED_PARAMS {
+        if let Some((_, value)) = url.query_pairs().find(|(k, _)| k == name) {
+            canonical.query_pairs_mut().append_pair(name, &value);
+        }
+    }
+    canonical.query().unwrap_or_default().to_string()
+}
+
+pub fn signature(key: &[u8], path: &str, query: &str, expires: u64) -> String {
+    hash::hmac_sha256(key, format!("{}?{}:{}", path, query, expires).as_bytes())
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+fn verify(key: &[u8], path: &str, query: &str, expires: u64, sig: &str, now: u64) -> bool {
+    now < expires && secure::ct_eq(signature(key, path, query, expires), sig.to_ascii_lowercase())
+}
+
+fn signing_key(env: &Env) -> Option<String> {
+    env.secret("SUB_SIGNING_KEY").map(|x| x.to_string()).ok()
+}
+
+// true when the url carries a valid /sub signature, or signing isn't
+// configured. /sub/raw takes the same links.
+pub fn is_valid(req: &Request, env: &Env) -> Result<bool> {
+    if signing_key(env).is_none() {
+        return Ok(true);
+    }
+    is_signed(req, env, SUB_PATH)
+}
+
+// true when the url carries a valid signature for `path` and its
+// subscription params, which lets other routes accept what was signed for
+// /sub. false without a key.
+pub fn is_signed(req: &Request, env: &Env, path: &str) -> Result<bool> {
+    let Some(key) = signing_key(env) else {
+        return Ok(false);
+    };
+    let url = req.url()?;
+    let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.to_string());
+    let (Some(expires), Some(sig)) = (param("expires").and_then(|x| x.parse().ok()), param("sig")) else {
+        return Ok(false);
+    };
+    Ok(verify(key.as_bytes(), path, &canonical_query(&url), expires, &sig, Date::now().as_millis() / 1000))
+}
+
+// a signed `path` on `base` valid for `ttl` seconds, for the subscription
+// params `base` carries. None without a key.
+pub fn sign_url(env: &Env, mut base: Url, path: &str, ttl: u64) -> Option<(Url, u64)> {
+    let key = signing_key(env)?;
+    let expires = Date::now().as_millis() / 1000 + ttl;
+    let query = canonical_query(&base);
+    let sig = signature(key.as_bytes(), path, &query, expires);
+    base.set_path(path);
+    base.set_query((!query.is_empty()).then_some(&query));
+    base.query_pairs_mut().append_pair("expires", &expires.to_string()).append_pair("sig", &sig);
+    Some((base, expires))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let sig = signature(b"key", "/sub", "uuid=a", 1000);
+        assert!(verify(b"key", "/sub", "uuid=a", 1000, &sig, 999));
+        assert!(verify(b"key", "/sub", "uuid=a", 1000, &sig.to_uppercase(), 999));
+        // expired, another path, query or expiry, another key
+        assert!(!verify(b"key", "/sub", "uuid=a", 1000, &sig, 1000));
+        assert!(!verify(b"key", "/link", "uuid=a", 1000, &sig, 999));
+        assert!(!verify(b"key", "/sub", "uuid=b", 1000, &sig, 999));
+        assert!(!verify(b"key", "/sub", "uuid=a", 2000, &sig, 999));
+        assert!(!verify(b"other", "/sub", "uuid=a", 1000, &sig, 999));
+    }
+
+    #[test]
+    fn test_canonical_query() {
+        let canonical = |url: &str| canonical_query(&Url::parse(url).unwrap());
+        // fixed order, unsigned params and repeats dropped
+        assert_eq!(
+            canonical("https://x/sub?tls=false&country=SG,JP&page=2&uuid=a&format=clash&type=vless&expires=1&sig=f&uuid=b"),
+            "uuid=a&format=clash&type=vless&country=SG%2CJP&tls=false"
+        );
+        assert_eq!(canonical("https://x/sub?expires=1&sig=f"), "");
+        // a value can't smuggle in another param
+        assert_ne!(canonical("https://x/sub?country=SG%26tls%3Dfalse"), canonical("https://x/sub?country=SG&tls=false"));
+    }
+}
@@ -0,0 +1,321 @@
+// where user records and usage live. kv by default; with a D1 database bound
+// as DB the same data goes to sql tables (migrations/), which reporting can
+// query with joins and aggregates kv listings can't express.
+use crate::accounting::Usage;
+use crate::users::Record;
+
+use serde::Deserialize;
+use uuid::Uuid;
+use wasm_bindgen::JsValue;
+use worker::*;
+
+static USERS_KEY: &str = "users";
+static USER_PATH_PREFIX: &str = "user-path:";
+static DAILY_PREFIX: &str = "usage-day:";
+static DAILY_TTL: u64 = 400 * 24 * 60 * 60; // a bit over a year of history
+
+pub trait Store {
+    async fn usage(&self, uuid: &Uuid) -> Result<Usage>;
+    // adds `delta`'s counters to the user's totals and takes its last_seen,
+    // returning the totals after the add. the user's record for `day`
+    // (yyyy-mm-dd) gets them too, once the totals landed an error there is
+    // only logged so callers can't add the same delta twice.
+    async fn add_usage(&self, day: &str, uuid: &Uuid, delta: &Usage) -> Result<Usage>;
+    async fn put_expiry(&self, uuid: &Uuid, expires_at: Option<u64>, notified: bool) -> Result<()>;
+    // per user and day records between `from` and `to`, inclusive
+    async fn daily_usage(&self, from: &str, to: &str) -> Result<Vec<(String, Uuid, Usage)>>;
+    async fn users(&self) -> Result<Vec<Record>>;
+    // the user whose secret tunnel path is `token`, looked up by key
+    async fn user_by_path(&self, token: &str) -> Result<Option<Record>>;
+}
+
+fn usage_key(uuid: &Uuid) -> String {
+    format!("usage:{}", uuid)
+}
+
+// "usage-day:2024-05-23:<uuid>", sorted by day so exports can stop early
+fn daily_key(day: &str, uuid: &Uuid) -> String {
+    format!("{}{}:{}", DAILY_PREFIX, day, uuid)
+}
+
+// adds `delta` to the user's record for `day`, kv has no upsert either
+async fn add_daily(kv: &kv::KvStore, day: &str, uuid: &Uuid, delta: &Usage) -> Result<()> {
+    let key = daily_key(day, uuid);
+    let mut daily: Usage = kv.get(&key).json().await?.unwrap_or_default();
+    daily.bytes_up += delta.bytes_up;
+    daily.bytes_down += delta.bytes_down;
+    daily.sessions += delta.sessions;
+    daily.last_seen = delta.last_seen;
+    kv.put(&key, serde_json::to_string(&daily)?)?.expiration_ttl(DAILY_TTL).execute().await?;
+    Ok(())
+}
+
+impl Store for kv::KvStore {
+    async fn usage(&self, uuid: &Uuid) -> Result<Usage> {
+        Ok(self.get(&usage_key(uuid)).json().await?.unwrap_or_default())
+    }
+
+    // kv has no atomic update, concurrent sessions of a user may race here
+    async fn add_usage(&self, day: &str, uuid: &Uuid, delta: &Usage) -> Result<Usage> {
+        let mut usage = self.usage(uuid).await?;
+        usage.bytes_up += delta.bytes_up;
+        usage.bytes_down += delta.bytes_down;
+        usage.sessions += delta.sessions;
+        usage.last_seen = delta.last_seen;
+        self.put(&usage_key(uuid), serde_json::to_string(&usage)?)?.execute().await?;
+        if let Err(e) = add_daily(self, day, uuid, delta).await {
+            console_log!("[storage]: daily usage of {} failed: {}", uuid, e);
+        }
+        Ok(usage)
+    }
+
+    async fn put_expiry(&self, uuid: &Uuid, expires_at: Option<u64>, notified: bool) -> Result<()> {
+        let mut usage = self.usage(uuid).await?;
+        usage.expires_at = expires_at;
+        usage.expiry_notified = notified;
+        self.put(&usage_key(uuid), serde_json::to_string(&usage)?)?.execute().await?;
+        Ok(())
+    }
+
+    async fn daily_usage(&self, from: &str, to: &str) -> Result<Vec<(String, Uuid, Usage)>> {
+        let mut records = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut list = self.list().prefix(DAILY_PREFIX.to_string());
+            if let Some(cursor) = cursor.take() {
+                list = list.cursor(cursor);
+            }
+            let page = list.execute().await?;
+
+            for key in page.keys {
+                let Some((day, uuid)) = key.name[DAILY_PREFIX.len()..].split_once(':') else {
+                    continue;
+                };
+                if day > to {
+                    return Ok(records);
+                }
+                let Ok(uuid) = Uuid::parse_str(uuid) else {
+                    continue;
+                };
+                if day < from {
+                    continue;
+                }
+                if let Some(usage) = self.get(&key.name).json().await? {
+                    records.push((day.to_string(), uuid, usage));
+                }
+            }
+
+            if page.list_complete || page.cursor.is_none() {
+                return Ok(records);
+            }
+            cursor = page.cursor;
+        }
+    }
+
+    // `users` holds the whole table as one json array
+    async fn users(&self) -> Result<Vec<Record>> {
+        Ok(self.get(USERS_KEY).json().await?.unwrap_or_default())
+    }
+
+    // "user-path:<token>" holds the user's uuid
+    async fn user_by_path(&self, token: &str) -> Result<Option<Record>> {
+        let uuid = self.get(&format!("{}{}", USER_PATH_PREFIX, token)).text().await?;
+        Ok(uuid.map(|uuid| Record { uuid, psk: None }))
+    }
+}
+
+// a usage or usage_daily row, d1 has no booleans
+#[derive(Deserialize)]
+struct UsageRow {
+    #[serde(default)]
+    day: String,
+    uuid: String,
+    bytes_up: u64,
+    bytes_down: u64,
+    sessions: u64,
+    last_seen: u64,
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    expiry_notified: u8,
+}
+
+impl From<UsageRow> for Usage {
+    fn from(row: UsageRow) -> Self {
+        Self {
+            bytes_up: row.bytes_up,
+            bytes_down: row.bytes_down,
+            sessions: row.sessions,
+            last_seen: row.last_seen,
+            expires_at: row.expires_at,
+            expiry_notified: row.expiry_notified != 0,
+        }
+    }
+}
+
+// javascript numbers, exact up to 2^53 bytes
+fn number(x: u64) -> JsValue {
+    JsValue::from_f64(x as f64)
+}
+
+impl Store for D1Database {
+    async fn usage(&self, uuid: &Uuid) -> Result<Usage> {
+        let row: Option<UsageRow> = self
+            .prepare("SELECT * FROM usage WHERE uuid = ?1")
+            .bind(&[uuid.to_string().into()])?
+            .first(None)
+            .await?;
+        Ok(row.map(Usage::from).unwrap_or_default())
+    }
+
+    // two upserts in one batch, so concurrent sessions don't lose bytes and
+    // the totals and the day's record land together or not at all
+    async fn add_usage(&self, day: &str, uuid: &Uuid, delta: &Usage) -> Result<Usage> {
+        let totals = self
+            .prepare(
+                "INSERT INTO usage (uuid, bytes_up, bytes_down, sessions, last_seen) VALUES (?1, ?2, ?3, ?4, ?5) \
+                 ON CONFLICT (uuid) DO UPDATE SET bytes_up = bytes_up + excluded.bytes_up, \
+                 bytes_down = bytes_down + excluded.bytes_down, sessions = sessions + excluded.sessions, last_seen = excluded.last_seen \
+                 RETURNING *",
+            )
+            .bind(&[
+                uuid.to_string().into(),
+                number(delta.bytes_up),
+                number(delta.bytes_down),
+                number(delta.sessions),
+                number(delta.last_seen),
+            ])?;
+        let daily = self
+            .prepare(
+                "INSERT INTO usage_daily (day, uuid, bytes_up, bytes_down, sessions, last_seen) VALUES (?1, ?2, ?3, ?4, ?5, ?6) \
+                 ON CONFLICT (day, uuid) DO UPDATE SET bytes_up = bytes_up + excluded.bytes_up, \
+                 bytes_down = bytes_down + excluded.bytes_down, sessions = sessions + excluded.sessions, last_seen = excluded.last_seen",
+            )
+            .bind(&[
+                day.into(),
+                uuid.to_string().into(),
+                number(delta.bytes_up),
+                number(delta.bytes_down),
+                number(delta.sessions),
+                number(delta.last_seen),
+            ])?;
+        let results = self.batch(vec![totals, daily]).await?;
+        let row = match results.first() {
+            Some(result) => result.results::<UsageRow>()?.into_iter().next(),
+            None => None,
+        };
+        row.map(Usage::from).ok_or_else(|| Error::RustError("usage upsert returned no row".to_string()))
+    }
+
+    // leaves the counters alone, sessions may be adding to them meanwhile
+    async fn put_expiry(&self, uuid: &Uuid, expires_at: Option<u64>, notified: bool) -> Result<()> {
+        self.prepare(
+            "INSERT INTO usage (uuid, expires_at, expiry_notified) VALUES (?1, ?2, ?3) \
+             ON CONFLICT (uuid) DO UPDATE SET expires_at = excluded.expires_at, expiry_notified = excluded.expiry_notified",
+        )
+        .bind(&[uuid.to_string().into(), expires_at.map_or(JsValue::NULL, number), number(notified as u64)])?
+        .run()
+        .await?;
+        Ok(())
+    }
+
+    async fn daily_usage(&self, from: &str, to: &str) -> Result<Vec<(String, Uuid, Usage)>> {
+        let rows: Vec<UsageRow> = self
+            .prepare("SELECT * FROM usage_daily WHERE day BETWEEN ?1 AND ?2 ORDER BY day, uuid")
+            .bind(&[from.into(), to.into()])?
+            .all()
+            .await?
+            .results()?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| Some((row.day.clone(), Uuid::parse_str(&row.uuid).ok()?, row.into())))
+            .collect())
+    }
+
+    async fn users(&self) -> Result<Vec<Record>> {
+        self.prepare("SELECT uuid, psk FROM users").all().await?.results()
+    }
+
+    async fn user_by_path(&self, token: &str) -> Result<Option<Record>> {
+        self.prepare("SELECT uuid, psk FROM users WHERE path = ?1")
+            .bind(&[token.into()])?
+            .first(None)
+            .await
+    }
+}
+
+// d1 when bound, else kv. kv stays at hand for what never moves to d1:
+// alert dedup, bans and metrics
+pub struct Storage {
+    pub kv: kv::KvStore,
+    db: Option<D1Database>,
+}
+
+impl Storage {
+    pub fn from_env(env: &Env) -> Result<Self> {
+        Ok(Self { kv: env.kv("library")?, db: env.d1("DB").ok() })
+    }
+}
+
+impl Store for Storage {
+    async fn usage(&self, uuid: &Uuid) -> Result<Usage> {
+        match &self.db {
+            Some(db) => db.usage(uuid).await,
+            None => self.kv.usage(uuid).await,
+        }
+    }
+
+    async fn add_usage(&self, day: &str, uuid: &Uuid, delta: &Usage) -> Result<Usage> {
+        match &self.db {
+            Some(db) => db.add_usage(day, uuid, delta).await,
+            None => self.kv.add_usage(day, uuid, delta).await,
+        }
+    }
+
+    async fn put_expiry(&self, uuid: &Uuid, expires_at: Option<u64>, notified: bool) -> Result<()> {
+        match &self.db {
+            Some(db) => db.put_expiry(uuid, expires_at, notified).await,
+            None => self.kv.put_expiry(uuid, expires_at, notified).await,
+        }
+    }
+
+    async fn daily_usage(&self, from: &str, to: &str) -> Result<Vec<(String, Uuid, Usage)>> {
+        match &self.db {
+            Some(db) => db.daily_usage(from, to).await,
+            None => self.kv.daily_usage(from, to).await,
+        }
+    }
+
+    async fn users(&self) -> Result<Vec<Record>> {
+        match &self.db {
+            Some(db) => db.users().await,
+            None => self.kv.users().await,
+        }
+    }
+
+    async fn user_by_path(&self, token: &str) -> Result<Option<Record>> {
+        match &self.db {
+            Some(db) => db.user_by_path(token).await,
+            None => self.kv.user_by_path(token).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_usage_row() {
+        let row: UsageRow = serde_json::from_value(json!({
+            "uuid": "38425afe-8466-4876-8223-f3d604ca3c18",
+            "bytes_up": 1, "bytes_down": 2, "sessions": 3, "last_seen": 4,
+            "expires_at": null, "expiry_notified": 1,
+        }))
+        .unwrap();
+        let usage = Usage::from(row);
+        assert_eq!((usage.total(), usage.sessions, usage.expires_at), (3, 3, None));
+        assert!(usage.expiry_notified);
+    }
+}
@@ -0,0 +1,376 @@
+// subscription bodies rendered on the worker, for clients fetching
+// /sub?format= directly instead of going through the generator page. every
+// proxy becomes one link per protocol, pointing at this host with the proxy
+// as the path, e.g. wss://<host>/1.2.3.4-443.
+use crate::common::digest;
+use crate::proxylist::{self, Proxy, ProxyList};
+use crate::signing;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+use worker::*;
+
+static CACHE_PATH: &str = "/__sub/";
+static CACHE_TTL: u64 = 5 * 60; // 5 minutes
+pub static DEFAULT_LIMIT: usize = 100;
+pub static MAX_LIMIT: usize = 1000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // base64 of the share links, what v2rayN and most apps import
+    V2ray,
+    Clash,
+    // the share links one per line, for copying and clients that don't
+    // take base64
+    Raw,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "v2ray" => Some(Self::V2ray),
+            "clash" => Some(Self::Clash),
+            "raw" => Some(Self::Raw),
+            _ => None,
+        }
+    }
+
+    // what a client fetching /sub without `format=` gets, by its User-Agent
+    // like subconverter does. the sing-box based apps import share links.
+    pub fn from_user_agent(user_agent: &str) -> Option<Self> {
+        let ua = user_agent.to_ascii_lowercase();
+        if ["clash", "mihomo", "stash"].iter().any(|x| ua.contains(x)) {
+            return Some(Self::Clash);
+        }
+        if ["v2rayn", "v2rayng", "shadowrocket", "sing-box", "hiddify", "nekobox", "quantumult"].iter().any(|x| ua.contains(x)) {
+            return Some(Self::V2ray);
+        }
+        None
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::V2ray => "v2ray",
+            Self::Clash => "clash",
+            Self::Raw => "raw",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::V2ray | Self::Raw => "text/plain; charset=utf-8",
+            Self::Clash => "application/x-yaml; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Vless,
+    Vmess,
+    Trojan,
+    Shadowsocks,
+}
+
+impl Protocol {
+    // "mix" is all of them, like on the generator page
+    fn parse_list(name: &str) -> Option<Vec<Self>> {
+        match name {
+            "vless" => Some(vec![Self::Vless]),
+            "vmess" => Some(vec![Self::Vmess]),
+            "trojan" => Some(vec![Self::Trojan]),
+            "ss" | "shadowsocks" => Some(vec![Self::Shadowsocks]),
+            "mix" => Some(vec![Self::Vless, Self::Vmess, Self::Trojan, Self::Shadowsocks]),
+            _ => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Vless => "VLESS",
+            Self::Vmess => "VMESS",
+            Self::Trojan => "TROJAN",
+            Self::Shadowsocks => "SS",
+        }
+    }
+}
+
+// what a subscription is rendered from, all but the host and the
+// shadowsocks credentials come from the query
+pub struct Options {
+    pub uuid: Uuid,
+    pub host: String,
+    pub format: Format,
+    pub protocols: Vec<Protocol>,
+    // empty for every country
+    pub countries: Vec<String>,
+    pub tls: bool,
+    // 1-based
+    pub page: usize,
+    pub limit: usize,
+    // method and password, "none" and the uuid unless configured
+    pub shadowsocks: (String, String),
+}
+
+impl Options {
+    // ?uuid=&format=&type=vless|vmess|trojan|ss|mix&country=SG,JP&tls=false
+    // &page=&limit=
+    pub fn from_url(url: &Url, host: &str, format: Format) -> std::result::Result<Self, &'static str> {
+        let param = |name: &str| url.query_pairs().find(|(k, _)| k == name).map(|(_, v)| v.to_string());
+        let uuid = param("uuid").and_then(|x| Uuid::parse_str(&x).ok()).ok_or("missing or invalid uuid")?;
+        let protocols = Protocol::parse_list(&param("type").unwrap_or_else(|| "vless".to_string())).ok_or("unknown type")?;
+        let countries = param("country")
+            .map(|x| x.split(',').map(|x| x.trim().to_uppercase()).filter(|x| !x.is_empty()).collect())
+            .unwrap_or_default();
+        let number = |name: &str, default: usize| match param(name) {
+            Some(x) => x.parse::<usize>().ok().filter(|x| *x > 0).ok_or("page and limit must be positive numbers"),
+            None => Ok(default),
+        };
+        Ok(Self {
+            uuid,
+            host: host.to_string(),
+            format,
+            protocols,
+            countries,
+            tls: param("tls").is_none_or(|x| x != "false" && x != "0"),
+            page: number("page", 1)?,
+            limit: number("limit", DEFAULT_LIMIT)?.min(MAX_LIMIT),
+            shadowsocks: ("none".to_string(), uuid.to_string()),
+        })
+    }
+
+    // everything the body depends on, the uuid and password only as a
+    // digest. the cache api keys by url, so it's one on this host.
+    fn cache_key(&self) -> String {
+        let secret = format!("{}:{}:{}", self.uuid, self.shadowsocks.0, self.shadowsocks.1);
+        let fingerprint: String = digest::sha256(&[secret.as_bytes()])[..8].iter().map(|x| format!("{:02x}", x)).collect();
+        let protocols: Vec<_> = self.protocols.iter().map(|x| x.label()).collect();
+        format!(
+            "https://{}{}{}:{}:{}:{}:{}:{}:{}",
+            self.host,
+            CACHE_PATH,
+            fingerprint,
+            self.format.name(),
+            protocols.join(","),
+            self.countries.join(","),
+            self.tls,
+            self.page,
+            self.limit
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Page {
+    pub body: String,
+    // proxies matching the filters across all pages
+    pub total: usize,
+}
+
+// from the colo's cache when rendered in the last few minutes, proxy list
+// edits show up once that runs out. not kv: the query is the caller's to
+// pick and every miss would be a write against the namespace's quota.
+pub async fn cached(kv: &kv::KvStore, options: &Options) -> Result<Page> {
+    let cache = Cache::default();
+    let key = options.cache_key();
+    if let Some(mut res) = cache.get(&key, true).await? {
+        return res.json().await;
+    }
+    let page = render(&proxylist::load(kv).await?, options);
+    let mut res = Response::from_json(&page)?;
+    res.headers_mut().set("Cache-Control", &format!("max-age={}", CACHE_TTL))?;
+    cache.put(&key, res).await?;
+    Ok(page)
+}
+
+pub fn render(list: &ProxyList, options: &Options) -> Page {
+    let mut entries: Vec<_> = list
+        .iter()
+        .filter(|(country, _)| options.countries.is_empty() || options.countries.contains(country))
+        .flat_map(|(country, proxies)| proxies.iter().map(move |x| (country.as_str(), x)))
+        .collect();
+    entries.sort_by_key(|(country, proxy)| (*country, proxylist::key_of(proxy)));
+    let total = entries.len();
+
+    let start = (options.page - 1).saturating_mul(options.limit).min(total);
+    let end = (start + options.limit).min(total);
+    let items = entries[start..end]
+        .iter()
+        .flat_map(|(country, proxy)| options.protocols.iter().map(move |protocol| (*country, *proxy, *protocol)));
+
+    let body = match options.format {
+        Format::V2ray | Format::Raw => {
+            let links: Vec<_> = items.map(|(country, proxy, protocol)| link(options, country, proxy, protocol)).collect();
+            let links = links.join("\n");
+            if options.format == Format::Raw { links } else { STANDARD.encode(links) }
+        }
+        Format::Clash => {
+            let mut yaml = String::from("proxies:\n");
+            for (country, proxy, protocol) in items {
+                yaml.push_str(&clash_proxy(options, country, proxy, protocol));
+            }
+            yaml
+        }
+    };
+    Page { body, total }
+}
+
+fn name(options: &Options, country: &str, proxy: &Proxy, protocol: Protocol) -> String {
+    let label = proxy.label.clone().unwrap_or_else(|| proxylist::key_of(proxy));
+    format!("{} {} [{}-{}]", country, label, protocol.label(), if options.tls { "TLS" } else { "NTLS" })
+}
+
+fn path(proxy: &Proxy) -> String {
+    format!("/{}-{}", proxy.addr, proxy.port)
+}
+
+fn port(options: &Options) -> u16 {
+    if options.tls { 443 } else { 80 }
+}
+
+// percent-encodes everything but rfc 3986's unreserved characters
+fn encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn link(options: &Options, country: &str, proxy: &Proxy, protocol: Protocol) -> String {
+    let (host, port, name) = (&options.host, port(options), name(options, country, proxy, protocol));
+    let security = if options.tls { "tls" } else { "none" };
+    let ws = format!("type=ws&host={}&path={}&sni={}", host, encode(&path(proxy)), host);
+    match protocol {
+        Protocol::Vless => format!("vless://{}@{}:{}?encryption=none&security={}&{}#{}", options.uuid, host, port, security, ws, encode(&name)),
+        Protocol::Trojan => format!("trojan://{}@{}:{}?security={}&{}#{}", options.uuid, host, port, security, ws, encode(&name)),
+        Protocol::Vmess => {
+            let config = json!({
+                "v": "2", "ps": name, "add": host, "port": port, "id": options.uuid.to_string(), "aid": "0", "scy": "zero",
+                "net": "ws", "type": "none", "host": host, "path": path(proxy), "tls": if options.tls { "tls" } else { "" }, "sni": host,
+            });
+            format!("vmess://{}", STANDARD.encode(config.to_string()))
+        }
+        // sip002, the 2022 methods take their base64 key percent-encoded
+        // instead of base64 userinfo
+        Protocol::Shadowsocks => {
+            let (method, password) = &options.shadowsocks;
+            let userinfo = match method.starts_with("2022-") {
+                true => format!("{}:{}", encode(method), encode(password)),
+                false => URL_SAFE_NO_PAD.encode(format!("{}:{}", method, password)),
+            };
+            let tls = if options.tls { "tls;" } else { "" };
+            let plugin = format!("v2ray-plugin;mode=websocket;{}host={};path={};mux=0", tls, host, path(proxy));
+            format!("ss://{}@{}:{}/?plugin={}#{}", userinfo, host, port, encode(&plugin), encode(&name))
+        }
+    }
+}
+
+fn clash_proxy(options: &Options, country: &str, proxy: &Proxy, protocol: Protocol) -> String {
+    let (host, port, name) = (&options.host, port(options), name(options, country, proxy, protocol));
+    let ws_opts = format!("  network: ws\n  ws-opts:\n    path: {}\n    headers:\n      Host: {}\n", path(proxy), host);
+    let head = format!("- name: \"{}\"\n  server: {}\n  port: {}\n  udp: true\n  skip-cert-verify: true\n", name, host, port);
+    match protocol {
+        Protocol::Vless => format!("{}  type: vless\n  uuid: {}\n  tls: {}\n  servername: {}\n{}", head, options.uuid, options.tls, host, ws_opts),
+        Protocol::Vmess => format!("{}  type: vmess\n  uuid: {}\n  alterId: 0\n  cipher: zero\n  tls: {}\n  servername: {}\n{}", head, options.uuid, options.tls, host, ws_opts),
+        Protocol::Trojan => format!("{}  type: trojan\n  password: {}\n  sni: {}\n{}", head, options.uuid, host, ws_opts),
+        Protocol::Shadowsocks => {
+            let (method, password) = &options.shadowsocks;
+            format!(
+                "{}  type: ss\n  cipher: {}\n  password: \"{}\"\n  plugin: v2ray-plugin\n  plugin-opts:\n    mode: websocket\n    tls: {}\n    host: {}\n    path: {}\n    mux: false\n",
+                head, method, password, options.tls, host, path(proxy)
+            )
+        }
+    }
+}
+
+// what the link and sub pages need to build configs for `host`, matching
+// `link`. every protocol shares the tunnel path, "{proxy}" being an
+// "addr-port" or country codes like "SG,JP".
+pub fn settings(host: &str, uuid: Option<&Uuid>, shadowsocks_method: &str) -> serde_json::Value {
+    let path = "/{proxy}";
+    json!({
+        "host": host,
+        "uuid": uuid.map(Uuid::to_string),
+        "sni": host,
+        "network": "ws",
+        "ports": { "tls": 443, "ntls": 80 },
+        "early_data_header": "Sec-WebSocket-Protocol",
+        "protocols": {
+            "vless": { "path": path, "encryption": "none" },
+            "vmess": { "path": path, "alter_id": 0, "security": "zero" },
+            "trojan": { "path": path },
+            "ss": { "path": path, "method": shadowsocks_method, "plugin": "v2ray-plugin", "mode": "websocket" },
+        },
+        "subscription": { "path": signing::SUB_PATH, "formats": [Format::V2ray.name(), Format::Clash.name(), Format::Raw.name()] },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(query: &str) -> Options {
+        let url = Url::parse(&format!("https://sub.example.com/sub?uuid=38425afe-8466-4876-8223-f3d604ca3c18&{}", query)).unwrap();
+        Options::from_url(&url, "sub.example.com", Format::V2ray).unwrap()
+    }
+
+    #[test]
+    fn test_from_user_agent() {
+        assert_eq!(Format::from_user_agent("ClashMetaForAndroid/2.10.1.Meta"), Some(Format::Clash));
+        assert_eq!(Format::from_user_agent("mihomo/1.18.3"), Some(Format::Clash));
+        assert_eq!(Format::from_user_agent("v2rayN/6.42"), Some(Format::V2ray));
+        assert_eq!(Format::from_user_agent("Shadowrocket/2070 CFNetwork/1496.0.7 Darwin/23.5.0"), Some(Format::V2ray));
+        assert_eq!(Format::from_user_agent("SFA/1.9.0 (sing-box 1.9.0)"), Some(Format::V2ray));
+        assert_eq!(Format::from_user_agent("Mozilla/5.0 (X11; Linux x86_64) Firefox/126.0"), None);
+    }
+
+    #[test]
+    fn test_link() {
+        let list = proxylist::parse(r#"{"SG": ["1.1.1.1:443#SG Oracle"]}"#).unwrap();
+        let proxy = &list["SG"][0];
+        assert_eq!(
+            link(&options(""), "SG", proxy, Protocol::Vless),
+            "vless://38425afe-8466-4876-8223-f3d604ca3c18@sub.example.com:443?encryption=none&security=tls&type=ws&host=sub.example.com&path=%2F1.1.1.1-443&sni=sub.example.com#SG%20SG%20Oracle%20%5BVLESS-TLS%5D"
+        );
+        assert_eq!(
+            link(&options("tls=false"), "SG", proxy, Protocol::Shadowsocks),
+            "ss://bm9uZTozODQyNWFmZS04NDY2LTQ4NzYtODIyMy1mM2Q2MDRjYTNjMTg@sub.example.com:80/?plugin=v2ray-plugin%3Bmode%3Dwebsocket%3Bhost%3Dsub.example.com%3Bpath%3D%2F1.1.1.1-443%3Bmux%3D0#SG%20SG%20Oracle%20%5BSS-NTLS%5D"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let list = proxylist::parse(r#"{"SG": ["1.1.1.1:443", "2.2.2.2:443"], "JP": ["3.3.3.3:443"]}"#).unwrap();
+        let paths = |query: &str| {
+            let page = render(&list, &options(query));
+            let body = String::from_utf8(STANDARD.decode(page.body).unwrap()).unwrap();
+            let paths = body.lines().filter_map(|x| x.split("path=%2F").nth(1)?.split('&').next()).map(str::to_string).collect::<Vec<_>>();
+            (page.total, paths)
+        };
+        // sorted by country then proxy, paged after filtering
+        assert_eq!(paths("limit=2"), (3, vec!["3.3.3.3-443".to_string(), "1.1.1.1-443".to_string()]));
+        assert_eq!(paths("limit=2&page=2"), (3, vec!["2.2.2.2-443".to_string()]));
+        assert_eq!(paths("country=sg&type=vless").1.len(), 2);
+        assert_eq!(paths("page=9"), (3, vec![]));
+        assert_eq!(render(&list, &options("type=mix")).total, 3);
+        let mut raw = options("limit=1");
+        raw.format = Format::Raw;
+        assert!(render(&list, &raw).body.starts_with("vless://"));
+        assert!(Options::from_url(&Url::parse("https://x/sub?uuid=x").unwrap(), "x", Format::V2ray).is_err());
+    }
+
+    #[test]
+    fn test_settings() {
+        let uuid = Uuid::parse_str("38425afe-8466-4876-8223-f3d604ca3c18").unwrap();
+        let settings = settings("sub.example.com", Some(&uuid), "none");
+        assert_eq!(settings["uuid"], uuid.to_string());
+        assert_eq!(settings["protocols"]["vmess"]["security"], "zero");
+        assert_eq!(settings["ports"]["ntls"], port(&options("tls=false")));
+        assert!(super::settings("x", None, "none")["uuid"].is_null());
+    }
+}
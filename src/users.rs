@@ -0,0 +1,63 @@
+// the user table: in kv `users` holds a json array of
+// {"uuid": "...", "psk": "<base64>"}, in d1 it's the users table with the
+// same columns plus `path` (see `storage`). the psk is only needed by
+// shadowsocks 2022 multi-user deployments. users with a secret tunnel path
+// are looked up by it, in kv through a `user-path:<path>` key holding the
+// uuid. usage is accounted to the uuid.
+use crate::common::protocol::shadowsocks_body::{self, Method, User};
+use crate::common::secure;
+use crate::storage::{Storage, Store};
+
+use serde::Deserialize;
+use uuid::Uuid;
+use worker::*;
+
+#[derive(Deserialize)]
+pub struct Record {
+    pub uuid: String,
+    #[serde(default)]
+    pub psk: Option<String>,
+}
+
+// entries with a malformed uuid or a psk of the wrong length are skipped
+pub async fn shadowsocks_users(storage: &Storage, method: Method) -> Result<Vec<User>> {
+    let entries = storage.users().await?;
+    Ok(entries
+        .into_iter()
+        .filter_map(|entry| {
+            let uuid = Uuid::parse_str(&entry.uuid).ok()?;
+            let psk = shadowsocks_body::decode_psk(method, entry.psk.as_deref()?)
+                .map_err(|e| console_warn!("[users]: {} {}", uuid, e))
+                .ok()?;
+            Some(User { uuid, psk })
+        })
+        .collect())
+}
+
+// the user whose secret path is `token`, for `/:token/:proxyip` tunnels
+pub async fn path_user(storage: &Storage, token: &str) -> Result<Option<Uuid>> {
+    if token.is_empty() {
+        return Ok(None);
+    }
+    let entry = storage.user_by_path(token).await?;
+    Ok(entry.and_then(|entry| Uuid::parse_str(&entry.uuid).ok()))
+}
+
+// whether the `/:token/:proxyip` segment is a user's secret path rather than
+// the TUNNEL_TOKEN. with both configured either one opens the tunnel.
+pub fn is_user_path(token: &str, tunnel_token: Option<&str>) -> bool {
+    !tunnel_token.is_some_and(|expected| secure::ct_eq(token, expected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_user_path() {
+        assert!(is_user_path("alice-path", None));
+        assert!(is_user_path("alice-path", Some("tunnel-token")));
+        assert!(!is_user_path("tunnel-token", Some("tunnel-token")));
+        assert!(is_user_path("tunnel-token-", Some("tunnel-token")));
+    }
+}